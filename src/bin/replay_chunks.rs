@@ -0,0 +1,118 @@
+use clap::Parser;
+use snapchain::storage::store::engine::ChunkDivergence;
+use snapchain::utils::chunk_replay::{replay_chunks, ChunkSource, ReplayOptions};
+use std::error::Error;
+use std::process;
+
+/// Replays a shard's chunks into a fresh engine and reports the first one that doesn't replay to
+/// the state it committed to.
+#[derive(Parser)]
+struct Cli {
+    /// Path to a stopped node's shard db (e.g. .rocks/shard1)
+    #[arg(long, conflicts_with = "addr", required_unless_present = "addr")]
+    db_path: Option<String>,
+
+    /// Rpc address of a node to read the chunks from (e.g. http://127.0.0.1:3383)
+    #[arg(long)]
+    addr: Option<String>,
+
+    #[arg(long, default_value_t = 1)]
+    shard_id: u32,
+
+    /// Last block to replay, everything if not set
+    #[arg(long)]
+    stop_block: Option<u64>,
+
+    #[arg(long, default_value_t = 16)]
+    trie_branching_factor: u32,
+
+    /// Replay transactions for different fids in parallel, to check it matches the history
+    #[arg(long)]
+    parallel_replay: bool,
+}
+
+fn print_keys(title: &str, keys: &[Vec<u8>]) {
+    println!("{} ({}):", title, keys.len());
+    for key in keys {
+        println!(" - {}", hex::encode(key));
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Cli::parse();
+
+    let mut source = match (args.db_path, args.addr) {
+        (Some(db_path), _) => ChunkSource::open_db(&db_path, args.trie_branching_factor)?,
+        (None, Some(addr)) => ChunkSource::connect(addr).await?,
+        (None, None) => unreachable!(),
+    };
+    let options = ReplayOptions {
+        shard_id: args.shard_id,
+        stop_block_number: args.stop_block,
+        trie_branching_factor: args.trie_branching_factor,
+        parallel_replay: args.parallel_replay,
+    };
+
+    let report = replay_chunks(&mut source, &options).await?;
+    println!(
+        "Replayed {} chunks up to block {}, shard root {}",
+        report.chunks_replayed,
+        report.last_block_number,
+        hex::encode(&report.shard_root)
+    );
+
+    let Some(divergence) = report.divergence else {
+        return Ok(());
+    };
+
+    println!(
+        "Diverged at block {}: {}",
+        divergence.block_number, divergence.reason
+    );
+    match divergence.details {
+        Some(ChunkDivergence::Transaction {
+            fid,
+            expected_account_root,
+            account_root,
+            expected_receipts,
+            receipts,
+            ..
+        }) => {
+            println!("Transaction for fid {}", fid);
+            println!(
+                " expected account root: {}",
+                hex::encode(expected_account_root)
+            );
+            println!(" replayed account root: {}", hex::encode(account_root));
+            if expected_receipts != receipts {
+                println!(" expected receipts: {:?}", expected_receipts);
+                println!(" replayed receipts: {:?}", receipts);
+            }
+            print_keys(
+                "Trie keys missing from the replay",
+                &divergence.missing_trie_keys,
+            );
+            print_keys(
+                "Unexpected trie keys in the replay",
+                &divergence.unexpected_trie_keys,
+            );
+            if !divergence.later_blocks_with_fid.is_empty() {
+                println!(
+                    "The fid changed again in blocks {:?}, the source's keys include those changes",
+                    divergence.later_blocks_with_fid
+                );
+            }
+        }
+        Some(ChunkDivergence::ShardRoot { expected, actual }) => {
+            println!("All account roots match, but the shard root doesn't");
+            println!(" expected shard root: {}", hex::encode(expected));
+            println!(" replayed shard root: {}", hex::encode(actual));
+        }
+        None => {
+            println!("The chunk replays fine on its own, the halt wasn't caused by its contents")
+        }
+    }
+
+    process::exit(1);
+}
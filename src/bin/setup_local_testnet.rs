@@ -1,6 +1,6 @@
 use clap::Parser;
-use libp2p::identity::ed25519::SecretKey;
-use std::time::Duration;
+use libp2p::identity::ed25519::{Keypair, SecretKey};
+use std::time::{Duration, SystemTime};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -38,6 +38,7 @@ async fn main() {
 
     let base_rpc_port = 3382;
     let base_gossip_port = 50050;
+    let mut genesis_validators = vec![];
     for i in 1..=nodes {
         let id = i;
         let db_dir = format!("nodes/{id}/.rocks");
@@ -49,11 +50,21 @@ async fn main() {
                 std::fs::remove_dir_all(db_dir.clone()).expect("Failed to remove .rocks directory");
             }
         }
-        let secret_key = hex::encode(SecretKey::generate());
+        let secret_key = SecretKey::generate();
+        let public_key = hex::encode(Keypair::from(secret_key.clone()).public().to_bytes());
+        let secret_key = hex::encode(secret_key);
         let rpc_port = base_rpc_port + i;
         let gossip_port = base_gossip_port + i;
         let host = format!("127.0.0.1{i}");
         let rpc_address = format!("{host}:{rpc_port}");
+        genesis_validators.push(format!(
+            r#"
+[[validators]]
+public_key = "{public_key}"
+voting_power = 1
+rpc_address = "{rpc_address}"
+"#
+        ));
         let gossip_multi_addr = format!("/ip4/{host}/udp/{gossip_port}/quic-v1");
         let other_nodes_addresses = (1..=nodes)
             .filter(|&x| x != id)
@@ -84,6 +95,7 @@ bootstrap_peers = "{other_nodes_addresses}"
 [consensus]
 private_key = "{secret_key}"
 propose_value_delay = "{propose_value_delay}"
+genesis_path = "nodes/genesis.toml"
             "#
         );
 
@@ -97,5 +109,23 @@ propose_value_delay = "{propose_value_delay}"
         .expect("Failed to write config file");
         // Print a message
     }
+
+    // Every node is a validator, consensus starts as soon as they're up
+    let genesis_time = humantime::format_rfc3339_seconds(SystemTime::now());
+    let genesis_file_content = format!(
+        r#"
+chain_id = "snapchain-local"
+network = "devnet"
+genesis_time = "{genesis_time}"
+num_shards = 1
+{}"#,
+        genesis_validators.join("")
+    );
+    std::fs::write(
+        "nodes/genesis.toml",
+        genesis_file_content.trim().to_string() + "\n",
+    )
+    .expect("Failed to write genesis file");
+
     println!("Created configs for {nodes} nodes");
 }
@@ -1,3 +1,4 @@
+use crate::storage::trie::node_cache::DEFAULT_TRIE_NODE_CACHE_BYTES;
 use crate::{connectors, consensus, network};
 use clap::Parser;
 use figment::{
@@ -37,6 +38,13 @@ pub struct Config {
     pub clear_db: bool,
     pub statsd: StatsdConfig,
     pub trie_branching_factor: u32,
+    pub trie_node_cache_bytes: usize,
+    // Shards without any state are bootstrapped from this snapshot, either another node's rpc url
+    // (http://...) or a local directory written by the ExportShardSnapshot admin rpc
+    pub snapshot_source: String,
+    // When set, the shards are run as read only replicas that don't take part in consensus and
+    // catch up by syncing their tries from the node at this rpc address
+    pub trie_sync_source: String,
 }
 
 impl Default for Config {
@@ -52,6 +60,9 @@ impl Default for Config {
             clear_db: false,
             statsd: StatsdConfig::default(),
             trie_branching_factor: 16,
+            trie_node_cache_bytes: DEFAULT_TRIE_NODE_CACHE_BYTES,
+            snapshot_source: "".to_string(),
+            trie_sync_source: "".to_string(),
         }
     }
 }
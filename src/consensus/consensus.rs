@@ -13,18 +13,23 @@ use malachite_config::TimeoutConfig;
 use malachite_consensus::{Effect, ProposedValue, Resume, SignedConsensusMsg};
 use malachite_metrics::Metrics;
 
+use crate::consensus::equivocation::EquivocationDetector;
+use crate::consensus::sync::{SyncedValues, SyncedValuesResult};
 use crate::consensus::timers::{TimeoutElapsed, TimerScheduler};
 use crate::consensus::validator::ShardValidator;
+use crate::consensus::wal::ConsensusWal;
 use crate::core::types::{
-    Height, ShardId, SnapchainContext, SnapchainShard, SnapchainValidator,
+    Height, ProposalPart, ShardId, SnapchainContext, SnapchainShard, SnapchainValidator,
     SnapchainValidatorContext,
 };
 use crate::network::gossip::GossipEvent;
-use crate::proto::FullProposal;
+use crate::proto::{ConfirmedVotes, FullProposal};
+use crate::storage::store::engine::MempoolMessage;
 pub use malachite_consensus::Params as ConsensusParams;
 pub use malachite_consensus::State as ConsensusState;
 use ractor::time::send_after;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use tokio::time::Instant;
 
 pub type ConsensusRef<Ctx> = ActorRef<ConsensusMsg<Ctx>>;
@@ -34,6 +39,8 @@ pub type RxDecision = mpsc::Receiver<Decision>;
 
 pub enum SystemMessage {
     Consensus(ConsensusMsg<SnapchainValidatorContext>),
+    /// A message for the given shard's mempool, received from another validator
+    Mempool(u32, MempoolMessage),
 }
 
 type Timers<Ctx> = TimerScheduler<Timeout, ConsensusMsg<Ctx>>;
@@ -44,6 +51,57 @@ impl<Ctx: Context + SnapchainContext> From<TimeoutElapsed<Timeout>> for Consensu
     }
 }
 
+/// Consensus step timeouts. Each round that times out in a step waits the step's delta longer than
+/// the previous one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TimeoutsConfig {
+    #[serde(with = "humantime_serde")]
+    pub propose: Duration,
+    #[serde(with = "humantime_serde")]
+    pub propose_delta: Duration,
+    #[serde(with = "humantime_serde")]
+    pub prevote: Duration,
+    #[serde(with = "humantime_serde")]
+    pub prevote_delta: Duration,
+    #[serde(with = "humantime_serde")]
+    pub precommit: Duration,
+    #[serde(with = "humantime_serde")]
+    pub precommit_delta: Duration,
+    #[serde(with = "humantime_serde")]
+    pub commit: Duration,
+}
+
+impl TimeoutsConfig {
+    pub fn to_timeout_config(&self) -> TimeoutConfig {
+        TimeoutConfig {
+            timeout_propose: self.propose,
+            timeout_propose_delta: self.propose_delta,
+            timeout_prevote: self.prevote,
+            timeout_prevote_delta: self.prevote_delta,
+            timeout_precommit: self.precommit,
+            timeout_precommit_delta: self.precommit_delta,
+            timeout_commit: self.commit,
+            ..TimeoutConfig::default()
+        }
+    }
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        let defaults = TimeoutConfig::default();
+        Self {
+            propose: defaults.timeout_propose,
+            propose_delta: defaults.timeout_propose_delta,
+            prevote: defaults.timeout_prevote,
+            prevote_delta: defaults.timeout_prevote_delta,
+            precommit: defaults.timeout_precommit,
+            precommit_delta: defaults.timeout_precommit_delta,
+            commit: defaults.timeout_commit,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub private_key: String,
@@ -52,7 +110,25 @@ pub struct Config {
     #[serde(with = "humantime_serde")]
     pub propose_value_delay: Duration,
 
+    // Instead of proposing after propose_value_delay no matter what, keep waiting for mempool
+    // messages until max_propose_delay (capped at half the propose timeout), so idle shards
+    // produce fewer empty chunks
+    pub wait_for_messages: bool,
+    #[serde(with = "humantime_serde")]
+    pub max_propose_delay: Duration,
+
+    // Timeouts of every shard (0 is the block shard) without an entry in shard_timeouts, which is
+    // keyed by shard id
+    pub timeouts: TimeoutsConfig,
+    pub shard_timeouts: HashMap<String, TimeoutsConfig>,
+
     pub max_messages_per_block: u32,
+
+    // Merge user messages for different fids in a chunk in parallel
+    pub parallel_replay: bool,
+
+    // The validator set and shard count every node starts from
+    pub genesis_path: String,
 }
 
 impl Config {
@@ -73,6 +149,18 @@ impl Config {
         self.shard_ids().len() as u32
     }
 
+    pub fn timeout_config(&self, shard_id: u32) -> TimeoutConfig {
+        self.shard_timeouts
+            .get(&shard_id.to_string())
+            .unwrap_or(&self.timeouts)
+            .to_timeout_config()
+    }
+
+    // None when proposers don't wait for messages
+    pub fn max_propose_delay(&self) -> Option<Duration> {
+        self.wait_for_messages.then_some(self.max_propose_delay)
+    }
+
     pub fn with_shard_ids(&self, shard_ids: Vec<u32>) -> Self {
         Self {
             private_key: self.private_key.clone(),
@@ -82,7 +170,13 @@ impl Config {
                 .collect::<Vec<String>>()
                 .join(","),
             propose_value_delay: self.propose_value_delay,
+            wait_for_messages: self.wait_for_messages,
+            max_propose_delay: self.max_propose_delay,
+            timeouts: self.timeouts.clone(),
+            shard_timeouts: self.shard_timeouts.clone(),
             max_messages_per_block: self.max_messages_per_block,
+            parallel_replay: self.parallel_replay,
+            genesis_path: self.genesis_path.clone(),
         }
     }
 }
@@ -93,7 +187,13 @@ impl Default for Config {
             private_key: hex::encode(SecretKey::generate()),
             shard_ids: "1".to_string(),
             propose_value_delay: Duration::from_millis(250),
+            wait_for_messages: false,
+            max_propose_delay: Duration::from_secs(1),
+            timeouts: TimeoutsConfig::default(),
+            shard_timeouts: HashMap::new(),
             max_messages_per_block: 250, //TODO
+            parallel_replay: false,
+            genesis_path: "genesis.toml".to_string(),
         }
     }
 }
@@ -116,6 +216,10 @@ pub enum ConsensusMsg<Ctx: SnapchainContext> {
     ReceivedFullProposal(FullProposal),
     RegisterValidator(SnapchainValidator),
 
+    /// Sent by the sync task, see ShardValidator::start_sync
+    ApplySyncedValues(SyncedValues, mpsc::Sender<SyncedValuesResult>),
+    SyncFinished,
+
     TimeoutElapsed(TimeoutElapsed<Timeout>),
 }
 
@@ -127,6 +231,7 @@ impl ConsensusMsg<SnapchainValidatorContext> {
             ConsensusMsg::ReceivedProposedValue(proposed) => proposed.height.shard_index,
             ConsensusMsg::ReceivedSignedVote(vote) => vote.height.shard_index,
             ConsensusMsg::ReceivedSignedProposal(proposal) => proposal.height.shard_index,
+            ConsensusMsg::ReceivedProposalPart(part) => part.height.shard_index,
             ConsensusMsg::ReceivedFullProposal(full_proposal) => full_proposal.height().shard_index,
             ConsensusMsg::RegisterValidator(validator) => validator.shard_index,
 
@@ -168,12 +273,17 @@ impl Timeouts {
     }
 }
 
+// Votes and proposals received before consensus starts are held until it does, up to this many
+const MAX_PENDING_MESSAGES: usize = 1024;
+
 pub struct Consensus {
     ctx: SnapchainValidatorContext,
     params: ConsensusParams<SnapchainValidatorContext>,
     timeout_config: TimeoutConfig,
     metrics: Metrics,
     shard_id: SnapchainShard,
+    // How long to wait before starting consensus, until the genesis time
+    start_delay: Duration,
 }
 
 // pub type ConsensusMsg<Ctx> = ConsensusMsg<Ctx>;
@@ -192,6 +302,15 @@ pub struct State<Ctx: SnapchainContext> {
 
     /// The set of validators (by address) we are connected to.
     shard_validator: ShardValidator,
+
+    /// The messages we signed for the heights not decided yet
+    wal: ConsensusWal,
+
+    /// The messages other validators signed for the heights not decided yet
+    equivocations: EquivocationDetector,
+
+    /// Votes and proposals received before consensus started
+    pending: Vec<ConsensusMsg<SnapchainValidatorContext>>,
     gossip_tx: mpsc::Sender<GossipEvent<SnapchainValidatorContext>>,
     name: String,
 }
@@ -203,6 +322,7 @@ impl Consensus {
         params: ConsensusParams<SnapchainValidatorContext>,
         timeout_config: TimeoutConfig,
         metrics: Metrics,
+        start_delay: Duration,
     ) -> Self {
         Self {
             ctx,
@@ -210,6 +330,7 @@ impl Consensus {
             params,
             timeout_config,
             metrics,
+            start_delay,
         }
     }
 
@@ -220,12 +341,16 @@ impl Consensus {
         params: ConsensusParams<SnapchainValidatorContext>,
         timeout_config: TimeoutConfig,
         metrics: Metrics,
+        start_delay: Duration,
         gossip_tx: mpsc::Sender<GossipEvent<SnapchainValidatorContext>>,
         shard_validator: ShardValidator,
+        wal: ConsensusWal,
+        equivocations: EquivocationDetector,
     ) -> Result<ActorRef<ConsensusMsg<SnapchainValidatorContext>>, ractor::SpawnErr> {
-        let node = Self::new(ctx, shard_id, params, timeout_config, metrics);
+        let node = Self::new(ctx, shard_id, params, timeout_config, metrics, start_delay);
 
-        let (actor_ref, _) = Actor::spawn(None, node, (gossip_tx, shard_validator)).await?;
+        let (actor_ref, _) =
+            Actor::spawn(None, node, (gossip_tx, shard_validator, wal, equivocations)).await?;
         Ok(actor_ref)
     }
 
@@ -240,7 +365,7 @@ impl Consensus {
             state: &mut state.consensus,
             metrics: &self.metrics,
             with: effect => {
-                self.handle_effect(myself, &mut state.shard_validator, &mut state.timers, &mut state.timeouts, state.gossip_tx.clone(), effect).await
+                self.handle_effect(myself, &mut state.shard_validator, &mut state.wal, &mut state.timers, &mut state.timeouts, state.gossip_tx.clone(), effect).await
             }
         )
     }
@@ -279,10 +404,20 @@ impl Consensus {
                     vote.shard_hash, vote.height, vote.round, self.params.address
                 );
                 if !state.shard_validator.started {
-                    warn!("Consensus not started yet when receiving vote, starting");
-                    self.start_height(&myself, state, vote.height.clone())
-                        .await?;
+                    self.hold_until_started(state, ConsensusMsg::ReceivedSignedVote(vote));
+                    return Ok(());
+                }
+
+                // The driver expects votes to be signed by a member of the validator set
+                if !self.is_signed_by_validator(state, &vote) {
+                    warn!(
+                        height = %vote.height,
+                        round = %vote.round,
+                        "Vote isn't signed by a validator, dropping it"
+                    );
+                    return Ok(());
                 }
+                state.equivocations.observe_vote(&vote);
 
                 if let Err(e) = self
                     .process_input(&myself, state, ConsensusInput::Vote(vote))
@@ -300,10 +435,24 @@ impl Consensus {
                 );
 
                 if !state.shard_validator.started {
-                    warn!("Consensus not started yet when receiving proposal, starting");
-                    self.start_height(&myself, state, proposal.height.clone())
-                        .await?;
+                    self.hold_until_started(state, ConsensusMsg::ReceivedSignedProposal(proposal));
+                    return Ok(());
+                }
+
+                let height = proposal.height;
+                let round = proposal.round;
+                let value = proposal.shard_hash.clone();
+                // The driver expects proposals to be signed by the proposer for the round
+                if !self.is_from_expected_proposer(state, &proposal) {
+                    warn!(
+                        %height,
+                        %round,
+                        "Proposal isn't from the expected proposer, dropping it"
+                    );
+                    return Ok(());
                 }
+                state.wal.observe_proposal(&proposal);
+                state.equivocations.observe_proposal(&proposal);
 
                 if let Err(e) = self
                     .process_input(&myself, state, ConsensusInput::Proposal(proposal))
@@ -311,93 +460,87 @@ impl Consensus {
                 {
                     error!("Error when processing proposal: {e:?}");
                 }
+
+                if let Some(full_proposal) =
+                    state
+                        .shard_validator
+                        .record_signed_proposal(height, round, value.clone())
+                {
+                    if full_proposal.shard_hash() == value {
+                        self.add_full_proposal(&myself, state, full_proposal).await;
+                    } else {
+                        warn!(
+                            %height,
+                            %round,
+                            "Full proposal doesn't match the signed proposal, dropping it"
+                        );
+                    }
+                }
                 Ok(())
             }
 
             ConsensusMsg::RegisterValidator(validator) => {
-                let address = validator.address.to_hex();
-                if !state.shard_validator.add_validator(validator.clone()) {
-                    // We already saw that peer, ignoring...
-                    return Ok(());
-                }
-
-                let connected_peers = state.shard_validator.validator_count();
-                info!(
-                    "Connected to peer {address}. Total peers: {:?}",
-                    connected_peers
-                );
-                // let total_peers = state.consensus.driver.validator_set().count() - 1;
-
-                // println!("Connected to {connected_peers}/{total_peers} peers");
-
-                self.metrics.connected_peers.inc();
-
-                if connected_peers == 3 {
-                    info!("Enough peers ({connected_peers}) connected to start consensus");
-
-                    let height = state.consensus.driver.height();
-                    send_after(Duration::from_secs(10), myself.get_cell(), move || {
-                        info!("Starting consensus");
-                        ConsensusMsg::<SnapchainValidatorContext>::StartHeight(height)
-                    });
+                // The validator set comes from the genesis file, registrations only tell us how
+                // to reach its members
+                if !state.shard_validator.update_validator(&validator) {
+                    debug!(
+                        "Ignoring registration from unknown validator {}",
+                        validator.address.to_hex()
+                    );
+                } else if state.shard_validator.is_behind() {
+                    state.shard_validator.start_sync(myself.clone());
                 }
                 Ok(())
             }
 
-            ConsensusMsg::ReceivedProposalPart(_part) => {
-                // TODO: implement
+            ConsensusMsg::ApplySyncedValues(values, reply_tx) => {
+                let result = state.shard_validator.apply_synced_values(values);
+                // The channel is only used for this reply, it has room for it
+                let _ = reply_tx.try_send(result);
                 Ok(())
             }
 
-            ConsensusMsg::ReceivedFullProposal(full_proposal) => {
-                let height = full_proposal.height.clone().unwrap();
-                debug!(
-                    "Received proposed value: {:?} at {:?}",
-                    height, self.params.address
-                );
+            ConsensusMsg::SyncFinished => {
+                self.finish_sync(&myself, state).await;
+                Ok(())
+            }
 
-                let current_height = state.shard_validator.get_current_height();
+            ConsensusMsg::ReceivedProposalPart(signed_part) => {
+                let part = &signed_part.message;
+                let signed_by_proposer = self
+                    .expected_proposer(state, part.height, part.round)
+                    .is_some_and(|proposer| {
+                        proposer.address == part.proposer
+                            && self.ctx.verify_signed_proposal_part(
+                                part,
+                                &signed_part.signature,
+                                &proposer.public_key,
+                            )
+                    });
+                if !signed_by_proposer {
+                    warn!(
+                        height = %part.height,
+                        round = %part.round,
+                        "Dropping proposal part not signed by the proposer"
+                    );
+                    return Ok(());
+                }
 
-                // TODO(aditi): Add a check for the node being too far behind to start up via rpc sync once we have a way to pick up missed blocks in bulk
-                if !state
-                    .shard_validator
-                    .saw_proposal_from_validator(full_proposal.proposer_address())
-                    && (height.block_number > current_height + 1)
-                {
-                    let validator_set = state.shard_validator.get_validator_set();
-                    match validator_set.get_by_address(&full_proposal.proposer_address()) {
-                        None => {
-                            error!("Missing validator {}", full_proposal.proposer_address());
-                        }
-                        Some(validator) => {
-                            state
-                                .shard_validator
-                                .sync_against_validator(&validator)
-                                .await
-                        }
-                    };
-                    match self.start_height(&myself, state, height).await {
-                        Ok(()) => {}
-                        Err(err) => {
-                            error!("Error starting consensus at height {}. {}", height, err);
-                        }
+                let (height, round) = (part.height, part.round);
+                match state.shard_validator.add_proposal_part(signed_part.message) {
+                    Ok(Some(full_proposal)) => {
+                        self.received_full_proposal(&myself, state, full_proposal)
+                            .await;
                     }
+                    Ok(None) => {}
+                    Err(err) => warn!(%height, %round, "Dropping proposal part: {}", err),
                 }
+                Ok(())
+            }
 
-                let proposed_value = state.shard_validator.add_proposed_value(full_proposal);
-
-                let result = self
-                    .process_input(
-                        &myself,
-                        state,
-                        ConsensusInput::ReceivedProposedValue(proposed_value),
-                    )
+            ConsensusMsg::ReceivedFullProposal(full_proposal) => {
+                self.received_full_proposal(&myself, state, full_proposal)
                     .await;
-
-                if let Err(e) = result {
-                    error!("Error when processing GossipEvent message: {e:?}");
-                }
-
                 Ok(())
             }
 
@@ -441,6 +584,172 @@ impl Consensus {
         }
     }
 
+    // The proposer of the height and round under select_proposer, None if it has no validators
+    fn expected_proposer(
+        &self,
+        state: &State<SnapchainValidatorContext>,
+        height: Height,
+        round: Round,
+    ) -> Option<SnapchainValidator> {
+        if round.as_i64() < 0 {
+            return None;
+        }
+        let validator_set = state
+            .shard_validator
+            .get_validator_set_at(height.block_number);
+        if validator_set.count() == 0 {
+            return None;
+        }
+        Some(
+            self.ctx
+                .select_proposer(&validator_set, height, round)
+                .clone(),
+        )
+    }
+
+    fn is_from_expected_proposer(
+        &self,
+        state: &State<SnapchainValidatorContext>,
+        proposal: &SignedProposal<SnapchainValidatorContext>,
+    ) -> bool {
+        match self.expected_proposer(state, proposal.height, proposal.round) {
+            Some(proposer) => {
+                proposer.address == proposal.proposer
+                    && proposer
+                        .public_key
+                        .verify(&proposal.to_sign_bytes(), &proposal.signature.0)
+            }
+            None => false,
+        }
+    }
+
+    fn is_signed_by_validator(
+        &self,
+        state: &State<SnapchainValidatorContext>,
+        vote: &SignedVote<SnapchainValidatorContext>,
+    ) -> bool {
+        state
+            .shard_validator
+            .get_validator_set_at(vote.height.block_number)
+            .get_by_address(&vote.voter)
+            .is_some_and(|validator| {
+                validator
+                    .public_key
+                    .verify(&vote.to_sign_bytes(), &vote.signature.0)
+            })
+    }
+
+    fn verify_full_proposal(
+        &self,
+        state: &State<SnapchainValidatorContext>,
+        full_proposal: &FullProposal,
+    ) -> Result<(), String> {
+        if full_proposal.proposed_value.is_none() {
+            return Err("no proposed value".to_string());
+        }
+        let Some(proposer) =
+            self.expected_proposer(state, full_proposal.height(), full_proposal.round())
+        else {
+            return Err("no proposer for the height and round".to_string());
+        };
+        if proposer.address != full_proposal.proposer_address() {
+            return Err(format!(
+                "proposed by {} instead of {}",
+                full_proposal.proposer_address(),
+                proposer.address
+            ));
+        }
+        if !self
+            .ctx
+            .verify_full_proposal(full_proposal, &proposer.public_key)
+        {
+            return Err("invalid signature".to_string());
+        }
+        Ok(())
+    }
+
+    async fn add_full_proposal(
+        &self,
+        myself: &ActorRef<ConsensusMsg<SnapchainValidatorContext>>,
+        state: &mut State<SnapchainValidatorContext>,
+        full_proposal: FullProposal,
+    ) {
+        let proposed_value = state.shard_validator.add_proposed_value(full_proposal);
+
+        let result = self
+            .process_input(
+                myself,
+                state,
+                ConsensusInput::ReceivedProposedValue(proposed_value),
+            )
+            .await;
+
+        if let Err(e) = result {
+            error!("Error when processing GossipEvent message: {e:?}");
+        }
+    }
+
+    // A full proposal, gossiped whole or assembled from its parts
+    async fn received_full_proposal(
+        &self,
+        myself: &ActorRef<ConsensusMsg<SnapchainValidatorContext>>,
+        state: &mut State<SnapchainValidatorContext>,
+        full_proposal: FullProposal,
+    ) {
+        let height = full_proposal.height.clone().unwrap();
+        debug!(
+            "Received proposed value: {:?} at {:?}",
+            height, self.params.address
+        );
+
+        if let Err(err) = self.verify_full_proposal(state, &full_proposal) {
+            warn!(%height, "Dropping full proposal: {}", err);
+            return;
+        }
+
+        // The proposer has the blocks before the height, download them before voting
+        if height.block_number > state.shard_validator.get_current_height() + 1 {
+            state
+                .shard_validator
+                .observe_peer_height(&full_proposal.proposer_address(), height.block_number - 1);
+            state.shard_validator.start_sync(myself.clone());
+        }
+
+        // Only accept the value the proposer signed in its proposal, which may arrive later
+        let round = full_proposal.round();
+        match state.shard_validator.signed_proposal_value(height, round) {
+            None => {
+                state
+                    .shard_validator
+                    .add_pending_full_proposal(full_proposal);
+            }
+            Some(value) if *value != full_proposal.shard_hash() => {
+                warn!(
+                    %height,
+                    %round,
+                    "Full proposal doesn't match the signed proposal, dropping it"
+                );
+            }
+            Some(_) => self.add_full_proposal(myself, state, full_proposal).await,
+        }
+    }
+
+    // The sync task applied what it could download, consensus continues after it
+    async fn finish_sync(
+        &self,
+        myself: &ActorRef<ConsensusMsg<SnapchainValidatorContext>>,
+        state: &mut State<SnapchainValidatorContext>,
+    ) {
+        state.shard_validator.finish_sync();
+        let height = Height::new(
+            self.shard_id.shard_id(),
+            state.shard_validator.get_current_height() + 1,
+        );
+        if let Err(err) = self.start_height(myself, state, height).await {
+            error!("Error starting consensus at height {}. {}", height, err);
+        }
+    }
+
     async fn start_height(
         &self,
         myself: &ActorRef<ConsensusMsg<SnapchainValidatorContext>>,
@@ -454,7 +763,9 @@ impl Consensus {
             );
             return Ok(());
         }
-        let validator_set = state.shard_validator.get_validator_set();
+        let validator_set = state
+            .shard_validator
+            .get_validator_set_at(height.block_number);
         debug!(
             "Starting height: {height} with {:?} validators",
             validator_set.count()
@@ -472,14 +783,49 @@ impl Consensus {
             error!("Error when starting height {height}: {e:?}");
         }
 
+        state.equivocations.start_height(height);
+
+        // Messages we signed for the height before restarting, so the driver knows about them
+        for msg in state.wal.messages(height) {
+            let input = match msg {
+                SignedConsensusMsg::Vote(vote) => ConsensusInput::Vote(vote),
+                SignedConsensusMsg::Proposal(proposal) => ConsensusInput::Proposal(proposal),
+            };
+            if let Err(e) = self.process_input(myself, state, input).await {
+                error!("Error when replaying own message at height {height}: {e:?}");
+            }
+        }
+
+        // Messages peers sent before we started, handled after the ones already queued
+        for msg in std::mem::take(&mut state.pending) {
+            if let Err(e) = myself.cast(msg) {
+                error!("Error when replaying pending message: {e:?}");
+            }
+        }
+
         Ok(())
     }
 
+    // Consensus starts at the genesis time (see `post_start`), not when the first peer does
+    fn hold_until_started(
+        &self,
+        state: &mut State<SnapchainValidatorContext>,
+        msg: ConsensusMsg<SnapchainValidatorContext>,
+    ) {
+        if state.pending.len() >= MAX_PENDING_MESSAGES {
+            warn!("Consensus not started yet and too many pending messages, dropping message");
+            return;
+        }
+        debug!("Consensus not started yet, holding message until it is");
+        state.pending.push(msg);
+    }
+
     #[tracing::instrument(skip_all)]
     async fn handle_effect(
         &self,
         myself: &ActorRef<ConsensusMsg<SnapchainValidatorContext>>,
         shard_validator: &mut ShardValidator,
+        wal: &mut ConsensusWal,
         timers: &mut Timers<SnapchainValidatorContext>,
         timeouts: &mut Timeouts,
         gossip_tx: mpsc::Sender<GossipEvent<SnapchainValidatorContext>>,
@@ -533,6 +879,14 @@ impl Consensus {
             Effect::Broadcast(gossip_msg) => {
                 match gossip_msg {
                     SignedConsensusMsg::Proposal(proposal) => {
+                        if let Err(err) = wal.record_proposal(&proposal) {
+                            error!(
+                                height = %proposal.height,
+                                round = %proposal.round,
+                                "Refusing to broadcast proposal: {}", err
+                            );
+                            return Ok(Resume::Continue);
+                        }
                         debug!(
                             "Broadcasting proposal gossip message: {:?} {:?} from {:?}",
                             proposal.height, proposal.round, proposal.proposer
@@ -542,6 +896,14 @@ impl Consensus {
                             .await?;
                     }
                     SignedConsensusMsg::Vote(vote) => {
+                        if let Err(err) = wal.record_vote(&vote) {
+                            error!(
+                                height = %vote.height,
+                                round = %vote.round,
+                                "Refusing to broadcast vote: {}", err
+                            );
+                            return Ok(Resume::Continue);
+                        }
                         debug!(
                             "Broadcasting vote gossip message: {:?} {:?} {:?} from {:?}",
                             vote.vote_type, vote.height, vote.round, vote.voter
@@ -556,8 +918,20 @@ impl Consensus {
             }
 
             Effect::GetValue(height, round, timeout) => {
+                // Proposed before restarting, the value can't be built again
+                if let Some(value) = wal.proposal_value(height, round) {
+                    warn!(%height, %round, %value, "Already proposed a value, not proposing again");
+                    return Ok(Resume::Continue);
+                }
+                // The other validators prevote nil once the propose step times out
+                if shard_validator.is_halted() {
+                    warn!(%height, %round, "Shard is halted, not proposing");
+                    return Ok(Resume::Continue);
+                }
                 let timeout = timeouts.duration_for(timeout.step);
-                let full_proposal = shard_validator.propose_value(height, round, timeout).await;
+                let full_proposal = self.ctx.sign_full_proposal(
+                    shard_validator.propose_value(height, round, timeout).await,
+                );
 
                 let value = full_proposal.shard_hash();
 
@@ -567,16 +941,20 @@ impl Consensus {
                     error!("Error when forwarding locally proposed value: {e:?}");
                 }
 
-                gossip_tx
-                    .send(GossipEvent::BroadcastFullProposal(full_proposal))
-                    .await?;
+                for part in ProposalPart::split(&full_proposal) {
+                    gossip_tx
+                        .send(GossipEvent::BroadcastProposalPart(
+                            self.ctx.sign_proposal_part(part),
+                        ))
+                        .await?;
+                }
 
                 Ok(Resume::Continue)
             }
 
             Effect::GetValidatorSet(height) => Ok(Resume::ValidatorSet(
                 height,
-                Some(shard_validator.get_validator_set()),
+                Some(shard_validator.get_validator_set_at(height.block_number)),
             )),
 
             Effect::Decide {
@@ -590,7 +968,22 @@ impl Consensus {
                     self.params.address,
                     commits.len()
                 );
-                shard_validator.decide(height, round, value.clone()).await;
+                let votes = ConfirmedVotes {
+                    votes: commits
+                        .iter()
+                        .map(|commit| commit.message.to_proto())
+                        .collect(),
+                    signatures: commits
+                        .iter()
+                        .map(|commit| commit.signature.0.clone())
+                        .collect(),
+                };
+                shard_validator
+                    .decide(height, round, value.clone(), votes)
+                    .await;
+                if let Err(err) = wal.prune(height) {
+                    error!("Unable to prune the consensus wal after {height}: {err}");
+                }
                 let result = myself.cast(ConsensusMsg::StartHeight(height.increment()));
                 if let Err(e) = result {
                     error!("Error when starting next height after decision on {height}: {e:?}");
@@ -608,6 +1001,8 @@ impl Actor for Consensus {
     type Arguments = (
         mpsc::Sender<GossipEvent<SnapchainValidatorContext>>,
         ShardValidator,
+        ConsensusWal,
+        EquivocationDetector,
     );
 
     #[tracing::instrument(name = "consensus", skip_all)]
@@ -627,6 +1022,9 @@ impl Actor for Consensus {
             timeouts: Timeouts::new(self.timeout_config),
             consensus: ConsensusState::new(self.ctx.clone(), self.params.clone()),
             shard_validator: args.1,
+            wal: args.2,
+            equivocations: args.3,
+            pending: vec![],
             gossip_tx: args.0,
             name,
         })
@@ -634,17 +1032,19 @@ impl Actor for Consensus {
 
     async fn post_start(
         &self,
-        _myself: ActorRef<ConsensusMsg<SnapchainValidatorContext>>,
+        myself: ActorRef<ConsensusMsg<SnapchainValidatorContext>>,
         state: &mut State<SnapchainValidatorContext>,
     ) -> Result<(), ActorProcessingErr> {
         state.timers.cancel_all();
-        // Add ourselves to the validator set
-        state.shard_validator.add_validator(SnapchainValidator::new(
-            self.shard_id.clone(),
-            self.ctx.public_key(),
-            None,
-            state.shard_validator.get_current_height(),
-        ));
+
+        let height = Height::new(
+            self.shard_id.shard_id(),
+            state.shard_validator.get_current_height() + 1,
+        );
+        send_after(self.start_delay, myself.get_cell(), move || {
+            info!("Starting consensus");
+            ConsensusMsg::<SnapchainValidatorContext>::StartHeight(height)
+        });
         Ok(())
     }
 
@@ -0,0 +1,228 @@
+use crate::core::types::{Height, SnapchainValidatorContext};
+use crate::proto::consensus_message::ConsensusMessage as Message;
+use crate::proto::{ConsensusMessage, Evidence, VoteType};
+use crate::storage::store::evidence::EvidenceStore;
+use crate::utils::statsd_wrapper::StatsdClientWrapper;
+use malachite_common::{SignedProposal, SignedVote};
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{error, warn};
+
+// Messages are only kept for heights this far above the one being decided, so peers can't make the
+// node remember messages for any number of future heights
+const MAX_HEIGHTS_AHEAD: u64 = 2;
+
+// Vote type, or proposal
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum MessageKind {
+    Prevote,
+    Precommit,
+    Proposal,
+}
+
+/// Remembers the first vote of each type and the first proposal each validator signed for the
+/// height being decided and the few after it, and records evidence when it signs a different one.
+/// Messages must be checked to be signed by a validator of the height before they're observed, or
+/// anyone could frame a validator.
+pub struct EquivocationDetector {
+    shard_id: u32,
+    // Being decided, nothing is observed until it's set
+    height: Option<Height>,
+    seen: BTreeMap<(Height, i64, MessageKind, Vec<u8>), ConsensusMessage>,
+    evidence_store: EvidenceStore,
+    statsd_client: StatsdClientWrapper,
+}
+
+impl EquivocationDetector {
+    pub fn new(
+        shard_id: u32,
+        evidence_store: EvidenceStore,
+        statsd_client: StatsdClientWrapper,
+    ) -> Self {
+        EquivocationDetector {
+            shard_id,
+            height: None,
+            seen: BTreeMap::new(),
+            evidence_store,
+            statsd_client,
+        }
+    }
+
+    pub fn observe_vote(
+        &mut self,
+        vote: &SignedVote<SnapchainValidatorContext>,
+    ) -> Option<Evidence> {
+        let proto_vote = vote.to_proto();
+        let kind = match proto_vote.r#type() {
+            VoteType::Prevote => MessageKind::Prevote,
+            VoteType::Precommit => MessageKind::Precommit,
+        };
+        let key = (
+            vote.height,
+            proto_vote.round,
+            kind,
+            proto_vote.voter.clone(),
+        );
+        self.observe(
+            key,
+            ConsensusMessage {
+                consensus_message: Some(Message::Vote(proto_vote)),
+                signature: vote.signature.0.clone(),
+            },
+        )
+    }
+
+    pub fn observe_proposal(
+        &mut self,
+        proposal: &SignedProposal<SnapchainValidatorContext>,
+    ) -> Option<Evidence> {
+        let proto_proposal = proposal.to_proto();
+        let key = (
+            proposal.height,
+            proto_proposal.round,
+            MessageKind::Proposal,
+            proto_proposal.proposer.clone(),
+        );
+        self.observe(
+            key,
+            ConsensusMessage {
+                consensus_message: Some(Message::Proposal(proto_proposal)),
+                signature: proposal.signature.0.clone(),
+            },
+        )
+    }
+
+    fn observe(
+        &mut self,
+        key: (Height, i64, MessageKind, Vec<u8>),
+        msg: ConsensusMessage,
+    ) -> Option<Evidence> {
+        let in_window = self.height.is_some_and(|current| {
+            key.0 >= current && key.0.block_number <= current.block_number + MAX_HEIGHTS_AHEAD
+        });
+        if !in_window {
+            return None;
+        }
+
+        let first = match self.seen.get(&key) {
+            None => {
+                self.seen.insert(key, msg);
+                return None;
+            }
+            // The same message gossiped again
+            Some(first) if first.consensus_message == msg.consensus_message => return None,
+            Some(first) => first.clone(),
+        };
+
+        let (height, round, kind, signer) = key;
+        warn!(
+            %height,
+            round,
+            ?kind,
+            validator = hex::encode(&signer),
+            "Validator signed conflicting messages"
+        );
+        let evidence = Evidence {
+            first: Some(first),
+            second: Some(msg),
+            detected_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_millis() as u64),
+        };
+        match self.evidence_store.put_evidence(&evidence) {
+            Ok(true) => {
+                self.statsd_client
+                    .count_with_shard(self.shard_id, "consensus.equivocations", 1);
+            }
+            Ok(false) => {}
+            Err(err) => error!("Unable to store evidence: {}", err),
+        }
+        Some(evidence)
+    }
+
+    /// Forgets the messages of the heights before the one being decided
+    pub fn start_height(&mut self, height: Height) {
+        self.height = Some(height);
+        self.seen.retain(|(seen, ..), _| *seen >= height);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::{Address, ShardHash, Vote};
+    use crate::storage::db::RocksDB;
+    use libp2p::identity::ed25519::Keypair;
+    use malachite_common::{Context, NilOrVal, Round};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_conflicting_votes_are_recorded() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db = RocksDB::new(dir.path().join("evidence").to_str().unwrap());
+        db.open().unwrap();
+        let evidence_store = EvidenceStore::new(Arc::new(db));
+        let statsd_client = StatsdClientWrapper::new(
+            cadence::StatsdClient::builder("", cadence::NopMetricSink {}).build(),
+            true,
+        );
+        let mut detector = EquivocationDetector::new(1, evidence_store.clone(), statsd_client);
+
+        let keypair = Keypair::generate();
+        let address = Address(keypair.public().to_bytes());
+        let ctx = SnapchainValidatorContext::new(keypair);
+        let height = Height::new(1, 3);
+        let prevote = |hash: u8| {
+            ctx.sign_vote(Vote::new_prevote(
+                height,
+                Round::new(0),
+                NilOrVal::Val(ShardHash {
+                    shard_index: 1,
+                    hash: vec![hash],
+                }),
+                address.clone(),
+            ))
+        };
+
+        // Nothing is observed before consensus starts a height
+        assert!(detector.observe_vote(&prevote(1)).is_none());
+        assert!(detector.observe_vote(&prevote(2)).is_none());
+
+        detector.start_height(height);
+        assert!(detector.observe_vote(&prevote(1)).is_none());
+        assert!(detector.observe_vote(&prevote(1)).is_none());
+        let evidence = detector.observe_vote(&prevote(2)).unwrap();
+        assert_eq!(evidence.first.unwrap().signature, prevote(1).signature.0);
+        assert_eq!(evidence_store.get_evidence(Some(1)).unwrap().len(), 1);
+
+        // A precommit is a different message type, not a conflict
+        let precommit = ctx.sign_vote(Vote::new_precommit(
+            height,
+            Round::new(0),
+            NilOrVal::Nil,
+            address.clone(),
+        ));
+        assert!(detector.observe_vote(&precommit).is_none());
+
+        detector.start_height(Height::new(1, 4));
+        assert!(detector.observe_vote(&prevote(3)).is_none());
+        assert_eq!(evidence_store.get_evidence(None).unwrap().len(), 1);
+
+        // Too far ahead of the height being decided to be remembered
+        let far_height = Height::new(1, 4 + MAX_HEIGHTS_AHEAD + 1);
+        let far_prevote = |hash: u8| {
+            ctx.sign_vote(Vote::new_prevote(
+                far_height,
+                Round::new(0),
+                NilOrVal::Val(ShardHash {
+                    shard_index: 1,
+                    hash: vec![hash],
+                }),
+                address.clone(),
+            ))
+        };
+        assert!(detector.observe_vote(&far_prevote(1)).is_none());
+        assert!(detector.observe_vote(&far_prevote(2)).is_none());
+        assert!(detector.seen.is_empty());
+    }
+}
@@ -0,0 +1,201 @@
+use crate::core::types::{PublicKey, SnapchainShard, SnapchainValidator, SnapchainValidatorSet};
+use crate::proto::FarcasterNetwork;
+use figment::providers::{Format, Toml};
+use figment::Figment;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum GenesisError {
+    #[error("genesis file not found: {0}")]
+    NotFound(String),
+
+    #[error(transparent)]
+    ParseError(#[from] Box<figment::Error>),
+
+    #[error("invalid genesis: {0}")]
+    Invalid(String),
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct GenesisValidator {
+    // Hex encoded ed25519 public key
+    pub public_key: String,
+    pub voting_power: u64,
+    pub rpc_address: String,
+}
+
+/// Parameters every node in the network must agree on before the first block. The validators
+/// validate every shard, and the set only changes through the protocol after genesis.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Genesis {
+    pub chain_id: String,
+    // "mainnet", "testnet" or "devnet"
+    pub network: String,
+    // Consensus starts at this time, e.g. "2024-12-01T00:00:00Z"
+    #[serde(with = "humantime_serde")]
+    pub genesis_time: SystemTime,
+    pub num_shards: u32,
+    pub validators: Vec<GenesisValidator>,
+}
+
+fn invalid(reason: String) -> GenesisError {
+    GenesisError::Invalid(reason)
+}
+
+impl Genesis {
+    pub fn load(path: &str) -> Result<Self, GenesisError> {
+        if !Path::new(path).exists() {
+            return Err(GenesisError::NotFound(path.to_string()));
+        }
+        let genesis: Genesis = Figment::from(Toml::file(path))
+            .extract()
+            .map_err(Box::new)?;
+        genesis.validate()?;
+        Ok(genesis)
+    }
+
+    pub fn validate(&self) -> Result<(), GenesisError> {
+        if self.chain_id.is_empty() {
+            return Err(invalid("chain_id is required".to_string()));
+        }
+        self.farcaster_network()?;
+        if self.num_shards == 0 {
+            return Err(invalid("num_shards must be at least 1".to_string()));
+        }
+        if self.validators.is_empty() {
+            return Err(invalid("at least one validator is required".to_string()));
+        }
+
+        let mut public_keys = HashSet::new();
+        for validator in &self.validators {
+            Self::parse_public_key(&validator.public_key)?;
+            if !public_keys.insert(validator.public_key.to_lowercase()) {
+                return Err(invalid(format!(
+                    "duplicate validator {}",
+                    validator.public_key
+                )));
+            }
+            if validator.voting_power == 0 {
+                return Err(invalid(format!(
+                    "validator {} has no voting power",
+                    validator.public_key
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_public_key(public_key: &str) -> Result<PublicKey, GenesisError> {
+        hex::decode(public_key)
+            .ok()
+            .and_then(|bytes| PublicKey::try_from_bytes(&bytes).ok())
+            .ok_or_else(|| invalid(format!("invalid validator public key {}", public_key)))
+    }
+
+    pub fn farcaster_network(&self) -> Result<FarcasterNetwork, GenesisError> {
+        match self.network.as_str() {
+            "mainnet" => Ok(FarcasterNetwork::Mainnet),
+            "testnet" => Ok(FarcasterNetwork::Testnet),
+            "devnet" => Ok(FarcasterNetwork::Devnet),
+            network => Err(invalid(format!("unknown network {}", network))),
+        }
+    }
+
+    /// The initial validator set of the shard (shard 0 is the block shard). The genesis must be
+    /// valid (see `validate`).
+    pub fn validator_set(&self, shard: &SnapchainShard) -> SnapchainValidatorSet {
+        let validators = self
+            .validators
+            .iter()
+            .map(|validator| {
+                let public_key = Self::parse_public_key(&validator.public_key).unwrap();
+                SnapchainValidator::new(
+                    shard.clone(),
+                    public_key,
+                    Some(validator.rpc_address.clone()),
+                    0,
+                )
+                .with_voting_power(validator.voting_power)
+            })
+            .collect();
+        SnapchainValidatorSet::new(validators)
+    }
+
+    // Zero once the genesis time has passed
+    pub fn time_until_start(&self) -> Duration {
+        self.genesis_time
+            .duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::ed25519::Keypair;
+    use malachite_common::{Validator, ValidatorSet};
+
+    fn genesis(validators: Vec<GenesisValidator>) -> Genesis {
+        Genesis {
+            chain_id: "snapchain-test".to_string(),
+            network: "devnet".to_string(),
+            genesis_time: SystemTime::UNIX_EPOCH,
+            num_shards: 2,
+            validators,
+        }
+    }
+
+    fn validator(keypair: &Keypair, voting_power: u64) -> GenesisValidator {
+        GenesisValidator {
+            public_key: hex::encode(keypair.public().to_bytes()),
+            voting_power,
+            rpc_address: "127.0.0.1:3383".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_validator_set() {
+        let keypair1 = Keypair::generate();
+        let keypair2 = Keypair::generate();
+        let genesis = genesis(vec![validator(&keypair1, 1), validator(&keypair2, 3)]);
+        assert!(genesis.validate().is_ok());
+        assert_eq!(genesis.time_until_start(), Duration::ZERO);
+
+        let validator_set = genesis.validator_set(&SnapchainShard::new(1));
+        assert_eq!(validator_set.count(), 2);
+        assert_eq!(validator_set.total_voting_power(), 4);
+        assert_eq!(validator_set.shard_id(), 1);
+        let validator2 = validator_set
+            .validators
+            .iter()
+            .find(|v| v.public_key == keypair2.public())
+            .unwrap();
+        assert_eq!(validator2.voting_power(), 3);
+        assert_eq!(validator2.rpc_address, Some("127.0.0.1:3383".to_string()));
+    }
+
+    #[test]
+    fn test_invalid_genesis() {
+        let keypair = Keypair::generate();
+
+        assert!(genesis(vec![]).validate().is_err());
+        assert!(genesis(vec![validator(&keypair, 0)]).validate().is_err());
+        assert!(
+            genesis(vec![validator(&keypair, 1), validator(&keypair, 2)])
+                .validate()
+                .is_err()
+        );
+
+        let mut bad_key = validator(&keypair, 1);
+        bad_key.public_key = "1234".to_string();
+        assert!(genesis(vec![bad_key]).validate().is_err());
+
+        let mut bad_network = genesis(vec![validator(&keypair, 1)]);
+        bad_network.network = "othernet".to_string();
+        assert!(bad_network.validate().is_err());
+    }
+}
@@ -1,4 +1,10 @@
 pub mod consensus;
+pub mod equivocation;
+pub mod genesis;
+pub mod proposal_parts;
 pub mod proposer;
+pub mod sync;
 mod timers;
 pub mod validator;
+pub mod validator_set;
+pub mod wal;
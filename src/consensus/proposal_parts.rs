@@ -0,0 +1,377 @@
+use crate::core::types::{Address, Height, ProposalPart, MAX_PROPOSAL_PART_BYTES};
+use crate::proto::FullProposal;
+use prost::Message;
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+// Bounds the memory a proposer can make validators buffer for a single proposal
+const MAX_PROPOSAL_PARTS: u32 = 1024;
+
+// Parts are only buffered for heights this far above the one being decided. The proposal of a single
+// height above them is still assembled, so a node that fell behind learns how far it has to sync.
+const MAX_HEIGHTS_AHEAD: u64 = 2;
+
+// Bounds the memory the unfinished proposals of each proposer can use, room for the largest proposal.
+// Each proposer has its own budget, so a proposer can't keep the others' proposals from being
+// assembled.
+const MAX_BUFFERED_BYTES_PER_PROPOSER: usize =
+    MAX_PROPOSAL_PARTS as usize * MAX_PROPOSAL_PART_BYTES;
+
+#[derive(Error, Debug)]
+pub enum ProposalPartError {
+    #[error("Invalid part {index} of {count}")]
+    InvalidIndex { index: u32, count: u32 },
+
+    #[error("Part has {0} bytes, more than a part can have")]
+    PartTooLarge(usize),
+
+    #[error("Part is for height {0}, outside the heights being assembled")]
+    HeightOutOfRange(Height),
+
+    #[error("Part doesn't belong to the proposal being assembled")]
+    ProposalMismatch,
+
+    #[error("Assembled proposal doesn't match its hash")]
+    HashMismatch,
+
+    #[error("Assembled proposal is for a different height or round")]
+    HeightMismatch,
+
+    #[error(transparent)]
+    DecodeError(#[from] prost::DecodeError),
+}
+
+struct PartStream {
+    proposer: Address,
+    proposal_hash: Vec<u8>,
+    count: u32,
+    parts: BTreeMap<u32, Vec<u8>>,
+    // Parts gossiped again after the proposal was assembled are ignored
+    assembled: bool,
+}
+
+impl PartStream {
+    fn buffered_bytes(&self) -> usize {
+        self.parts.values().map(|data| data.len()).sum()
+    }
+}
+
+/// Collects the parts of the proposals being gossiped, by height and round, until a proposal is
+/// complete. Parts must be checked to be signed by the proposer of their height and round before
+/// they're added.
+#[derive(Default)]
+pub struct ProposalParts {
+    // Being decided, no part is added until it's set
+    height: Option<Height>,
+    streams: BTreeMap<(Height, i64), PartStream>,
+    // Sum of the data of the parts in each proposer's streams
+    buffered_bytes: BTreeMap<Address, usize>,
+}
+
+impl ProposalParts {
+    pub fn new() -> Self {
+        ProposalParts {
+            height: None,
+            streams: BTreeMap::new(),
+            buffered_bytes: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the full proposal once all its parts were added
+    pub fn add_part(
+        &mut self,
+        part: ProposalPart,
+    ) -> Result<Option<FullProposal>, ProposalPartError> {
+        if part.index >= part.count || part.count > MAX_PROPOSAL_PARTS {
+            return Err(ProposalPartError::InvalidIndex {
+                index: part.index,
+                count: part.count,
+            });
+        }
+        if part.data.len() > MAX_PROPOSAL_PART_BYTES {
+            return Err(ProposalPartError::PartTooLarge(part.data.len()));
+        }
+        self.check_height(part.height)?;
+
+        let key = (part.height, part.round.as_i64());
+        self.reserve(&part.proposer, key, part.data.len());
+        let stream = self.streams.entry(key).or_insert_with(|| PartStream {
+            proposer: part.proposer.clone(),
+            proposal_hash: part.proposal_hash.clone(),
+            count: part.count,
+            parts: BTreeMap::new(),
+            assembled: false,
+        });
+        // Only the first proposal of a height and round is assembled
+        if stream.proposer != part.proposer
+            || stream.proposal_hash != part.proposal_hash
+            || stream.count != part.count
+        {
+            return Err(ProposalPartError::ProposalMismatch);
+        }
+        if stream.assembled || stream.parts.contains_key(&part.index) {
+            return Ok(None);
+        }
+        *self.buffered_bytes.entry(part.proposer).or_default() += part.data.len();
+        stream.parts.insert(part.index, part.data);
+        if stream.parts.len() < stream.count as usize {
+            return Ok(None);
+        }
+
+        stream.assembled = true;
+        let encoded: Vec<u8> = std::mem::take(&mut stream.parts)
+            .into_values()
+            .flatten()
+            .collect();
+        release(&mut self.buffered_bytes, &stream.proposer, encoded.len());
+        if blake3::hash(&encoded).as_bytes()[..] != stream.proposal_hash[..] {
+            return Err(ProposalPartError::HashMismatch);
+        }
+        let full_proposal = FullProposal::decode(&encoded[..])?;
+        if full_proposal.height != Some(part.height) || full_proposal.round != key.1 {
+            return Err(ProposalPartError::HeightMismatch);
+        }
+        Ok(Some(full_proposal))
+    }
+
+    // Heights above the window only keep the highest one seen, its streams replace the lower one's
+    fn check_height(&mut self, height: Height) -> Result<(), ProposalPartError> {
+        let Some(current) = self.height else {
+            return Err(ProposalPartError::HeightOutOfRange(height));
+        };
+        if height < current {
+            return Err(ProposalPartError::HeightOutOfRange(height));
+        }
+        let last_buffered = current.increment_by(MAX_HEIGHTS_AHEAD);
+        if height <= last_buffered {
+            return Ok(());
+        }
+        let highest_ahead = self.streams.keys().map(|(h, _)| *h).max();
+        match highest_ahead {
+            Some(highest) if highest > height => Err(ProposalPartError::HeightOutOfRange(height)),
+            Some(highest) if highest > last_buffered && highest < height => {
+                self.retain(|(h, _), _| *h != highest);
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // A proposer only has one value to propose at a time, so its other unfinished proposals are
+    // dropped when it runs out of room. That way the part always fits, the largest proposal fits in
+    // the budget.
+    fn reserve(&mut self, proposer: &Address, key: (Height, i64), len: usize) {
+        let buffered = self.buffered_bytes.get(proposer).copied().unwrap_or(0);
+        if buffered + len > MAX_BUFFERED_BYTES_PER_PROPOSER {
+            self.retain(|stream_key, stream| {
+                stream.proposer != *proposer || stream.assembled || *stream_key == key
+            });
+        }
+    }
+
+    fn retain(&mut self, keep: impl Fn(&(Height, i64), &PartStream) -> bool) {
+        let buffered_bytes = &mut self.buffered_bytes;
+        self.streams.retain(|key, stream| {
+            let kept = keep(key, stream);
+            if !kept {
+                release(buffered_bytes, &stream.proposer, stream.buffered_bytes());
+            }
+            kept
+        });
+    }
+
+    /// Forgets the parts of the heights below the one being decided
+    pub fn start_height(&mut self, height: Height) {
+        self.height = Some(height);
+        self.retain(|(h, _), _| *h >= height);
+    }
+}
+
+fn release(buffered_bytes: &mut BTreeMap<Address, usize>, proposer: &Address, len: usize) {
+    if let Some(buffered) = buffered_bytes.get_mut(proposer) {
+        *buffered -= len;
+        if *buffered == 0 {
+            buffered_bytes.remove(proposer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::full_proposal::ProposedValue;
+    use crate::proto::{ShardChunk, ShardHeader};
+    use malachite_common::Round;
+
+    fn full_proposal(height: Height, transactions_size: usize) -> FullProposal {
+        FullProposal {
+            height: Some(height),
+            round: 0,
+            proposer: vec![1; 32],
+            proposed_value: Some(ProposedValue::Shard(ShardChunk {
+                header: Some(ShardHeader {
+                    height: Some(height),
+                    ..Default::default()
+                }),
+                // Stands in for the encoded transactions
+                hash: vec![7; transactions_size],
+                transactions: vec![],
+                votes: None,
+            })),
+            signature: vec![2; 64],
+        }
+    }
+
+    #[test]
+    fn test_parts_are_reassembled_in_any_order() {
+        let height = Height::new(1, 5);
+        let proposal = full_proposal(height, 3 * MAX_PROPOSAL_PART_BYTES);
+        let mut parts = ProposalPart::split(&proposal);
+        assert_eq!(parts.len(), 4);
+
+        let mut proposal_parts = ProposalParts::new();
+        proposal_parts.start_height(height);
+        let last = parts.pop().unwrap();
+        parts.reverse();
+        for part in parts.clone() {
+            assert!(proposal_parts.add_part(part).unwrap().is_none());
+        }
+        // Duplicates are ignored
+        assert!(proposal_parts.add_part(parts[0].clone()).unwrap().is_none());
+        assert_eq!(
+            proposal_parts.add_part(last.clone()).unwrap(),
+            Some(proposal.clone())
+        );
+        assert!(proposal_parts.add_part(last).unwrap().is_none());
+
+        // Parts of another proposal for the same height and round
+        let other = ProposalPart::split(&full_proposal(height, 10));
+        assert!(matches!(
+            proposal_parts.add_part(other[0].clone()),
+            Err(ProposalPartError::ProposalMismatch)
+        ));
+
+        proposal_parts.start_height(height.increment());
+        assert!(matches!(
+            proposal_parts.add_part(other[0].clone()),
+            Err(ProposalPartError::HeightOutOfRange(_))
+        ));
+        proposal_parts.start_height(height);
+        assert_eq!(
+            proposal_parts.add_part(other[0].clone()).unwrap(),
+            Some(full_proposal(height, 10))
+        );
+    }
+
+    #[test]
+    fn test_tampered_parts_are_rejected() {
+        let height = Height::new(1, 5);
+        let mut parts = ProposalPart::split(&full_proposal(height, MAX_PROPOSAL_PART_BYTES));
+        assert_eq!(parts.len(), 2);
+
+        let mut proposal_parts = ProposalParts::new();
+        proposal_parts.start_height(height);
+        parts[1].data[0] ^= 1;
+        assert!(proposal_parts.add_part(parts[0].clone()).unwrap().is_none());
+        assert!(matches!(
+            proposal_parts.add_part(parts[1].clone()),
+            Err(ProposalPartError::HashMismatch)
+        ));
+
+        parts[0].index = 2;
+        assert!(matches!(
+            proposal_parts.add_part(parts[0].clone()),
+            Err(ProposalPartError::InvalidIndex { index: 2, count: 2 })
+        ));
+    }
+
+    #[test]
+    fn test_parts_are_only_buffered_near_the_height_being_decided() {
+        let height = Height::new(1, 5);
+        let part = |block_number| {
+            ProposalPart::split(&full_proposal(Height::new(1, block_number), 10))[0].clone()
+        };
+
+        let mut proposal_parts = ProposalParts::new();
+        assert!(matches!(
+            proposal_parts.add_part(part(5)),
+            Err(ProposalPartError::HeightOutOfRange(_))
+        ));
+
+        proposal_parts.start_height(height);
+        assert!(matches!(
+            proposal_parts.add_part(part(4)),
+            Err(ProposalPartError::HeightOutOfRange(_))
+        ));
+        assert!(proposal_parts.add_part(part(5)).unwrap().is_some());
+        assert!(proposal_parts.add_part(part(7)).unwrap().is_some());
+
+        // Only the highest height above the window is assembled
+        assert!(proposal_parts.add_part(part(20)).unwrap().is_some());
+        assert!(matches!(
+            proposal_parts.add_part(part(10)),
+            Err(ProposalPartError::HeightOutOfRange(_))
+        ));
+        assert!(proposal_parts.add_part(part(30)).unwrap().is_some());
+        assert_eq!(
+            proposal_parts
+                .streams
+                .keys()
+                .map(|(h, _)| *h)
+                .collect::<Vec<_>>(),
+            vec![height, Height::new(1, 7), Height::new(1, 30)]
+        );
+    }
+
+    #[test]
+    fn test_unfinished_proposals_cant_starve_other_proposers() {
+        let height = Height::new(1, 5);
+        let byzantine = Address([3; 32]);
+        let part = |height: Height, round: i64, index: u32| ProposalPart {
+            height,
+            round: Round::new(round),
+            proposer: byzantine.clone(),
+            index,
+            count: MAX_PROPOSAL_PARTS,
+            proposal_hash: vec![round as u8; 32],
+            data: vec![0; MAX_PROPOSAL_PART_BYTES],
+        };
+
+        let mut proposal_parts = ProposalParts::new();
+        proposal_parts.start_height(height);
+        assert!(matches!(
+            proposal_parts.add_part(ProposalPart {
+                data: vec![0; MAX_PROPOSAL_PART_BYTES + 1],
+                ..part(height, 0, 0)
+            }),
+            Err(ProposalPartError::PartTooLarge(_))
+        ));
+
+        // Two of the largest proposals, neither finished. The second one replaces the first.
+        for (height, round) in [(height, 0), (height.increment(), 0)] {
+            for index in 0..MAX_PROPOSAL_PARTS - 1 {
+                assert!(proposal_parts
+                    .add_part(part(height, round, index))
+                    .unwrap()
+                    .is_none());
+            }
+        }
+        assert_eq!(
+            proposal_parts.buffered_bytes[&byzantine],
+            (MAX_PROPOSAL_PARTS as usize - 1) * MAX_PROPOSAL_PART_BYTES
+        );
+        assert!(!proposal_parts.streams.contains_key(&(height, 0)));
+
+        // Another proposer's proposal of a later round is still assembled
+        let mut proposal = full_proposal(height, 3 * MAX_PROPOSAL_PART_BYTES);
+        proposal.round = 1;
+        for (index, part) in ProposalPart::split(&proposal).into_iter().enumerate() {
+            let assembled = proposal_parts.add_part(part).unwrap();
+            assert_eq!(assembled.is_some(), index == 3);
+        }
+
+        // Forgetting a height frees its bytes
+        proposal_parts.start_height(height.increment_by(2));
+        assert!(proposal_parts.buffered_bytes.is_empty());
+    }
+}
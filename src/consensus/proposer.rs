@@ -1,21 +1,24 @@
-use crate::core::types::{
-    proto, Address, Height, ShardHash, ShardId, SnapchainShard, SnapchainValidator,
+use crate::consensus::sync::{
+    verify_synced_block, verify_synced_shard_chunk, SyncError, SyncedValuesResult,
+};
+use crate::consensus::validator_set::{ValidatorSetError, ValidatorSets};
+use crate::core::merkle::{
+    shard_headers_root, transactions_root, validator_messages_root, EMPTY_ROOT,
+};
+use crate::core::types::{proto, Address, Height, ShardHash, ShardId, SnapchainShard};
+use crate::proto::{Block, BlockHeader, ConfirmedVotes, FullProposal, ShardChunk, ShardHeader};
+use crate::storage::store::engine::{
+    BlockEngine, ReplayedStateChange, ShardEngine, ShardStateChange,
 };
-use crate::proto::hub_service_client::HubServiceClient;
-use crate::proto::{Block, BlockHeader, FullProposal, ShardChunk, ShardHeader};
-use crate::proto::{BlocksRequest, ShardChunksRequest};
-use crate::storage::store::engine::{BlockEngine, ShardEngine, ShardStateChange};
 use crate::storage::store::BlockStorageError;
 use malachite_common::{Round, Validity};
-use prost::Message;
 use std::collections::BTreeMap;
 use std::time::Duration;
 use thiserror::Error;
 use tokio::sync::mpsc;
 use tokio::time::Instant;
 use tokio::{select, time};
-use tonic::Request;
-use tracing::{error, info, warn};
+use tracing::{error, warn};
 
 const FARCASTER_EPOCH: u64 = 1609459200; // January 1, 2021 UTC
 
@@ -39,24 +42,29 @@ pub trait Proposer {
     // Receive a block/shard chunk proposed by another validator and return whether it is valid
     fn add_proposed_value(&mut self, full_proposal: &FullProposal) -> Validity;
 
-    // Consensus has confirmed the block/shard_chunk, apply it to the local state
-    async fn decide(&mut self, height: Height, round: Round, value: ShardHash);
+    // Consensus has confirmed the block/shard_chunk, apply it to the local state. The votes are its
+    // commit certificate.
+    async fn decide(
+        &mut self,
+        height: Height,
+        round: Round,
+        value: ShardHash,
+        votes: ConfirmedVotes,
+    );
 
     fn get_confirmed_height(&self) -> Height;
-
-    async fn sync_against_validator(
-        &mut self,
-        validator: &SnapchainValidator,
-    ) -> Result<(), Box<dyn std::error::Error>>;
 }
 
 pub struct ShardProposer {
     shard_id: SnapchainShard,
     address: Address,
     proposed_chunks: BTreeMap<ShardHash, FullProposal>,
+    // Proposed chunks that were already replayed, so they don't need to be replayed again on decide
+    replayed_chunks: BTreeMap<ShardHash, ReplayedStateChange>,
     tx_decision: mpsc::Sender<ShardChunk>,
     engine: ShardEngine,
     propose_value_delay: Duration,
+    max_propose_delay: Option<Duration>,
 }
 
 impl ShardProposer {
@@ -66,20 +74,81 @@ impl ShardProposer {
         engine: ShardEngine,
         tx_decision: mpsc::Sender<ShardChunk>,
         propose_value_delay: Duration,
+        max_propose_delay: Option<Duration>,
     ) -> ShardProposer {
         ShardProposer {
             shard_id,
             address,
             proposed_chunks: BTreeMap::new(),
+            replayed_chunks: BTreeMap::new(),
             tx_decision,
             engine,
             propose_value_delay,
+            max_propose_delay,
         }
     }
 
+    pub fn is_halted(&self) -> bool {
+        self.engine.is_halted()
+    }
+
     async fn publish_new_shard_chunk(&self, shard_chunk: &ShardChunk) {
         let _ = &self.tx_decision.send(shard_chunk.clone()).await;
     }
+
+    // The chunk must continue from the last committed one, or synced chunks wouldn't verify
+    fn check_proposed_chunk<'a>(
+        &self,
+        chunk: &'a ShardChunk,
+        height: Option<Height>,
+    ) -> Result<&'a ShardHeader, &'static str> {
+        let header = chunk.header.as_ref().ok_or("missing header")?;
+        if header.height.is_none() || header.height != height {
+            return Err("height doesn't match the proposal");
+        }
+        let (parent_hash, parent_timestamp) = match self.engine.get_last_shard_chunk() {
+            Some(parent) => (parent.hash, parent.header.map_or(0, |h| h.timestamp)),
+            None => (EMPTY_ROOT.to_vec(), 0),
+        };
+        if header.parent_hash != parent_hash {
+            return Err("parent hash doesn't match the last chunk");
+        }
+        if header.timestamp < parent_timestamp {
+            return Err("timestamp is before the last chunk's");
+        }
+        if chunk.hash != header.hash() {
+            return Err("hash doesn't match the header");
+        }
+        if header.transactions_root != transactions_root(&chunk.transactions) {
+            return Err("transactions root doesn't match the transactions");
+        }
+        Ok(header)
+    }
+
+    /// Verifies and commits downloaded chunks in order, stopping at the first one that fails
+    pub fn apply_synced_chunks(
+        &mut self,
+        shard_chunks: Vec<ShardChunk>,
+        validator_sets: &ValidatorSets,
+    ) -> SyncedValuesResult {
+        let shard_id = self.shard_id.shard_id();
+        let engine = &mut self.engine;
+        let apply = |shard_chunk: ShardChunk| {
+            let height = Height::new(shard_id, engine.get_confirmed_height().block_number + 1);
+            let parent_hash = match engine.get_last_shard_chunk() {
+                Some(chunk) => chunk.hash,
+                None => EMPTY_ROOT.to_vec(),
+            };
+            verify_synced_shard_chunk(&shard_chunk, height, &parent_hash, validator_sets)?;
+            engine.commit_shard_chunk(&shard_chunk);
+            if engine.is_halted() {
+                return Err(SyncError::Halted);
+            }
+            Ok(())
+        };
+        let result = shard_chunks.into_iter().try_for_each(apply);
+        (self.engine.get_confirmed_height().block_number, result)
+    }
 }
 
 impl Proposer for ShardProposer {
@@ -87,34 +156,40 @@ impl Proposer for ShardProposer {
         &mut self,
         height: Height,
         round: Round,
-        _timeout: Duration,
+        timeout: Duration,
     ) -> FullProposal {
+        // Leave the other half of the propose timeout for the proposal to reach the validators
+        let max_wait = match self.max_propose_delay {
+            Some(max_propose_delay) => max_propose_delay
+                .min(timeout / 2)
+                .max(self.propose_value_delay),
+            None => self.propose_value_delay,
+        };
         // TODO: perhaps not the best place to get our messages, but this is (currently) the
         // last place we're still in an async function
         let messages = self
             .engine
-            .pull_messages(self.propose_value_delay)
+            .wait_for_messages(self.propose_value_delay, max_wait)
             .await
             .unwrap(); // TODO: don't unwrap
 
         let previous_chunk = self.engine.get_last_shard_chunk();
         let parent_hash = match previous_chunk {
             Some(chunk) => chunk.hash.clone(),
-            None => vec![0, 32],
+            None => EMPTY_ROOT.to_vec(),
         };
 
-        let state_change = self
+        let (state_change, replayed) = self
             .engine
-            .propose_state_change(self.shard_id.shard_id(), messages);
+            .propose_replayed_state_change(self.shard_id.shard_id(), messages);
         let shard_header = ShardHeader {
             parent_hash,
             timestamp: current_time(),
             height: Some(height.clone()),
             shard_root: state_change.new_state_root.clone(),
+            transactions_root: transactions_root(&state_change.transactions),
         };
-        let hash = blake3::hash(&shard_header.encode_to_vec())
-            .as_bytes()
-            .to_vec();
+        let hash = shard_header.hash();
 
         let chunk = ShardChunk {
             header: Some(shard_header),
@@ -132,72 +207,72 @@ impl Proposer for ShardProposer {
             round: round.as_i64(),
             proposed_value: Some(proto::full_proposal::ProposedValue::Shard(chunk)),
             proposer: self.address.to_vec(),
+            signature: vec![], // Signed by consensus before it's broadcast
         };
-        self.proposed_chunks.insert(shard_hash, proposal.clone());
+        self.proposed_chunks
+            .insert(shard_hash.clone(), proposal.clone());
+        self.replayed_chunks.insert(shard_hash, replayed);
         proposal
     }
 
     fn add_proposed_value(&mut self, full_proposal: &FullProposal) -> Validity {
-        if let Some(proto::full_proposal::ProposedValue::Shard(chunk)) =
-            full_proposal.proposed_value.clone()
-        {
-            self.proposed_chunks
-                .insert(full_proposal.shard_hash(), full_proposal.clone());
-            let state = ShardStateChange {
-                shard_id: chunk.header.clone().unwrap().height.unwrap().shard_index,
-                new_state_root: chunk.header.clone().unwrap().shard_root.clone(),
-                transactions: chunk.transactions.clone(),
-            };
-            return if self.engine.validate_state_change(&state) {
+        let Some(proto::full_proposal::ProposedValue::Shard(chunk)) = &full_proposal.proposed_value
+        else {
+            error!("Invalid proposed value: {:?}", full_proposal.proposed_value);
+            return Validity::Invalid;
+        };
+        let header = match self.check_proposed_chunk(chunk, full_proposal.height) {
+            Ok(header) => header,
+            Err(reason) => {
+                error!(height = ?full_proposal.height, "Invalid shard chunk: {}", reason);
+                return Validity::Invalid;
+            }
+        };
+        self.proposed_chunks
+            .insert(full_proposal.shard_hash(), full_proposal.clone());
+        let state = ShardStateChange {
+            shard_id: self.shard_id.shard_id(),
+            new_state_root: header.shard_root.clone(),
+            transactions: chunk.transactions.clone(),
+        };
+        match self.engine.replay_state_change(&state) {
+            Some(replayed) => {
+                self.replayed_chunks
+                    .insert(full_proposal.shard_hash(), replayed);
                 Validity::Valid
-            } else {
+            }
+            None => {
                 error!("Invalid state change for shard: {:?}", state.shard_id);
                 Validity::Invalid
-            };
+            }
         }
-        error!("Invalid proposed value: {:?}", full_proposal.proposed_value);
-        Validity::Invalid // TODO: Validate proposer signature?
     }
 
-    async fn decide(&mut self, _height: Height, _round: Round, value: ShardHash) {
-        if let Some(proposal) = self.proposed_chunks.get(&value) {
-            self.publish_new_shard_chunk(proposal.shard_chunk().unwrap())
-                .await;
-            self.engine
-                .commit_shard_chunk(proposal.shard_chunk().unwrap());
-            self.proposed_chunks.remove(&value);
+    async fn decide(
+        &mut self,
+        _height: Height,
+        _round: Round,
+        value: ShardHash,
+        votes: ConfirmedVotes,
+    ) {
+        if let Some(proposal) = self.proposed_chunks.remove(&value) {
+            let mut shard_chunk = proposal.shard_chunk().unwrap().clone();
+            shard_chunk.votes = Some(votes);
+            self.publish_new_shard_chunk(&shard_chunk).await;
+            match self.replayed_chunks.remove(&value) {
+                Some(replayed) => self
+                    .engine
+                    .commit_replayed_shard_chunk(&shard_chunk, replayed),
+                None => self.engine.commit_shard_chunk(&shard_chunk),
+            }
         }
+        // Any other replayed chunks were replayed on top of the previous state
+        self.replayed_chunks.clear();
     }
 
     fn get_confirmed_height(&self) -> Height {
         self.engine.get_confirmed_height()
     }
-
-    async fn sync_against_validator(
-        &mut self,
-        validator: &SnapchainValidator,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let prev_block_number = self.engine.get_confirmed_height().block_number;
-
-        match &validator.rpc_address {
-            None => return Ok(()),
-            Some(rpc_address) => {
-                let destination_addr = format!("http://{}", rpc_address.clone());
-                let mut rpc_client = HubServiceClient::connect(destination_addr).await?;
-                let request = Request::new(ShardChunksRequest {
-                    shard_id: self.shard_id.shard_id(),
-                    start_block_number: prev_block_number + 1,
-                    stop_block_number: None,
-                });
-                let missing_shard_chunks = rpc_client.get_shard_chunks(request).await?;
-                for shard_chunk in missing_shard_chunks.get_ref().shard_chunks.clone() {
-                    self.engine.commit_shard_chunk(&shard_chunk);
-                }
-            }
-        }
-
-        Ok(())
-    }
 }
 
 #[derive(Error, Debug)]
@@ -221,8 +296,73 @@ pub enum BlockProposerError {
     BlockStorageError(#[from] BlockStorageError),
 }
 
+// How far a block's timestamp can be from the local clock, in seconds
+const MAX_BLOCK_TIME_DRIFT: u64 = 30;
+
+#[derive(Error, Debug)]
+pub enum BlockValidationError {
+    #[error("Block missing header")]
+    MissingHeader,
+
+    #[error("Block missing height")]
+    MissingHeight,
+
+    #[error("Expected block {expected}, got {actual}")]
+    UnexpectedHeight { expected: u64, actual: u64 },
+
+    #[error("Parent hash doesn't match the last block")]
+    ParentHashMismatch,
+
+    #[error("Block hash doesn't match the header")]
+    HashMismatch,
+
+    #[error("Timestamp {timestamp} is before the last block's {previous}")]
+    TimestampNotMonotonic { previous: u64, timestamp: u64 },
+
+    #[error("Timestamp {timestamp} is too far from the local time {now}")]
+    TimestampDrift { timestamp: u64, now: u64 },
+
+    #[error("Shard chunk missing header")]
+    ChunkMissingHeader,
+
+    #[error("Invalid chunk {block_number} of shard {shard_index}: {reason}")]
+    InvalidChunk {
+        shard_index: u32,
+        block_number: u64,
+        reason: String,
+    },
+
+    #[error("Expected chunk {expected} of shard {shard_index}, got {actual}")]
+    NonContiguousChunks {
+        shard_index: u32,
+        expected: u64,
+        actual: u64,
+    },
+
+    #[error("Shard headers hash doesn't match the chunks")]
+    ShardHeadersHashMismatch,
+
+    #[error("Validator messages root doesn't match the validator messages")]
+    ValidatorMessagesRootMismatch,
+
+    #[error("Validators hash doesn't match the validator set")]
+    ValidatorsHashMismatch,
+
+    #[error("Blocks only include validator set updates")]
+    UnexpectedValidatorMessage,
+
+    #[error(transparent)]
+    ValidatorSetError(#[from] ValidatorSetError),
+}
+
+fn parent_hash(previous_block: Option<&Block>) -> Vec<u8> {
+    match previous_block {
+        Some(block) => block.hash.clone(),
+        None => EMPTY_ROOT.to_vec(),
+    }
+}
+
 pub struct BlockProposer {
-    #[allow(dead_code)] // TODO
     shard_id: SnapchainShard,
 
     address: Address,
@@ -255,6 +395,31 @@ impl BlockProposer {
         }
     }
 
+    fn receive_decided_chunks(&mut self) {
+        // TODO(aditi): This breaks if syncd shard chunks show up in shard_decision_rx.
+        while let Ok(chunk) = self.shard_decision_rx.try_recv() {
+            let chunk_height = chunk.header.clone().unwrap().height.unwrap();
+            self.pending_chunks
+                .entry(chunk_height.block_number)
+                .or_default()
+                .push(chunk);
+        }
+    }
+
+    // Drops the chunks that were included in a committed block
+    fn prune_pending_chunks(&mut self) {
+        let engine = &self.engine;
+        for chunks in self.pending_chunks.values_mut() {
+            chunks.retain(|chunk| {
+                let height = chunk.header.as_ref().unwrap().height.unwrap();
+                engine
+                    .last_chunk_height(height.shard_index)
+                    .map_or(true, |last| height.block_number > last)
+            });
+        }
+        self.pending_chunks.retain(|_, chunks| !chunks.is_empty());
+    }
+
     async fn collect_confirmed_shard_chunks(
         &mut self,
         height: Height,
@@ -270,16 +435,7 @@ impl BlockProposer {
             let timeout = time::sleep_until(deadline);
             select! {
                 _ = poll_interval.tick() => {
-                    // TODO(aditi): This breaks if syncd shard chunks show up in shard_decision_rx.
-                    if let Ok(chunk) = self.shard_decision_rx.try_recv() {
-                        let chunk_height = chunk.header.clone().unwrap().height.unwrap();
-                        let chunk_block_number = chunk_height.block_number;
-                        if self.pending_chunks.contains_key(&chunk_block_number) {
-                            self.pending_chunks.get_mut(&chunk_block_number).unwrap().push(chunk);
-                        } else {
-                            self.pending_chunks.insert(chunk_block_number, vec![chunk]);
-                        }
-                    }
+                    self.receive_decided_chunks();
                     if let Some(chunks) = self.pending_chunks.get(&requested_height) {
                         if chunks.len() == self.num_shards as usize {
                             break;
@@ -293,11 +449,159 @@ impl BlockProposer {
             }
         }
 
-        if let Some(chunks) = self.pending_chunks.get(&requested_height) {
-            chunks.clone()
-        } else {
-            vec![]
+        // Chunks that missed earlier blocks are included too, so every shard's chunks are
+        // contiguous across blocks
+        self.prune_pending_chunks();
+        let mut chunks: Vec<ShardChunk> = self
+            .pending_chunks
+            .range(..=requested_height)
+            .flat_map(|(_, chunks)| chunks.iter().cloned())
+            .collect();
+        chunks.sort_by_key(|chunk| {
+            let height = chunk.header.as_ref().unwrap().height.unwrap();
+            (height.shard_index, height.block_number)
+        });
+        chunks
+    }
+
+    fn is_local_chunk(&self, chunk: &ShardChunk, height: Height) -> bool {
+        self.pending_chunks
+            .get(&height.block_number)
+            .is_some_and(|chunks| {
+                chunks.iter().any(|local| {
+                    local.hash == chunk.hash
+                        && local.header.as_ref().and_then(|header| header.height) == Some(height)
+                })
+            })
+    }
+
+    fn validate_shard_chunk(&self, chunk: &ShardChunk) -> Result<(), BlockValidationError> {
+        let header = chunk
+            .header
+            .as_ref()
+            .ok_or(BlockValidationError::ChunkMissingHeader)?;
+        let height = header
+            .height
+            .ok_or(BlockValidationError::ChunkMissingHeader)?;
+        let invalid = |reason: &str| BlockValidationError::InvalidChunk {
+            shard_index: height.shard_index,
+            block_number: height.block_number,
+            reason: reason.to_string(),
+        };
+
+        if chunk.hash != header.hash() {
+            return Err(invalid("hash doesn't match the header"));
+        }
+        if header.transactions_root != transactions_root(&chunk.transactions) {
+            return Err(invalid("transactions root doesn't match the transactions"));
+        }
+        if self.is_local_chunk(chunk, height) {
+            return Ok(());
         }
+        let Some(votes) = &chunk.votes else {
+            return Err(invalid("not decided locally and has no commit certificate"));
+        };
+        let value = ShardHash {
+            shard_index: height.shard_index,
+            hash: chunk.hash.clone(),
+        };
+        self.engine
+            .validator_sets()
+            .verify_commit_certificate(height, &value, votes)
+            .map_err(|err| invalid(&err.to_string()))
+    }
+
+    fn validate_block(&self, block: &Block) -> Result<(), BlockValidationError> {
+        let header = block
+            .header
+            .as_ref()
+            .ok_or(BlockValidationError::MissingHeader)?;
+        let height = header.height.ok_or(BlockValidationError::MissingHeight)?;
+
+        let confirmed_height = self.engine.get_confirmed_height();
+        if height.block_number != confirmed_height.block_number + 1 {
+            return Err(BlockValidationError::UnexpectedHeight {
+                expected: confirmed_height.block_number + 1,
+                actual: height.block_number,
+            });
+        }
+
+        let previous_block = self.engine.get_last_block();
+        if header.parent_hash != parent_hash(previous_block.as_ref()) {
+            return Err(BlockValidationError::ParentHashMismatch);
+        }
+        if block.hash != header.hash() {
+            return Err(BlockValidationError::HashMismatch);
+        }
+
+        let previous_timestamp = previous_block
+            .as_ref()
+            .and_then(|block| block.header.as_ref())
+            .map_or(0, |header| header.timestamp);
+        if header.timestamp < previous_timestamp {
+            return Err(BlockValidationError::TimestampNotMonotonic {
+                previous: previous_timestamp,
+                timestamp: header.timestamp,
+            });
+        }
+        let now = current_time();
+        if header.timestamp.abs_diff(now) > MAX_BLOCK_TIME_DRIFT {
+            return Err(BlockValidationError::TimestampDrift {
+                timestamp: header.timestamp,
+                now,
+            });
+        }
+
+        // The chunks of each shard must continue from the last one included in a block
+        let mut next_chunk_heights: BTreeMap<u32, u64> = BTreeMap::new();
+        for chunk in &block.shard_chunks {
+            self.validate_shard_chunk(chunk)?;
+            let chunk_height = chunk.header.as_ref().unwrap().height.unwrap();
+            let shard_index = chunk_height.shard_index;
+            let expected = match next_chunk_heights.get(&shard_index) {
+                Some(next) => Some(*next),
+                None if confirmed_height.block_number == 0 => Some(1),
+                None => self
+                    .engine
+                    .last_chunk_height(shard_index)
+                    .map(|last| last + 1),
+            };
+            if let Some(expected) = expected {
+                if chunk_height.block_number != expected {
+                    return Err(BlockValidationError::NonContiguousChunks {
+                        shard_index,
+                        expected,
+                        actual: chunk_height.block_number,
+                    });
+                }
+            }
+            next_chunk_heights.insert(shard_index, chunk_height.block_number + 1);
+        }
+        if header.shard_headers_hash != shard_headers_root(&block.shard_chunks) {
+            return Err(BlockValidationError::ShardHeadersHashMismatch);
+        }
+        if header.validator_messages_root != validator_messages_root(&block.validator_messages) {
+            return Err(BlockValidationError::ValidatorMessagesRootMismatch);
+        }
+
+        // The header must commit to the validator set of its height, and included updates must be valid
+        let validator_sets = self.engine.validator_sets();
+        if header.validators_hash != validator_sets.validators_hash(height.block_number) {
+            return Err(BlockValidationError::ValidatorsHashMismatch);
+        }
+        let mut updates = vec![];
+        for msg in &block.validator_messages {
+            let Some(update) = &msg.validator_set_update else {
+                return Err(BlockValidationError::UnexpectedValidatorMessage);
+            };
+            updates.push(update.clone());
+        }
+        validator_sets.validate_block_updates(
+            &updates,
+            height.block_number,
+            &block.shard_chunks,
+        )?;
+        Ok(())
     }
 
     async fn publish_new_block(&self, block: Block) {
@@ -310,6 +614,28 @@ impl BlockProposer {
             }
         }
     }
+
+    /// Verifies and commits downloaded blocks in order, stopping at the first one that fails
+    pub fn apply_synced_blocks(
+        &mut self,
+        blocks: Vec<Block>,
+        validator_sets: &ValidatorSets,
+    ) -> SyncedValuesResult {
+        let engine = &mut self.engine;
+        let apply = |block: Block| {
+            let height = engine.get_confirmed_height().increment();
+            let parent_hash = parent_hash(engine.get_last_block().as_ref());
+            verify_synced_block(&block, height, &parent_hash, validator_sets)?;
+            engine.commit_block(block);
+            if engine.get_confirmed_height() != height {
+                return Err(SyncError::CommitFailed(height.block_number));
+            }
+            Ok(())
+        };
+        let result = blocks.into_iter().try_for_each(apply);
+        self.prune_pending_chunks();
+        (self.get_confirmed_height().block_number, result)
+    }
 }
 
 impl Proposer for BlockProposer {
@@ -322,22 +648,26 @@ impl Proposer for BlockProposer {
         let shard_chunks = self.collect_confirmed_shard_chunks(height, timeout).await;
 
         let previous_block = self.engine.get_last_block();
-        let parent_hash = match previous_block {
-            Some(block) => block.hash.clone(),
-            None => vec![0, 32],
-        };
+        let validator_sets = self.engine.validator_sets();
+        let validator_messages =
+            validator_sets.pending_messages(height.block_number, &shard_chunks);
         let block_header = BlockHeader {
-            parent_hash,
+            parent_hash: parent_hash(previous_block.as_ref()),
             chain_id: 0,
             version: 0,
-            shard_headers_hash: vec![],
-            validators_hash: vec![],
-            timestamp: current_time(),
+            shard_headers_hash: shard_headers_root(&shard_chunks),
+            validators_hash: validator_sets.validators_hash(height.block_number),
+            // Never before the previous block, even if the local clock is behind
+            timestamp: current_time().max(
+                previous_block
+                    .as_ref()
+                    .and_then(|block| block.header.as_ref())
+                    .map_or(0, |header| header.timestamp),
+            ),
             height: Some(height.clone()),
+            validator_messages_root: validator_messages_root(&validator_messages),
         };
-        let hash = blake3::hash(&block_header.encode_to_vec())
-            .as_bytes()
-            .to_vec();
+        let hash = block_header.hash();
 
         let block = Block {
             header: Some(block_header),
@@ -345,6 +675,7 @@ impl Proposer for BlockProposer {
             validators: None,
             votes: None,
             shard_chunks,
+            validator_messages,
         };
 
         let shard_hash = ShardHash {
@@ -357,6 +688,7 @@ impl Proposer for BlockProposer {
             round: round.as_i64(),
             proposed_value: Some(proto::full_proposal::ProposedValue::Block(block)),
             proposer: self.address.to_vec(),
+            signature: vec![], // Signed by consensus before it's broadcast
         };
 
         self.proposed_blocks.insert(shard_hash, proposal.clone());
@@ -364,55 +696,39 @@ impl Proposer for BlockProposer {
     }
 
     fn add_proposed_value(&mut self, full_proposal: &FullProposal) -> Validity {
-        if let Some(proto::full_proposal::ProposedValue::Block(_block)) =
-            full_proposal.proposed_value.clone()
-        {
-            self.proposed_blocks
-                .insert(full_proposal.shard_hash(), full_proposal.clone());
+        let Some(proto::full_proposal::ProposedValue::Block(block)) = &full_proposal.proposed_value
+        else {
+            error!("Invalid proposed value: {:?}", full_proposal.proposed_value);
+            return Validity::Invalid;
+        };
+
+        self.receive_decided_chunks();
+        if let Err(err) = self.validate_block(block) {
+            warn!(height = %full_proposal.height(), "Invalid proposed block: {}", err);
+            return Validity::Invalid;
         }
-        Validity::Valid // TODO: Validate proposer signature?
+        self.proposed_blocks
+            .insert(full_proposal.shard_hash(), full_proposal.clone());
+        Validity::Valid
     }
 
-    async fn decide(&mut self, height: Height, _round: Round, value: ShardHash) {
-        if let Some(proposal) = self.proposed_blocks.get(&value) {
-            self.publish_new_block(proposal.block().unwrap()).await;
-            self.engine.commit_block(proposal.block().unwrap());
-            self.proposed_blocks.remove(&value);
-            self.pending_chunks.remove(&height.block_number);
+    async fn decide(
+        &mut self,
+        _height: Height,
+        _round: Round,
+        value: ShardHash,
+        votes: ConfirmedVotes,
+    ) {
+        if let Some(proposal) = self.proposed_blocks.remove(&value) {
+            let mut block = proposal.block().unwrap();
+            block.votes = Some(votes);
+            self.publish_new_block(block.clone()).await;
+            self.engine.commit_block(block);
+            self.prune_pending_chunks();
         }
     }
 
     fn get_confirmed_height(&self) -> Height {
         self.engine.get_confirmed_height()
     }
-
-    async fn sync_against_validator(
-        &mut self,
-        validator: &SnapchainValidator,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        let prev_block_number = self.engine.get_confirmed_height().block_number;
-
-        match &validator.rpc_address {
-            None => return Ok(()),
-            Some(rpc_address) => {
-                info!({ rpc_address }, "Starting block sync against a validator");
-                let destination_addr = format!("http://{}", rpc_address.clone());
-                let mut rpc_client = HubServiceClient::connect(destination_addr).await?;
-                let request = Request::new(BlocksRequest {
-                    shard_id: self.shard_id.shard_id(),
-                    start_block_number: prev_block_number + 1,
-                    stop_block_number: None,
-                });
-                let mut missing_blocks_rx = rpc_client.get_blocks(request).await?;
-                let mut num_blocks_synced = 0;
-                while let Ok(Some(block)) = missing_blocks_rx.get_mut().message().await {
-                    self.engine.commit_block(block.clone());
-                    num_blocks_synced += 1;
-                }
-                info!({ rpc_address, num_blocks_synced }, "Finished block sync against a validator");
-            }
-        }
-
-        Ok(())
-    }
 }
@@ -0,0 +1,678 @@
+use crate::consensus::validator_set::ValidatorSets;
+use crate::core::merkle::{shard_headers_root, transactions_root, validator_messages_root};
+use crate::core::types::{Address, Height, ShardHash, SnapchainValidator};
+use crate::proto::hub_service_client::HubServiceClient;
+use crate::proto::{Block, BlocksRequest, ConfirmedVotes, ShardChunk, ShardChunksRequest};
+use futures::future::join_all;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use thiserror::Error;
+use tokio::time::{timeout, Instant};
+use tonic::Request;
+use tracing::{info, warn};
+
+// Blocks or chunks requested from a peer at once
+const SYNC_PAGE_SIZE: u64 = 100;
+
+// Peers downloaded from in parallel, each serving a different page
+const MAX_PARALLEL_PEERS: usize = 4;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Failed requests in a row before an endpoint isn't synced from for a while. Endpoints serving data
+// that doesn't verify are blacklisted right away.
+const MAX_PEER_FAILURES: u32 = 3;
+const BLACKLIST_DURATION: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Error, Debug)]
+pub enum SyncError {
+    #[error("No peers to sync from")]
+    NoPeers,
+
+    #[error("Request timed out")]
+    Timeout,
+
+    #[error("Peer returned nothing from {0}")]
+    EmptyResponse(u64),
+
+    #[error("Invalid {block_number}: {reason}")]
+    Invalid { block_number: u64, reason: String },
+
+    #[error("Shard is halted")]
+    Halted,
+
+    #[error("Unable to commit {0}")]
+    CommitFailed(u64),
+
+    #[error("Consensus stopped")]
+    Stopped,
+
+    #[error(transparent)]
+    RpcTransportError(#[from] tonic::transport::Error),
+
+    #[error(transparent)]
+    RpcResponseError(#[from] tonic::Status),
+}
+
+impl SyncError {
+    fn invalid(block_number: u64, reason: impl ToString) -> Self {
+        SyncError::Invalid {
+            block_number,
+            reason: reason.to_string(),
+        }
+    }
+}
+
+struct SyncPeer {
+    rpc_address: Option<String>,
+    height: u64,
+}
+
+// Failures are counted by rpc address, so a peer can't shed its record by showing up under another
+// validator, and validators sharing an endpoint share its record
+#[derive(Default)]
+struct Endpoint {
+    failures: u32,
+    blacklisted_until: Option<Instant>,
+}
+
+impl Endpoint {
+    fn is_usable(&self, now: Instant) -> bool {
+        self.blacklisted_until.map_or(true, |until| until <= now)
+    }
+}
+
+struct Inner {
+    peers: BTreeMap<Address, SyncPeer>,
+    endpoints: HashMap<String, Endpoint>,
+}
+
+impl Inner {
+    fn peer(&mut self, address: &Address) -> &mut SyncPeer {
+        self.peers.entry(address.clone()).or_insert(SyncPeer {
+            rpc_address: None,
+            height: 0,
+        })
+    }
+
+    // Usable endpoints and the highest block a validator reachable there has
+    fn usable_endpoints(&self, now: Instant) -> HashMap<&String, u64> {
+        let mut endpoints = HashMap::new();
+        for peer in self.peers.values() {
+            let Some(rpc_address) = &peer.rpc_address else {
+                continue;
+            };
+            if !self
+                .endpoints
+                .get(rpc_address)
+                .map_or(true, |endpoint| endpoint.is_usable(now))
+            {
+                continue;
+            }
+            let height = endpoints.entry(rpc_address).or_insert(0);
+            *height = peer.height.max(*height);
+        }
+        endpoints
+    }
+}
+
+/// The heights the validators of a shard are known to have and how reliably their endpoints served
+/// blocks or chunks. Tells when the node is behind, and which endpoints to download the missing
+/// range from. Shared with the task downloading it.
+#[derive(Clone)]
+pub struct SyncPeers {
+    shard_id: u32,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl SyncPeers {
+    pub fn new(shard_id: u32) -> Self {
+        SyncPeers {
+            shard_id,
+            inner: Arc::new(Mutex::new(Inner {
+                peers: BTreeMap::new(),
+                endpoints: HashMap::new(),
+            })),
+        }
+    }
+
+    // From the validator's registration. Its height is self reported, so it isn't trusted.
+    pub fn update(&self, validator: &SnapchainValidator) {
+        if validator.rpc_address.is_some() {
+            let mut inner = self.inner.lock().unwrap();
+            inner.peer(&validator.address).rpc_address = validator.rpc_address.clone();
+        }
+    }
+
+    // The validator signed something showing it has confirmed at least this block, e.g. it
+    // proposed the next one
+    pub fn observe_height(&self, address: &Address, block_number: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let peer = inner.peer(address);
+        peer.height = peer.height.max(block_number);
+    }
+
+    /// The highest block a peer that can be synced from has
+    pub fn target_height(&self) -> u64 {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .usable_endpoints(Instant::now())
+            .into_values()
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn is_behind(&self, confirmed_block_number: u64) -> bool {
+        self.target_height() > confirmed_block_number
+    }
+
+    // Usable endpoints that have the block, the most reliable first
+    fn endpoints_with(&self, block_number: u64) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        let mut endpoints: Vec<_> = inner
+            .usable_endpoints(Instant::now())
+            .into_iter()
+            .filter(|(_, height)| *height >= block_number)
+            .map(|(rpc_address, height)| {
+                let failures = inner
+                    .endpoints
+                    .get(rpc_address)
+                    .map_or(0, |endpoint| endpoint.failures);
+                (failures, u64::MAX - height, rpc_address.clone())
+            })
+            .collect();
+        endpoints.sort();
+        endpoints
+            .into_iter()
+            .map(|(_, _, rpc_address)| rpc_address)
+            .collect()
+    }
+
+    fn record_success(&self, rpc_address: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(endpoint) = inner.endpoints.get_mut(rpc_address) {
+            endpoint.failures = 0;
+        }
+    }
+
+    fn record_failure(&self, rpc_address: &str, err: &SyncError) {
+        let mut inner = self.inner.lock().unwrap();
+        let endpoint = inner.endpoints.entry(rpc_address.to_string()).or_default();
+        endpoint.failures += 1;
+        if matches!(err, SyncError::Invalid { .. }) || endpoint.failures >= MAX_PEER_FAILURES {
+            warn!(
+                shard_id = self.shard_id,
+                peer = rpc_address,
+                "Blacklisting sync peer: {}",
+                err
+            );
+            endpoint.failures = 0;
+            endpoint.blacklisted_until = Some(Instant::now() + BLACKLIST_DURATION);
+        }
+    }
+
+    /// Downloads blocks start..=stop in pages, one page per endpoint in parallel, and applies them
+    /// in order. A page that fails to download or apply is requested again from another endpoint
+    /// on the next pass. Returns the last block number applied.
+    pub async fn download<T, F, Fut, A, AFut>(
+        &self,
+        start: u64,
+        stop: u64,
+        fetch: F,
+        mut apply: A,
+    ) -> Result<u64, SyncError>
+    where
+        // Fetches the blocks from the peer at the rpc address, stop is exclusive
+        F: Fn(String, u64, u64) -> Fut,
+        Fut: Future<Output = Result<Vec<T>, SyncError>>,
+        // Applies the page in order, returning the last block number applied even if it fails
+        A: FnMut(Vec<T>) -> AFut,
+        AFut: Future<Output = (u64, Result<(), SyncError>)>,
+    {
+        let mut next = start;
+        while next <= stop {
+            let endpoints = self.endpoints_with(next);
+            if endpoints.is_empty() {
+                return Err(SyncError::NoPeers);
+            }
+
+            let requests = endpoints
+                .into_iter()
+                .take(MAX_PARALLEL_PEERS)
+                .enumerate()
+                .map(|(i, rpc_address)| {
+                    let page_start = next + i as u64 * SYNC_PAGE_SIZE;
+                    let page_stop = (page_start + SYNC_PAGE_SIZE).min(stop + 1);
+                    (rpc_address, page_start, page_stop)
+                })
+                .filter(|(_, page_start, _)| *page_start <= stop)
+                .map(|(rpc_address, page_start, page_stop)| {
+                    let request = fetch(rpc_address.clone(), page_start, page_stop);
+                    async move {
+                        let result = match timeout(REQUEST_TIMEOUT, request).await {
+                            Ok(result) => result,
+                            Err(_) => Err(SyncError::Timeout),
+                        };
+                        (rpc_address, page_start, result)
+                    }
+                });
+
+            for (rpc_address, page_start, result) in join_all(requests).await {
+                // An earlier page failed or came back short, this one doesn't continue from the
+                // last block applied
+                if page_start != next {
+                    break;
+                }
+                let result = match result {
+                    Ok(items) if items.is_empty() => Err(SyncError::EmptyResponse(page_start)),
+                    Ok(items) => {
+                        let (last_applied, result) = apply(items).await;
+                        next = last_applied + 1;
+                        result
+                    }
+                    Err(err) => Err(err),
+                };
+                match result {
+                    Ok(()) => self.record_success(&rpc_address),
+                    // Not the peer's fault, retrying won't help
+                    Err(
+                        err @ (SyncError::Halted | SyncError::CommitFailed(_) | SyncError::Stopped),
+                    ) => return Err(err),
+                    Err(err) => {
+                        warn!(
+                            shard_id = self.shard_id,
+                            peer = rpc_address,
+                            block_number = next,
+                            "Sync request failed: {}",
+                            err
+                        );
+                        self.record_failure(&rpc_address, &err);
+                        break;
+                    }
+                }
+            }
+        }
+
+        info!(shard_id = self.shard_id, start, stop, "Finished sync");
+        Ok(next - 1)
+    }
+}
+
+/// Downloaded blocks or chunks, sent to the consensus actor to be verified and committed in order
+#[derive(Clone, Debug)]
+pub enum SyncedValues {
+    Blocks(Vec<Block>),
+    ShardChunks(Vec<ShardChunk>),
+}
+
+// The last block number applied, and why the rest wasn't
+pub type SyncedValuesResult = (u64, Result<(), SyncError>);
+
+pub async fn fetch_shard_chunks(
+    rpc_address: String,
+    shard_id: u32,
+    start_block_number: u64,
+    stop_block_number: u64,
+) -> Result<Vec<ShardChunk>, SyncError> {
+    let mut rpc_client = HubServiceClient::connect(format!("http://{}", rpc_address)).await?;
+    let request = Request::new(ShardChunksRequest {
+        shard_id,
+        start_block_number,
+        stop_block_number: Some(stop_block_number),
+    });
+    Ok(rpc_client
+        .get_shard_chunks(request)
+        .await?
+        .into_inner()
+        .shard_chunks)
+}
+
+pub async fn fetch_blocks(
+    rpc_address: String,
+    shard_id: u32,
+    start_block_number: u64,
+    stop_block_number: u64,
+) -> Result<Vec<Block>, SyncError> {
+    let mut rpc_client = HubServiceClient::connect(format!("http://{}", rpc_address)).await?;
+    let request = Request::new(BlocksRequest {
+        shard_id,
+        start_block_number,
+        stop_block_number: Some(stop_block_number),
+    });
+    let mut stream = rpc_client.get_blocks(request).await?.into_inner();
+    let mut blocks = vec![];
+    while let Some(block) = stream.message().await? {
+        blocks.push(block);
+    }
+    Ok(blocks)
+}
+
+fn verify_certificate(
+    validator_sets: &ValidatorSets,
+    height: Height,
+    hash: &[u8],
+    votes: Option<&ConfirmedVotes>,
+) -> Result<(), SyncError> {
+    let Some(votes) = votes else {
+        return Err(SyncError::invalid(
+            height.block_number,
+            "no commit certificate",
+        ));
+    };
+    let value = ShardHash {
+        shard_index: height.shard_index,
+        hash: hash.to_vec(),
+    };
+    validator_sets
+        .verify_commit_certificate(height, &value, votes)
+        .map_err(|err| SyncError::invalid(height.block_number, err))
+}
+
+/// Checks that a downloaded chunk is the expected one, continues from the parent and was decided
+/// by the shard's validators
+pub fn verify_synced_shard_chunk(
+    chunk: &ShardChunk,
+    expected_height: Height,
+    parent_hash: &[u8],
+    validator_sets: &ValidatorSets,
+) -> Result<(), SyncError> {
+    let block_number = expected_height.block_number;
+    let header = chunk
+        .header
+        .as_ref()
+        .ok_or_else(|| SyncError::invalid(block_number, "missing header"))?;
+    if header.height != Some(expected_height) {
+        return Err(SyncError::invalid(block_number, "unexpected height"));
+    }
+    if header.parent_hash != parent_hash {
+        return Err(SyncError::invalid(
+            block_number,
+            "parent hash doesn't match the last chunk",
+        ));
+    }
+    verify_chunk_contents(chunk, block_number)?;
+    verify_certificate(
+        validator_sets,
+        expected_height,
+        &chunk.hash,
+        chunk.votes.as_ref(),
+    )
+}
+
+// Certificates only cover the chunk's hash, which only covers its header
+fn verify_chunk_contents(chunk: &ShardChunk, block_number: u64) -> Result<(), SyncError> {
+    let header = chunk
+        .header
+        .as_ref()
+        .ok_or_else(|| SyncError::invalid(block_number, "missing chunk header"))?;
+    if chunk.hash != header.hash() {
+        return Err(SyncError::invalid(
+            block_number,
+            "chunk hash doesn't match its header",
+        ));
+    }
+    if header.transactions_root != transactions_root(&chunk.transactions) {
+        return Err(SyncError::invalid(
+            block_number,
+            "transactions root doesn't match the transactions",
+        ));
+    }
+    Ok(())
+}
+
+/// Checks that a downloaded block is the expected one, continues from the parent and was decided
+/// by the validators. The block's certificate covers its chunks' headers through the shard headers
+/// root, and the headers cover the chunks' transactions.
+pub fn verify_synced_block(
+    block: &Block,
+    expected_height: Height,
+    parent_hash: &[u8],
+    validator_sets: &ValidatorSets,
+) -> Result<(), SyncError> {
+    let block_number = expected_height.block_number;
+    let header = block
+        .header
+        .as_ref()
+        .ok_or_else(|| SyncError::invalid(block_number, "missing header"))?;
+    if header.height != Some(expected_height) {
+        return Err(SyncError::invalid(block_number, "unexpected height"));
+    }
+    if header.parent_hash != parent_hash {
+        return Err(SyncError::invalid(
+            block_number,
+            "parent hash doesn't match the last block",
+        ));
+    }
+    if block.hash != header.hash() {
+        return Err(SyncError::invalid(
+            block_number,
+            "hash doesn't match the header",
+        ));
+    }
+    if header.shard_headers_hash != shard_headers_root(&block.shard_chunks) {
+        return Err(SyncError::invalid(
+            block_number,
+            "shard headers hash doesn't match the chunks",
+        ));
+    }
+    for chunk in &block.shard_chunks {
+        verify_chunk_contents(chunk, block_number)?;
+    }
+    if header.validator_messages_root != validator_messages_root(&block.validator_messages) {
+        return Err(SyncError::invalid(
+            block_number,
+            "validator messages root doesn't match the messages",
+        ));
+    }
+    if header.validators_hash != validator_sets.validators_hash(block_number) {
+        return Err(SyncError::invalid(
+            block_number,
+            "validators hash doesn't match the validator set",
+        ));
+    }
+    verify_certificate(
+        validator_sets,
+        expected_height,
+        &block.hash,
+        block.votes.as_ref(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::SnapchainShard;
+    use crate::proto::{self, BlockHeader};
+    use crate::storage::store::test_helper;
+    use libp2p::identity::ed25519::Keypair;
+    use prost::Message;
+    use std::future::ready;
+
+    fn validator(rpc_address: &str, current_height: u64) -> SnapchainValidator {
+        SnapchainValidator::new(
+            SnapchainShard::new(1),
+            Keypair::generate().public(),
+            Some(rpc_address.to_string()),
+            current_height,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_download_skips_peers_serving_invalid_blocks() {
+        let peers = SyncPeers::new(1);
+        let good = validator("good", 0);
+        let bad = validator("bad", 0);
+        peers.update(&good);
+        peers.update(&bad);
+        peers.observe_height(&good.address, 250);
+        peers.observe_height(&bad.address, 300);
+        assert_eq!(peers.target_height(), 300);
+        assert!(peers.is_behind(10));
+
+        let mut applied = vec![];
+        let last = peers
+            .download(
+                1,
+                250,
+                |rpc_address, start, stop| async move {
+                    match rpc_address.as_str() {
+                        // Skips a block
+                        "bad" => Ok((start..stop).map(|i| i + 1).collect()),
+                        _ => Ok((start..stop).collect::<Vec<u64>>()),
+                    }
+                },
+                |block_numbers| {
+                    let mut result = Ok(());
+                    for block_number in block_numbers {
+                        let expected = applied.len() as u64 + 1;
+                        if block_number != expected {
+                            result = Err(SyncError::invalid(expected, "unexpected height"));
+                            break;
+                        }
+                        applied.push(block_number);
+                    }
+                    ready((applied.len() as u64, result))
+                },
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(last, 250);
+        assert_eq!(applied, (1..=250).collect::<Vec<u64>>());
+        // The bad peer no longer counts towards the target
+        assert_eq!(peers.target_height(), 250);
+        assert!(!peers.is_behind(250));
+    }
+
+    #[tokio::test]
+    async fn test_download_fails_without_peers() {
+        let peers = SyncPeers::new(1);
+        let flaky = validator("flaky", 0);
+        peers.update(&flaky);
+        peers.observe_height(&flaky.address, 20);
+
+        let result = peers
+            .download(
+                1,
+                20,
+                |_, start, _| async move { Err::<Vec<u64>, _>(SyncError::EmptyResponse(start)) },
+                |_| ready((0, Ok(()))),
+            )
+            .await;
+
+        // Blacklisted after failing repeatedly
+        assert!(matches!(result, Err(SyncError::NoPeers)));
+        assert_eq!(peers.target_height(), 0);
+    }
+
+    #[test]
+    fn test_only_observed_heights_are_targeted() {
+        let peers = SyncPeers::new(1);
+        // Registrations are self reported
+        let peer = validator("peer", 1000);
+        peers.update(&peer);
+        assert_eq!(peers.target_height(), 0);
+
+        // Heights of validators that can't be reached don't count
+        let unreachable = Address(Keypair::generate().public().to_bytes());
+        peers.observe_height(&unreachable, 50);
+        assert_eq!(peers.target_height(), 0);
+
+        peers.observe_height(&peer.address, 20);
+        assert_eq!(peers.target_height(), 20);
+    }
+
+    #[test]
+    fn test_blacklists_endpoints() {
+        let peers = SyncPeers::new(1);
+        let validator1 = validator("shared", 0);
+        let validator2 = validator("shared", 0);
+        peers.update(&validator1);
+        peers.update(&validator2);
+        peers.observe_height(&validator1.address, 10);
+        peers.observe_height(&validator2.address, 20);
+        assert_eq!(peers.endpoints_with(1), vec!["shared".to_string()]);
+
+        // Blacklisting the endpoint rules out every validator behind it, even under a new address
+        peers.record_failure("shared", &SyncError::invalid(1, "unexpected height"));
+        assert_eq!(peers.target_height(), 0);
+        let validator3 = validator("shared", 0);
+        peers.update(&validator3);
+        peers.observe_height(&validator3.address, 30);
+        assert_eq!(peers.target_height(), 0);
+    }
+
+    // A block with a chunk, decided by the only validator of validator_sets
+    fn certified_block(keypair: &Keypair, validator_sets: &ValidatorSets) -> Block {
+        let mut chunk = test_helper::default_shard_chunk();
+        chunk.header.as_mut().unwrap().height = Some(Height::new(1, 1));
+        chunk.header.as_mut().unwrap().transactions_root = transactions_root(&chunk.transactions);
+        chunk.hash = chunk.header.as_ref().unwrap().hash();
+        test_helper::certify_shard_chunk(&mut chunk, keypair);
+
+        let height = Height::new(0, 1);
+        let shard_chunks = vec![chunk];
+        let header = BlockHeader {
+            height: Some(height),
+            validators_hash: validator_sets.validators_hash(1),
+            shard_headers_hash: shard_headers_root(&shard_chunks),
+            validator_messages_root: validator_messages_root(&[]),
+            ..Default::default()
+        };
+        let hash = header.hash();
+        let vote = proto::Vote {
+            r#type: proto::VoteType::Precommit as i32,
+            height: Some(height),
+            round: 0,
+            value: Some(proto::ShardHash {
+                shard_index: 0,
+                hash: hash.clone(),
+            }),
+            voter: keypair.public().to_bytes().to_vec(),
+        };
+        let signature = keypair.sign(&vote.encode_to_vec());
+        Block {
+            header: Some(header),
+            hash,
+            shard_chunks,
+            validators: None,
+            votes: Some(ConfirmedVotes {
+                votes: vec![vote],
+                signatures: vec![signature],
+            }),
+            validator_messages: vec![],
+        }
+    }
+
+    #[test]
+    fn test_synced_blocks_are_checked_down_to_chunk_transactions() {
+        let keypair = Keypair::generate();
+        let validator_sets = test_helper::validator_sets(&keypair);
+        let block = certified_block(&keypair, &validator_sets);
+        let height = Height::new(0, 1);
+        verify_synced_block(&block, height, &[], &validator_sets).unwrap();
+
+        // Still certified, the certificate doesn't cover the transactions
+        let mut tampered = block.clone();
+        tampered.shard_chunks[0].transactions[0].fid += 1;
+        assert!(matches!(
+            verify_synced_block(&tampered, height, &[], &validator_sets),
+            Err(SyncError::Invalid {
+                block_number: 1,
+                ..
+            })
+        ));
+
+        let mut tampered = block.clone();
+        tampered.shard_chunks[0].hash = vec![1; 32];
+        assert!(matches!(
+            verify_synced_block(&tampered, height, &[], &validator_sets),
+            Err(SyncError::Invalid {
+                block_number: 1,
+                ..
+            })
+        ));
+    }
+}
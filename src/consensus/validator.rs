@@ -1,13 +1,21 @@
+use crate::consensus::consensus::ConsensusMsg;
+use crate::consensus::proposal_parts::{ProposalPartError, ProposalParts};
 use crate::consensus::proposer::{BlockProposer, Proposer, ShardProposer};
+use crate::consensus::sync::{
+    fetch_blocks, fetch_shard_chunks, SyncError, SyncPeers, SyncedValues, SyncedValuesResult,
+};
+use crate::consensus::validator_set::ValidatorSets;
 use crate::core::types::{
-    Address, Height, ShardHash, SnapchainShard, SnapchainValidator, SnapchainValidatorContext,
-    SnapchainValidatorSet,
+    Address, Height, ProposalPart, ShardHash, SnapchainShard, SnapchainValidator,
+    SnapchainValidatorContext, SnapchainValidatorSet,
 };
-use crate::proto::FullProposal;
+use crate::proto::{ConfirmedVotes, FullProposal};
 use malachite_common::{Round, ValidatorSet};
 use malachite_consensus::ProposedValue;
-use std::collections::HashSet;
+use ractor::ActorRef;
+use std::collections::BTreeMap;
 use std::time::Duration;
+use tokio::sync::mpsc;
 use tracing::error;
 
 pub struct ShardValidator {
@@ -16,7 +24,12 @@ pub struct ShardValidator {
     #[allow(dead_code)] // TODO
     address: Address,
 
-    validator_set: SnapchainValidatorSet,
+    validator_sets: ValidatorSets,
+    // Latest rpc address and height registered by each validator
+    registrations: BTreeMap<Address, SnapchainValidator>,
+    sync_peers: SyncPeers,
+    // A task is downloading blocks or chunks, see start_sync
+    syncing: bool,
     confirmed_height: Option<Height>,
     current_round: Round,
     current_height: Option<Height>,
@@ -26,20 +39,29 @@ pub struct ShardValidator {
     block_proposer: Option<BlockProposer>,
     shard_proposer: Option<ShardProposer>,
     pub started: bool,
-    pub saw_proposal_from_validator: HashSet<Address>,
+    // Values of the signed proposals received, by height and round
+    signed_proposal_values: BTreeMap<(Height, i64), ShardHash>,
+    // Full proposals waiting for the signed proposal of their height and round
+    pending_full_proposals: BTreeMap<(Height, i64), FullProposal>,
+    // Parts of the full proposals being received
+    proposal_parts: ProposalParts,
 }
 
 impl ShardValidator {
     pub fn new(
         address: Address,
         shard: SnapchainShard,
+        validator_sets: ValidatorSets,
         block_proposer: Option<BlockProposer>,
         shard_proposer: Option<ShardProposer>,
     ) -> ShardValidator {
         ShardValidator {
             shard_id: shard.clone(),
             address: address.clone(),
-            validator_set: SnapchainValidatorSet::new(vec![]),
+            validator_sets,
+            registrations: BTreeMap::new(),
+            sync_peers: SyncPeers::new(shard.shard_id()),
+            syncing: false,
             confirmed_height: None,
             current_round: Round::new(0),
             current_height: None,
@@ -47,16 +69,34 @@ impl ShardValidator {
             block_proposer,
             shard_proposer,
             started: false,
-            saw_proposal_from_validator: HashSet::new(),
+            signed_proposal_values: BTreeMap::new(),
+            pending_full_proposals: BTreeMap::new(),
+            proposal_parts: ProposalParts::new(),
         }
     }
 
+    // The set of the height being decided, or the next one if consensus hasn't started
     pub fn get_validator_set(&self) -> SnapchainValidatorSet {
-        self.validator_set.clone()
+        let block_number = match self.current_height {
+            Some(height) => height.block_number,
+            None => self.get_current_height() + 1,
+        };
+        self.get_validator_set_at(block_number)
+    }
+
+    // A chunk height for shards other than the block shard
+    pub fn get_validator_set_at(&self, block_number: u64) -> SnapchainValidatorSet {
+        let mut validator_set = self
+            .validator_sets
+            .validator_set(&self.shard_id, block_number);
+        for registration in self.registrations.values() {
+            validator_set.update(registration);
+        }
+        validator_set
     }
 
     pub fn validator_count(&self) -> usize {
-        self.validator_set.count()
+        self.get_validator_set().count()
     }
 
     pub fn get_current_height(&self) -> u64 {
@@ -68,52 +108,167 @@ impl ShardValidator {
         panic!("No proposer set on validator");
     }
 
-    pub fn add_validator(&mut self, validator: SnapchainValidator) -> bool {
-        self.validator_set.add(validator)
+    pub fn update_validator(&mut self, validator: &SnapchainValidator) -> bool {
+        if !self.get_validator_set().exists(&validator.address) {
+            return false;
+        }
+        self.registrations
+            .insert(validator.address.clone(), validator.clone());
+        self.sync_peers.update(validator);
+        true
     }
 
     pub fn start(&mut self) {
         self.started = true;
     }
 
-    pub fn saw_proposal_from_validator(&self, address: Address) -> bool {
-        self.saw_proposal_from_validator.contains(&address)
+    // The validator signed something showing it has confirmed at least this block
+    pub fn observe_peer_height(&mut self, address: &Address, block_number: u64) {
+        self.sync_peers.observe_height(address, block_number);
+    }
+
+    // Whether a validator reported a block this node doesn't have
+    pub fn is_behind(&self) -> bool {
+        self.sync_peers.is_behind(self.get_current_height())
     }
 
-    pub async fn sync_against_validator(&mut self, validator: &SnapchainValidator) {
-        if let Some(p) = &mut self.block_proposer {
-            match p.sync_against_validator(&validator).await {
-                Ok(()) => {}
-                Err(err) => error!("Error registering validator {:#?}", err),
+    /// Downloads the blocks or chunks the peers have and this node doesn't in a task, so the actor
+    /// keeps handling messages. The task sends the actor each page to apply with
+    /// ApplySyncedValues, then SyncFinished. Does nothing if a sync is already running.
+    pub fn start_sync(&mut self, actor: ActorRef<ConsensusMsg<SnapchainValidatorContext>>) {
+        let start = self.get_current_height() + 1;
+        let stop = self.sync_peers.target_height();
+        if self.syncing || stop < start {
+            return;
+        }
+        self.syncing = true;
+
+        let peers = self.sync_peers.clone();
+        let shard_id = self.shard_id.shard_id();
+        let is_block_shard = self.block_proposer.is_some();
+        tokio::spawn(async move {
+            let apply = |values: SyncedValues| {
+                let actor = actor.clone();
+                async move {
+                    let (reply_tx, mut reply_rx) = mpsc::channel(1);
+                    if actor
+                        .cast(ConsensusMsg::ApplySyncedValues(values, reply_tx))
+                        .is_err()
+                    {
+                        return (start - 1, Err(SyncError::Stopped));
+                    }
+                    reply_rx
+                        .recv()
+                        .await
+                        .unwrap_or((start - 1, Err(SyncError::Stopped)))
+                }
+            };
+            let result = if is_block_shard {
+                peers
+                    .download(
+                        start,
+                        stop,
+                        |rpc_address, start, stop| fetch_blocks(rpc_address, shard_id, start, stop),
+                        |blocks| apply(SyncedValues::Blocks(blocks)),
+                    )
+                    .await
+            } else {
+                peers
+                    .download(
+                        start,
+                        stop,
+                        |rpc_address, start, stop| {
+                            fetch_shard_chunks(rpc_address, shard_id, start, stop)
+                        },
+                        |shard_chunks| apply(SyncedValues::ShardChunks(shard_chunks)),
+                    )
+                    .await
             };
-        } else if let Some(p) = &mut self.shard_proposer {
-            match p.sync_against_validator(&validator).await {
-                Ok(()) => {}
-                Err(err) => error!("Error registering validator {:#?}", err),
+            if let Err(err) = result {
+                error!(shard_id, start, stop, "Unable to sync: {}", err);
+            }
+            if let Err(err) = actor.cast(ConsensusMsg::SyncFinished) {
+                error!(shard_id, "Unable to report the end of the sync: {:?}", err);
+            }
+        });
+    }
+
+    // Verifies and commits a page downloaded by the sync task
+    pub fn apply_synced_values(&mut self, values: SyncedValues) -> SyncedValuesResult {
+        match (values, &mut self.block_proposer, &mut self.shard_proposer) {
+            (SyncedValues::Blocks(blocks), Some(p), _) => {
+                p.apply_synced_blocks(blocks, &self.validator_sets)
             }
+            (SyncedValues::ShardChunks(shard_chunks), _, Some(p)) => {
+                p.apply_synced_chunks(shard_chunks, &self.validator_sets)
+            }
+            _ => unreachable!("The sync task downloads the values of the validator's shard"),
         }
     }
 
+    pub fn finish_sync(&mut self) {
+        self.syncing = false;
+    }
+
+    // Returns the full proposal of the same height and round, if it arrived first
+    pub fn record_signed_proposal(
+        &mut self,
+        height: Height,
+        round: Round,
+        value: ShardHash,
+    ) -> Option<FullProposal> {
+        let key = (height, round.as_i64());
+        self.signed_proposal_values.insert(key, value);
+        self.pending_full_proposals.remove(&key)
+    }
+
+    pub fn signed_proposal_value(&self, height: Height, round: Round) -> Option<&ShardHash> {
+        self.signed_proposal_values.get(&(height, round.as_i64()))
+    }
+
+    pub fn add_pending_full_proposal(&mut self, full_proposal: FullProposal) {
+        let key = (full_proposal.height(), full_proposal.round);
+        self.pending_full_proposals.insert(key, full_proposal);
+    }
+
+    // Returns the full proposal once all its parts arrived
+    pub fn add_proposal_part(
+        &mut self,
+        part: ProposalPart,
+    ) -> Result<Option<FullProposal>, ProposalPartError> {
+        self.proposal_parts.add_part(part)
+    }
+
     pub fn start_round(&mut self, height: Height, round: Round, proposer: Address) {
         self.current_height = Some(height);
         self.current_round = round;
+        self.proposal_parts.start_height(height);
         self.current_proposer = Some(proposer);
     }
 
-    pub async fn decide(&mut self, height: Height, _: Round, value: ShardHash) {
+    pub async fn decide(
+        &mut self,
+        height: Height,
+        _: Round,
+        value: ShardHash,
+        votes: ConfirmedVotes,
+    ) {
         if let Some(block_proposer) = &mut self.block_proposer {
             block_proposer
-                .decide(height, self.current_round, value)
+                .decide(height, self.current_round, value, votes)
                 .await;
         } else if let Some(shard_proposer) = &mut self.shard_proposer {
             shard_proposer
-                .decide(height, self.current_round, value)
+                .decide(height, self.current_round, value, votes)
                 .await;
         } else {
             panic!("No proposer set");
         }
         self.confirmed_height = Some(height);
         self.current_round = Round::Nil;
+        self.signed_proposal_values.retain(|(h, _), _| *h > height);
+        self.pending_full_proposals.retain(|(h, _), _| *h > height);
+        self.proposal_parts.start_height(height.increment());
     }
 
     pub fn add_proposed_value(
@@ -129,8 +284,6 @@ impl ShardValidator {
             panic!("No proposer set");
         };
 
-        self.saw_proposal_from_validator
-            .insert(full_proposal.proposer_address());
         ProposedValue {
             height: full_proposal.height(),
             round: full_proposal.round(),
@@ -141,6 +294,13 @@ impl ShardValidator {
         }
     }
 
+    // A halted shard can't build on its state, so it doesn't propose until it's resumed
+    pub fn is_halted(&self) -> bool {
+        self.shard_proposer
+            .as_ref()
+            .is_some_and(|shard_proposer| shard_proposer.is_halted())
+    }
+
     pub async fn propose_value(
         &mut self,
         height: Height,
@@ -0,0 +1,980 @@
+use crate::consensus::genesis::Genesis;
+use crate::core::merkle::merkle_root;
+use crate::core::types::{
+    proto, PublicKey, SnapchainShard, SnapchainValidator, SnapchainValidatorSet,
+};
+use crate::proto::{
+    Block, ConfirmedVotes, Height, ShardChunk, ShardHash, ValidatorMessage, ValidatorSetUpdate,
+    VoteType,
+};
+use crate::storage::store::{BlockStorageError, BlockStore};
+use prost::Message;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+
+// Shards run ahead of the block shard, an update must activate late enough that every shard has
+// committed the block that includes it before reaching its activation height
+pub const MIN_ACTIVATION_DELAY: u64 = 100;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ValidatorSetError {
+    #[error("activation block number {activation_block_number} must be at least {min}")]
+    ActivationTooSoon {
+        activation_block_number: u64,
+        min: u64,
+    },
+
+    #[error("validator set update has no validators")]
+    EmptyUpdate,
+
+    #[error("invalid validator public key {0}")]
+    InvalidPublicKey(String),
+
+    #[error("duplicate validator {0}")]
+    DuplicateValidator(String),
+
+    #[error("validator set of shard {shard_index} would be empty at height {height}")]
+    EmptyValidatorSet { shard_index: u32, height: u64 },
+
+    #[error("block including validator set updates has no chunk of shard {0}")]
+    MissingShardChunk(u32),
+
+    #[error("{votes} votes but {signatures} signatures")]
+    SignatureCountMismatch { votes: usize, signatures: usize },
+
+    #[error("vote is not a precommit for the value")]
+    UnexpectedVote,
+
+    #[error("vote from unknown validator {0}")]
+    UnknownVoter(String),
+
+    #[error("update signed by unknown validator {0}")]
+    UnknownSigner(String),
+
+    #[error("invalid signature from {0}")]
+    InvalidSignature(String),
+
+    #[error("{voting_power} of {total} voting power is not a quorum")]
+    NoQuorum { voting_power: u64, total: u64 },
+}
+
+// Keyed by signer, so the set is in the same order on all nodes
+type Validators = BTreeMap<Vec<u8>, proto::Validator>;
+
+#[derive(Clone)]
+struct CommittedUpdate {
+    update: ValidatorSetUpdate,
+    // Height each shard activates the update at. The block shard (0) activates it at its
+    // activation block number, the other shards the same number of chunks after their last chunk
+    // in the block that included it.
+    activations: BTreeMap<u32, u64>,
+}
+
+struct Inner {
+    genesis: Validators,
+    num_shards: u32,
+    // In the order they were committed
+    updates: Vec<CommittedUpdate>,
+    // Submitted to this node, waiting to be included in a block it proposes
+    pending: Vec<ValidatorSetUpdate>,
+    confirmed_block_number: u64,
+    // Height of the last chunk of each shard included in a committed block
+    last_chunk_heights: BTreeMap<u32, u64>,
+}
+
+impl Inner {
+    fn validators_at(&self, shard_index: u32, height: u64) -> Validators {
+        validators_at(&self.genesis, &self.updates, shard_index, height)
+    }
+}
+
+fn validators_at(
+    genesis: &Validators,
+    updates: &[CommittedUpdate],
+    shard_index: u32,
+    height: u64,
+) -> Validators {
+    let mut active: Vec<(u64, &ValidatorSetUpdate)> = updates
+        .iter()
+        .filter_map(|committed| {
+            let activation = *committed.activations.get(&shard_index)?;
+            (activation <= height).then_some((activation, &committed.update))
+        })
+        .collect();
+    // Stable, so updates activating at the same height apply in the order they were committed
+    active.sort_by_key(|(activation, _)| *activation);
+
+    let mut validators = genesis.clone();
+    for (_, update) in active {
+        apply_update(&mut validators, update);
+    }
+    validators
+}
+
+fn apply_update(validators: &mut Validators, update: &ValidatorSetUpdate) {
+    for validator in &update.validators {
+        if validator.voting_power == 0 {
+            validators.remove(&validator.signer);
+        } else {
+            validators.insert(validator.signer.clone(), validator.clone());
+        }
+    }
+}
+
+fn activations(
+    update: &ValidatorSetUpdate,
+    block_number: u64,
+    shard_chunks: &[ShardChunk],
+) -> BTreeMap<u32, u64> {
+    let delay = update.activation_block_number.saturating_sub(block_number);
+    let mut activations = BTreeMap::from([(0, update.activation_block_number)]);
+    for height in shard_chunks
+        .iter()
+        .filter_map(|chunk| chunk.header.as_ref()?.height)
+    {
+        let activation = activations
+            .entry(height.shard_index)
+            .or_insert(height.block_number + delay);
+        *activation = (*activation).max(height.block_number + delay);
+    }
+    activations
+}
+
+// Every shard must keep a validator from each activation on
+fn check_not_empty(
+    genesis: &Validators,
+    updates: &[CommittedUpdate],
+) -> Result<(), ValidatorSetError> {
+    for committed in updates {
+        for (&shard_index, &height) in &committed.activations {
+            if validators_at(genesis, updates, shard_index, height).is_empty() {
+                return Err(ValidatorSetError::EmptyValidatorSet {
+                    shard_index,
+                    height,
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+// More than 2/3 of the set's voting power
+fn check_quorum(validators: &Validators, voting_power: u64) -> Result<(), ValidatorSetError> {
+    let total: u64 = validators.values().map(|v| v.voting_power).sum();
+    if voting_power * 3 <= total * 2 {
+        return Err(ValidatorSetError::NoQuorum {
+            voting_power,
+            total,
+        });
+    }
+    Ok(())
+}
+
+/// The validator set of every height: the genesis set, plus the updates committed in blocks. The
+/// block shard applies an update from its activation block number on, and every other shard from
+/// the chunk height the block fixes for it. Updates are recorded when their block is committed, so
+/// all shards of the node see them.
+#[derive(Clone)]
+pub struct ValidatorSets {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl ValidatorSets {
+    pub fn new(genesis: &Genesis, block_store: &BlockStore) -> Result<Self, BlockStorageError> {
+        let validator_sets = Self::from_genesis(genesis);
+        for block in block_store.get_validator_set_update_blocks()? {
+            validator_sets.apply_block(&block);
+        }
+        let confirmed_block_number = block_store.max_block_number()?;
+        let last_chunk_heights = block_store.get_last_chunk_heights(genesis.num_shards)?;
+        {
+            let mut inner = validator_sets.inner.lock().unwrap();
+            inner.confirmed_block_number = confirmed_block_number;
+            inner.last_chunk_heights = last_chunk_heights.into_iter().collect();
+        }
+        Ok(validator_sets)
+    }
+
+    pub fn from_genesis(genesis: &Genesis) -> Self {
+        let validators = genesis
+            .validators
+            .iter()
+            .map(|validator| {
+                // Already validated when the genesis was loaded
+                let signer = hex::decode(&validator.public_key).unwrap();
+                let validator = proto::Validator {
+                    fid: 0,
+                    signer: signer.clone(),
+                    rpc_address: validator.rpc_address.clone(),
+                    shard_index: 0,
+                    current_height: 0,
+                    voting_power: validator.voting_power,
+                };
+                (signer, validator)
+            })
+            .collect();
+        ValidatorSets {
+            inner: Arc::new(Mutex::new(Inner {
+                genesis: validators,
+                num_shards: genesis.num_shards,
+                updates: vec![],
+                pending: vec![],
+                confirmed_block_number: 0,
+                last_chunk_heights: BTreeMap::new(),
+            })),
+        }
+    }
+
+    pub fn num_shards(&self) -> u32 {
+        self.inner.lock().unwrap().num_shards
+    }
+
+    fn record(&self, update: ValidatorSetUpdate, block_number: u64, shard_chunks: &[ShardChunk]) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.pending.retain(|pending| *pending != update);
+        let activations = activations(&update, block_number, shard_chunks);
+        inner.updates.push(CommittedUpdate {
+            update,
+            activations,
+        });
+    }
+
+    /// Whether every update that can be active at the height of the shard is known. Updates in
+    /// blocks not committed yet activate at least `MIN_ACTIVATION_DELAY` past the last block, and
+    /// past the shard's last chunk in a block.
+    pub fn is_known_at(&self, shard_index: u32, height: u64) -> bool {
+        let inner = self.inner.lock().unwrap();
+        let last_height = if shard_index == 0 {
+            inner.confirmed_block_number
+        } else {
+            inner
+                .last_chunk_heights
+                .get(&shard_index)
+                .copied()
+                .unwrap_or(0)
+        };
+        height <= last_height + MIN_ACTIVATION_DELAY
+    }
+
+    /// The set at the height of the shard: a block number for the block shard, a chunk height for
+    /// the others
+    pub fn validator_set(&self, shard: &SnapchainShard, height: u64) -> SnapchainValidatorSet {
+        let validators = self
+            .inner
+            .lock()
+            .unwrap()
+            .validators_at(shard.shard_id(), height);
+        SnapchainValidatorSet::new(
+            validators
+                .values()
+                .filter_map(|validator| {
+                    let public_key = PublicKey::try_from_bytes(&validator.signer).ok()?;
+                    Some(
+                        SnapchainValidator::new(
+                            shard.clone(),
+                            public_key,
+                            Some(validator.rpc_address.clone()),
+                            0,
+                        )
+                        .with_voting_power(validator.voting_power),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    /// Merkle root of the keys, voting power and rpc address of every validator at the block
+    /// number, so light clients can follow set transitions from block headers
+    pub fn validators_hash(&self, block_number: u64) -> Vec<u8> {
+        let validators = self.inner.lock().unwrap().validators_at(0, block_number);
+        let leaves: Vec<Vec<u8>> = validators
+            .values()
+            .map(|validator| validator.encode_to_vec())
+            .collect();
+        merkle_root(&leaves)
+    }
+
+    /// Checks that the votes are signed precommits for the value, by more than 2/3 of the voting
+    /// power of the validators at the height
+    pub fn verify_commit_certificate(
+        &self,
+        height: Height,
+        value: &ShardHash,
+        votes: &ConfirmedVotes,
+    ) -> Result<(), ValidatorSetError> {
+        if votes.votes.len() != votes.signatures.len() {
+            return Err(ValidatorSetError::SignatureCountMismatch {
+                votes: votes.votes.len(),
+                signatures: votes.signatures.len(),
+            });
+        }
+
+        let validators = self
+            .inner
+            .lock()
+            .unwrap()
+            .validators_at(height.shard_index, height.block_number);
+        let mut voters = HashSet::new();
+        let mut voting_power = 0;
+        for (vote, signature) in votes.votes.iter().zip(&votes.signatures) {
+            if vote.r#type() != VoteType::Precommit
+                || vote.height != Some(height)
+                || vote.value.as_ref() != Some(value)
+            {
+                return Err(ValidatorSetError::UnexpectedVote);
+            }
+            let Some(validator) = validators.get(&vote.voter) else {
+                return Err(ValidatorSetError::UnknownVoter(hex::encode(&vote.voter)));
+            };
+            let valid = PublicKey::try_from_bytes(&vote.voter)
+                .is_ok_and(|public_key| public_key.verify(&vote.encode_to_vec(), signature));
+            if !valid {
+                return Err(ValidatorSetError::InvalidSignature(hex::encode(
+                    &vote.voter,
+                )));
+            }
+            // Each validator counts once
+            if voters.insert(&vote.voter) {
+                voting_power += validator.voting_power;
+            }
+        }
+
+        check_quorum(&validators, voting_power)
+    }
+
+    /// Checks that the update is well formed and approved by more than 2/3 of the voting power of
+    /// the set at the block including it
+    pub fn validate_update(
+        &self,
+        update: &ValidatorSetUpdate,
+        block_number: u64,
+    ) -> Result<(), ValidatorSetError> {
+        let min = block_number + MIN_ACTIVATION_DELAY;
+        if update.activation_block_number < min {
+            return Err(ValidatorSetError::ActivationTooSoon {
+                activation_block_number: update.activation_block_number,
+                min,
+            });
+        }
+        if update.validators.is_empty() {
+            return Err(ValidatorSetError::EmptyUpdate);
+        }
+
+        let mut signers = HashSet::new();
+        for validator in &update.validators {
+            if PublicKey::try_from_bytes(&validator.signer).is_err() {
+                return Err(ValidatorSetError::InvalidPublicKey(hex::encode(
+                    &validator.signer,
+                )));
+            }
+            if !signers.insert(&validator.signer) {
+                return Err(ValidatorSetError::DuplicateValidator(hex::encode(
+                    &validator.signer,
+                )));
+            }
+        }
+
+        let validators = self.inner.lock().unwrap().validators_at(0, block_number);
+        let sign_bytes = update.to_sign_bytes();
+        let mut approvers = HashSet::new();
+        let mut voting_power = 0;
+        for signature in &update.signatures {
+            let Some(validator) = validators.get(&signature.signer) else {
+                return Err(ValidatorSetError::UnknownSigner(hex::encode(
+                    &signature.signer,
+                )));
+            };
+            let valid = PublicKey::try_from_bytes(&signature.signer)
+                .is_ok_and(|public_key| public_key.verify(&sign_bytes, &signature.signature));
+            if !valid {
+                return Err(ValidatorSetError::InvalidSignature(hex::encode(
+                    &signature.signer,
+                )));
+            }
+            if approvers.insert(&signature.signer) {
+                voting_power += validator.voting_power;
+            }
+        }
+        check_quorum(&validators, voting_power)
+    }
+
+    /// Checks that the updates can be included in the block together, applied in order on top of
+    /// every update committed so far. A block including updates must have a chunk of every shard,
+    /// to fix the height the shards activate them at.
+    pub fn validate_block_updates(
+        &self,
+        updates: &[ValidatorSetUpdate],
+        block_number: u64,
+        shard_chunks: &[ShardChunk],
+    ) -> Result<(), ValidatorSetError> {
+        if updates.is_empty() {
+            return Ok(());
+        }
+        let num_shards = self.inner.lock().unwrap().num_shards;
+        for shard_index in 1..=num_shards {
+            let included = shard_chunks.iter().any(|chunk| {
+                chunk
+                    .header
+                    .as_ref()
+                    .and_then(|header| header.height)
+                    .is_some_and(|height| height.shard_index == shard_index)
+            });
+            if !included {
+                return Err(ValidatorSetError::MissingShardChunk(shard_index));
+            }
+        }
+        for update in updates {
+            self.validate_update(update, block_number)?;
+        }
+        self.check_not_empty_with(updates, block_number, shard_chunks)
+    }
+
+    fn check_not_empty_with(
+        &self,
+        updates: &[ValidatorSetUpdate],
+        block_number: u64,
+        shard_chunks: &[ShardChunk],
+    ) -> Result<(), ValidatorSetError> {
+        let (genesis, mut committed) = {
+            let inner = self.inner.lock().unwrap();
+            (inner.genesis.clone(), inner.updates.clone())
+        };
+        committed.extend(updates.iter().map(|update| CommittedUpdate {
+            update: update.clone(),
+            activations: activations(update, block_number, shard_chunks),
+        }));
+        check_not_empty(&genesis, &committed)
+    }
+
+    pub fn submit(&self, update: ValidatorSetUpdate) -> Result<(), ValidatorSetError> {
+        let block_number = self.inner.lock().unwrap().confirmed_block_number + 1;
+        self.validate_update(&update, block_number)?;
+        // The shards are checked once the update is proposed, with the chunks of its block
+        self.check_not_empty_with(&[update.clone()], block_number, &[])?;
+        self.inner.lock().unwrap().pending.push(update);
+        Ok(())
+    }
+
+    /// Pending updates that can still be included together in the block, as validator messages
+    pub fn pending_messages(
+        &self,
+        block_number: u64,
+        shard_chunks: &[ShardChunk],
+    ) -> Vec<ValidatorMessage> {
+        let pending = self.inner.lock().unwrap().pending.clone();
+        let mut included = vec![];
+        for update in pending {
+            included.push(update);
+            if self
+                .validate_block_updates(&included, block_number, shard_chunks)
+                .is_err()
+            {
+                included.pop();
+            }
+        }
+        included
+            .into_iter()
+            .map(|update| ValidatorMessage {
+                on_chain_event: None,
+                fname_transfer: None,
+                validator_set_update: Some(update),
+            })
+            .collect()
+    }
+
+    /// Called once the block is committed
+    pub fn apply_block(&self, block: &Block) {
+        let Some(height) = block.header.as_ref().and_then(|header| header.height) else {
+            return;
+        };
+        for msg in &block.validator_messages {
+            if let Some(update) = &msg.validator_set_update {
+                self.record(update.clone(), height.block_number, &block.shard_chunks);
+            }
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.confirmed_block_number = inner.confirmed_block_number.max(height.block_number);
+        for chunk_height in block
+            .shard_chunks
+            .iter()
+            .filter_map(|chunk| chunk.header.as_ref()?.height)
+        {
+            let last_height = inner
+                .last_chunk_heights
+                .entry(chunk_height.shard_index)
+                .or_default();
+            *last_height = (*last_height).max(chunk_height.block_number);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consensus::genesis::GenesisValidator;
+    use crate::proto::{BlockHeader, ShardHeader, ValidatorSignature};
+    use crate::storage::db::RocksDB;
+    use libp2p::identity::ed25519::Keypair;
+    use malachite_common::{Validator, ValidatorSet};
+    use std::time::SystemTime;
+
+    fn genesis(keypairs: &[&Keypair]) -> Genesis {
+        Genesis {
+            chain_id: "snapchain-test".to_string(),
+            network: "devnet".to_string(),
+            genesis_time: SystemTime::UNIX_EPOCH,
+            num_shards: 1,
+            validators: keypairs
+                .iter()
+                .map(|keypair| GenesisValidator {
+                    public_key: hex::encode(keypair.public().to_bytes()),
+                    voting_power: 1,
+                    rpc_address: "127.0.0.1:3383".to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    fn validator(keypair: &Keypair, voting_power: u64) -> proto::Validator {
+        proto::Validator {
+            fid: 0,
+            signer: keypair.public().to_bytes().to_vec(),
+            rpc_address: "127.0.0.1:3384".to_string(),
+            shard_index: 0,
+            current_height: 0,
+            voting_power,
+        }
+    }
+
+    // A block with a chunk of shard 1 at the chunk height
+    fn block(block_number: u64, chunk_height: u64, messages: Vec<ValidatorMessage>) -> Block {
+        Block {
+            header: Some(BlockHeader {
+                height: Some(Height::new(0, block_number)),
+                ..Default::default()
+            }),
+            hash: vec![],
+            shard_chunks: vec![chunk(chunk_height)],
+            validators: None,
+            votes: None,
+            validator_messages: messages,
+        }
+    }
+
+    fn chunk(chunk_height: u64) -> ShardChunk {
+        ShardChunk {
+            header: Some(ShardHeader {
+                height: Some(Height::new(1, chunk_height)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn signed(update: ValidatorSetUpdate, keypairs: &[&Keypair]) -> ValidatorSetUpdate {
+        let sign_bytes = update.to_sign_bytes();
+        ValidatorSetUpdate {
+            signatures: keypairs
+                .iter()
+                .map(|keypair| ValidatorSignature {
+                    signer: keypair.public().to_bytes().to_vec(),
+                    signature: keypair.sign(&sign_bytes),
+                })
+                .collect(),
+            ..update
+        }
+    }
+
+    fn message(update: ValidatorSetUpdate) -> ValidatorMessage {
+        ValidatorMessage {
+            on_chain_event: None,
+            fname_transfer: None,
+            validator_set_update: Some(update),
+        }
+    }
+
+    #[test]
+    fn test_updates_apply_from_activation() {
+        let keypair1 = Keypair::generate();
+        let keypair2 = Keypair::generate();
+        let validator_sets = ValidatorSets::from_genesis(&genesis(&[&keypair1]));
+        let block_shard = SnapchainShard::new(0);
+        let shard = SnapchainShard::new(1);
+        let activation = 1 + MIN_ACTIVATION_DELAY;
+        let genesis_hash = validator_sets.validators_hash(1);
+
+        let update = ValidatorSetUpdate {
+            activation_block_number: activation,
+            validators: vec![validator(&keypair2, 2)],
+            signatures: vec![],
+        };
+        assert_eq!(
+            validator_sets.submit(update.clone()),
+            Err(ValidatorSetError::NoQuorum {
+                voting_power: 0,
+                total: 1
+            })
+        );
+        assert_eq!(
+            validator_sets.submit(signed(update.clone(), &[&keypair1])),
+            Ok(())
+        );
+        let messages = validator_sets.pending_messages(1, &[chunk(10)]);
+        assert_eq!(messages.len(), 1);
+
+        // The block has chunk 10 of shard 1, so the shard activates it at chunk 10 + 100
+        validator_sets.apply_block(&block(1, 10, messages));
+        assert!(validator_sets.pending_messages(2, &[chunk(11)]).is_empty());
+
+        let before = validator_sets.validator_set(&block_shard, activation - 1);
+        assert_eq!(before.count(), 1);
+        assert_eq!(validator_sets.validators_hash(activation - 1), genesis_hash);
+
+        let after = validator_sets.validator_set(&block_shard, activation);
+        assert_eq!(after.count(), 2);
+        assert_eq!(after.total_voting_power(), 3);
+        assert_eq!(after.shard_id(), 0);
+        assert_ne!(validator_sets.validators_hash(activation), genesis_hash);
+
+        let shard_activation = 10 + MIN_ACTIVATION_DELAY;
+        assert_eq!(
+            validator_sets
+                .validator_set(&shard, shard_activation - 1)
+                .count(),
+            1
+        );
+        let after = validator_sets.validator_set(&shard, shard_activation);
+        assert_eq!(after.count(), 2);
+        assert_eq!(after.shard_id(), 1);
+
+        // Removing the genesis validator leaves the new one, the genesis validator still approves
+        // it since the new one isn't active yet
+        let removal = signed(
+            ValidatorSetUpdate {
+                activation_block_number: activation + 1,
+                validators: vec![validator(&keypair1, 0)],
+                signatures: vec![],
+            },
+            &[&keypair1],
+        );
+        assert_eq!(
+            validator_sets.validate_block_updates(&[removal.clone()], 2, &[chunk(11)]),
+            Ok(())
+        );
+        validator_sets.apply_block(&block(2, 11, vec![message(removal)]));
+        let removed = validator_sets.validator_set(&block_shard, activation + 1);
+        assert_eq!(removed.count(), 1);
+        assert_eq!(removed.validators[0].public_key, keypair2.public());
+        assert_eq!(removed.validators[0].voting_power(), 2);
+        let removed = validator_sets.validator_set(&shard, 11 + MIN_ACTIVATION_DELAY);
+        assert_eq!(removed.count(), 1);
+    }
+
+    #[test]
+    fn test_invalid_updates() {
+        let keypair1 = Keypair::generate();
+        let keypair2 = Keypair::generate();
+        let validator_sets = ValidatorSets::from_genesis(&genesis(&[&keypair1]));
+
+        let too_soon = ValidatorSetUpdate {
+            activation_block_number: 10,
+            validators: vec![validator(&keypair2, 1)],
+            signatures: vec![],
+        };
+        assert!(matches!(
+            validator_sets.validate_update(&too_soon, 5),
+            Err(ValidatorSetError::ActivationTooSoon { .. })
+        ));
+        validator_sets.apply_block(&block(4, 4, vec![]));
+        assert!(validator_sets
+            .submit(signed(too_soon, &[&keypair1]))
+            .is_err());
+        assert!(validator_sets.pending_messages(5, &[chunk(5)]).is_empty());
+
+        let activation_block_number = 1 + MIN_ACTIVATION_DELAY;
+        let empty = ValidatorSetUpdate {
+            activation_block_number,
+            validators: vec![],
+            signatures: vec![],
+        };
+        assert_eq!(
+            validator_sets.validate_update(&empty, 1),
+            Err(ValidatorSetError::EmptyUpdate)
+        );
+
+        let mut bad_key = validator(&keypair2, 1);
+        bad_key.signer = vec![1, 2, 3];
+        let bad_key = ValidatorSetUpdate {
+            activation_block_number,
+            validators: vec![bad_key],
+            signatures: vec![],
+        };
+        assert!(matches!(
+            validator_sets.validate_update(&bad_key, 1),
+            Err(ValidatorSetError::InvalidPublicKey(_))
+        ));
+
+        let duplicate = ValidatorSetUpdate {
+            activation_block_number,
+            validators: vec![validator(&keypair2, 1), validator(&keypair2, 2)],
+            signatures: vec![],
+        };
+        assert!(matches!(
+            validator_sets.validate_update(&duplicate, 1),
+            Err(ValidatorSetError::DuplicateValidator(_))
+        ));
+
+        let add = ValidatorSetUpdate {
+            activation_block_number,
+            validators: vec![validator(&keypair2, 1)],
+            signatures: vec![],
+        };
+        assert!(matches!(
+            validator_sets.validate_update(&signed(add.clone(), &[&keypair2]), 1),
+            Err(ValidatorSetError::UnknownSigner(_))
+        ));
+        let mut forged = signed(add.clone(), &[&keypair1]);
+        forged.validators[0].voting_power = 2;
+        assert!(matches!(
+            validator_sets.validate_update(&forged, 1),
+            Err(ValidatorSetError::InvalidSignature(_))
+        ));
+
+        // Every shard must be in the block, to fix when it activates the update
+        let add = signed(add, &[&keypair1]);
+        assert_eq!(
+            validator_sets.validate_block_updates(&[add.clone()], 1, &[]),
+            Err(ValidatorSetError::MissingShardChunk(1))
+        );
+        assert_eq!(
+            validator_sets.validate_block_updates(&[add], 1, &[chunk(1)]),
+            Ok(())
+        );
+
+        let remove_all = signed(
+            ValidatorSetUpdate {
+                activation_block_number,
+                validators: vec![validator(&keypair1, 0)],
+                signatures: vec![],
+            },
+            &[&keypair1],
+        );
+        assert_eq!(
+            validator_sets.validate_block_updates(&[remove_all], 1, &[chunk(1)]),
+            Err(ValidatorSetError::EmptyValidatorSet {
+                shard_index: 0,
+                height: activation_block_number
+            })
+        );
+    }
+
+    #[test]
+    fn test_updates_in_a_block_are_validated_together() {
+        let keypair1 = Keypair::generate();
+        let keypair2 = Keypair::generate();
+        let validator_sets = ValidatorSets::from_genesis(&genesis(&[&keypair1, &keypair2]));
+        let removal = |keypair: &Keypair, activation_block_number: u64| {
+            signed(
+                ValidatorSetUpdate {
+                    activation_block_number,
+                    validators: vec![validator(keypair, 0)],
+                    signatures: vec![],
+                },
+                &[&keypair1, &keypair2],
+            )
+        };
+        let activation = 1 + MIN_ACTIVATION_DELAY;
+        let removal1 = removal(&keypair1, activation);
+        let removal2 = removal(&keypair2, activation + 1);
+
+        // Each is fine on its own, but not both
+        for update in [&removal1, &removal2] {
+            assert_eq!(
+                validator_sets.validate_block_updates(&[update.clone()], 1, &[chunk(1)]),
+                Ok(())
+            );
+        }
+        assert_eq!(
+            validator_sets.validate_block_updates(
+                &[removal1.clone(), removal2.clone()],
+                1,
+                &[chunk(1)]
+            ),
+            Err(ValidatorSetError::EmptyValidatorSet {
+                shard_index: 0,
+                height: activation + 1
+            })
+        );
+
+        validator_sets.submit(removal1.clone()).unwrap();
+        validator_sets.submit(removal2.clone()).unwrap();
+        let messages = validator_sets.pending_messages(1, &[chunk(1)]);
+        assert_eq!(messages, vec![message(removal1)]);
+
+        // Nor once the first one is committed
+        validator_sets.apply_block(&block(1, 1, messages));
+        assert!(matches!(
+            validator_sets.validate_block_updates(&[removal2], 2, &[chunk(2)]),
+            Err(ValidatorSetError::EmptyValidatorSet { .. })
+        ));
+    }
+
+    #[test]
+    fn test_commit_certificate() {
+        let keypairs = [
+            Keypair::generate(),
+            Keypair::generate(),
+            Keypair::generate(),
+        ];
+        let validator_sets =
+            ValidatorSets::from_genesis(&genesis(&keypairs.iter().collect::<Vec<_>>()));
+        let height = Height::new(1, 5);
+        let value = ShardHash {
+            shard_index: 1,
+            hash: vec![1, 2, 3],
+        };
+        let certificate = |signers: &[&Keypair], vote_type: VoteType| {
+            let votes: Vec<proto::Vote> = signers
+                .iter()
+                .map(|keypair| proto::Vote {
+                    r#type: vote_type as i32,
+                    height: Some(height),
+                    round: 0,
+                    value: Some(value.clone()),
+                    voter: keypair.public().to_bytes().to_vec(),
+                })
+                .collect();
+            let signatures = signers
+                .iter()
+                .zip(&votes)
+                .map(|(keypair, vote)| keypair.sign(&vote.encode_to_vec()))
+                .collect();
+            ConfirmedVotes { votes, signatures }
+        };
+
+        let all: Vec<&Keypair> = keypairs.iter().collect();
+        let quorum = certificate(&all, VoteType::Precommit);
+        assert_eq!(
+            validator_sets.verify_commit_certificate(height, &value, &quorum),
+            Ok(())
+        );
+        let other_value = ShardHash {
+            shard_index: 1,
+            hash: vec![4],
+        };
+        assert_eq!(
+            validator_sets.verify_commit_certificate(height, &other_value, &quorum),
+            Err(ValidatorSetError::UnexpectedVote)
+        );
+
+        assert_eq!(
+            validator_sets.verify_commit_certificate(
+                height,
+                &value,
+                &certificate(&all[..2], VoteType::Precommit)
+            ),
+            Err(ValidatorSetError::NoQuorum {
+                voting_power: 2,
+                total: 3
+            })
+        );
+        assert_eq!(
+            validator_sets.verify_commit_certificate(
+                height,
+                &value,
+                &certificate(&all, VoteType::Prevote)
+            ),
+            Err(ValidatorSetError::UnexpectedVote)
+        );
+
+        // The same validator voting twice doesn't count twice
+        let duplicated = certificate(&[all[0], all[1], all[1]], VoteType::Precommit);
+        assert!(matches!(
+            validator_sets.verify_commit_certificate(height, &value, &duplicated),
+            Err(ValidatorSetError::NoQuorum { .. })
+        ));
+
+        let unknown = Keypair::generate();
+        assert!(matches!(
+            validator_sets.verify_commit_certificate(
+                height,
+                &value,
+                &certificate(&[all[0], all[1], &unknown], VoteType::Precommit)
+            ),
+            Err(ValidatorSetError::UnknownVoter(_))
+        ));
+
+        let mut forged = quorum.clone();
+        forged.signatures[2] = forged.signatures[1].clone();
+        assert!(matches!(
+            validator_sets.verify_commit_certificate(height, &value, &forged),
+            Err(ValidatorSetError::InvalidSignature(_))
+        ));
+    }
+
+    #[test]
+    fn test_updates_are_loaded_from_blocks() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db = RocksDB::new(dir.path().join("blocks").to_str().unwrap());
+        db.open().unwrap();
+        let block_store = BlockStore::new(Arc::new(db));
+
+        let keypair1 = Keypair::generate();
+        let keypair2 = Keypair::generate();
+        let genesis = genesis(&[&keypair1]);
+        let validator_sets = ValidatorSets::new(&genesis, &block_store).unwrap();
+
+        let activation = 1 + MIN_ACTIVATION_DELAY;
+        let update = ValidatorSetUpdate {
+            activation_block_number: activation,
+            validators: vec![validator(&keypair2, 1)],
+            signatures: vec![],
+        };
+        validator_sets.submit(signed(update, &[&keypair1])).unwrap();
+        let block = block(1, 5, validator_sets.pending_messages(1, &[chunk(5)]));
+        block_store.put_block(block.clone()).unwrap();
+        validator_sets.apply_block(&block);
+
+        // A restarted node sees the same sets
+        let reloaded = ValidatorSets::new(&genesis, &block_store).unwrap();
+        for block_number in [activation - 1, activation] {
+            assert_eq!(
+                reloaded.validators_hash(block_number),
+                validator_sets.validators_hash(block_number)
+            );
+        }
+        let block_shard = SnapchainShard::new(0);
+        assert_eq!(reloaded.validator_set(&block_shard, activation).count(), 2);
+        let shard = SnapchainShard::new(1);
+        let shard_activation = 5 + MIN_ACTIVATION_DELAY;
+        assert_eq!(
+            reloaded.validator_set(&shard, shard_activation - 1).count(),
+            1
+        );
+        assert_eq!(reloaded.validator_set(&shard, shard_activation).count(), 2);
+        assert_eq!(reloaded.inner.lock().unwrap().confirmed_block_number, 1);
+        assert!(reloaded.is_known_at(1, shard_activation));
+        assert!(!reloaded.is_known_at(1, shard_activation + 1));
+    }
+
+    #[test]
+    fn test_sets_are_known_until_uncommitted_updates_could_activate() {
+        let keypair = Keypair::generate();
+        let validator_sets = ValidatorSets::from_genesis(&genesis(&[&keypair]));
+
+        // Any update would be in block 1 at the earliest
+        assert!(validator_sets.is_known_at(0, MIN_ACTIVATION_DELAY));
+        assert!(!validator_sets.is_known_at(0, MIN_ACTIVATION_DELAY + 1));
+        assert!(validator_sets.is_known_at(1, MIN_ACTIVATION_DELAY));
+        assert!(!validator_sets.is_known_at(1, MIN_ACTIVATION_DELAY + 1));
+
+        validator_sets.apply_block(&block(3, 20, vec![]));
+        assert!(validator_sets.is_known_at(0, 3 + MIN_ACTIVATION_DELAY));
+        assert!(!validator_sets.is_known_at(0, 4 + MIN_ACTIVATION_DELAY));
+        assert!(validator_sets.is_known_at(1, 20 + MIN_ACTIVATION_DELAY));
+        assert!(!validator_sets.is_known_at(1, 21 + MIN_ACTIVATION_DELAY));
+        // Shards without a chunk in a block yet
+        assert!(!validator_sets.is_known_at(2, MIN_ACTIVATION_DELAY + 1));
+    }
+}
@@ -0,0 +1,454 @@
+use crate::core::types::{
+    proto, Height, Proposal, ShardHash, Signature, SnapchainValidatorContext, Vote,
+};
+use crate::proto::consensus_message::ConsensusMessage as Message;
+use crate::proto::consensus_wal_entry::Entry;
+use crate::proto::{ConsensusLock, ConsensusMessage, ConsensusWalEntry, VoteType};
+use malachite_common::{Round, SignedProposal, SignedVote};
+use malachite_consensus::SignedConsensusMsg;
+use prost::Message as _;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use tracing::warn;
+
+#[derive(Error, Debug)]
+pub enum WalError {
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+
+    #[error(transparent)]
+    DecodeError(#[from] prost::DecodeError),
+
+    #[error("Already voted {existing:?} in {vote_type:?} of height {height} round {round}")]
+    ConflictingVote {
+        height: Height,
+        round: i64,
+        vote_type: VoteType,
+        existing: Option<ShardHash>,
+    },
+
+    #[error("Already proposed {existing} at height {height} round {round}")]
+    ConflictingProposal {
+        height: Height,
+        round: i64,
+        existing: ShardHash,
+    },
+
+    #[error("Locked on {locked} since round {locked_round} of height {height}, and no newer polka was seen")]
+    VoteAgainstLock {
+        height: Height,
+        locked_round: i64,
+        locked: ShardHash,
+    },
+}
+
+/// The messages this validator signed for the heights not decided yet, and the value it's locked
+/// on. Every entry is synced to disk before the message is broadcast, so a validator that restarts
+/// can replay its own messages and won't sign anything that conflicts with them.
+pub struct ConsensusWal {
+    path: PathBuf,
+    file: File,
+    entries: Vec<ConsensusWalEntry>,
+    // Proof-of-lock round of the proposals seen, by height and round. Not persisted, a validator
+    // that restarts needs a new polka to unlock.
+    pol_rounds: BTreeMap<(Height, i64), i64>,
+}
+
+fn entry_height(entry: &ConsensusWalEntry) -> Option<Height> {
+    match &entry.entry {
+        Some(Entry::Message(msg)) => match &msg.consensus_message {
+            Some(Message::Vote(vote)) => vote.height,
+            Some(Message::Proposal(proposal)) => proposal.height,
+            None => None,
+        },
+        Some(Entry::Lock(lock)) => lock.height,
+        None => None,
+    }
+}
+
+fn encode_entry(entry: &ConsensusWalEntry) -> Vec<u8> {
+    let bytes = entry.encode_to_vec();
+    let mut record = (bytes.len() as u32).to_be_bytes().to_vec();
+    record.extend(bytes);
+    record
+}
+
+impl ConsensusWal {
+    pub fn open(path: &Path) -> Result<Self, WalError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let bytes = if path.exists() {
+            fs::read(path)?
+        } else {
+            vec![]
+        };
+
+        let mut entries = vec![];
+        let mut offset = 0;
+        while offset + 4 <= bytes.len() {
+            let len = u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+            if offset + 4 + len > bytes.len() {
+                break;
+            }
+            entries.push(ConsensusWalEntry::decode(
+                &bytes[offset + 4..offset + 4 + len],
+            )?);
+            offset += 4 + len;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        // The node stopped while appending, the message was never broadcast
+        if offset < bytes.len() {
+            warn!(
+                path = path.to_string_lossy().as_ref(),
+                "Dropping partially written consensus wal entry"
+            );
+            file.set_len(offset as u64)?;
+            file.sync_all()?;
+        }
+
+        Ok(ConsensusWal {
+            path: path.to_path_buf(),
+            file,
+            entries,
+            pol_rounds: BTreeMap::new(),
+        })
+    }
+
+    fn append(&mut self, entry: ConsensusWalEntry) -> Result<(), WalError> {
+        self.file.write_all(&encode_entry(&entry))?;
+        self.file.sync_data()?;
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    fn messages_at(&self, height: Height) -> impl Iterator<Item = &ConsensusMessage> {
+        self.entries
+            .iter()
+            .filter_map(move |entry| match &entry.entry {
+                Some(Entry::Message(msg)) if entry_height(entry) == Some(height) => Some(msg),
+                _ => None,
+            })
+    }
+
+    fn own_vote(&self, height: Height, round: i64, vote_type: VoteType) -> Option<&proto::Vote> {
+        self.messages_at(height)
+            .filter_map(|msg| match &msg.consensus_message {
+                Some(Message::Vote(vote)) => Some(vote),
+                _ => None,
+            })
+            .find(|vote| vote.round == round && vote.r#type() == vote_type)
+    }
+
+    /// Value this validator proposed at the height and round, if any
+    pub fn proposal_value(&self, height: Height, round: Round) -> Option<ShardHash> {
+        self.messages_at(height)
+            .find_map(|msg| match &msg.consensus_message {
+                Some(Message::Proposal(proposal)) if proposal.round == round.as_i64() => {
+                    proposal.value.clone()
+                }
+                _ => None,
+            })
+    }
+
+    // The latest lock of the height
+    pub fn lock(&self, height: Height) -> Option<&ConsensusLock> {
+        self.entries
+            .iter()
+            .filter_map(|entry| match &entry.entry {
+                Some(Entry::Lock(lock)) if lock.height == Some(height) => Some(lock),
+                _ => None,
+            })
+            .last()
+    }
+
+    /// Signed messages of the height, in the order they were broadcast
+    pub fn messages(&self, height: Height) -> Vec<SignedConsensusMsg<SnapchainValidatorContext>> {
+        self.messages_at(height)
+            .filter_map(|msg| {
+                let signature = Signature(msg.signature.clone());
+                match msg.consensus_message.clone()? {
+                    Message::Vote(vote) => Some(SignedConsensusMsg::Vote(SignedVote::new(
+                        Vote::from_proto(vote),
+                        signature,
+                    ))),
+                    Message::Proposal(proposal) => Some(SignedConsensusMsg::Proposal(
+                        SignedProposal::new(Proposal::from_proto(proposal), signature),
+                    )),
+                }
+            })
+            .collect()
+    }
+
+    pub fn observe_proposal(&mut self, proposal: &Proposal) {
+        self.pol_rounds.insert(
+            (proposal.height, proposal.round.as_i64()),
+            proposal.pol_round.as_i64(),
+        );
+    }
+
+    /// Records the vote, unless it conflicts with one already signed. Recording the same vote
+    /// again is a no-op, so it can be rebroadcast.
+    pub fn record_vote(
+        &mut self,
+        vote: &SignedVote<SnapchainValidatorContext>,
+    ) -> Result<(), WalError> {
+        let proto_vote = vote.to_proto();
+        let height = vote.height;
+        let round = proto_vote.round;
+        let vote_type = proto_vote.r#type();
+
+        if let Some(existing) = self.own_vote(height, round, vote_type) {
+            if existing.value == proto_vote.value {
+                return Ok(());
+            }
+            return Err(WalError::ConflictingVote {
+                height,
+                round,
+                vote_type,
+                existing: existing.value.clone(),
+            });
+        }
+
+        // A locked validator only prevotes for another value with a proof-of-lock newer than its lock
+        if let (VoteType::Prevote, Some(value), Some(lock)) =
+            (vote_type, &proto_vote.value, self.lock(height))
+        {
+            let locked = lock.value.clone().unwrap_or_default();
+            let unlocked = self
+                .pol_rounds
+                .get(&(height, round))
+                .is_some_and(|pol_round| *pol_round >= lock.round);
+            if lock.round < round && locked != *value && !unlocked {
+                return Err(WalError::VoteAgainstLock {
+                    height,
+                    locked_round: lock.round,
+                    locked,
+                });
+            }
+        }
+
+        self.append(ConsensusWalEntry {
+            entry: Some(Entry::Message(ConsensusMessage {
+                consensus_message: Some(Message::Vote(proto_vote.clone())),
+                signature: vote.signature.0.clone(),
+            })),
+        })?;
+        if let (VoteType::Precommit, Some(value)) = (vote_type, proto_vote.value) {
+            self.append(ConsensusWalEntry {
+                entry: Some(Entry::Lock(ConsensusLock {
+                    height: Some(height),
+                    round,
+                    value: Some(value),
+                })),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Records the proposal, unless another value was already proposed at its height and round
+    pub fn record_proposal(
+        &mut self,
+        proposal: &SignedProposal<SnapchainValidatorContext>,
+    ) -> Result<(), WalError> {
+        self.observe_proposal(proposal);
+        match self.proposal_value(proposal.height, proposal.round) {
+            Some(existing) if existing == proposal.shard_hash => Ok(()),
+            Some(existing) => Err(WalError::ConflictingProposal {
+                height: proposal.height,
+                round: proposal.round.as_i64(),
+                existing,
+            }),
+            None => self.append(ConsensusWalEntry {
+                entry: Some(Entry::Message(ConsensusMessage {
+                    consensus_message: Some(Message::Proposal(proposal.to_proto())),
+                    signature: proposal.signature.0.clone(),
+                })),
+            }),
+        }
+    }
+
+    /// Drops the entries of the decided height and below
+    pub fn prune(&mut self, decided: Height) -> Result<(), WalError> {
+        let before = self.entries.len();
+        self.entries
+            .retain(|entry| entry_height(entry).is_some_and(|height| height > decided));
+        self.pol_rounds.retain(|(height, _), _| *height > decided);
+        if self.entries.len() == before {
+            return Ok(());
+        }
+
+        // Written aside and renamed, so a crash leaves either the old or the new log
+        let tmp_path = self.path.with_extension("tmp");
+        let mut tmp = File::create(&tmp_path)?;
+        for entry in &self.entries {
+            tmp.write_all(&encode_entry(entry))?;
+        }
+        tmp.sync_all()?;
+        fs::rename(&tmp_path, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::types::Address;
+    use libp2p::identity::ed25519::Keypair;
+    use malachite_common::{Context, NilOrVal};
+
+    fn value(hash: u8) -> ShardHash {
+        ShardHash {
+            shard_index: 1,
+            hash: vec![hash],
+        }
+    }
+
+    struct Signer {
+        ctx: SnapchainValidatorContext,
+        address: Address,
+    }
+
+    impl Signer {
+        fn new() -> Self {
+            let keypair = Keypair::generate();
+            Signer {
+                address: Address(keypair.public().to_bytes()),
+                ctx: SnapchainValidatorContext::new(keypair),
+            }
+        }
+
+        fn prevote(
+            &self,
+            round: i64,
+            value: Option<ShardHash>,
+        ) -> SignedVote<SnapchainValidatorContext> {
+            let value = value.map_or(NilOrVal::Nil, NilOrVal::Val);
+            self.ctx.sign_vote(Vote::new_prevote(
+                Height::new(1, 5),
+                Round::new(round),
+                value,
+                self.address.clone(),
+            ))
+        }
+
+        fn precommit(&self, round: i64, value: ShardHash) -> SignedVote<SnapchainValidatorContext> {
+            self.ctx.sign_vote(Vote::new_precommit(
+                Height::new(1, 5),
+                Round::new(round),
+                NilOrVal::Val(value),
+                self.address.clone(),
+            ))
+        }
+
+        fn proposal(
+            &self,
+            round: i64,
+            pol_round: i64,
+            value: ShardHash,
+        ) -> SignedProposal<SnapchainValidatorContext> {
+            self.ctx.sign_proposal(Proposal {
+                height: Height::new(1, 5),
+                round: Round::new(round),
+                shard_hash: value,
+                pol_round: Round::new(pol_round),
+                proposer: self.address.clone(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_conflicting_messages_are_refused_after_restart() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("wal").join("shard1.wal");
+        let signer = Signer::new();
+        let height = Height::new(1, 5);
+
+        let mut wal = ConsensusWal::open(&path).unwrap();
+        wal.record_proposal(&signer.proposal(0, -1, value(1)))
+            .unwrap();
+        wal.record_vote(&signer.prevote(0, Some(value(1)))).unwrap();
+        wal.record_vote(&signer.precommit(0, value(1))).unwrap();
+        drop(wal);
+
+        let mut wal = ConsensusWal::open(&path).unwrap();
+        assert_eq!(wal.messages(height).len(), 3);
+        assert_eq!(wal.lock(height).unwrap().value, Some(value(1)));
+        assert_eq!(wal.proposal_value(height, Round::new(0)), Some(value(1)));
+
+        // The same messages can be rebroadcast, conflicting ones are refused
+        assert!(wal.record_vote(&signer.prevote(0, Some(value(1)))).is_ok());
+        assert!(matches!(
+            wal.record_vote(&signer.prevote(0, Some(value(2)))),
+            Err(WalError::ConflictingVote { .. })
+        ));
+        assert!(matches!(
+            wal.record_vote(&signer.prevote(0, None)),
+            Err(WalError::ConflictingVote { .. })
+        ));
+        assert!(matches!(
+            wal.record_proposal(&signer.proposal(0, -1, value(2))),
+            Err(WalError::ConflictingProposal { .. })
+        ));
+        assert_eq!(wal.messages(height).len(), 3);
+    }
+
+    #[test]
+    fn test_lock() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("shard1.wal");
+        let signer = Signer::new();
+        let height = Height::new(1, 5);
+
+        let mut wal = ConsensusWal::open(&path).unwrap();
+        wal.record_vote(&signer.precommit(0, value(1))).unwrap();
+
+        // Prevoting nil or the locked value is fine, another value needs a newer polka
+        assert!(wal.record_vote(&signer.prevote(1, None)).is_ok());
+        assert!(wal.record_vote(&signer.prevote(2, Some(value(1)))).is_ok());
+        assert!(matches!(
+            wal.record_vote(&signer.prevote(3, Some(value(2)))),
+            Err(WalError::VoteAgainstLock { .. })
+        ));
+        wal.observe_proposal(&signer.proposal(4, 2, value(2)));
+        assert!(wal.record_vote(&signer.prevote(4, Some(value(2)))).is_ok());
+
+        wal.record_vote(&signer.precommit(4, value(2))).unwrap();
+        let lock = wal.lock(height).unwrap();
+        assert_eq!(lock.round, 4);
+        assert_eq!(lock.value, Some(value(2)));
+    }
+
+    #[test]
+    fn test_prune_and_partial_entries() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("shard1.wal");
+        let signer = Signer::new();
+
+        let mut wal = ConsensusWal::open(&path).unwrap();
+        wal.record_vote(&signer.prevote(0, Some(value(1)))).unwrap();
+        wal.prune(Height::new(1, 4)).unwrap();
+        assert_eq!(wal.messages(Height::new(1, 5)).len(), 1);
+        wal.prune(Height::new(1, 5)).unwrap();
+        assert!(wal.messages(Height::new(1, 5)).is_empty());
+        wal.record_vote(&signer.prevote(1, Some(value(1)))).unwrap();
+        drop(wal);
+
+        // A crash while appending leaves a partial entry, which is dropped
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 9, 1]).unwrap();
+        drop(file);
+
+        let mut wal = ConsensusWal::open(&path).unwrap();
+        assert_eq!(wal.messages(Height::new(1, 5)).len(), 1);
+        wal.record_vote(&signer.prevote(2, Some(value(1)))).unwrap();
+        drop(wal);
+        let wal = ConsensusWal::open(&path).unwrap();
+        assert_eq!(wal.messages(Height::new(1, 5)).len(), 2);
+    }
+}
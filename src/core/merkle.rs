@@ -0,0 +1,94 @@
+use crate::proto::{ShardChunk, Transaction, ValidatorMessage};
+use prost::Message;
+
+// Leaves and inner nodes are hashed with different prefixes, so an inner node can't be passed off
+// as a leaf
+const LEAF_PREFIX: u8 = 0;
+const NODE_PREFIX: u8 = 1;
+
+/// Root of an empty tree, also the parent hash of the first block and shard chunk
+pub const EMPTY_ROOT: [u8; 32] = [0; 32];
+
+fn hash_leaf(leaf: &[u8]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[LEAF_PREFIX]);
+    hasher.update(leaf);
+    *hasher.finalize().as_bytes()
+}
+
+fn hash_node(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(&[NODE_PREFIX]);
+    hasher.update(left);
+    hasher.update(right);
+    *hasher.finalize().as_bytes()
+}
+
+/// Binary Merkle root of the leaves, in order. A node without a sibling is carried up to the next
+/// level as is, rather than paired with itself, so no two lists of leaves share a root.
+pub fn merkle_root<T: AsRef<[u8]>>(leaves: &[T]) -> Vec<u8> {
+    if leaves.is_empty() {
+        return EMPTY_ROOT.to_vec();
+    }
+    let mut level: Vec<[u8; 32]> = leaves.iter().map(|leaf| hash_leaf(leaf.as_ref())).collect();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => hash_node(left, right),
+                [node] => *node,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    level[0].to_vec()
+}
+
+pub fn transactions_root(transactions: &[Transaction]) -> Vec<u8> {
+    let leaves: Vec<Vec<u8>> = transactions.iter().map(|tx| tx.encode_to_vec()).collect();
+    merkle_root(&leaves)
+}
+
+// Commits a block to the headers of its chunks, which commit to their transactions
+pub fn shard_headers_root(shard_chunks: &[ShardChunk]) -> Vec<u8> {
+    let leaves: Vec<Vec<u8>> = shard_chunks
+        .iter()
+        .map(|chunk| chunk.header.clone().unwrap_or_default().encode_to_vec())
+        .collect();
+    merkle_root(&leaves)
+}
+
+pub fn validator_messages_root(messages: &[ValidatorMessage]) -> Vec<u8> {
+    let leaves: Vec<Vec<u8>> = messages.iter().map(|msg| msg.encode_to_vec()).collect();
+    merkle_root(&leaves)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merkle_root() {
+        let empty: [&[u8]; 0] = [];
+        assert_eq!(merkle_root(&empty), EMPTY_ROOT.to_vec());
+
+        let single = merkle_root(&[b"a"]);
+        assert_eq!(single, hash_leaf(b"a").to_vec());
+
+        let three = merkle_root(&[b"a", b"b", b"c"]);
+        let expected = hash_node(
+            &hash_node(&hash_leaf(b"a"), &hash_leaf(b"b")),
+            &hash_leaf(b"c"),
+        );
+        assert_eq!(three, expected.to_vec());
+
+        // Order, duplicates and each leaf change the root
+        assert_ne!(three, merkle_root(&[b"b", b"a", b"c"]));
+        assert_ne!(three, merkle_root(&[b"a", b"b", b"c", b"c"]));
+        assert_ne!(three, merkle_root(&[b"a", b"b", b"d"]));
+
+        // An inner node is not a valid leaf
+        let node = hash_node(&hash_leaf(b"a"), &hash_leaf(b"b"));
+        assert_ne!(merkle_root(&[b"a", b"b"]), merkle_root(&[node]));
+    }
+}
@@ -1,4 +1,6 @@
 pub mod error;
+pub mod merkle;
 mod message;
+pub mod signatures;
 pub mod types;
 pub mod util;
@@ -0,0 +1,276 @@
+use crate::proto;
+use crate::storage::util::blake3_20;
+use curve25519_dalek::edwards::CompressedEdwardsY;
+use curve25519_dalek::scalar::Scalar;
+use ed25519_dalek::{Signature, VerifyingKey};
+use prost::Message as _;
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+
+pub const DEFAULT_VERIFIED_MESSAGE_CACHE_SIZE: usize = 100_000;
+
+fn data_bytes(message: &proto::Message) -> Option<Vec<u8>> {
+    match (&message.data_bytes, &message.data) {
+        (Some(data_bytes), _) => Some(data_bytes.clone()),
+        (None, Some(data)) => Some(data.encode_to_vec()),
+        (None, None) => None,
+    }
+}
+
+// A message whose hash matches its data, with a well formed signature and signer
+struct SignedHash<'a> {
+    hash: &'a [u8],
+    signature: Signature,
+    signer: VerifyingKey,
+}
+
+impl<'a> SignedHash<'a> {
+    fn parse(message: &'a proto::Message) -> Option<Self> {
+        if message.hash_scheme != proto::HashScheme::Blake3 as i32
+            || message.signature_scheme != proto::SignatureScheme::Ed25519 as i32
+        {
+            return None;
+        }
+        if blake3_20(&data_bytes(message)?) != message.hash {
+            return None;
+        }
+        Some(SignedHash {
+            hash: &message.hash,
+            signature: Signature::from_slice(&message.signature).ok()?,
+            signer: VerifyingKey::try_from(message.signer.as_slice()).ok()?,
+        })
+    }
+
+    fn verify_strict(&self) -> bool {
+        self.signer
+            .verify_strict(self.hash, &self.signature)
+            .is_ok()
+    }
+
+    // Batch verification only reaches the same result as verify_strict for canonically encoded
+    // keys and signatures without small order or torsion components. Anything else is verified on
+    // its own.
+    fn is_batchable(&self) -> bool {
+        let canonical_s: Option<Scalar> =
+            Scalar::from_canonical_bytes(*self.signature.s_bytes()).into();
+        canonical_s.is_some()
+            && is_prime_order_point(self.signer.as_bytes())
+            && is_prime_order_point(self.signature.r_bytes())
+    }
+}
+
+fn is_prime_order_point(bytes: &[u8; 32]) -> bool {
+    let Some(point) = CompressedEdwardsY(*bytes).decompress() else {
+        return false;
+    };
+    point.compress().as_bytes() == bytes && !point.is_small_order() && point.is_torsion_free()
+}
+
+/// Returns true if the message hash matches its data and the hash is signed by the signer.
+/// Signatures are checked with `verify_strict`, so every node reaches the same result for the
+/// same message.
+pub fn verify_message(message: &proto::Message) -> bool {
+    SignedHash::parse(message).is_some_and(|signed| signed.verify_strict())
+}
+
+/// Same result as calling `verify_message` on each message, but the signatures are verified in a
+/// single batch. If the batch fails, its signatures are verified one by one to find the invalid
+/// ones.
+pub fn verify_messages(messages: &[&proto::Message]) -> Vec<bool> {
+    let signed: Vec<Option<SignedHash>> = messages
+        .iter()
+        .map(|message| SignedHash::parse(message))
+        .collect();
+
+    let mut results = vec![false; messages.len()];
+    let mut batch = vec![];
+    for (i, signed) in signed.iter().enumerate() {
+        match signed {
+            Some(signed) if signed.is_batchable() => batch.push((i, signed)),
+            Some(signed) => results[i] = signed.verify_strict(),
+            None => {}
+        }
+    }
+    if batch.is_empty() {
+        return results;
+    }
+
+    let hashes: Vec<&[u8]> = batch.iter().map(|(_, signed)| signed.hash).collect();
+    let signatures: Vec<Signature> = batch.iter().map(|(_, signed)| signed.signature).collect();
+    let signers: Vec<VerifyingKey> = batch.iter().map(|(_, signed)| signed.signer).collect();
+    let batch_valid = ed25519_dalek::verify_batch(&hashes, &signatures, &signers).is_ok();
+    for (i, signed) in batch {
+        results[i] = batch_valid || signed.verify_strict();
+    }
+    results
+}
+
+/// Identifies a message along with its signature. The hash is truncated and only covers the
+/// message data, so the full digest of the data, the signer and the signature are all part of
+/// the key.
+pub fn message_key(message: &proto::Message) -> Vec<u8> {
+    let data_hash = blake3::hash(&data_bytes(message).unwrap_or_default());
+    [
+        data_hash.as_bytes().as_slice(),
+        message.hash.as_slice(),
+        message.signer.as_slice(),
+        message.signature.as_slice(),
+    ]
+    .concat()
+}
+
+#[derive(Default)]
+struct VerifiedMessageCacheInner {
+    keys: HashSet<Vec<u8>>,
+    // Insertion order, the oldest entries are evicted first
+    order: VecDeque<Vec<u8>>,
+}
+
+/// Remembers messages whose signatures were already verified (e.g. when they were submitted), so
+/// they don't need to be verified again when they show up in a proposal. Clones share the cache.
+#[derive(Clone)]
+pub struct VerifiedMessageCache {
+    max_size: usize,
+    inner: Arc<Mutex<VerifiedMessageCacheInner>>,
+}
+
+impl Default for VerifiedMessageCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_VERIFIED_MESSAGE_CACHE_SIZE)
+    }
+}
+
+impl VerifiedMessageCache {
+    pub fn new(max_size: usize) -> Self {
+        VerifiedMessageCache {
+            max_size,
+            inner: Arc::new(Mutex::new(VerifiedMessageCacheInner::default())),
+        }
+    }
+
+    pub fn contains(&self, message: &proto::Message) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.keys.contains(&message_key(message))
+    }
+
+    pub fn insert(&self, message: &proto::Message) {
+        if self.max_size == 0 {
+            return;
+        }
+        let key = message_key(message);
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.keys.insert(key.clone()) {
+            return;
+        }
+        inner.order.push_back(key);
+        while inner.order.len() > self.max_size {
+            if let Some(evicted) = inner.order.pop_front() {
+                inner.keys.remove(&evicted);
+            }
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.inner.lock().unwrap().keys.len()
+    }
+
+    /// Verifies the messages that aren't cached yet and caches the valid ones.
+    /// Returns whether each message is valid.
+    pub fn verify(&self, messages: &[&proto::Message]) -> Vec<bool> {
+        let uncached: Vec<usize> = (0..messages.len())
+            .filter(|&i| !self.contains(messages[i]))
+            .collect();
+        let uncached_messages: Vec<&proto::Message> =
+            uncached.iter().map(|&i| messages[i]).collect();
+
+        let mut results = vec![true; messages.len()];
+        for (&i, valid) in uncached.iter().zip(verify_messages(&uncached_messages)) {
+            results[i] = valid;
+            if valid {
+                self.insert(messages[i]);
+            }
+        }
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::factory::messages_factory;
+
+    fn cast(text: &str) -> proto::Message {
+        messages_factory::casts::create_cast_add(1234, text, Some(0), None)
+    }
+
+    #[test]
+    fn test_verify_messages() {
+        let valid1 = cast("msg1");
+        let valid2 = cast("msg2");
+        let mut bad_signature = cast("msg3");
+        bad_signature.signature = valid1.signature.clone();
+        let mut bad_hash = cast("msg4");
+        bad_hash.hash = valid2.hash.clone();
+
+        assert!(verify_message(&valid1));
+        assert!(!verify_message(&bad_signature));
+        assert!(!verify_message(&bad_hash));
+
+        assert_eq!(verify_messages(&[&valid1, &valid2]), vec![true, true]);
+        assert_eq!(
+            verify_messages(&[&valid1, &bad_signature, &valid2, &bad_hash]),
+            vec![true, false, true, false]
+        );
+        assert!(verify_messages(&[]).is_empty());
+    }
+
+    #[test]
+    fn test_non_canonical_signatures_are_verified_strictly() {
+        let valid = cast("msg1");
+        // Adding the group order to s gives a non canonical encoding of it, which isn't batched
+        let mut non_canonical = cast("msg2");
+        let mut order = [0u8; 32];
+        order[..16].copy_from_slice(&[
+            0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9,
+            0xde, 0x14,
+        ]);
+        order[31] = 0x10;
+        let mut carry = 0u16;
+        for i in 0..32 {
+            let sum = non_canonical.signature[32 + i] as u16 + order[i] as u16 + carry;
+            non_canonical.signature[32 + i] = sum as u8;
+            carry = sum >> 8;
+        }
+
+        assert!(!verify_message(&non_canonical));
+        assert_eq!(
+            verify_messages(&[&valid, &non_canonical]),
+            vec![true, false]
+        );
+    }
+
+    #[test]
+    fn test_verified_message_cache() {
+        let cache = VerifiedMessageCache::new(2);
+        let valid1 = cast("msg1");
+        let valid2 = cast("msg2");
+        let valid3 = cast("msg3");
+        let mut bad_signature = valid1.clone();
+        bad_signature.signature = valid2.signature.clone();
+
+        assert_eq!(cache.verify(&[&valid1, &bad_signature]), vec![true, false]);
+        assert!(cache.contains(&valid1));
+        // Same hash, but a different signature
+        assert!(!cache.contains(&bad_signature));
+        // Same hash and signature, but different data
+        let mut bad_data = valid1.clone();
+        bad_data.data.as_mut().unwrap().timestamp += 1;
+        assert!(!cache.contains(&bad_data));
+
+        cache.insert(&valid2);
+        cache.insert(&valid3);
+        assert_eq!(cache.size(), 2);
+        assert!(!cache.contains(&valid1));
+        assert!(cache.contains(&valid3));
+    }
+}
@@ -14,7 +14,10 @@ use tracing::warn;
 pub use crate::proto; // TODO: reconsider how this is imported
 
 use crate::proto::full_proposal::ProposedValue;
-use crate::proto::{Block, FullProposal, ShardChunk};
+use crate::proto::{
+    Block, BlockHeader, FullProposal, RegisterValidator, ShardChunk, ShardHeader,
+    ValidatorSetUpdate,
+};
 pub use proto::Height;
 pub use proto::ShardHash;
 
@@ -199,6 +202,19 @@ impl malachite_common::Value for ShardHash {
     }
 }
 
+// The hash of a shard chunk or block is the hash of its header, which commits to the contents
+impl ShardHeader {
+    pub fn hash(&self) -> Vec<u8> {
+        blake3::hash(&self.encode_to_vec()).as_bytes().to_vec()
+    }
+}
+
+impl BlockHeader {
+    pub fn hash(&self) -> Vec<u8> {
+        blake3::hash(&self.encode_to_vec()).as_bytes().to_vec()
+    }
+}
+
 impl FullProposal {
     pub fn shard_hash(&self) -> ShardHash {
         match &self.proposed_value {
@@ -238,11 +254,39 @@ impl FullProposal {
         self.height.clone().unwrap()
     }
 
+    pub fn to_sign_bytes(&self) -> Vec<u8> {
+        FullProposal {
+            signature: vec![],
+            ..self.clone()
+        }
+        .encode_to_vec()
+    }
+
     pub fn round(&self) -> Round {
         Round::new(self.round)
     }
 }
 
+impl RegisterValidator {
+    pub fn to_sign_bytes(&self) -> Vec<u8> {
+        RegisterValidator {
+            signature: vec![],
+            ..self.clone()
+        }
+        .encode_to_vec()
+    }
+}
+
+impl ValidatorSetUpdate {
+    pub fn to_sign_bytes(&self) -> Vec<u8> {
+        ValidatorSetUpdate {
+            signatures: vec![],
+            ..self.clone()
+        }
+        .encode_to_vec()
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct SnapchainValidator {
     pub shard_index: u32,
@@ -250,6 +294,7 @@ pub struct SnapchainValidator {
     pub public_key: PublicKey,
     pub rpc_address: Option<String>,
     pub current_height: u64,
+    pub voting_power: u64,
 }
 
 impl SnapchainValidator {
@@ -265,8 +310,14 @@ impl SnapchainValidator {
             public_key,
             rpc_address,
             current_height,
+            voting_power: 1,
         }
     }
+
+    pub fn with_voting_power(mut self, voting_power: u64) -> Self {
+        self.voting_power = voting_power;
+        self
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -303,6 +354,23 @@ impl SnapchainValidatorSet {
         self.validators.iter().any(|v| v.address == *address)
     }
 
+    // Only refreshes how to reach a validator that's already in the set, membership is fixed by
+    // the genesis file and changes through the protocol
+    pub fn update(&mut self, validator: &SnapchainValidator) -> bool {
+        match self
+            .validators
+            .iter_mut()
+            .find(|v| v.address == validator.address)
+        {
+            Some(existing) => {
+                existing.rpc_address = validator.rpc_address.clone();
+                existing.current_height = validator.current_height;
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn shard_id(&self) -> u32 {
         if self.validators.is_empty() {
             0
@@ -450,16 +518,70 @@ impl Proposal {
     }
 }
 
+// Proposals are gossiped in parts, well under gossipsub's message size limit
+pub const MAX_PROPOSAL_PART_BYTES: usize = 32 * 1024;
+
+/// A piece of an encoded full proposal. The parts of a proposal carry its hash, so parts of
+/// different proposals for the same height and round can't be mixed.
 #[derive(Clone, Debug, PartialEq, Eq)]
-pub struct SinglePartProposal {
+pub struct ProposalPart {
     pub height: Height,
-    pub proposal_round: Round,
+    pub round: Round,
     pub proposer: Address,
-}
+    pub index: u32,
+    pub count: u32,
+    pub proposal_hash: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+impl ProposalPart {
+    /// Splits a signed full proposal into the parts to gossip, in order
+    pub fn split(full_proposal: &FullProposal) -> Vec<ProposalPart> {
+        let encoded = full_proposal.encode_to_vec();
+        let proposal_hash = blake3::hash(&encoded).as_bytes().to_vec();
+        let data: Vec<&[u8]> = encoded.chunks(MAX_PROPOSAL_PART_BYTES).collect();
+        let count = data.len() as u32;
+        data.into_iter()
+            .enumerate()
+            .map(|(index, data)| ProposalPart {
+                height: full_proposal.height(),
+                round: full_proposal.round(),
+                proposer: full_proposal.proposer_address(),
+                index: index as u32,
+                count,
+                proposal_hash: proposal_hash.clone(),
+                data: data.to_vec(),
+            })
+            .collect()
+    }
+
+    pub fn to_proto(&self) -> proto::ProposalPart {
+        proto::ProposalPart {
+            height: Some(self.height),
+            round: self.round.as_i64(),
+            proposer: self.proposer.to_vec(),
+            index: self.index,
+            count: self.count,
+            proposal_hash: self.proposal_hash.clone(),
+            data: self.data.clone(),
+        }
+    }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-pub enum ProposalPart {
-    FullProposal(SinglePartProposal),
+    pub fn from_proto(proto: proto::ProposalPart) -> Self {
+        Self {
+            height: proto.height.unwrap(),
+            round: Round::new(proto.round),
+            proposer: Address::from_vec(proto.proposer),
+            index: proto.index,
+            count: proto.count,
+            proposal_hash: proto.proposal_hash,
+            data: proto.data,
+        }
+    }
+
+    pub fn to_sign_bytes(&self) -> Vec<u8> {
+        self.to_proto().encode_to_vec()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -477,6 +599,22 @@ impl SnapchainValidatorContext {
     pub fn public_key(&self) -> PublicKey {
         self.keypair.public()
     }
+
+    pub fn sign_full_proposal(&self, full_proposal: FullProposal) -> FullProposal {
+        let signature = self.keypair.sign(&full_proposal.to_sign_bytes());
+        FullProposal {
+            signature,
+            ..full_proposal
+        }
+    }
+
+    pub fn verify_full_proposal(
+        &self,
+        full_proposal: &FullProposal,
+        public_key: &PublicKey,
+    ) -> bool {
+        public_key.verify(&full_proposal.to_sign_bytes(), &full_proposal.signature)
+    }
 }
 
 impl ShardedContext for SnapchainValidatorContext {
@@ -553,16 +691,17 @@ impl malachite_common::Context for SnapchainValidatorContext {
     }
 
     fn sign_proposal_part(&self, proposal_part: Self::ProposalPart) -> SignedProposalPart<Self> {
-        SignedProposalPart::new(proposal_part, Signature(vec![]))
+        let signature = self.keypair.sign(&proposal_part.to_sign_bytes());
+        SignedProposalPart::new(proposal_part, Signature(signature))
     }
 
     fn verify_signed_proposal_part(
         &self,
-        _proposal_part: &ProposalPart,
-        _signature: &Signature,
-        _public_key: &PublicKey,
+        proposal_part: &ProposalPart,
+        signature: &Signature,
+        public_key: &PublicKey,
     ) -> bool {
-        todo!()
+        public_key.verify(&proposal_part.to_sign_bytes(), &signature.0)
     }
 
     fn new_proposal(
@@ -604,12 +743,11 @@ impl SnapchainContext for SnapchainValidatorContext {}
 
 impl malachite_common::ProposalPart<SnapchainValidatorContext> for ProposalPart {
     fn is_first(&self) -> bool {
-        // Only one part for now
-        true
+        self.index == 0
     }
 
     fn is_last(&self) -> bool {
-        true
+        self.index + 1 == self.count
     }
 }
 
@@ -708,6 +846,6 @@ impl malachite_common::Validator<SnapchainValidatorContext> for SnapchainValidat
     }
 
     fn voting_power(&self) -> VotingPower {
-        1
+        self.voting_power
     }
 }
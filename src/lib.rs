@@ -2,6 +2,7 @@ pub mod cfg;
 pub mod connectors;
 pub mod consensus;
 pub mod core;
+pub mod mempool;
 pub mod network;
 pub mod node;
 pub mod perf;
@@ -14,15 +14,19 @@ use tracing::{error, info, warn};
 use tracing_subscriber::EnvFilter;
 
 use snapchain::consensus::consensus::SystemMessage;
+use snapchain::consensus::genesis::Genesis;
+use snapchain::consensus::validator_set::ValidatorSets;
 use snapchain::core::types::proto;
 use snapchain::network::admin_server::{DbManager, MyAdminService};
 use snapchain::network::gossip::GossipEvent;
 use snapchain::network::gossip::SnapchainGossip;
 use snapchain::network::server::MyHubService;
-use snapchain::node::snapchain_node::SnapchainNode;
+use snapchain::node::read_only_replica::ReadOnlyReplica;
+use snapchain::node::snapchain_node::{shard_db_path, SnapchainNode};
 use snapchain::proto::admin_service_server::AdminServiceServer;
 use snapchain::proto::hub_service_server::HubServiceServer;
 use snapchain::storage::db::RocksDB;
+use snapchain::storage::store::snapshot;
 use snapchain::utils::statsd_wrapper::StatsdClientWrapper;
 
 #[tokio::main]
@@ -89,30 +93,25 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let keypair = app_config.consensus.keypair().clone();
 
+    let genesis = match Genesis::load(&app_config.consensus.genesis_path) {
+        Ok(genesis) => genesis,
+        Err(e) => {
+            error!(error = ?e, "Failed to load genesis from {}", app_config.consensus.genesis_path);
+            return Err(e.into());
+        }
+    };
+    info!(
+        chain_id = %genesis.chain_id,
+        num_shards = genesis.num_shards,
+        validators = genesis.validators.len(),
+        "Loaded genesis"
+    );
+
     info!(
         "Starting Snapchain node with public key: {}",
         hex::encode(keypair.public().to_bytes())
     );
 
-    let (system_tx, mut system_rx) = mpsc::channel::<SystemMessage>(100);
-
-    let gossip_result =
-        SnapchainGossip::create(keypair.clone(), app_config.gossip, system_tx.clone());
-
-    if let Err(e) = gossip_result {
-        error!(error = ?e, "Failed to create SnapchainGossip");
-        return Ok(());
-    }
-
-    let mut gossip = gossip_result?;
-    let gossip_tx = gossip.tx.clone();
-
-    tokio::spawn(async move {
-        info!("Starting gossip");
-        gossip.start().await;
-        info!("Gossip Stopped");
-    });
-
     if !app_config.fnames.disable {
         let mut fetcher = snapchain::connectors::fname::Fetcher::new(app_config.fnames.clone());
 
@@ -141,31 +140,140 @@ async fn main() -> Result<(), Box<dyn Error>> {
     // Use the new non-global metrics registry when we upgrade to newer version of malachite
     let _ = Metrics::register(registry);
 
+    let shard_ids = app_config.consensus.shard_ids();
+    if !app_config.snapshot_source.is_empty() {
+        // Snapshots are only imported if their chunk was decided by the validators at its height,
+        // which the blocks up to it tell. A directory can't serve them, the block store must
+        // already have them.
+        let validator_sets = ValidatorSets::new(&genesis, &block_store)?;
+        if snapshot::is_rpc_source(&app_config.snapshot_source) {
+            let result = snapshot::sync_blocks_from_rpc(
+                &block_store,
+                &app_config.snapshot_source,
+                &validator_sets,
+            )
+            .await;
+            if let Err(err) = result {
+                error!(error = ?err, "Failed to sync blocks from snapshot source");
+                return Err(err.into());
+            }
+        }
+        for shard_id in &shard_ids {
+            let db = RocksDB::new(&shard_db_path(&app_config.rocksdb_dir, *shard_id));
+            db.open().unwrap();
+            // Runs before the shard's trie and its node cache exist
+            let result = snapshot::bootstrap_from_snapshot(
+                &db,
+                *shard_id,
+                &app_config.snapshot_source,
+                app_config.trie_branching_factor,
+                &validator_sets,
+                None,
+            )
+            .await;
+            db.close();
+            if let Err(err) = result {
+                error!(shard_id, error = ?err, "Failed to bootstrap shard from snapshot");
+                return Err(err.into());
+            }
+        }
+    }
+
+    // Read only replicas don't take part in consensus or gossip, they only sync and serve reads
+    if !app_config.trie_sync_source.is_empty() {
+        info!(
+            sync_source = app_config.trie_sync_source,
+            "Running shards as read only replicas"
+        );
+        let replica = ReadOnlyReplica::create(
+            shard_ids,
+            app_config.trie_sync_source.clone(),
+            &app_config.rocksdb_dir,
+            statsd_client.clone(),
+            app_config.trie_branching_factor,
+            app_config.trie_node_cache_bytes,
+            app_config.consensus.max_messages_per_block,
+        );
+        let service = MyHubService::new(
+            block_store.clone(),
+            replica.shard_stores,
+            replica.shard_senders,
+            statsd_client,
+            None,
+        );
+        select! {
+            _ = ctrl_c() => {
+                info!("Received Ctrl-C, shutting down");
+            }
+            resp = Server::builder()
+                .add_service(HubServiceServer::new(service))
+                .serve(grpc_socket_addr) => {
+                error!(result = ?resp, "grpc server stopped");
+            }
+        }
+        return Ok(());
+    }
+
+    let (system_tx, mut system_rx) = mpsc::channel::<SystemMessage>(100);
+
+    let gossip_result = SnapchainGossip::create(
+        keypair.clone(),
+        app_config.gossip,
+        system_tx.clone(),
+        app_config.consensus.shard_ids(),
+    );
+
+    if let Err(e) = gossip_result {
+        error!(error = ?e, "Failed to create SnapchainGossip");
+        return Ok(());
+    }
+
+    let mut gossip = gossip_result?;
+    let gossip_tx = gossip.tx.clone();
+
+    tokio::spawn(async move {
+        info!("Starting gossip");
+        gossip.start().await;
+        info!("Gossip Stopped");
+    });
+
+    let rollbacks = db_manager.scheduled_rollbacks(&shard_ids).unwrap();
     let node = SnapchainNode::create(
         keypair.clone(),
         app_config.consensus.clone(),
-        Some(app_config.rpc_address.clone()),
         gossip_tx.clone(),
         None,
         block_store.clone(),
         app_config.rocksdb_dir.clone(),
         statsd_client.clone(),
         app_config.trie_branching_factor,
+        app_config.trie_node_cache_bytes,
+        rollbacks,
+        &genesis,
     )
     .await;
-
-    let admin_service = MyAdminService::new(db_manager, node.shard_senders.clone());
+    db_manager.clear_scheduled_rollbacks(&shard_ids).unwrap();
+
+    let admin_service = MyAdminService::new(
+        db_manager,
+        node.shard_senders.clone(),
+        node.shard_stores.clone(),
+        node.validator_sets.clone(),
+        node.evidence_store.clone(),
+    );
 
     let rpc_shard_stores = node.shard_stores.clone();
     let rpc_shard_senders = node.shard_senders.clone();
 
     let rpc_block_store = block_store.clone();
+    let rpc_gossip_tx = gossip_tx.clone();
     tokio::spawn(async move {
         let service = MyHubService::new(
             rpc_block_store,
             rpc_shard_stores,
             rpc_shard_senders,
             statsd_client.clone(),
+            Some(rpc_gossip_tx),
         );
 
         let resp = Server::builder()
@@ -183,8 +291,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
         shutdown_tx.send(()).await.ok();
     });
 
-    // Create a timer for block creation
-    let mut block_interval = time::interval(Duration::from_secs(2));
+    // Blocks are paced by the consensus timeouts and propose delays, this only drives validator
+    // registration
+    let mut registration_interval = time::interval(Duration::from_secs(2));
 
     let mut tick_count = 0;
 
@@ -201,9 +310,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
                 node.stop();
                 return Ok(());
             }
-            _ = block_interval.tick() => {
+            _ = registration_interval.tick() => {
                 tick_count += 1;
-                // Every 5 ticks, re-register the validators so that new nodes can discover each other
+                // Every 5 ticks, re-register the validators so that peers know our rpc address and height
                 if tick_count % 5 == 0 {
                     let nonce = tick_count as u64;
                     for i in 0..=app_config.consensus.num_shards() {
@@ -218,16 +327,19 @@ async fn main() -> Result<(), Box<dyn Error>> {
                             }
                         };
 
-                        let register_validator = proto::RegisterValidator {
+                        let mut register_validator = proto::RegisterValidator {
                             validator: Some(proto::Validator {
                                 signer: keypair.public().to_bytes().to_vec(),
                                 fid: 0,
                                 rpc_address: app_config.rpc_address.clone(),
                                 shard_index: i,
-                                current_height
+                                current_height,
+                                voting_power: 0, // Comes from the genesis and validator set updates
                             }),
                             nonce,   // Need the nonce to avoid the gossip duplicate message check
+                            signature: vec![],
                         };
+                        register_validator.signature = keypair.sign(&register_validator.to_sign_bytes());
                         gossip_tx.send(GossipEvent::RegisterValidator(register_validator)).await?;
                     }
                     info!("Registering validator with nonce: {}", nonce);
@@ -240,6 +352,9 @@ async fn main() -> Result<(), Box<dyn Error>> {
                         // Forward to apropriate consesnsus actors
                         node.dispatch(consensus_msg);
                     }
+                    SystemMessage::Mempool(shard_id, mempool_msg) => {
+                        node.add_mempool_message(shard_id, mempool_msg);
+                    }
                 }
             }
         }
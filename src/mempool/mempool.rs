@@ -0,0 +1,235 @@
+use crate::proto::{self, MessageOutcome, Transaction};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+const MAX_RECENT_REJECTIONS: usize = 100;
+
+// Messages that are never included (e.g. the fid has no storage) are dropped after this long
+const MAX_PENDING_AGE: Duration = Duration::from_secs(60 * 60);
+
+// How often expired messages are pruned, since messages pulled into a proposal that is never
+// committed are not removed by a commit
+pub const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug)]
+pub struct PendingMessage {
+    pub message: proto::Message,
+    pub received_at: SystemTime,
+}
+
+impl PendingMessage {
+    pub fn age(&self) -> Duration {
+        self.received_at.elapsed().unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Rejection {
+    pub hash: Vec<u8>,
+    pub fid: u32,
+    pub reason: String,
+    pub rejected_at: SystemTime,
+}
+
+#[derive(Default)]
+struct MempoolInner {
+    pending: HashMap<Vec<u8>, PendingMessage>,
+    recent_rejections: VecDeque<Rejection>,
+}
+
+impl MempoolInner {
+    fn reject(&mut self, hash: Vec<u8>, fid: u32, reason: String) {
+        self.pending.remove(&hash);
+        if self.recent_rejections.len() >= MAX_RECENT_REJECTIONS {
+            self.recent_rejections.pop_front();
+        }
+        self.recent_rejections.push_back(Rejection {
+            hash,
+            fid,
+            reason,
+            rejected_at: SystemTime::now(),
+        });
+    }
+
+    fn prune_expired(&mut self) {
+        self.pending
+            .retain(|_, pending| pending.age() < MAX_PENDING_AGE);
+    }
+}
+
+/// Tracks the user messages submitted to a shard that have not been included in a committed
+/// shard chunk yet. The messages themselves flow to the engine through `Senders::messages_tx`,
+/// this only keeps enough state around to inspect the backlog.
+#[derive(Clone, Default)]
+pub struct Mempool {
+    inner: Arc<Mutex<MempoolInner>>,
+}
+
+impl Mempool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&self, message: &proto::Message) {
+        let mut inner = self.inner.lock().unwrap();
+        inner
+            .pending
+            .entry(message.hash.clone())
+            .or_insert_with(|| PendingMessage {
+                message: message.clone(),
+                received_at: SystemTime::now(),
+            });
+    }
+
+    pub fn record_rejection(&self, message: &proto::Message, reason: String) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.reject(message.hash.clone(), message.fid(), reason);
+    }
+
+    // Called once a shard chunk is committed. Messages are no longer pending once they've been
+    // part of a committed chunk, regardless of whether they were merged. The ones that failed
+    // replay are recorded as rejections.
+    pub fn remove_committed(&self, transactions: &[Transaction]) {
+        let mut inner = self.inner.lock().unwrap();
+        for transaction in transactions {
+            for message in &transaction.user_messages {
+                inner.pending.remove(&message.hash);
+            }
+            for receipt in &transaction.receipts {
+                if matches!(
+                    receipt.outcome(),
+                    MessageOutcome::Invalid | MessageOutcome::MergeConflict
+                ) {
+                    inner.reject(
+                        receipt.hash.clone(),
+                        transaction.fid as u32,
+                        receipt.reason.clone(),
+                    );
+                }
+            }
+        }
+        inner.prune_expired();
+    }
+
+    pub fn prune_expired(&self) {
+        self.inner.lock().unwrap().prune_expired();
+    }
+
+    pub fn size(&self) -> usize {
+        self.inner.lock().unwrap().pending.len()
+    }
+
+    pub fn get(&self, hash: &[u8]) -> Option<PendingMessage> {
+        self.inner.lock().unwrap().pending.get(hash).cloned()
+    }
+
+    pub fn pending_counts_by_fid(&self) -> HashMap<u32, u64> {
+        let inner = self.inner.lock().unwrap();
+        let mut counts = HashMap::new();
+        for pending in inner.pending.values() {
+            *counts.entry(pending.message.fid()).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    pub fn oldest_message_age(&self) -> Option<Duration> {
+        let inner = self.inner.lock().unwrap();
+        inner.pending.values().map(|pending| pending.age()).max()
+    }
+
+    pub fn recent_rejections(&self) -> Vec<Rejection> {
+        let inner = self.inner.lock().unwrap();
+        inner.recent_rejections.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::factory::messages_factory;
+
+    #[test]
+    fn test_pending_messages_are_removed_on_commit() {
+        let mempool = Mempool::new();
+        let msg1 = messages_factory::casts::create_cast_add(1234, "msg1", None, None);
+        let msg2 = messages_factory::casts::create_cast_add(1234, "msg2", None, None);
+        let msg3 = messages_factory::casts::create_cast_add(1235, "msg3", None, None);
+
+        mempool.insert(&msg1);
+        mempool.insert(&msg2);
+        mempool.insert(&msg3);
+        // Duplicates are only tracked once
+        mempool.insert(&msg1);
+
+        assert_eq!(mempool.size(), 3);
+        assert_eq!(mempool.pending_counts_by_fid().get(&1234), Some(&2));
+        assert_eq!(mempool.pending_counts_by_fid().get(&1235), Some(&1));
+        assert!(mempool.oldest_message_age().is_some());
+        assert_eq!(mempool.get(&msg2.hash).unwrap().message, msg2);
+
+        mempool.remove_committed(&[Transaction {
+            fid: 1234,
+            user_messages: vec![msg1.clone(), msg2.clone()],
+            system_messages: vec![],
+            account_root: vec![],
+            receipts: vec![],
+        }]);
+
+        assert_eq!(mempool.size(), 1);
+        assert!(mempool.get(&msg1.hash).is_none());
+        assert!(mempool.get(&msg3.hash).is_some());
+        assert_eq!(mempool.pending_counts_by_fid().get(&1234), None);
+    }
+
+    #[test]
+    fn test_replay_failures_are_recorded_as_rejections() {
+        let mempool = Mempool::new();
+        let msg1 = messages_factory::casts::create_cast_add(1234, "msg1", None, None);
+        let msg2 = messages_factory::casts::create_cast_add(1234, "msg2", None, None);
+        mempool.insert(&msg1);
+        mempool.insert(&msg2);
+
+        mempool.remove_committed(&[Transaction {
+            fid: 1234,
+            user_messages: vec![msg1.clone(), msg2.clone()],
+            system_messages: vec![],
+            account_root: vec![],
+            receipts: vec![
+                proto::MessageReceipt {
+                    hash: msg1.hash.clone(),
+                    outcome: MessageOutcome::Merged as i32,
+                    reason: "".to_string(),
+                },
+                proto::MessageReceipt {
+                    hash: msg2.hash.clone(),
+                    outcome: MessageOutcome::Invalid as i32,
+                    reason: "invalid_signature".to_string(),
+                },
+            ],
+        }]);
+
+        assert_eq!(mempool.size(), 0);
+        let rejections = mempool.recent_rejections();
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].hash, msg2.hash);
+        assert_eq!(rejections[0].reason, "invalid_signature");
+    }
+
+    #[test]
+    fn test_rejections_are_bounded() {
+        let mempool = Mempool::new();
+        let msg = messages_factory::casts::create_cast_add(1234, "msg", None, None);
+        mempool.insert(&msg);
+
+        for i in 0..MAX_RECENT_REJECTIONS + 5 {
+            mempool.record_rejection(&msg, format!("reason {}", i));
+        }
+
+        assert_eq!(mempool.size(), 0);
+        let rejections = mempool.recent_rejections();
+        assert_eq!(rejections.len(), MAX_RECENT_REJECTIONS);
+        assert_eq!(rejections[0].reason, "reason 5");
+        assert_eq!(rejections[0].fid, 1234);
+    }
+}
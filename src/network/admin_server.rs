@@ -1,9 +1,18 @@
+use crate::consensus::validator_set::ValidatorSets;
+use crate::mempool::mempool::Mempool;
 use crate::proto::admin_service_server::AdminService;
 use crate::proto::ValidatorMessage;
 use crate::proto::{self, OnChainEvent};
+use crate::storage::db::RocksDB;
 use crate::storage::store::engine::{MempoolMessage, Senders};
+use crate::storage::store::evidence::EvidenceStore;
+use crate::storage::store::health::ShardHealth;
+use crate::storage::store::snapshot;
+use crate::storage::store::stores::Stores;
 use rocksdb;
 use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
 use std::{io, path, process};
 use thiserror::Error;
 use tokio::sync::mpsc;
@@ -46,6 +55,43 @@ impl DbManager {
         Ok(())
     }
 
+    // Rollbacks are applied when the node starts, before consensus is running (see
+    // SnapchainNode::create)
+    pub fn scheduled_rollbacks(
+        &self,
+        shard_ids: &[u32],
+    ) -> Result<HashMap<u32, u64>, AdminServiceError> {
+        let mut rollbacks = HashMap::new();
+        if let Some(ref db) = self.db {
+            for shard_id in shard_ids {
+                if let Some(value) = db.get(make_rollback_key(*shard_id))? {
+                    if let Ok(bytes) = value.as_slice().try_into() {
+                        rollbacks.insert(*shard_id, u64::from_be_bytes(bytes));
+                    }
+                }
+            }
+        }
+        Ok(rollbacks)
+    }
+
+    pub fn clear_scheduled_rollbacks(&self, shard_ids: &[u32]) -> Result<(), AdminServiceError> {
+        if let Some(ref db) = self.db {
+            for shard_id in shard_ids {
+                db.delete(make_rollback_key(*shard_id))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn schedule_rollback(&self, shard_id: u32, block_number: u64) -> Result<(), Status> {
+        if let Some(ref db) = self.db {
+            db.put(make_rollback_key(shard_id), block_number.to_be_bytes())
+                .map_err(|err| Status::internal(format!("failed to schedule rollback: {}", err)))
+        } else {
+            Err(Status::internal("admin database is not open"))
+        }
+    }
+
     fn schedule_destruction(&self) -> Result<(), Status> {
         if let Some(ref db) = self.db {
             db.put(DB_DESTROY_KEY, &[]).map_err(|err| {
@@ -63,6 +109,11 @@ impl DbManager {
 pub struct MyAdminService {
     db_manager: DbManager,
     message_tx: mpsc::Sender<MempoolMessage>,
+    mempools: HashMap<u32, Mempool>,
+    shard_health: HashMap<u32, ShardHealth>,
+    shard_dbs: HashMap<u32, Arc<RocksDB>>,
+    validator_sets: ValidatorSets,
+    evidence_store: EvidenceStore,
 }
 
 #[derive(Debug, Error)]
@@ -75,14 +126,100 @@ pub enum AdminServiceError {
 }
 
 const DB_DESTROY_KEY: &[u8] = b"__destroy_all_databases_on_start__";
+const DB_ROLLBACK_KEY_PREFIX: &[u8] = b"__rollback_shard_on_start__";
+
+fn make_rollback_key(shard_id: u32) -> Vec<u8> {
+    [DB_ROLLBACK_KEY_PREFIX, &shard_id.to_be_bytes()].concat()
+}
 
 impl MyAdminService {
-    pub fn new(db_manager: DbManager, shard_senders: HashMap<u32, Senders>) -> Self {
+    pub fn new(
+        db_manager: DbManager,
+        shard_senders: HashMap<u32, Senders>,
+        shard_stores: HashMap<u32, Stores>,
+        validator_sets: ValidatorSets,
+        evidence_store: EvidenceStore,
+    ) -> Self {
         // TODO(aditi): This logic will change once a mempool exists
         let message_tx = shard_senders.get(&1u32).unwrap().messages_tx.clone();
+        let mempools = shard_senders
+            .iter()
+            .map(|(shard_id, senders)| (*shard_id, senders.mempool.clone()))
+            .collect();
+        let shard_health = shard_senders
+            .iter()
+            .map(|(shard_id, senders)| (*shard_id, senders.health.clone()))
+            .collect();
+        let shard_dbs = shard_stores
+            .iter()
+            .map(|(shard_id, stores)| (*shard_id, stores.db.clone()))
+            .collect();
         Self {
             db_manager,
             message_tx,
+            mempools,
+            shard_health,
+            shard_dbs,
+            validator_sets,
+            evidence_store,
+        }
+    }
+
+    fn shard_status(shard_id: u32, health: &ShardHealth) -> proto::ShardStatus {
+        match health.halted() {
+            Some(halt) => proto::ShardStatus {
+                shard_id,
+                halted: true,
+                halted_block_number: halt.block_number,
+                halted_reason: halt.reason,
+                halted_at: halt
+                    .halted_at
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_millis() as u64),
+            },
+            None => proto::ShardStatus {
+                shard_id,
+                halted: false,
+                halted_block_number: 0,
+                halted_reason: "".to_string(),
+                halted_at: 0,
+            },
+        }
+    }
+
+    fn shard_mempool_info(shard_id: u32, mempool: &Mempool) -> proto::ShardMempoolInfo {
+        let mut pending_by_fid: Vec<proto::FidPendingCount> = mempool
+            .pending_counts_by_fid()
+            .into_iter()
+            .map(|(fid, count)| proto::FidPendingCount {
+                fid: fid as u64,
+                count,
+            })
+            .collect();
+        pending_by_fid.sort_by(|a, b| b.count.cmp(&a.count).then(a.fid.cmp(&b.fid)));
+
+        let recent_rejections = mempool
+            .recent_rejections()
+            .into_iter()
+            .map(|rejection| proto::MempoolRejection {
+                hash: rejection.hash,
+                fid: rejection.fid as u64,
+                reason: rejection.reason,
+                timestamp: rejection
+                    .rejected_at
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_millis() as u64),
+            })
+            .collect();
+
+        proto::ShardMempoolInfo {
+            shard_id,
+            size: mempool.size() as u64,
+            pending_by_fid,
+            oldest_message_age_ms: mempool
+                .oldest_message_age()
+                .map_or(0, |age| age.as_millis() as u64),
+            recent_rejections,
         }
     }
 }
@@ -127,6 +264,7 @@ impl AdminService for MyAdminService {
             .send(MempoolMessage::ValidatorMessage(ValidatorMessage {
                 on_chain_event: Some(onchain_event.clone()),
                 fname_transfer: None,
+                validator_set_update: None,
             }))
             .await;
         match result {
@@ -137,4 +275,199 @@ impl AdminService for MyAdminService {
             Err(err) => Err(Status::from_error(Box::new(err))),
         }
     }
+
+    async fn get_mempool_info(
+        &self,
+        request: Request<proto::MempoolInfoRequest>,
+    ) -> Result<Response<proto::MempoolInfoResponse>, Status> {
+        let shard_id = request.into_inner().shard_id;
+
+        let mut shards: Vec<proto::ShardMempoolInfo> = match shard_id {
+            Some(shard_id) => match self.mempools.get(&shard_id) {
+                Some(mempool) => vec![Self::shard_mempool_info(shard_id, mempool)],
+                None => {
+                    return Err(Status::invalid_argument(format!(
+                        "no mempool for shard {}",
+                        shard_id
+                    )))
+                }
+            },
+            None => self
+                .mempools
+                .iter()
+                .map(|(shard_id, mempool)| Self::shard_mempool_info(*shard_id, mempool))
+                .collect(),
+        };
+        shards.sort_by_key(|info| info.shard_id);
+
+        Ok(Response::new(proto::MempoolInfoResponse { shards }))
+    }
+
+    async fn get_pending_message(
+        &self,
+        request: Request<proto::PendingMessageRequest>,
+    ) -> Result<Response<proto::PendingMessageResponse>, Status> {
+        let hash = request.into_inner().hash;
+
+        for (shard_id, mempool) in &self.mempools {
+            if let Some(pending) = mempool.get(&hash) {
+                return Ok(Response::new(proto::PendingMessageResponse {
+                    shard_id: *shard_id,
+                    age_ms: pending.age().as_millis() as u64,
+                    message: Some(pending.message),
+                }));
+            }
+        }
+
+        Err(Status::not_found(format!(
+            "message {} is not pending",
+            hex::encode(&hash)
+        )))
+    }
+
+    async fn get_shard_status(
+        &self,
+        request: Request<proto::ShardStatusRequest>,
+    ) -> Result<Response<proto::ShardStatusResponse>, Status> {
+        let shard_id = request.into_inner().shard_id;
+
+        let mut shards: Vec<proto::ShardStatus> = match shard_id {
+            Some(shard_id) => match self.shard_health.get(&shard_id) {
+                Some(health) => vec![Self::shard_status(shard_id, health)],
+                None => {
+                    return Err(Status::invalid_argument(format!(
+                        "unknown shard {}",
+                        shard_id
+                    )))
+                }
+            },
+            None => self
+                .shard_health
+                .iter()
+                .map(|(shard_id, health)| Self::shard_status(*shard_id, health))
+                .collect(),
+        };
+        shards.sort_by_key(|status| status.shard_id);
+
+        Ok(Response::new(proto::ShardStatusResponse { shards }))
+    }
+
+    async fn rollback_shard(
+        &self,
+        request: Request<proto::RollbackShardRequest>,
+    ) -> Result<Response<proto::RollbackShardResponse>, Status> {
+        let request = request.into_inner();
+        info!(
+            shard_id = request.shard_id,
+            block_number = request.block_number,
+            "Received call to [rollback_shard] RPC"
+        );
+
+        if !self.shard_health.contains_key(&request.shard_id) {
+            return Err(Status::invalid_argument(format!(
+                "unknown shard {}",
+                request.shard_id
+            )));
+        }
+        self.db_manager
+            .schedule_rollback(request.shard_id, request.block_number)?;
+        warn!(
+            shard_id = request.shard_id,
+            block_number = request.block_number,
+            "rollback scheduled, it will be applied on restart"
+        );
+
+        Ok(Response::new(proto::RollbackShardResponse {}))
+    }
+
+    async fn resume_shard(
+        &self,
+        request: Request<proto::ResumeShardRequest>,
+    ) -> Result<Response<proto::ResumeShardResponse>, Status> {
+        let shard_id = request.into_inner().shard_id;
+        info!(shard_id, "Received call to [resume_shard] RPC");
+
+        let Some(health) = self.shard_health.get(&shard_id) else {
+            return Err(Status::invalid_argument(format!(
+                "unknown shard {}",
+                shard_id
+            )));
+        };
+        if let Some(halt) = health.halted() {
+            health.resume();
+            warn!(
+                shard_id,
+                block_number = halt.block_number,
+                reason = %halt.reason,
+                "shard resumed"
+            );
+        }
+
+        Ok(Response::new(proto::ResumeShardResponse {}))
+    }
+
+    async fn export_shard_snapshot(
+        &self,
+        request: Request<proto::ExportShardSnapshotRequest>,
+    ) -> Result<Response<proto::ExportShardSnapshotResponse>, Status> {
+        let request = request.into_inner();
+        info!(
+            shard_id = request.shard_id,
+            dir = request.dir,
+            "Received call to [export_shard_snapshot] RPC"
+        );
+
+        let Some(db) = self.shard_dbs.get(&request.shard_id).cloned() else {
+            return Err(Status::invalid_argument(format!(
+                "unknown shard {}",
+                request.shard_id
+            )));
+        };
+        if request.dir.is_empty() {
+            return Err(Status::invalid_argument("dir is required"));
+        }
+
+        let manifest = tokio::task::spawn_blocking(move || {
+            snapshot::export_snapshot_to_dir(&db, request.shard_id, path::Path::new(&request.dir))
+        })
+        .await
+        .map_err(|err| Status::internal(err.to_string()))?
+        .map_err(|err| Status::internal(format!("failed to export snapshot: {}", err)))?;
+
+        Ok(Response::new(proto::ExportShardSnapshotResponse {
+            manifest: Some(manifest),
+        }))
+    }
+
+    async fn submit_validator_set_update(
+        &self,
+        request: Request<proto::ValidatorSetUpdate>,
+    ) -> Result<Response<proto::ValidatorSetUpdateResponse>, Status> {
+        let update = request.into_inner();
+        info!(
+            activation_block_number = update.activation_block_number,
+            validators = update.validators.len(),
+            "Received call to [submit_validator_set_update] RPC"
+        );
+
+        self.validator_sets
+            .submit(update)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+
+        Ok(Response::new(proto::ValidatorSetUpdateResponse {}))
+    }
+
+    async fn get_evidence(
+        &self,
+        request: Request<proto::EvidenceRequest>,
+    ) -> Result<Response<proto::EvidenceResponse>, Status> {
+        let shard_id = request.into_inner().shard_id;
+
+        let evidence = self
+            .evidence_store
+            .get_evidence(shard_id)
+            .map_err(|err| Status::internal(format!("failed to read evidence: {}", err)))?;
+
+        Ok(Response::new(proto::EvidenceResponse { evidence }))
+    }
 }
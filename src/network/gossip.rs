@@ -1,16 +1,18 @@
 use crate::consensus::consensus::{ConsensusMsg, SystemMessage};
 use crate::core::types::{
-    proto, Proposal, ShardId, Signature, SnapchainContext, SnapchainShard, SnapchainValidator,
-    SnapchainValidatorContext, Vote,
+    proto, Proposal, ProposalPart, ShardId, Signature, SnapchainContext, SnapchainShard,
+    SnapchainValidator, SnapchainValidatorContext, Vote,
 };
+use crate::storage::store::engine::MempoolMessage;
 use futures::StreamExt;
 use libp2p::identity::ed25519::Keypair;
 use libp2p::swarm::dial_opts::DialOpts;
 use libp2p::{gossipsub, noise, swarm::NetworkBehaviour, swarm::SwarmEvent, tcp, yamux, Swarm};
-use malachite_common::{SignedProposal, SignedVote};
+use malachite_common::{SignedProposal, SignedProposalPart, SignedVote};
 use prost::Message;
 use serde::{Deserialize, Serialize};
 use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
 use std::hash::{Hash, Hasher};
 use std::time::Duration;
 use tokio::io;
@@ -20,6 +22,11 @@ use tracing::{debug, info, warn};
 
 const DEFAULT_GOSSIP_PORT: u16 = 3382;
 const DEFAULT_GOSSIP_HOST: &str = "127.0.0.1";
+const CONSENSUS_TOPIC: &str = "test-net";
+
+fn mempool_topic(shard_id: u32) -> gossipsub::IdentTopic {
+    gossipsub::IdentTopic::new(format!("{}-shard-{}-mempool", CONSENSUS_TOPIC, shard_id))
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -58,8 +65,9 @@ impl Config {
 pub enum GossipEvent<Ctx: SnapchainContext> {
     BroadcastSignedVote(SignedVote<Ctx>),
     BroadcastSignedProposal(SignedProposal<Ctx>),
-    BroadcastFullProposal(proto::FullProposal),
+    BroadcastProposalPart(SignedProposalPart<Ctx>),
     RegisterValidator(proto::RegisterValidator),
+    BroadcastMempoolMessage(u32, MempoolMessage),
 }
 
 #[derive(NetworkBehaviour)]
@@ -72,6 +80,7 @@ pub struct SnapchainGossip {
     pub tx: mpsc::Sender<GossipEvent<SnapchainValidatorContext>>,
     rx: mpsc::Receiver<GossipEvent<SnapchainValidatorContext>>,
     system_tx: Sender<SystemMessage>,
+    mempool_topics: HashMap<gossipsub::TopicHash, u32>,
 }
 
 impl SnapchainGossip {
@@ -79,6 +88,7 @@ impl SnapchainGossip {
         keypair: Keypair,
         config: Config,
         system_tx: Sender<SystemMessage>,
+        shard_ids: Vec<u32>,
     ) -> Result<Self, Box<dyn std::error::Error>> {
         let mut swarm = libp2p::SwarmBuilder::with_existing_identity(keypair.clone().into())
             .with_tokio()
@@ -133,7 +143,7 @@ impl SnapchainGossip {
         }
 
         // Create a Gossipsub topic
-        let topic = gossipsub::IdentTopic::new("test-net");
+        let topic = gossipsub::IdentTopic::new(CONSENSUS_TOPIC);
         // subscribes to our topic
         let result = swarm.behaviour_mut().gossipsub.subscribe(&topic);
         if let Err(e) = result {
@@ -141,6 +151,18 @@ impl SnapchainGossip {
             return Err(Box::new(e));
         }
 
+        // Each shard has its own mempool topic, so we only receive messages for shards we validate
+        let mut mempool_topics = HashMap::new();
+        for shard_id in shard_ids {
+            let topic = mempool_topic(shard_id);
+            let result = swarm.behaviour_mut().gossipsub.subscribe(&topic);
+            if let Err(e) = result {
+                warn!("Failed to subscribe to mempool topic: {:?}", e);
+                return Err(Box::new(e));
+            }
+            mempool_topics.insert(topic.hash(), shard_id);
+        }
+
         // Listen on all assigned port for this id
         swarm.listen_on(config.address.parse()?)?;
 
@@ -150,6 +172,7 @@ impl SnapchainGossip {
             tx,
             rx,
             system_tx,
+            mempool_topics,
         })
     }
 
@@ -189,14 +212,35 @@ impl SnapchainGossip {
                                                 warn!("Failed to send system block message: {:?}", e);
                                             }
                                         },
-                                        Some(proto::gossip_message::GossipMessage::Validator(validator)) => {
+                                        Some(proto::gossip_message::GossipMessage::ProposalPart(signed_part)) => {
+                                            let Some(part) = signed_part.part.filter(|part| part.height.is_some()) else {
+                                                warn!("Received proposal part without a height from peer: {}", peer_id);
+                                                continue;
+                                            };
+                                            let signed_part = SignedProposalPart {
+                                                message: ProposalPart::from_proto(part),
+                                                signature: Signature(signed_part.signature),
+                                            };
+                                            let consensus_message = ConsensusMsg::ReceivedProposalPart(signed_part);
+                                            let res = self.system_tx.send(SystemMessage::Consensus(consensus_message)).await;
+                                            if let Err(e) = res {
+                                                warn!("Failed to send system proposal part message: {:?}", e);
+                                            }
+                                        },
+                                        Some(proto::gossip_message::GossipMessage::Validator(register_validator)) => {
                                             debug!("Received validator registration from peer: {}", peer_id);
-                                            if let Some(validator) = validator.validator {
+                                            let sign_bytes = register_validator.to_sign_bytes();
+                                            if let Some(validator) = register_validator.validator {
                                                 let public_key = libp2p::identity::ed25519::PublicKey::try_from_bytes(&validator.signer);
                                                 if public_key.is_err() {
                                                     warn!("Failed to decode public key from peer: {}", peer_id);
                                                     continue;
                                                 }
+                                                // Anyone can gossip a registration, only the validator's key can sign it
+                                                if !public_key.as_ref().unwrap().verify(&sign_bytes, &register_validator.signature) {
+                                                    warn!("Invalid validator registration signature from peer: {}", peer_id);
+                                                    continue;
+                                                }
                                                 let rpc_address = validator.rpc_address;
                                                 let shard_index = validator.shard_index;
                                                 let validator = SnapchainValidator::new(SnapchainShard::new(shard_index), public_key.unwrap(), Some(rpc_address), validator.current_height);
@@ -237,6 +281,22 @@ impl SnapchainGossip {
                                             }
 
                                         }
+                                        Some(proto::gossip_message::GossipMessage::MempoolMessage(mempool_message)) => {
+                                            let Some(shard_id) = self.mempool_topics.get(&message.topic).cloned() else {
+                                                warn!("Received mempool message on unknown topic {} from peer: {}", message.topic, peer_id);
+                                                continue;
+                                            };
+                                            match mempool_message.mempool_message {
+                                                Some(proto::mempool_message::MempoolMessage::UserMessage(user_message)) => {
+                                                    debug!("Received mempool message {} for shard {} from peer: {}", user_message.hex_hash(), shard_id, peer_id);
+                                                    let res = self.system_tx.send(SystemMessage::Mempool(shard_id, MempoolMessage::UserMessage(user_message))).await;
+                                                    if let Err(e) = res {
+                                                        warn!("Failed to send system mempool message: {:?}", e);
+                                                    }
+                                                },
+                                                None => warn!("Received empty mempool message from peer: {}", peer_id),
+                                            }
+                                        }
                                         _ => warn!("Unhandled message from peer: {}", peer_id),
                                     }
                                 },
@@ -270,9 +330,12 @@ impl SnapchainGossip {
                             let encoded_message = gossip_message.encode_to_vec();
                             self.publish(encoded_message);
                         }
-                        Some(GossipEvent::BroadcastFullProposal(full_proposal)) => {
+                        Some(GossipEvent::BroadcastProposalPart(part)) => {
                             let gossip_message = proto::GossipMessage {
-                                gossip_message: Some(proto::gossip_message::GossipMessage::FullProposal(full_proposal)),
+                                gossip_message: Some(proto::gossip_message::GossipMessage::ProposalPart(proto::SignedProposalPart {
+                                    part: Some(part.to_proto()),
+                                    signature: part.signature.0,
+                                })),
                             };
                             let encoded_message = gossip_message.encode_to_vec();
                             self.publish(encoded_message);
@@ -285,6 +348,20 @@ impl SnapchainGossip {
                             let encoded_message = gossip_message.encode_to_vec();
                             self.publish(encoded_message);
                         },
+                        Some(GossipEvent::BroadcastMempoolMessage(shard_id, message)) => {
+                            let mempool_message = match message {
+                                MempoolMessage::UserMessage(user_message) => proto::mempool_message::MempoolMessage::UserMessage(user_message),
+                                // Validator messages are sourced independently by every validator
+                                MempoolMessage::ValidatorMessage(_) => continue,
+                            };
+                            let gossip_message = proto::GossipMessage {
+                                gossip_message: Some(proto::gossip_message::GossipMessage::MempoolMessage(proto::MempoolMessage {
+                                    mempool_message: Some(mempool_message),
+                                })),
+                            };
+                            let encoded_message = gossip_message.encode_to_vec();
+                            self.publish_to_topic(mempool_topic(shard_id), encoded_message);
+                        },
                         None => {
                             // no-op
                         }
@@ -295,7 +372,11 @@ impl SnapchainGossip {
     }
 
     fn publish(&mut self, message: Vec<u8>) {
-        let topic = gossipsub::IdentTopic::new("test-net");
+        let topic = gossipsub::IdentTopic::new(CONSENSUS_TOPIC);
+        self.publish_to_topic(topic, message);
+    }
+
+    fn publish_to_topic(&mut self, topic: gossipsub::IdentTopic, message: Vec<u8>) {
         if let Err(e) = self.swarm.behaviour_mut().gossipsub.publish(topic, message) {
             warn!("Failed to publish gossip message: {:?}", e);
         }
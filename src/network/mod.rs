@@ -1,6 +1,7 @@
 pub mod admin_server;
 pub mod gossip;
 pub mod server;
+pub mod trie_sync;
 
 #[cfg(test)]
 mod server_tests;
@@ -1,28 +1,46 @@
 use std::collections::HashMap;
 
 use crate::core::error::HubError;
+use crate::core::types::SnapchainValidatorContext;
+use crate::network::gossip::GossipEvent;
+use crate::network::trie_sync;
 use crate::proto;
 use crate::proto::hub_service_server::HubService;
+use crate::proto::shard_snapshot_response::Response as SnapshotResponse;
 use crate::proto::Block;
 use crate::proto::HubEvent;
 use crate::proto::{BlocksRequest, ShardChunksRequest, ShardChunksResponse, SubscribeRequest};
+use crate::proto::{FidRequest, MessagesResponse};
+use crate::proto::{MessageStatus, MessageStatusRequest, WaitForMessageRequest};
+use crate::proto::{ShardChunk, ShardChunkRangeRequest, ShardChunkRangeResponse};
+use crate::proto::{ShardSnapshotRequest, ShardSnapshotResponse};
+use crate::proto::{SyncIds, SyncMessagesResponse, TrieNodePrefix};
+use crate::proto::{TrieNodeMetadataResponse, TrieNodeSnapshotResponse};
 use crate::storage::db::PageOptions;
-use crate::storage::store::engine::{MempoolMessage, Senders, ShardEngine};
+use crate::storage::store::engine::{EngineError, MempoolMessage, Senders, ShardEngine};
+use crate::storage::store::message_status;
+use crate::storage::store::snapshot::{self, SnapshotError};
 use crate::storage::store::stores::{StoreLimits, Stores};
 use crate::storage::store::BlockStore;
 use crate::utils::statsd_wrapper::StatsdClientWrapper;
 use hex::ToHex;
-use tokio::sync::mpsc;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, Semaphore};
 use tokio_stream::wrappers::ReceiverStream;
 use tonic::{Request, Response, Status};
-use tracing::info;
+use tracing::{info, warn};
 
 pub struct MyHubService {
     block_store: BlockStore,
     shard_stores: HashMap<u32, Stores>,
     shard_senders: HashMap<u32, Senders>,
+    // User messages are all submitted to this shard until they're routed by fid
+    message_shard_id: u32,
     message_tx: mpsc::Sender<MempoolMessage>,
     statsd_client: StatsdClientWrapper,
+    gossip_tx: Option<mpsc::Sender<GossipEvent<SnapchainValidatorContext>>>,
+    snapshot_exports: Arc<Semaphore>,
 }
 
 impl MyHubService {
@@ -31,20 +49,81 @@ impl MyHubService {
         shard_stores: HashMap<u32, Stores>,
         shard_senders: HashMap<u32, Senders>,
         statsd_client: StatsdClientWrapper,
+        gossip_tx: Option<mpsc::Sender<GossipEvent<SnapchainValidatorContext>>>,
     ) -> Self {
         // TODO(aditi): This logic will change once a mempool exists
-        let message_tx = shard_senders.get(&1u32).unwrap().messages_tx.clone();
+        let message_shard_id = 1u32;
+        let message_tx = shard_senders
+            .get(&message_shard_id)
+            .unwrap()
+            .messages_tx
+            .clone();
 
         Self {
             block_store,
             shard_senders,
             shard_stores,
+            message_shard_id,
             message_tx,
             statsd_client,
+            gossip_tx,
+            snapshot_exports: Arc::new(Semaphore::new(MAX_CONCURRENT_SNAPSHOT_EXPORTS)),
         }
     }
+
+    // Committed statuses take precedence over the mempool, since a message stays in the mempool
+    // of validators that didn't include it until a chunk with it is committed
+    fn lookup_message_status(&self, hash: &[u8]) -> Result<Option<MessageStatus>, HubError> {
+        for stores in self.shard_stores.values() {
+            if let Some(status) = message_status::get_message_status(&stores.db, hash)? {
+                return Ok(Some(status));
+            }
+        }
+
+        for (shard_id, senders) in &self.shard_senders {
+            if senders.mempool.get(hash).is_some() {
+                return Ok(Some(MessageStatus {
+                    hash: hash.to_vec(),
+                    shard_id: *shard_id,
+                    block_number: 0,
+                    transaction_index: 0,
+                    outcome: proto::MessageOutcome::Pending as i32,
+                    reason: "".to_string(),
+                }));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn get_shard_stores(&self, shard_id: u32) -> Result<&Stores, Status> {
+        self.shard_stores
+            .get(&shard_id)
+            .ok_or_else(|| Status::invalid_argument(format!("unknown shard {}", shard_id)))
+    }
+
+    // TODO: This is a hack to get around the fact that self cannot be made mutable
+    fn readonly_engine(&self, shard_id: u32) -> Result<ShardEngine, Status> {
+        let stores = self.get_shard_stores(shard_id)?;
+        Ok(ShardEngine::new(
+            stores.db.clone(),
+            stores.trie.clone(),
+            shard_id,
+            StoreLimits::default(),
+            self.statsd_client.clone(),
+            100,
+        ))
+    }
 }
 
+// Upper bound on sync ids per GetAllMessagesBySyncIds call, to keep responses reasonably sized
+const MAX_SYNC_IDS_PER_REQUEST: usize = 1024;
+
+const DEFAULT_WAIT_FOR_MESSAGE_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Exporting a snapshot reads the whole shard db, so anyone can only make a node do it so often
+const MAX_CONCURRENT_SNAPSHOT_EXPORTS: usize = 1;
+
 #[tonic::async_trait]
 impl HubService for MyHubService {
     async fn submit_message(
@@ -58,19 +137,15 @@ impl HubService for MyHubService {
 
         let message = request.into_inner();
 
-        let stores = self.shard_stores.get(&1u32).unwrap();
-        // TODO: This is a hack to get around the fact that self cannot be made mutable
-        let mut readonly_engine = ShardEngine::new(
-            stores.db.clone(),
-            stores.trie.clone(),
-            1,
-            StoreLimits::default(),
-            self.statsd_client.clone(),
-            100,
-        );
+        let senders = self.shard_senders.get(&self.message_shard_id).unwrap();
+        let mut readonly_engine = self.readonly_engine(self.message_shard_id)?;
+        // Messages verified here aren't verified again when they're proposed
+        readonly_engine.set_verified_messages(senders.verified_messages.clone());
         let result = readonly_engine.simulate_message(&message);
+        let mempool = &senders.mempool;
 
         if let Err(err) = result {
+            mempool.record_rejection(&message, err.to_string());
             return Err(Status::invalid_argument(format!(
                 "Invalid message: {}",
                 err.to_string()
@@ -84,6 +159,7 @@ impl HubService for MyHubService {
 
         match result {
             Ok(_) => {
+                mempool.insert(&message);
                 self.statsd_client.count("rpc.submit_message.success", 1);
                 info!("successfully submitted message");
             }
@@ -94,6 +170,20 @@ impl HubService for MyHubService {
             }
         }
 
+        // Share the message with the other validators of its shard, so it's included no matter who
+        // proposes next
+        if let Some(gossip_tx) = &self.gossip_tx {
+            let result = gossip_tx
+                .send(GossipEvent::BroadcastMempoolMessage(
+                    self.message_shard_id,
+                    MempoolMessage::UserMessage(message.clone()),
+                ))
+                .await;
+            if let Err(e) = result {
+                warn!("error gossiping message: {:?}", e.to_string());
+            }
+        }
+
         let elapsed = start_time.elapsed().as_millis();
 
         let response = Response::new(message);
@@ -187,6 +277,33 @@ impl HubService for MyHubService {
         }
     }
 
+    async fn get_shard_chunk_range(
+        &self,
+        request: Request<ShardChunkRangeRequest>,
+    ) -> Result<Response<ShardChunkRangeResponse>, Status> {
+        let shard_store = &self
+            .get_shard_stores(request.get_ref().shard_id)?
+            .shard_store;
+        let block_number = |chunk: ShardChunk| {
+            chunk
+                .header
+                .and_then(|header| header.height)
+                .map(|height| height.block_number)
+        };
+        let first_block_number = shard_store
+            .get_first_shard_chunk()
+            .map_err(|err| Status::from_error(Box::new(err)))?
+            .and_then(block_number);
+        let last_block_number = shard_store
+            .get_last_shard_chunk()
+            .map_err(|err| Status::from_error(Box::new(err)))?
+            .and_then(block_number);
+        Ok(Response::new(ShardChunkRangeResponse {
+            first_block_number,
+            last_block_number,
+        }))
+    }
+
     type SubscribeStream = ReceiverStream<Result<HubEvent, Status>>;
 
     async fn subscribe(
@@ -268,4 +385,247 @@ impl HubService for MyHubService {
 
         Ok(Response::new(ReceiverStream::new(client_rx)))
     }
+
+    async fn get_message_status(
+        &self,
+        request: Request<MessageStatusRequest>,
+    ) -> Result<Response<MessageStatus>, Status> {
+        let hash = request.into_inner().hash;
+        info!(
+            hash = hash.encode_hex::<String>(),
+            "Received call to [get_message_status] RPC"
+        );
+
+        match self.lookup_message_status(&hash) {
+            Err(err) => Err(Status::from_error(Box::new(err))),
+            Ok(None) => Err(Status::not_found("message not found")),
+            Ok(Some(status)) => Ok(Response::new(status)),
+        }
+    }
+
+    type WaitForMessageStream = ReceiverStream<Result<MessageStatus, Status>>;
+
+    async fn wait_for_message(
+        &self,
+        request: Request<WaitForMessageRequest>,
+    ) -> Result<Response<Self::WaitForMessageStream>, Status> {
+        let hash = request.get_ref().hash.clone();
+        let timeout = request
+            .get_ref()
+            .timeout_ms
+            .map_or(DEFAULT_WAIT_FOR_MESSAGE_TIMEOUT, Duration::from_millis);
+        info!(
+            hash = hash.encode_hex::<String>(),
+            "Received call to [wait_for_message] RPC"
+        );
+
+        let (server_tx, client_rx) = mpsc::channel::<Result<MessageStatus, Status>>(10);
+
+        // Subscribe before looking up the current status, so a commit in between isn't missed
+        let (found_tx, mut found_rx) = mpsc::channel::<MessageStatus>(1);
+        for senders in self.shard_senders.values() {
+            let mut status_rx = senders.message_status_tx.subscribe();
+            let found_tx = found_tx.clone();
+            let hash = hash.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        _ = found_tx.closed() => break,
+                        status = status_rx.recv() => match status {
+                            Ok(status) => {
+                                if status.hash == hash {
+                                    let _ = found_tx.send(status).await;
+                                    break;
+                                }
+                            }
+                            Err(broadcast::error::RecvError::Lagged(_)) => {}
+                            Err(broadcast::error::RecvError::Closed) => break,
+                        },
+                    }
+                }
+            });
+        }
+        drop(found_tx);
+
+        let current_status = self
+            .lookup_message_status(&hash)
+            .map_err(|err| Status::from_error(Box::new(err)))?;
+
+        if let Some(status) = &current_status {
+            if message_status::is_final_outcome(status.outcome) {
+                found_rx.close();
+                let _ = server_tx.send(Ok(status.clone())).await;
+                return Ok(Response::new(ReceiverStream::new(client_rx)));
+            }
+        }
+
+        tokio::spawn(async move {
+            if let Some(status) = current_status {
+                if server_tx.send(Ok(status)).await.is_err() {
+                    return;
+                }
+            }
+
+            match tokio::time::timeout(timeout, found_rx.recv()).await {
+                Ok(Some(status)) => {
+                    let _ = server_tx.send(Ok(status)).await;
+                }
+                Ok(None) => {
+                    let _ = server_tx
+                        .send(Err(Status::unavailable("message status updates closed")))
+                        .await;
+                }
+                Err(_) => {
+                    let _ = server_tx
+                        .send(Err(Status::deadline_exceeded(
+                            "message was not included before the timeout",
+                        )))
+                        .await;
+                }
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(client_rx)))
+    }
+
+    async fn get_casts_by_fid(
+        &self,
+        request: Request<FidRequest>,
+    ) -> Result<Response<MessagesResponse>, Status> {
+        let request = request.into_inner();
+        info!(
+            fid = request.fid,
+            at_block_number = request.at_block_number,
+            "Received call to [get_casts_by_fid] RPC"
+        );
+        let engine = self.readonly_engine(request.shard_id)?;
+
+        match engine.get_casts_by_fid_at(request.fid as u32, request.at_block_number) {
+            Ok(messages) => Ok(Response::new(MessagesResponse { messages })),
+            Err(EngineError::StateNotAvailable(block_number)) => Err(Status::not_found(format!(
+                "state at block {} is not available",
+                block_number
+            ))),
+            Err(err) => Err(Status::internal(err.to_string())),
+        }
+    }
+
+    async fn get_sync_snapshot_by_prefix(
+        &self,
+        request: Request<TrieNodePrefix>,
+    ) -> Result<Response<TrieNodeSnapshotResponse>, Status> {
+        let request = request.into_inner();
+        let stores = self.get_shard_stores(request.shard_id)?;
+
+        let snapshot = stores
+            .get_sync_snapshot(&request.prefix)
+            .map_err(|err| Status::internal(err.to_string()))?;
+        let root_hash = stores
+            .get_sync_metadata(&[])
+            .map_err(|err| Status::internal(err.to_string()))?
+            .map_or("".to_string(), |metadata| metadata.hash);
+
+        Ok(Response::new(TrieNodeSnapshotResponse {
+            prefix: snapshot.prefix,
+            excluded_hashes: snapshot.excluded_hashes,
+            num_messages: snapshot.num_messages as u64,
+            root_hash,
+        }))
+    }
+
+    async fn get_sync_metadata_by_prefix(
+        &self,
+        request: Request<TrieNodePrefix>,
+    ) -> Result<Response<TrieNodeMetadataResponse>, Status> {
+        let request = request.into_inner();
+        let stores = self.get_shard_stores(request.shard_id)?;
+
+        match stores.get_sync_metadata(&request.prefix) {
+            Err(err) => Err(Status::internal(err.to_string())),
+            Ok(None) => Err(Status::not_found("no trie node for prefix")),
+            Ok(Some(metadata)) => Ok(Response::new(trie_sync::metadata_to_proto(&metadata))),
+        }
+    }
+
+    async fn get_all_sync_ids_by_prefix(
+        &self,
+        request: Request<TrieNodePrefix>,
+    ) -> Result<Response<SyncIds>, Status> {
+        let request = request.into_inner();
+        let stores = self.get_shard_stores(request.shard_id)?;
+
+        let sync_ids = stores
+            .get_all_sync_ids(&request.prefix)
+            .map_err(|err| Status::internal(err.to_string()))?;
+
+        Ok(Response::new(SyncIds {
+            shard_id: request.shard_id,
+            sync_ids,
+        }))
+    }
+
+    async fn get_all_messages_by_sync_ids(
+        &self,
+        request: Request<SyncIds>,
+    ) -> Result<Response<SyncMessagesResponse>, Status> {
+        let request = request.into_inner();
+        let stores = self.get_shard_stores(request.shard_id)?;
+
+        if request.sync_ids.len() > MAX_SYNC_IDS_PER_REQUEST {
+            return Err(Status::invalid_argument(format!(
+                "too many sync ids, max is {}",
+                MAX_SYNC_IDS_PER_REQUEST
+            )));
+        }
+
+        let mut messages = vec![];
+        for sync_id in &request.sync_ids {
+            let found = stores
+                .get_by_sync_id(sync_id)
+                .map_err(|err| Status::invalid_argument(err.to_string()))?;
+            messages.extend(found);
+        }
+
+        Ok(Response::new(trie_sync::messages_to_proto(messages)))
+    }
+
+    type GetShardSnapshotStream = ReceiverStream<Result<ShardSnapshotResponse, Status>>;
+
+    async fn get_shard_snapshot(
+        &self,
+        request: Request<ShardSnapshotRequest>,
+    ) -> Result<Response<Self::GetShardSnapshotStream>, Status> {
+        let shard_id = request.get_ref().shard_id;
+        info!({ shard_id }, "Received call to [get_shard_snapshot] RPC");
+
+        let db = self.get_shard_stores(shard_id)?.db.clone();
+        let permit = self
+            .snapshot_exports
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| Status::resource_exhausted("another snapshot is being exported"))?;
+        let (server_tx, client_rx) = mpsc::channel::<Result<ShardSnapshotResponse, Status>>(16);
+
+        // Iterating over the whole db blocks, so it runs off the async runtime
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            let result = snapshot::export_snapshot(&db, shard_id, |batch| {
+                server_tx
+                    .blocking_send(Ok(ShardSnapshotResponse {
+                        response: Some(SnapshotResponse::Batch(batch)),
+                    }))
+                    .map_err(|_| SnapshotError::Cancelled)
+            });
+            let response = match result {
+                Ok(manifest) => Ok(ShardSnapshotResponse {
+                    response: Some(SnapshotResponse::Manifest(manifest)),
+                }),
+                Err(SnapshotError::Cancelled) => return,
+                Err(err) => Err(Status::internal(err.to_string())),
+            };
+            _ = server_tx.blocking_send(response);
+        });
+
+        Ok(Response::new(ReceiverStream::new(client_rx)))
+    }
 }
@@ -6,7 +6,8 @@ mod tests {
 
     use crate::network::server::MyHubService;
     use crate::proto::hub_service_server::HubService;
-    use crate::proto::SubscribeRequest;
+    use crate::proto::{FidRequest, SubscribeRequest};
+    use crate::proto::{Height, ShardChunk, ShardChunkRangeRequest, ShardHeader};
     use crate::proto::{HubEvent, HubEventType};
     use crate::storage::db::{self, RocksDB, RocksDbTransactionBatch};
     use crate::storage::store::engine::Senders;
@@ -118,6 +119,7 @@ mod tests {
                 stores,
                 senders,
                 statsd_client,
+                None,
             ),
         )
     }
@@ -172,17 +174,95 @@ mod tests {
 
     #[tokio::test]
     async fn test_submit_message_fails_with_error_for_invalid_messages() {
-        let (_stores, _senders, service) = make_server();
+        let (_stores, senders, service) = make_server();
 
         // Message with no fid registration
         let invalid_message = messages_factory::casts::create_cast_add(123, "test", None, None);
 
         let response = service
-            .submit_message(Request::new(invalid_message))
+            .submit_message(Request::new(invalid_message.clone()))
             .await
             .unwrap_err();
 
         assert_eq!(response.code(), tonic::Code::InvalidArgument);
         assert_eq!(response.message(), "Invalid message: missing fid");
+
+        let mempool = &senders.get(&1u32).unwrap().mempool;
+        assert_eq!(mempool.size(), 0);
+        let rejections = mempool.recent_rejections();
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].hash, invalid_message.hash);
+        assert_eq!(rejections[0].reason, "missing fid");
+    }
+
+    #[tokio::test]
+    async fn test_get_shard_chunk_range() {
+        let (stores, _senders, service) = make_server();
+
+        let range = service
+            .get_shard_chunk_range(Request::new(ShardChunkRangeRequest { shard_id: 1 }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(range.first_block_number, None);
+        assert_eq!(range.last_block_number, None);
+
+        for block_number in [3, 5] {
+            let chunk = ShardChunk {
+                header: Some(ShardHeader {
+                    height: Some(Height {
+                        shard_index: 1,
+                        block_number,
+                    }),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
+            stores[&1].shard_store.put_shard_chunk(&chunk).unwrap();
+        }
+
+        let range = service
+            .get_shard_chunk_range(Request::new(ShardChunkRangeRequest { shard_id: 1 }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(range.first_block_number, Some(3));
+        assert_eq!(range.last_block_number, Some(5));
+    }
+
+    #[tokio::test]
+    async fn test_get_casts_by_fid() {
+        let (_stores, _senders, service) = make_server();
+
+        let response = service
+            .get_casts_by_fid(Request::new(FidRequest {
+                shard_id: 1,
+                fid: 123,
+                at_block_number: None,
+            }))
+            .await
+            .unwrap();
+        assert!(response.into_inner().messages.is_empty());
+
+        // Nothing was committed yet, so there's no state to read at any height
+        let response = service
+            .get_casts_by_fid(Request::new(FidRequest {
+                shard_id: 1,
+                fid: 123,
+                at_block_number: Some(1),
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(response.code(), tonic::Code::NotFound);
+
+        let response = service
+            .get_casts_by_fid(Request::new(FidRequest {
+                shard_id: 3,
+                fid: 123,
+                at_block_number: None,
+            }))
+            .await
+            .unwrap_err();
+        assert_eq!(response.code(), tonic::Code::InvalidArgument);
     }
 }
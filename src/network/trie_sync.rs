@@ -0,0 +1,425 @@
+use crate::proto::hub_service_client::HubServiceClient;
+use crate::proto::{self, SyncIds, TrieNodeMetadataResponse, TrieNodePrefix};
+use crate::storage::store::engine::{EngineError, MempoolMessage, ShardEngine};
+use crate::storage::store::stores::{Stores, StoresError};
+use crate::storage::trie::errors::TrieError;
+use crate::storage::trie::merkle_trie::NodeMetadata;
+use std::collections::{HashSet, VecDeque};
+use std::future::Future;
+use thiserror::Error;
+use tonic::transport::Channel;
+use tonic::Request;
+use tracing::{info, warn};
+
+// Below this many items we fetch all sync ids for a prefix instead of walking its children
+const MAX_SYNC_IDS_PER_PREFIX: u64 = 1024;
+
+const MAX_MESSAGES_PER_REQUEST: usize = 256;
+
+#[derive(Error, Debug)]
+pub enum TrieSyncError {
+    #[error(transparent)]
+    RpcError(#[from] tonic::Status),
+
+    #[error(transparent)]
+    TransportError(#[from] tonic::transport::Error),
+
+    #[error(transparent)]
+    TrieError(#[from] TrieError),
+
+    #[error(transparent)]
+    StoresError(#[from] StoresError),
+
+    #[error(transparent)]
+    EngineError(#[from] EngineError),
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SyncStats {
+    pub prefixes_compared: u64,
+    pub sync_ids_missing: u64,
+    pub messages_fetched: u64,
+    pub messages_merged: u64,
+}
+
+pub fn metadata_to_proto(metadata: &NodeMetadata) -> TrieNodeMetadataResponse {
+    let mut children: Vec<TrieNodeMetadataResponse> =
+        metadata.children.values().map(metadata_to_proto).collect();
+    children.sort_by(|a, b| a.prefix.cmp(&b.prefix));
+
+    TrieNodeMetadataResponse {
+        prefix: metadata.prefix.clone(),
+        num_messages: metadata.num_messages as u64,
+        hash: metadata.hash.clone(),
+        children,
+    }
+}
+
+pub fn messages_to_proto(messages: Vec<MempoolMessage>) -> proto::SyncMessagesResponse {
+    let mut response = proto::SyncMessagesResponse {
+        messages: vec![],
+        validator_messages: vec![],
+    };
+    for message in messages {
+        match message {
+            MempoolMessage::UserMessage(msg) => response.messages.push(msg),
+            MempoolMessage::ValidatorMessage(msg) => response.validator_messages.push(msg),
+        }
+    }
+    response
+}
+
+// Where the sync engine reads the trie it's converging towards. Implemented by the rpc client
+// for syncing against a peer, and by the stores so two local engines can be synced directly.
+pub trait SyncSource {
+    fn get_sync_metadata(
+        &mut self,
+        shard_id: u32,
+        prefix: &[u8],
+    ) -> impl Future<Output = Result<Option<TrieNodeMetadataResponse>, TrieSyncError>> + Send;
+
+    fn get_all_sync_ids(
+        &mut self,
+        shard_id: u32,
+        prefix: &[u8],
+    ) -> impl Future<Output = Result<Vec<Vec<u8>>, TrieSyncError>> + Send;
+
+    fn get_messages_by_sync_ids(
+        &mut self,
+        shard_id: u32,
+        sync_ids: Vec<Vec<u8>>,
+    ) -> impl Future<Output = Result<Vec<MempoolMessage>, TrieSyncError>> + Send;
+}
+
+impl SyncSource for HubServiceClient<Channel> {
+    async fn get_sync_metadata(
+        &mut self,
+        shard_id: u32,
+        prefix: &[u8],
+    ) -> Result<Option<TrieNodeMetadataResponse>, TrieSyncError> {
+        let request = Request::new(TrieNodePrefix {
+            shard_id,
+            prefix: prefix.to_vec(),
+        });
+        match self.get_sync_metadata_by_prefix(request).await {
+            Ok(response) => Ok(Some(response.into_inner())),
+            Err(status) if status.code() == tonic::Code::NotFound => Ok(None),
+            Err(status) => Err(status.into()),
+        }
+    }
+
+    async fn get_all_sync_ids(
+        &mut self,
+        shard_id: u32,
+        prefix: &[u8],
+    ) -> Result<Vec<Vec<u8>>, TrieSyncError> {
+        let request = Request::new(TrieNodePrefix {
+            shard_id,
+            prefix: prefix.to_vec(),
+        });
+        let response = self.get_all_sync_ids_by_prefix(request).await?;
+        Ok(response.into_inner().sync_ids)
+    }
+
+    async fn get_messages_by_sync_ids(
+        &mut self,
+        shard_id: u32,
+        sync_ids: Vec<Vec<u8>>,
+    ) -> Result<Vec<MempoolMessage>, TrieSyncError> {
+        let request = Request::new(SyncIds { shard_id, sync_ids });
+        let response = self
+            .get_all_messages_by_sync_ids(request)
+            .await?
+            .into_inner();
+
+        let mut messages: Vec<MempoolMessage> = response
+            .messages
+            .into_iter()
+            .map(MempoolMessage::UserMessage)
+            .collect();
+        messages.extend(
+            response
+                .validator_messages
+                .into_iter()
+                .map(MempoolMessage::ValidatorMessage),
+        );
+        Ok(messages)
+    }
+}
+
+impl SyncSource for Stores {
+    async fn get_sync_metadata(
+        &mut self,
+        _shard_id: u32,
+        prefix: &[u8],
+    ) -> Result<Option<TrieNodeMetadataResponse>, TrieSyncError> {
+        Ok(Stores::get_sync_metadata(self, prefix)?.map(|metadata| metadata_to_proto(&metadata)))
+    }
+
+    async fn get_all_sync_ids(
+        &mut self,
+        _shard_id: u32,
+        prefix: &[u8],
+    ) -> Result<Vec<Vec<u8>>, TrieSyncError> {
+        Ok(Stores::get_all_sync_ids(self, prefix)?)
+    }
+
+    async fn get_messages_by_sync_ids(
+        &mut self,
+        _shard_id: u32,
+        sync_ids: Vec<Vec<u8>>,
+    ) -> Result<Vec<MempoolMessage>, TrieSyncError> {
+        let mut messages = vec![];
+        for sync_id in &sync_ids {
+            messages.extend(self.get_by_sync_id(sync_id)?);
+        }
+        Ok(messages)
+    }
+}
+
+/// Brings a shard's trie in line with another node's by walking the prefixes where the two
+/// tries diverge and merging whatever is missing locally. Items that only exist locally are left
+/// alone, so this converges towards the union of both tries.
+///
+/// The merged messages aren't part of any chunk, so the engine must be a read only replica (see
+/// `ShardEngine::set_read_only`), which nodes run when `trie_sync_source` is configured (see
+/// `ReadOnlyReplica`).
+pub struct TrieSyncEngine {
+    shard_id: u32,
+}
+
+impl TrieSyncEngine {
+    pub fn new(shard_id: u32) -> Self {
+        TrieSyncEngine { shard_id }
+    }
+
+    pub async fn sync_with_peer(
+        &self,
+        engine: &mut ShardEngine,
+        rpc_address: &str,
+    ) -> Result<SyncStats, TrieSyncError> {
+        let destination_addr = format!("http://{}", rpc_address);
+        let mut client = HubServiceClient::connect(destination_addr).await?;
+        self.sync(engine, &mut client).await
+    }
+
+    pub async fn sync(
+        &self,
+        engine: &mut ShardEngine,
+        source: &mut impl SyncSource,
+    ) -> Result<SyncStats, TrieSyncError> {
+        if !engine.is_read_only() {
+            return Err(EngineError::NotReadOnly.into());
+        }
+        let mut stats = SyncStats::default();
+        let local = engine.get_stores();
+
+        let mut prefixes = VecDeque::from([vec![]]);
+        while let Some(prefix) = prefixes.pop_front() {
+            stats.prefixes_compared += 1;
+
+            let Some(remote_node) = source.get_sync_metadata(self.shard_id, &prefix).await? else {
+                continue;
+            };
+            let local_node = local
+                .get_sync_metadata(&prefix)?
+                .map(|m| metadata_to_proto(&m));
+
+            if let Some(local_node) = &local_node {
+                if local_node.hash == remote_node.hash {
+                    continue;
+                }
+            }
+
+            if remote_node.num_messages <= MAX_SYNC_IDS_PER_PREFIX
+                || remote_node.children.is_empty()
+            {
+                self.sync_prefix(engine, source, &local, &prefix, &mut stats)
+                    .await?;
+                continue;
+            }
+
+            for remote_child in remote_node.children {
+                let local_hash = local_node.as_ref().and_then(|local_node| {
+                    local_node
+                        .children
+                        .iter()
+                        .find(|child| child.prefix == remote_child.prefix)
+                        .map(|child| child.hash.clone())
+                });
+                if local_hash.as_ref() != Some(&remote_child.hash) {
+                    prefixes.push_back(remote_child.prefix);
+                }
+            }
+        }
+
+        info!(
+            shard_id = self.shard_id,
+            prefixes_compared = stats.prefixes_compared,
+            sync_ids_missing = stats.sync_ids_missing,
+            messages_fetched = stats.messages_fetched,
+            messages_merged = stats.messages_merged,
+            "Finished trie sync"
+        );
+        Ok(stats)
+    }
+
+    async fn sync_prefix(
+        &self,
+        engine: &mut ShardEngine,
+        source: &mut impl SyncSource,
+        local: &Stores,
+        prefix: &[u8],
+        stats: &mut SyncStats,
+    ) -> Result<(), TrieSyncError> {
+        let local_ids: HashSet<Vec<u8>> = local.get_all_sync_ids(prefix)?.into_iter().collect();
+        let missing_ids: Vec<Vec<u8>> = source
+            .get_all_sync_ids(self.shard_id, prefix)
+            .await?
+            .into_iter()
+            .filter(|sync_id| !local_ids.contains(sync_id))
+            .collect();
+        stats.sync_ids_missing += missing_ids.len() as u64;
+
+        for sync_ids in missing_ids.chunks(MAX_MESSAGES_PER_REQUEST) {
+            let messages = source
+                .get_messages_by_sync_ids(self.shard_id, sync_ids.to_vec())
+                .await?;
+            if messages.len() < sync_ids.len() {
+                warn!(
+                    shard_id = self.shard_id,
+                    prefix = hex::encode(prefix),
+                    requested = sync_ids.len(),
+                    received = messages.len(),
+                    "Peer did not return all requested sync ids"
+                );
+            }
+            stats.messages_fetched += messages.len() as u64;
+            stats.messages_merged += engine.merge_synced_messages(messages)? as u64;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::store::test_helper::{self, register_user, FID_FOR_TEST};
+    use crate::storage::trie::merkle_trie::TrieKey;
+    use crate::utils::factory::messages_factory;
+
+    fn commit_messages(engine: &mut ShardEngine, messages: Vec<proto::Message>) {
+        let state_change = engine.propose_state_change(
+            1,
+            messages
+                .into_iter()
+                .map(MempoolMessage::UserMessage)
+                .collect(),
+        );
+        test_helper::validate_and_commit_state_change(engine, &state_change);
+    }
+
+    #[tokio::test]
+    async fn test_sync_converges_with_peer() {
+        let (mut peer, _peer_dir) = test_helper::new_engine();
+        let (mut engine, _engine_dir) = test_helper::new_engine();
+        engine.set_read_only(true);
+        let signer = test_helper::default_signer();
+
+        register_user(FID_FOR_TEST, signer.clone(), &mut peer).await;
+        let messages: Vec<proto::Message> = (0..3)
+            .map(|i| {
+                messages_factory::casts::create_cast_add(
+                    FID_FOR_TEST,
+                    &format!("msg{}", i),
+                    Some(i),
+                    Some(&signer),
+                )
+            })
+            .collect();
+        commit_messages(&mut peer, messages.clone());
+        assert_ne!(engine.trie_root_hash(), peer.trie_root_hash());
+
+        let sync_engine = TrieSyncEngine::new(1);
+        let stats = sync_engine
+            .sync(&mut engine, &mut peer.get_stores())
+            .await
+            .unwrap();
+
+        assert_eq!(stats.messages_merged, 3);
+        assert_eq!(engine.trie_root_hash(), peer.trie_root_hash());
+        let casts = engine.get_casts_by_fid(FID_FOR_TEST).unwrap();
+        assert_eq!(casts.messages_bytes.len(), 3);
+
+        // Once in sync, only the root is compared
+        let stats = sync_engine
+            .sync(&mut engine, &mut peer.get_stores())
+            .await
+            .unwrap();
+        assert_eq!(stats.prefixes_compared, 1);
+        assert_eq!(stats.messages_fetched, 0);
+    }
+
+    #[tokio::test]
+    async fn test_sync_requires_read_only_engine() {
+        let (peer, _peer_dir) = test_helper::new_engine();
+        let (mut engine, _engine_dir) = test_helper::new_engine();
+
+        let result = TrieSyncEngine::new(1)
+            .sync(&mut engine, &mut peer.get_stores())
+            .await;
+        assert!(matches!(
+            result,
+            Err(TrieSyncError::EngineError(EngineError::NotReadOnly))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sync_only_fetches_missing_messages() {
+        let (mut peer, _peer_dir) = test_helper::new_engine();
+        let (mut engine, _engine_dir) = test_helper::new_engine();
+        engine.set_read_only(true);
+        let signer = test_helper::default_signer();
+        let sync_engine = TrieSyncEngine::new(1);
+
+        register_user(FID_FOR_TEST, signer.clone(), &mut peer).await;
+        let msg1 =
+            messages_factory::casts::create_cast_add(FID_FOR_TEST, "msg1", Some(1), Some(&signer));
+        commit_messages(&mut peer, vec![msg1]);
+        sync_engine
+            .sync(&mut engine, &mut peer.get_stores())
+            .await
+            .unwrap();
+
+        let msg2 =
+            messages_factory::casts::create_cast_add(FID_FOR_TEST, "msg2", Some(2), Some(&signer));
+        commit_messages(&mut peer, vec![msg2]);
+
+        let stats = sync_engine
+            .sync(&mut engine, &mut peer.get_stores())
+            .await
+            .unwrap();
+
+        assert_eq!(stats.sync_ids_missing, 1);
+        assert_eq!(stats.messages_merged, 1);
+        assert_eq!(engine.trie_root_hash(), peer.trie_root_hash());
+    }
+
+    #[tokio::test]
+    async fn test_fnames_as_long_as_a_hash_are_found_by_sync_id() {
+        let (mut peer, _peer_dir) = test_helper::new_engine();
+        let fname = "abcdefghijklmnop.eth".to_string();
+        test_helper::register_fname(FID_FOR_TEST, &fname, None, &mut peer).await;
+
+        let sync_id = TrieKey::for_fname(FID_FOR_TEST, &fname);
+        let messages = peer.get_stores().get_by_sync_id(&sync_id).unwrap();
+        assert!(matches!(
+            &messages[..],
+            [MempoolMessage::ValidatorMessage(proto::ValidatorMessage {
+                fname_transfer: Some(_),
+                ..
+            })]
+        ));
+    }
+}
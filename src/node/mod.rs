@@ -1 +1,2 @@
+pub mod read_only_replica;
 pub mod snapchain_node;
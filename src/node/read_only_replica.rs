@@ -0,0 +1,76 @@
+use crate::network::trie_sync::TrieSyncEngine;
+use crate::node::snapchain_node::shard_db_path;
+use crate::storage::db::RocksDB;
+use crate::storage::store::engine::{Senders, ShardEngine};
+use crate::storage::store::stores::{StoreLimits, Stores};
+use crate::storage::trie::merkle_trie;
+use crate::storage::trie::node_cache::TrieNodeCache;
+use crate::utils::statsd_wrapper::StatsdClientWrapper;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::warn;
+
+// How often each shard's trie is compared with the source's
+const TRIE_SYNC_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Runs the node's shards as read only replicas. They don't take part in consensus, instead they
+/// catch up by merging whatever the source node's tries have and theirs don't (see
+/// `TrieSyncEngine`). Their state isn't tied to any chunk, they only serve reads.
+pub struct ReadOnlyReplica {
+    pub shard_stores: HashMap<u32, Stores>,
+    pub shard_senders: HashMap<u32, Senders>,
+}
+
+impl ReadOnlyReplica {
+    /// Opens the shards and starts syncing them from the rpc address of the source node
+    pub fn create(
+        shard_ids: Vec<u32>,
+        sync_source: String,
+        rocksdb_dir: &str,
+        statsd_client: StatsdClientWrapper,
+        trie_branching_factor: u32,
+        trie_node_cache_bytes: usize,
+        max_messages_per_block: u32,
+    ) -> Self {
+        let mut shard_stores = HashMap::new();
+        let mut shard_senders = HashMap::new();
+
+        for shard_id in shard_ids {
+            let db = RocksDB::new(&shard_db_path(rocksdb_dir, shard_id));
+            db.open().unwrap();
+            let trie = merkle_trie::MerkleTrie::new(trie_branching_factor)
+                .unwrap() //TODO: don't unwrap()
+                .with_node_cache(TrieNodeCache::new(trie_node_cache_bytes));
+            let mut engine = ShardEngine::new(
+                Arc::new(db),
+                trie,
+                shard_id,
+                StoreLimits::default(),
+                statsd_client.clone(),
+                max_messages_per_block,
+            );
+            engine.set_read_only(true);
+
+            shard_stores.insert(shard_id, engine.get_stores());
+            shard_senders.insert(shard_id, engine.get_senders());
+
+            let sync_source = sync_source.clone();
+            tokio::spawn(async move {
+                let trie_sync = TrieSyncEngine::new(shard_id);
+                let mut interval = tokio::time::interval(TRIE_SYNC_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    if let Err(err) = trie_sync.sync_with_peer(&mut engine, &sync_source).await {
+                        warn!(shard_id, sync_source, "Unable to sync trie: {}", err);
+                    }
+                }
+            });
+        }
+
+        ReadOnlyReplica {
+            shard_stores,
+            shard_senders,
+        }
+    }
+}
@@ -1,48 +1,75 @@
 use crate::consensus::consensus::{Config, Consensus, ConsensusMsg, ConsensusParams};
+use crate::consensus::equivocation::EquivocationDetector;
+use crate::consensus::genesis::Genesis;
 use crate::consensus::proposer::{BlockProposer, ShardProposer};
 use crate::consensus::validator::ShardValidator;
-use crate::core::types::{
-    Address, Height, ShardId, SnapchainShard, SnapchainValidator, SnapchainValidatorContext,
-    SnapchainValidatorSet,
-};
+use crate::consensus::validator_set::ValidatorSets;
+use crate::consensus::wal::ConsensusWal;
+use crate::core::types::{Address, Height, ShardId, SnapchainShard, SnapchainValidatorContext};
+use crate::mempool::mempool;
 use crate::network::gossip::GossipEvent;
 use crate::proto::{Block, ShardChunk};
 use crate::storage::db::RocksDB;
-use crate::storage::store::engine::{BlockEngine, Senders, ShardEngine};
+use crate::storage::store::engine::{BlockEngine, MempoolMessage, Senders, ShardEngine};
+use crate::storage::store::evidence::EvidenceStore;
 use crate::storage::store::stores::StoreLimits;
 use crate::storage::store::stores::Stores;
 use crate::storage::store::BlockStore;
 use crate::storage::trie::merkle_trie;
+use crate::storage::trie::node_cache::TrieNodeCache;
 use crate::utils::statsd_wrapper::StatsdClientWrapper;
 use libp2p::identity::ed25519::Keypair;
-use malachite_config::TimeoutConfig;
 use malachite_metrics::Metrics;
 use ractor::ActorRef;
 use std::collections::{BTreeMap, HashMap};
+use std::path::PathBuf;
 use std::sync::Arc;
 use tokio::sync::mpsc;
-use tracing::warn;
+use tracing::{debug, error, warn};
 
 const MAX_SHARDS: u32 = 3;
 
+pub fn shard_db_path(rocksdb_dir: &str, shard_id: u32) -> String {
+    format!("{}/shard{}", rocksdb_dir, shard_id)
+}
+
+pub fn consensus_wal_path(rocksdb_dir: &str, shard_id: u32) -> PathBuf {
+    PathBuf::from(format!("{}/wal/shard{}.wal", rocksdb_dir, shard_id))
+}
+
+fn open_consensus_wal(rocksdb_dir: &str, shard_id: u32) -> ConsensusWal {
+    match ConsensusWal::open(&consensus_wal_path(rocksdb_dir, shard_id)) {
+        Ok(wal) => wal,
+        Err(err) => panic!(
+            "Unable to open consensus wal of shard {}: {}",
+            shard_id, err
+        ),
+    }
+}
+
 pub struct SnapchainNode {
     pub consensus_actors: BTreeMap<u32, ActorRef<ConsensusMsg<SnapchainValidatorContext>>>,
     pub shard_stores: HashMap<u32, Stores>,
     pub shard_senders: HashMap<u32, Senders>,
+    pub validator_sets: ValidatorSets,
+    pub evidence_store: EvidenceStore,
     pub address: Address,
+    statsd_client: StatsdClientWrapper,
 }
 
 impl SnapchainNode {
     pub async fn create(
         keypair: Keypair,
         config: Config,
-        rpc_address: Option<String>,
         gossip_tx: mpsc::Sender<GossipEvent<SnapchainValidatorContext>>,
         block_tx: Option<mpsc::Sender<Block>>,
         block_store: BlockStore,
         rocksdb_dir: String,
         statsd_client: StatsdClientWrapper,
         trie_branching_factor: u32,
+        trie_node_cache_bytes: usize,
+        rollbacks: HashMap<u32, u64>,
+        genesis: &Genesis,
     ) -> Self {
         let validator_address = Address(keypair.public().to_bytes());
 
@@ -53,39 +80,43 @@ impl SnapchainNode {
         let mut shard_senders: HashMap<u32, Senders> = HashMap::new();
         let mut shard_stores: HashMap<u32, Stores> = HashMap::new();
 
+        // Evidence is kept with the blocks, it outlives any shard's state
+        let evidence_store = EvidenceStore::new(block_store.db.clone());
+
+        let validator_sets = match ValidatorSets::new(genesis, &block_store) {
+            Ok(validator_sets) => validator_sets,
+            Err(err) => panic!("Unable to load validator set updates: {}", err),
+        };
+
         // Create the shard validators
         for shard_id in config.shard_ids() {
             if shard_id == 0 {
                 panic!("Shard ID 0 is reserved for the block shard, created automaticaly");
             } else if shard_id > MAX_SHARDS {
                 panic!("Shard ID must be between 1 and 3");
+            } else if shard_id > genesis.num_shards {
+                panic!(
+                    "Shard ID {} is not in the genesis, which has {} shards",
+                    shard_id, genesis.num_shards
+                );
             }
 
-            let current_height = match block_store.max_block_number() {
-                Err(_) => 0,
-                Ok(height) => height,
-            };
             let shard = SnapchainShard::new(shard_id);
-            let shard_validator = SnapchainValidator::new(
-                shard.clone(),
-                keypair.public().clone(),
-                rpc_address.clone(),
-                current_height,
-            );
-            let shard_validator_set = SnapchainValidatorSet::new(vec![shard_validator]);
             let shard_consensus_params = ConsensusParams {
                 start_height: Height::new(shard.shard_id(), 1),
-                initial_validator_set: shard_validator_set,
+                initial_validator_set: validator_sets.validator_set(&shard, 1),
                 address: validator_address.clone(),
                 threshold_params: Default::default(),
             };
             let ctx = SnapchainValidatorContext::new(keypair.clone());
 
-            let db = RocksDB::new(format!("{}/shard{}", rocksdb_dir, shard_id).as_str());
+            let db = RocksDB::new(&shard_db_path(&rocksdb_dir, shard_id));
             db.open().unwrap();
 
-            let trie = merkle_trie::MerkleTrie::new(trie_branching_factor).unwrap(); //TODO: don't unwrap()
-            let engine = ShardEngine::new(
+            let trie = merkle_trie::MerkleTrie::new(trie_branching_factor)
+                .unwrap() //TODO: don't unwrap()
+                .with_node_cache(TrieNodeCache::new(trie_node_cache_bytes));
+            let mut engine = ShardEngine::new(
                 Arc::new(db),
                 trie,
                 shard_id,
@@ -93,6 +124,32 @@ impl SnapchainNode {
                 statsd_client.clone(),
                 config.max_messages_per_block,
             );
+            engine.set_parallel_replay(config.parallel_replay);
+            // The schedule is cleared once the node is created, so a rollback that fails halts
+            // the shard rather than being retried on every restart
+            if let Some(block_number) = rollbacks.get(&shard_id) {
+                if let Err(err) = engine.rollback_to(*block_number) {
+                    error!(shard_id, block_number, "Unable to roll back shard: {}", err);
+                    engine.halt(*block_number, &err);
+                }
+            }
+            // Restarting wouldn't fix it, so the shard halts and keeps serving reads until an
+            // operator rolls it back
+            if let Err(err) = engine.check_consistency() {
+                let block_number = engine.get_confirmed_height().block_number;
+                engine.halt(block_number, &err);
+            }
+
+            // Messages pulled into proposals that are never committed would otherwise stay pending
+            // until the next commit
+            let mempool = engine.get_senders().mempool;
+            tokio::spawn(async move {
+                let mut interval = tokio::time::interval(mempool::PRUNE_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    mempool.prune_expired();
+                }
+            });
 
             shard_senders.insert(shard_id, engine.get_senders());
             shard_stores.insert(shard_id, engine.get_stores());
@@ -103,11 +160,13 @@ impl SnapchainNode {
                 engine,
                 shard_decision_tx.clone(),
                 config.propose_value_delay,
+                config.max_propose_delay(),
             );
 
             let shard_validator = ShardValidator::new(
                 validator_address.clone(),
                 shard.clone(),
+                validator_sets.clone(),
                 None,
                 Some(shard_proposer),
             );
@@ -115,10 +174,13 @@ impl SnapchainNode {
                 ctx,
                 shard.clone(),
                 shard_consensus_params,
-                TimeoutConfig::default(),
+                config.timeout_config(shard_id),
                 Metrics::new(),
+                genesis.time_until_start(),
                 gossip_tx.clone(),
                 shard_validator,
+                open_consensus_wal(&rocksdb_dir, shard_id),
+                EquivocationDetector::new(shard_id, evidence_store.clone(), statsd_client.clone()),
             )
             .await
             .unwrap();
@@ -129,39 +191,28 @@ impl SnapchainNode {
         // Now create the block validator
         let block_shard = SnapchainShard::new(0);
 
-        let current_height = match block_store.max_block_number() {
-            Err(_) => 0,
-            Ok(height) => height,
-        };
         // We might want to use different keys for the block shard so signatures are different and cannot be accidentally used in the wrong shard
-        let block_validator = SnapchainValidator::new(
-            block_shard.clone(),
-            keypair.public().clone(),
-            rpc_address.clone(),
-            current_height,
-        );
-        let block_validator_set = SnapchainValidatorSet::new(vec![block_validator]);
-
         let block_consensus_params = ConsensusParams {
             start_height: Height::new(block_shard.shard_id(), 1),
-            initial_validator_set: block_validator_set,
+            initial_validator_set: validator_sets.validator_set(&block_shard, 1),
             address: validator_address.clone(),
             threshold_params: Default::default(),
         };
 
-        let engine = BlockEngine::new(block_store.clone());
+        let engine = BlockEngine::new(block_store.clone(), validator_sets.clone());
 
         let block_proposer = BlockProposer::new(
             validator_address.clone(),
             block_shard.clone(),
             shard_decision_rx,
-            config.num_shards(),
+            genesis.num_shards,
             block_tx,
             engine,
         );
         let block_validator = ShardValidator::new(
             validator_address.clone(),
             block_shard.clone(),
+            validator_sets.clone(),
             Some(block_proposer),
             None,
         );
@@ -170,10 +221,13 @@ impl SnapchainNode {
             ctx,
             block_shard,
             block_consensus_params,
-            TimeoutConfig::default(),
+            config.timeout_config(0),
             Metrics::new(),
+            genesis.time_until_start(),
             gossip_tx.clone(),
             block_validator,
+            open_consensus_wal(&rocksdb_dir, 0),
+            EquivocationDetector::new(0, evidence_store.clone(), statsd_client.clone()),
         )
         .await
         .unwrap();
@@ -184,6 +238,9 @@ impl SnapchainNode {
             address: validator_address,
             shard_senders,
             shard_stores,
+            validator_sets,
+            evidence_store,
+            statsd_client,
         }
     }
 
@@ -207,6 +264,66 @@ impl SnapchainNode {
         }
     }
 
+    // Adds a message gossiped by another validator to the local mempool, if it would merge
+    // cleanly against our current state. Simulating reads the shard's state, so it runs on a
+    // blocking thread rather than in the node's main loop.
+    pub fn add_mempool_message(&self, shard_id: u32, message: MempoolMessage) {
+        let (Some(stores), Some(senders)) = (
+            self.shard_stores.get(&shard_id).cloned(),
+            self.shard_senders.get(&shard_id).cloned(),
+        ) else {
+            warn!(shard_id, "No shard found, dropping mempool message");
+            return;
+        };
+        let statsd_client = self.statsd_client.clone();
+
+        tokio::task::spawn_blocking(move || {
+            if let MempoolMessage::UserMessage(msg) = &message {
+                let mut readonly_engine = ShardEngine::new(
+                    stores.db.clone(),
+                    stores.trie.clone(),
+                    shard_id,
+                    stores.store_limits.clone(),
+                    statsd_client.clone(),
+                    100,
+                );
+                readonly_engine.set_verified_messages(senders.verified_messages.clone());
+                if let Err(err) = readonly_engine.simulate_message(msg) {
+                    debug!(
+                        shard_id,
+                        hash = msg.hex_hash(),
+                        "Dropping invalid gossiped message: {}",
+                        err
+                    );
+                    senders.mempool.record_rejection(msg, err.to_string());
+                    statsd_client.count_with_shard(shard_id, "mempool.gossip.invalid", 1);
+                    return;
+                }
+            }
+
+            let user_message = match &message {
+                MempoolMessage::UserMessage(msg) => Some(msg.clone()),
+                MempoolMessage::ValidatorMessage(_) => None,
+            };
+
+            // Don't block if the mempool is full
+            match senders.messages_tx.try_send(message) {
+                Ok(()) => {
+                    if let Some(msg) = user_message {
+                        senders.mempool.insert(&msg);
+                    }
+                    statsd_client.count_with_shard(shard_id, "mempool.gossip.received", 1);
+                }
+                Err(err) => {
+                    warn!(
+                        shard_id,
+                        "Unable to add gossiped message to mempool: {}", err
+                    );
+                }
+            }
+        });
+    }
+
     pub fn dispatch(&self, msg: ConsensusMsg<SnapchainValidatorContext>) {
         let shard_id = msg.shard_id();
         if let Some(actor) = self.consensus_actors.get(&shard_id) {
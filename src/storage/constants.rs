@@ -39,6 +39,21 @@ pub enum RootPrefix {
 
     /* Used to index user submitted username proofs */
     UserNameProofByName = 16,
+
+    /* Used to index message inclusion status by message hash */
+    MessageStatus = 17,
+
+    /* Used to index state roots and event ids by block number */
+    HeightIndex = 18,
+
+    /* Used to store the previous values of keys written by each shard chunk, for rollbacks */
+    UndoLog = 19,
+
+    /* Used to index validator set updates by activation block number */
+    ValidatorSetUpdate = 20,
+
+    /* Used to store evidence of validators signing conflicting consensus messages */
+    Evidence = 21,
 }
 
 /** Copied from the JS code */
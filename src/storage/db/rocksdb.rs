@@ -342,6 +342,33 @@ impl RocksDB {
         Ok(all_done)
     }
 
+    // Iterates over all keys as of a point-in-time snapshot of the db, so writes that are committed
+    // while iterating aren't seen. The callback function should return true to stop the iteration.
+    pub fn for_each_in_snapshot<F>(&self, mut f: F) -> Result<(), HubError>
+    where
+        F: FnMut(&[u8], &[u8]) -> Result<bool, HubError>,
+    {
+        let db = self.db();
+        let Some(db) = db.as_ref() else {
+            return Err(RocksdbError::DbNotOpen.into());
+        };
+
+        let snapshot = db.snapshot();
+        let mut iter = snapshot.raw_iterator();
+        iter.seek_to_first();
+        while iter.valid() {
+            if let Some((key, value)) = iter.item() {
+                if f(key, value)? {
+                    break;
+                }
+            }
+            iter.next();
+        }
+        iter.status().map_err(|e| RocksdbError::InternalError(e))?;
+
+        Ok(())
+    }
+
     pub fn clear(&self) -> Result<u32, RocksdbError> {
         let mut deleted;
 
@@ -113,7 +113,7 @@ impl StoreEventHandler {
 }
 
 impl HubEvent {
-    fn make_event_key(event_id: u64) -> Vec<u8> {
+    pub(crate) fn make_event_key(event_id: u64) -> Vec<u8> {
         let mut key = Vec::with_capacity(1 + 8);
 
         key.push(RootPrefix::HubEvents as u8); // HubEvents prefix, 1 byte
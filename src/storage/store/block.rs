@@ -4,6 +4,7 @@ use crate::proto::Block;
 use crate::storage::constants::RootPrefix;
 use crate::storage::db::{PageOptions, RocksDB, RocksdbError};
 use prost::Message;
+use std::collections::{BTreeSet, HashMap};
 use std::sync::Arc;
 use thiserror::Error;
 
@@ -13,6 +14,9 @@ pub enum BlockStorageError {
     #[error(transparent)]
     RocksdbError(#[from] RocksdbError),
 
+    #[error(transparent)]
+    HubError(#[from] HubError),
+
     #[error("Block missing header")]
     BlockMissingHeader,
 
@@ -38,6 +42,20 @@ fn make_block_key(block_number: u64) -> Vec<u8> {
     key
 }
 
+fn make_validator_set_update_key(
+    activation_block_number: u64,
+    block_number: u64,
+    index: usize,
+) -> Vec<u8> {
+    let mut key = vec![RootPrefix::ValidatorSetUpdate as u8];
+    // Ordered by activation, then by the order they were committed in
+    key.extend_from_slice(&activation_block_number.to_be_bytes());
+    key.extend_from_slice(&block_number.to_be_bytes());
+    key.extend_from_slice(&(index as u32).to_be_bytes());
+
+    key
+}
+
 fn get_block_page_by_prefix(
     db: &RocksDB,
     page_options: &PageOptions,
@@ -148,11 +166,98 @@ pub fn put_block(db: &RocksDB, block: Block) -> Result<(), BlockStorageError> {
         .as_ref()
         .ok_or(BlockStorageError::BlockMissingHeight)?;
     let primary_key = make_block_key(height.block_number);
+    // Validator set updates are indexed in the same transaction, so they're never lost on restart
+    let updates = block
+        .validator_messages
+        .iter()
+        .filter_map(|msg| msg.validator_set_update.as_ref());
+    for (index, update) in updates.enumerate() {
+        let key = make_validator_set_update_key(
+            update.activation_block_number,
+            height.block_number,
+            index,
+        );
+        txn.put(key, update.encode_to_vec());
+    }
     txn.put(primary_key, block.encode_to_vec());
     db.commit(txn)?;
     Ok(())
 }
 
+pub fn get_block(db: &RocksDB, block_number: u64) -> Result<Option<Block>, BlockStorageError> {
+    match db.get(&make_block_key(block_number))? {
+        None => Ok(None),
+        Some(bytes) => Ok(Some(
+            Block::decode(bytes.as_slice()).map_err(HubError::from)?,
+        )),
+    }
+}
+
+// Every committed block that includes a validator set update, in block order
+pub fn get_validator_set_update_blocks(db: &RocksDB) -> Result<Vec<Block>, BlockStorageError> {
+    let mut block_numbers = BTreeSet::new();
+    db.for_each_iterator_by_prefix(
+        Some(vec![RootPrefix::ValidatorSetUpdate as u8]),
+        Some(vec![RootPrefix::ValidatorSetUpdate as u8 + 1]),
+        &PageOptions::default(),
+        |key, _value| {
+            // The block number follows the prefix and the activation block number
+            let block_number = key[9..17].try_into().map(u64::from_be_bytes);
+            block_numbers.insert(block_number.map_err(|_| {
+                HubError::invalid_internal_state("invalid validator set update key")
+            })?);
+            Ok(false)
+        },
+    )?;
+
+    let mut blocks = vec![];
+    for block_number in block_numbers {
+        if let Some(block) = get_block(db, block_number)? {
+            blocks.push(block);
+        }
+    }
+    Ok(blocks)
+}
+
+const CHUNK_HEIGHTS_SCAN_PAGE_SIZE: usize = 100;
+
+// A block doesn't have to include a chunk of every shard, so this scans back from the last block
+// until every shard's last chunk is found
+pub fn get_last_chunk_heights(
+    db: &RocksDB,
+    num_shards: u32,
+) -> Result<HashMap<u32, u64>, BlockStorageError> {
+    let mut last_chunk_heights = HashMap::new();
+    let mut page_token = None;
+    loop {
+        let page = get_blocks_in_range(
+            db,
+            &PageOptions {
+                page_size: Some(CHUNK_HEIGHTS_SCAN_PAGE_SIZE),
+                page_token,
+                reverse: true,
+            },
+            0,
+            None,
+        )?;
+        for chunk in page
+            .blocks
+            .iter()
+            .flat_map(|block| block.shard_chunks.iter().rev())
+        {
+            if let Some(height) = chunk.header.as_ref().and_then(|header| header.height) {
+                last_chunk_heights
+                    .entry(height.shard_index)
+                    .or_insert(height.block_number);
+            }
+        }
+        if last_chunk_heights.len() >= num_shards as usize || page.next_page_token.is_none() {
+            return Ok(last_chunk_heights);
+        }
+        page_token = page.next_page_token;
+    }
+}
+
 #[derive(Default, Clone)]
 pub struct BlockStore {
     pub db: Arc<RocksDB>,
@@ -179,6 +284,22 @@ impl BlockStore {
         }
     }
 
+    pub fn get_block(&self, block_number: u64) -> Result<Option<Block>, BlockStorageError> {
+        get_block(&self.db, block_number)
+    }
+
+    pub fn get_validator_set_update_blocks(&self) -> Result<Vec<Block>, BlockStorageError> {
+        get_validator_set_update_blocks(&self.db)
+    }
+
+    /// Height of the last chunk of each shard included in a block
+    pub fn get_last_chunk_heights(
+        &self,
+        num_shards: u32,
+    ) -> Result<HashMap<u32, u64>, BlockStorageError> {
+        get_last_chunk_heights(&self.db, num_shards)
+    }
+
     pub fn get_blocks(
         &self,
         start_block_number: u64,
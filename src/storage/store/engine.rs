@@ -1,21 +1,29 @@
 use super::account::{IntoU8, OnchainEventStorageError, UserDataStore};
+use crate::consensus::validator_set::ValidatorSets;
 use crate::core::error::HubError;
+use crate::core::signatures::{self, VerifiedMessageCache};
 use crate::core::types::Height;
+use crate::mempool::mempool::Mempool;
 use crate::proto::HubEvent;
 use crate::proto::Message;
 use crate::proto::UserNameProof;
-use crate::proto::{self, Block, MessageType, ShardChunk, Transaction};
+use crate::proto::{self, Block, MessageReceipt, MessageType, ShardChunk, Transaction};
 use crate::proto::{OnChainEvent, OnChainEventType};
-use crate::storage::db::{PageOptions, RocksDB, RocksDbTransactionBatch};
+use crate::storage::db::{PageOptions, RocksDB, RocksDbTransactionBatch, RocksdbError};
 use crate::storage::store::account::{CastStore, MessagesPage};
+use crate::storage::store::health::ShardHealth;
+use crate::storage::store::height_index;
+use crate::storage::store::message_status;
+use crate::storage::store::shard::ShardStorageError;
 use crate::storage::store::stores::{StoreLimits, Stores};
+use crate::storage::store::undo_log;
 use crate::storage::store::BlockStore;
 use crate::storage::trie;
 use crate::storage::trie::merkle_trie;
 use crate::utils::statsd_wrapper::StatsdClientWrapper;
 use itertools::Itertools;
 use merkle_trie::TrieKey;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::str;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
@@ -52,6 +60,33 @@ pub enum EngineError {
 
     #[error(transparent)]
     EngineMessageValidationError(#[from] MessageValidationError),
+
+    #[error(transparent)]
+    RocksdbError(#[from] RocksdbError),
+
+    #[error("state at block {0} is not available")]
+    StateNotAvailable(u64),
+
+    #[error("trie root does not match the shard chunk at block {0}")]
+    InconsistentState(u64),
+
+    #[error("hub event has no body")]
+    MissingEventBody,
+
+    #[error("shard is halted")]
+    ShardHalted,
+
+    #[error("engine is a read only replica")]
+    ReadOnly,
+
+    #[error("synced messages can only be merged into a read only replica")]
+    NotReadOnly,
+
+    #[error(transparent)]
+    ShardStorageError(#[from] ShardStorageError),
+
+    #[error(transparent)]
+    HubError(#[from] HubError),
 }
 
 #[derive(Error, Debug, Clone)]
@@ -73,12 +108,29 @@ pub enum MessageValidationError {
 
     #[error("fname not registered for fid")]
     MissingFname,
+
+    #[error("invalid message hash or signature")]
+    InvalidSignature,
 }
 
 impl MessageValidationError {
     pub fn new_store_error(hash: Vec<u8>) -> impl FnOnce(HubError) -> Self {
         move |inner: HubError| MessageValidationError::StoreError { inner, hash }
     }
+
+    /// A stable code for the error. Unlike the display message, it's safe to commit as part of a
+    /// receipt, so rewording an error doesn't change the state root.
+    pub fn code(&self) -> String {
+        match self {
+            MessageValidationError::NoMessageData => "no_message_data".to_string(),
+            MessageValidationError::MissingFid => "missing_fid".to_string(),
+            MessageValidationError::MissingSigner => "missing_signer".to_string(),
+            MessageValidationError::InvalidMessageType(_) => "invalid_message_type".to_string(),
+            MessageValidationError::StoreError { inner, .. } => inner.code.clone(),
+            MessageValidationError::MissingFname => "missing_fname".to_string(),
+            MessageValidationError::InvalidSignature => "invalid_signature".to_string(),
+        }
+    }
 }
 
 impl EngineError {
@@ -114,18 +166,89 @@ pub struct ShardStateChange {
 pub struct Senders {
     pub messages_tx: mpsc::Sender<MempoolMessage>,
     pub events_tx: broadcast::Sender<HubEvent>,
+    pub message_status_tx: broadcast::Sender<proto::MessageStatus>,
+    pub mempool: Mempool,
+    pub verified_messages: VerifiedMessageCache,
+    pub health: ShardHealth,
 }
 
 impl Senders {
     pub fn new(messages_tx: mpsc::Sender<MempoolMessage>) -> Senders {
         let (events_tx, _events_rx) = broadcast::channel::<HubEvent>(100);
+        let (message_status_tx, _message_status_rx) =
+            broadcast::channel::<proto::MessageStatus>(1000);
         Senders {
             events_tx,
+            message_status_tx,
             messages_tx,
+            mempool: Mempool::new(),
+            verified_messages: VerifiedMessageCache::default(),
+            health: ShardHealth::new(),
         }
     }
 }
 
+struct ReplayedTransaction {
+    account_root: Vec<u8>,
+    events: Vec<HubEvent>,
+    validation_errors: Vec<MessageValidationError>,
+    // One per user message, committed as part of the transaction
+    receipts: Vec<MessageReceipt>,
+    // Previously merged messages that were pruned or revoked by this transaction
+    removals: Vec<MessageReceipt>,
+}
+
+// State accumulated while replaying a transaction's system messages, which is then completed by
+// merging its user messages
+#[derive(Default)]
+struct TransactionReplay {
+    events: Vec<HubEvent>,
+    removals: Vec<MessageReceipt>,
+    system_messages_count: u64,
+    onchain_events_count: u64,
+    merged_fnames_count: u64,
+    revoked_messages_count: u64,
+    pruned_messages_count: u64,
+}
+
+#[derive(Default)]
+struct MergedUserMessages {
+    events: Vec<HubEvent>,
+    receipts: Vec<MessageReceipt>,
+    validation_errors: Vec<MessageValidationError>,
+    message_types: HashSet<MessageType>,
+}
+
+// The result of replaying a state change on top of the current state. It can be committed without
+// replaying the transactions again, as long as nothing else was committed in the meantime.
+pub struct ReplayedStateChange {
+    parent_version: u64,
+    parent_root: Vec<u8>,
+    shard_root: Vec<u8>,
+    transactions: Vec<Transaction>,
+    txn: RocksDbTransactionBatch,
+    events: Vec<HubEvent>,
+    message_results: Vec<Vec<MessageReceipt>>,
+}
+
+// Where replaying a shard chunk first stops matching what the chunk committed to
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChunkDivergence {
+    Transaction {
+        fid: u64,
+        expected_account_root: Vec<u8>,
+        account_root: Vec<u8>,
+        expected_receipts: Vec<MessageReceipt>,
+        receipts: Vec<MessageReceipt>,
+        // The fid's trie keys after replaying the chunk
+        trie_keys: Vec<Vec<u8>>,
+    },
+    ShardRoot {
+        expected: Vec<u8>,
+        actual: Vec<u8>,
+    },
+}
+
 struct TransactionCounts {
     transactions: u64,
     user_messages: u64,
@@ -140,6 +263,10 @@ pub struct ShardEngine {
     messages_rx: mpsc::Receiver<MempoolMessage>,
     statsd_client: StatsdClientWrapper,
     max_messages_per_block: u32,
+    // Incremented on every commit, used to tell whether a replayed state change is still current
+    state_version: u64,
+    parallel_replay: bool,
+    read_only: bool,
 }
 
 impl ShardEngine {
@@ -161,9 +288,35 @@ impl ShardEngine {
             db,
             statsd_client,
             max_messages_per_block,
+            state_version: 0,
+            parallel_replay: false,
+            read_only: false,
         }
     }
 
+    // When enabled, user messages for different fids in a chunk are merged in parallel. The
+    // results are identical to replaying transactions one by one.
+    pub fn set_parallel_replay(&mut self, parallel_replay: bool) {
+        self.parallel_replay = parallel_replay;
+    }
+
+    // A read only replica never proposes or votes on state changes, so its trie can be diff
+    // synced from another node (see merge_synced_messages) without the synced state ever being
+    // part of a chunk
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    // Share the cache of verified messages with another engine for the same shard, e.g. so a
+    // message verified by a readonly engine on submit isn't verified again on commit
+    pub fn set_verified_messages(&mut self, verified_messages: VerifiedMessageCache) {
+        self.senders.verified_messages = verified_messages;
+    }
+
     pub fn messages_tx(&self) -> mpsc::Sender<MempoolMessage> {
         self.senders.messages_tx.clone()
     }
@@ -197,18 +350,38 @@ impl ShardEngine {
     pub(crate) async fn pull_messages(
         &mut self,
         max_wait: Duration,
+    ) -> Result<Vec<MempoolMessage>, EngineError> {
+        self.wait_for_messages(max_wait, max_wait).await
+    }
+
+    /// Collects messages for at least min_wait, then keeps waiting until there is at least one
+    /// message or max_wait elapsed. Returns early once a block's worth of messages is collected.
+    /// Messages that were already committed or collected are skipped.
+    pub(crate) async fn wait_for_messages(
+        &mut self,
+        min_wait: Duration,
+        max_wait: Duration,
     ) -> Result<Vec<MempoolMessage>, EngineError> {
         let mut messages = Vec::new();
+        let mut hashes = HashSet::new();
+        let mut skipped = 0;
         let start_time = Instant::now();
 
         loop {
-            if start_time.elapsed() >= max_wait {
+            let elapsed = start_time.elapsed();
+            if elapsed >= max_wait || (elapsed >= min_wait && !messages.is_empty()) {
                 break;
             }
 
             while messages.len() < self.max_messages_per_block as usize {
                 match self.messages_rx.try_recv() {
-                    Ok(msg) => messages.push(msg),
+                    Ok(msg) => {
+                        if self.is_stale(&msg, &mut hashes)? {
+                            skipped += 1;
+                        } else {
+                            messages.push(msg);
+                        }
+                    }
                     Err(mpsc::error::TryRecvError::Empty) => break,
                     Err(err) => return Err(EngineError::from(err)),
                 }
@@ -221,23 +394,52 @@ impl ShardEngine {
             sleep(Duration::from_millis(5)).await;
         }
 
+        self.count("wait_for_messages.skipped", skipped);
         Ok(messages)
     }
 
+    // Every validator of the shard queues the user messages gossiped to it, but only the proposer
+    // drains its queue, so the others still hold the messages a chunk committed
+    fn is_stale(
+        &self,
+        message: &MempoolMessage,
+        hashes: &mut HashSet<Vec<u8>>,
+    ) -> Result<bool, EngineError> {
+        let MempoolMessage::UserMessage(msg) = message else {
+            return Ok(false);
+        };
+        if !hashes.insert(msg.hash.clone()) {
+            return Ok(true);
+        }
+        let status = message_status::get_message_status(&self.db, &msg.hash)
+            .map_err(EngineError::new_store_error(msg.hash.clone()))?;
+        Ok(status.is_some_and(|status| message_status::is_final_outcome(status.outcome)))
+    }
+
     fn prepare_proposal(
         &mut self,
         trie_ctx: &merkle_trie::Context,
         txn_batch: &mut RocksDbTransactionBatch,
         shard_id: u32,
         messages: Vec<MempoolMessage>,
-    ) -> Result<ShardStateChange, EngineError> {
+    ) -> Result<(ShardStateChange, Vec<HubEvent>, Vec<Vec<MessageReceipt>>), EngineError> {
         self.count("prepare_proposal.recv_messages", messages.len() as u64);
 
+        let mut events = vec![];
+        let mut message_results = vec![];
         let mut snapchain_txns = self.create_transactions_from_mempool(messages)?;
-        for snapchain_txn in &mut snapchain_txns {
-            let (account_root, _events, _) =
-                self.replay_snapchain_txn(trie_ctx, &snapchain_txn, txn_batch)?;
-            snapchain_txn.account_root = account_root;
+        let replayed_txns = self.replay_transactions(trie_ctx, &snapchain_txns, txn_batch)?;
+        for (snapchain_txn, replayed) in snapchain_txns.iter_mut().zip(replayed_txns) {
+            snapchain_txn.account_root = replayed.account_root;
+            snapchain_txn.receipts = replayed.receipts.clone();
+            events.extend(replayed.events);
+            message_results.push(
+                replayed
+                    .receipts
+                    .into_iter()
+                    .chain(replayed.removals)
+                    .collect(),
+            );
         }
 
         let count = Self::txn_counts(&snapchain_txns);
@@ -253,7 +455,7 @@ impl ShardEngine {
             transactions: snapchain_txns,
         };
 
-        Ok(result)
+        Ok((result, events, message_results))
     }
 
     // Groups messages by fid and creates a transaction for each fid
@@ -271,6 +473,7 @@ impl ShardEngine {
                 account_root: vec![], // Starts empty, will be updated after replay
                 system_messages: vec![],
                 user_messages: vec![],
+                receipts: vec![], // Also computed during replay
             };
             let storage_slot = self
                 .stores
@@ -307,6 +510,17 @@ impl ShardEngine {
         shard: u32,
         messages: Vec<MempoolMessage>,
     ) -> ShardStateChange {
+        self.propose_replayed_state_change(shard, messages).0
+    }
+
+    // Same as propose_state_change, but also returns the replayed result so the proposal can be
+    // committed without replaying it again (see commit_replayed_shard_chunk)
+    pub fn propose_replayed_state_change(
+        &mut self,
+        shard: u32,
+        messages: Vec<MempoolMessage>,
+    ) -> (ShardStateChange, ReplayedStateChange) {
+        let parent_root = self.trie_root_hash();
         let mut txn = RocksDbTransactionBatch::new();
 
         let count_fn = Self::make_count_fn(self.statsd_client.clone(), self.shard_id);
@@ -315,20 +529,53 @@ impl ShardEngine {
             count_fn("trie.db_get_count.for_propose", read_count);
         };
 
-        let result = self
-            .prepare_proposal(
+        let proposal = if self.read_only {
+            Err(EngineError::ReadOnly)
+        } else if self.is_halted() {
+            Err(EngineError::ShardHalted)
+        } else {
+            self.prepare_proposal(
                 &merkle_trie::Context::with_callback(count_callback),
                 &mut txn,
                 shard,
                 messages,
             )
-            .unwrap(); //TODO: don't unwrap()
+        };
 
         // TODO: this should probably operate automatically via drop trait
-        self.stores.trie.reload(&self.db).unwrap();
+        let reload_result = self.stores.trie.reload(&self.db);
+
+        // Propose an empty state change rather than nothing, consensus can still move on without
+        // this shard's state changing
+        let proposal = reload_result.map_err(EngineError::from).and(proposal);
+        let (result, events, message_results) = match proposal {
+            Ok(proposal) => proposal,
+            Err(err) => {
+                if !matches!(err, EngineError::ReadOnly) {
+                    let block_number = self.get_confirmed_height().block_number + 1;
+                    self.halt(block_number, &err);
+                }
+                txn = RocksDbTransactionBatch::new();
+                let empty_state_change = ShardStateChange {
+                    shard_id: shard,
+                    new_state_root: parent_root.clone(),
+                    transactions: vec![],
+                };
+                (empty_state_change, vec![], vec![])
+            }
+        };
 
         self.count("propose.invoked", 1);
-        result
+        let replayed = ReplayedStateChange {
+            parent_version: self.state_version,
+            parent_root,
+            shard_root: result.new_state_root.clone(),
+            transactions: result.transactions.clone(),
+            txn,
+            events,
+            message_results,
+        };
+        (result, replayed)
     }
 
     fn replay_proposal(
@@ -337,22 +584,38 @@ impl ShardEngine {
         txn_batch: &mut RocksDbTransactionBatch,
         transactions: &[Transaction],
         shard_root: &[u8],
-    ) -> Result<Vec<HubEvent>, EngineError> {
+    ) -> Result<(Vec<HubEvent>, Vec<Vec<MessageReceipt>>), EngineError> {
         let mut events = vec![];
-        for snapchain_txn in transactions {
-            let (account_root, txn_events, _) =
-                self.replay_snapchain_txn(trie_ctx, snapchain_txn, txn_batch)?;
+        let mut message_results = vec![];
+        let replayed_txns = self.replay_transactions(trie_ctx, transactions, txn_batch)?;
+        for (snapchain_txn, replayed) in transactions.iter().zip(replayed_txns) {
             // Reject early if account roots fail to match (shard roots will definitely fail)
-            if &account_root != &snapchain_txn.account_root {
+            if &replayed.account_root != &snapchain_txn.account_root {
                 warn!(
                     fid = snapchain_txn.fid,
-                    new_account_root = hex::encode(&account_root),
+                    new_account_root = hex::encode(&replayed.account_root),
                     tx_account_root = hex::encode(&snapchain_txn.account_root),
                     "Account root mismatch"
                 );
                 return Err(EngineError::HashMismatch);
             }
-            events.extend(txn_events);
+            if replayed.receipts != snapchain_txn.receipts {
+                warn!(
+                    fid = snapchain_txn.fid,
+                    num_receipts = replayed.receipts.len(),
+                    tx_num_receipts = snapchain_txn.receipts.len(),
+                    "Receipts mismatch"
+                );
+                return Err(EngineError::HashMismatch);
+            }
+            events.extend(replayed.events);
+            message_results.push(
+                replayed
+                    .receipts
+                    .into_iter()
+                    .chain(replayed.removals)
+                    .collect(),
+            );
         }
 
         let root1 = self.stores.trie.root_hash()?;
@@ -367,7 +630,168 @@ impl ShardEngine {
             return Err(EngineError::HashMismatch);
         }
 
-        Ok(events)
+        Ok((events, message_results))
+    }
+
+    fn replay_transactions(
+        &mut self,
+        trie_ctx: &merkle_trie::Context,
+        transactions: &[Transaction],
+        txn_batch: &mut RocksDbTransactionBatch,
+    ) -> Result<Vec<ReplayedTransaction>, EngineError> {
+        let invalid_signatures = self.verify_signatures(transactions);
+        if !self.parallel_replay {
+            return transactions
+                .iter()
+                .map(|snapchain_txn| {
+                    self.replay_snapchain_txn(
+                        trie_ctx,
+                        snapchain_txn,
+                        txn_batch,
+                        &invalid_signatures,
+                    )
+                })
+                .collect();
+        }
+
+        let mut replayed_txns = Vec::with_capacity(transactions.len());
+        let mut seen_fids = HashSet::new();
+        let mut start = 0;
+        while start < transactions.len() {
+            // Transactions with only user messages, for fids that haven't been touched by an
+            // earlier transaction, don't depend on each other and can be merged in parallel.
+            // Anything else is replayed on its own, in order.
+            let mut end = start;
+            while end < transactions.len()
+                && transactions[end].system_messages.is_empty()
+                && seen_fids.insert(transactions[end].fid)
+            {
+                end += 1;
+            }
+
+            if end == start {
+                let snapchain_txn = &transactions[start];
+                seen_fids.insert(snapchain_txn.fid);
+                // Fname transfers also revoke the username of the fid the name was transferred from
+                for msg in &snapchain_txn.system_messages {
+                    if let Some(fname_transfer) = &msg.fname_transfer {
+                        seen_fids.insert(fname_transfer.from_fid);
+                    }
+                }
+                replayed_txns.push(self.replay_snapchain_txn(
+                    trie_ctx,
+                    snapchain_txn,
+                    txn_batch,
+                    &invalid_signatures,
+                )?);
+                start += 1;
+                continue;
+            }
+
+            let merged_txns = Self::merge_user_messages_in_parallel(
+                &self.stores,
+                &transactions[start..end],
+                &invalid_signatures,
+            );
+            for (snapchain_txn, (fid_batch, mut merged)) in
+                transactions[start..end].iter().zip(merged_txns)
+            {
+                self.merge_fid_batch(txn_batch, fid_batch, &mut merged.events)?;
+                replayed_txns.push(self.finish_replay(
+                    trie_ctx,
+                    snapchain_txn,
+                    txn_batch,
+                    TransactionReplay::default(),
+                    merged,
+                )?);
+            }
+            start = end;
+        }
+
+        Ok(replayed_txns)
+    }
+
+    // Each transaction is merged into its own batch, results are returned in transaction order
+    fn merge_user_messages_in_parallel(
+        stores: &Stores,
+        transactions: &[Transaction],
+        invalid_signatures: &HashSet<Vec<u8>>,
+    ) -> Vec<(RocksDbTransactionBatch, MergedUserMessages)> {
+        let num_threads = std::thread::available_parallelism()
+            .map_or(1, |n| n.get())
+            .min(transactions.len());
+        let chunk_size = transactions.len().div_ceil(num_threads);
+
+        std::thread::scope(|scope| {
+            let handles = transactions
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .map(|snapchain_txn| {
+                                let mut fid_batch = RocksDbTransactionBatch::new();
+                                let merged = Self::merge_user_messages(
+                                    stores,
+                                    snapchain_txn,
+                                    &mut fid_batch,
+                                    invalid_signatures,
+                                );
+                                (fid_batch, merged)
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect()
+        })
+    }
+
+    // Events are written to the batch when they're merged, so ids were handed out in whatever
+    // order the threads ran in. Reissue them in transaction order, so they stay monotonic.
+    fn merge_fid_batch(
+        &self,
+        txn_batch: &mut RocksDbTransactionBatch,
+        mut fid_batch: RocksDbTransactionBatch,
+        events: &mut [HubEvent],
+    ) -> Result<(), EngineError> {
+        for event in events.iter() {
+            fid_batch.batch.remove(&HubEvent::make_event_key(event.id));
+        }
+        txn_batch.merge(fid_batch);
+
+        let event_handler = self.stores.cast_store.event_handler();
+        for event in events.iter_mut() {
+            event_handler.commit_transaction(txn_batch, event)?;
+        }
+        Ok(())
+    }
+
+    // Verifies the signatures of all user messages in the transactions, skipping messages that
+    // were already verified when they were submitted. Returns the keys (see
+    // signatures::message_key) of the messages with invalid signatures.
+    fn verify_signatures(&self, transactions: &[Transaction]) -> HashSet<Vec<u8>> {
+        let messages: Vec<&Message> = transactions
+            .iter()
+            .flat_map(|snapchain_txn| snapchain_txn.user_messages.iter())
+            .collect();
+        if messages.is_empty() {
+            return HashSet::new();
+        }
+
+        let results = self.senders.verified_messages.verify(&messages);
+        let invalid_signatures: HashSet<Vec<u8>> = messages
+            .iter()
+            .zip(results)
+            .filter(|(_, valid)| !valid)
+            .map(|(message, _)| signatures::message_key(message))
+            .collect();
+        self.count("signatures.invalid", invalid_signatures.len() as u64);
+        invalid_signatures
     }
 
     fn replay_snapchain_txn(
@@ -375,23 +799,24 @@ impl ShardEngine {
         trie_ctx: &merkle_trie::Context,
         snapchain_txn: &Transaction,
         txn_batch: &mut RocksDbTransactionBatch,
-    ) -> Result<(Vec<u8>, Vec<HubEvent>, Vec<MessageValidationError>), EngineError> {
-        let total_user_messages = snapchain_txn.user_messages.len();
-        let total_system_messages = snapchain_txn.system_messages.len();
-        let mut user_messages_count = 0;
-        let mut system_messages_count = 0;
-        let mut onchain_events_count = 0;
-        let mut merged_fnames_count = 0;
-        let mut merged_messages_count = 0;
-        let mut pruned_messages_count = 0;
-        let mut revoked_messages_count = 0;
-        let mut events = vec![];
-        let mut message_types = HashSet::new();
-        let mut revoked_signers = HashSet::new();
+        invalid_signatures: &HashSet<Vec<u8>>,
+    ) -> Result<ReplayedTransaction, EngineError> {
+        // System messages first, then user messages and finally prunes
+        let replay = self.replay_system_messages(trie_ctx, snapchain_txn, txn_batch)?;
+        let merged =
+            Self::merge_user_messages(&self.stores, snapchain_txn, txn_batch, invalid_signatures);
+        self.finish_replay(trie_ctx, snapchain_txn, txn_batch, replay, merged)
+    }
 
-        let mut validation_errors = vec![];
+    fn replay_system_messages(
+        &mut self,
+        trie_ctx: &merkle_trie::Context,
+        snapchain_txn: &Transaction,
+        txn_batch: &mut RocksDbTransactionBatch,
+    ) -> Result<TransactionReplay, EngineError> {
+        let mut replay = TransactionReplay::default();
+        let mut revoked_signers = HashSet::new();
 
-        // System messages first, then user messages and finally prunes
         for msg in &snapchain_txn.system_messages {
             if let Some(onchain_event) = &msg.on_chain_event {
                 let event = self
@@ -401,10 +826,10 @@ impl ShardEngine {
 
                 match event {
                     Ok(hub_event) => {
-                        onchain_events_count += 1;
+                        replay.onchain_events_count += 1;
                         self.update_trie(trie_ctx, &hub_event, txn_batch)?;
-                        events.push(hub_event.clone());
-                        system_messages_count += 1;
+                        replay.events.push(hub_event.clone());
+                        replay.system_messages_count += 1;
                         match &onchain_event.body {
                             Some(proto::on_chain_event::Body::SignerEventBody(signer_event)) => {
                                 if signer_event.event_type == proto::SignerEventType::Remove as i32
@@ -437,10 +862,10 @@ impl ShardEngine {
                 );
                 match event {
                     Ok(hub_event) => {
-                        merged_fnames_count += 1;
+                        replay.merged_fnames_count += 1;
                         self.update_trie(&merkle_trie::Context::new(), &hub_event, txn_batch)?;
-                        events.push(hub_event.clone());
-                        system_messages_count += 1;
+                        replay.events.push(hub_event.clone());
+                        replay.system_messages_count += 1;
                     }
                     Err(err) => {
                         warn!("Error merging fname transfer: {:?}", err);
@@ -460,13 +885,13 @@ impl ShardEngine {
                             .revoke(&existing_username, txn_batch);
                         match event {
                             Ok(hub_event) => {
-                                revoked_messages_count += 1;
+                                replay.revoked_messages_count += 1;
                                 self.update_trie(
                                     &merkle_trie::Context::new(),
                                     &hub_event,
                                     txn_batch,
                                 )?;
-                                events.push(hub_event.clone());
+                                replay.events.push(hub_event.clone());
                             }
                             Err(err) => {
                                 warn!("Error revoking existing username: {:?}", err);
@@ -484,9 +909,19 @@ impl ShardEngine {
             match result {
                 Ok(revoke_events) => {
                     for event in revoke_events {
-                        revoked_messages_count += 1;
+                        replay.revoked_messages_count += 1;
                         self.update_trie(trie_ctx, &event, txn_batch)?;
-                        events.push(event.clone());
+                        if let Some(proto::hub_event::Body::RevokeMessageBody(revoke)) = &event.body
+                        {
+                            if let Some(message) = &revoke.message {
+                                replay.removals.push(MessageReceipt {
+                                    hash: message.hash.clone(),
+                                    outcome: proto::MessageOutcome::Revoked as i32,
+                                    reason: "signer_removed".to_string(),
+                                });
+                            }
+                        }
+                        replay.events.push(event.clone());
                     }
                 }
                 Err(err) => {
@@ -500,18 +935,38 @@ impl ShardEngine {
             }
         }
 
+        Ok(replay)
+    }
+
+    // Validates and merges the user messages of a transaction into the stores. This only touches
+    // the transaction's fid (the trie is updated separately), so it can run for many fids at once.
+    fn merge_user_messages(
+        stores: &Stores,
+        snapchain_txn: &Transaction,
+        txn_batch: &mut RocksDbTransactionBatch,
+        invalid_signatures: &HashSet<Vec<u8>>,
+    ) -> MergedUserMessages {
+        let mut merged = MergedUserMessages::default();
+
         for msg in &snapchain_txn.user_messages {
             // Errors are validated based on the shard root
-            match self.validate_user_message(msg) {
+            let result = if invalid_signatures.contains(&signatures::message_key(msg)) {
+                Err(MessageValidationError::InvalidSignature)
+            } else {
+                Self::validate_user_message(stores, msg)
+            };
+            match result {
                 Ok(()) => {
-                    let result = self.merge_message(msg, txn_batch);
+                    let result = Self::merge_message(stores, msg, txn_batch);
                     match result {
                         Ok(event) => {
-                            merged_messages_count += 1;
-                            self.update_trie(trie_ctx, &event, txn_batch)?;
-                            events.push(event.clone());
-                            user_messages_count += 1;
-                            message_types.insert(msg.msg_type());
+                            merged.events.push(event);
+                            merged.message_types.insert(msg.msg_type());
+                            merged.receipts.push(MessageReceipt {
+                                hash: msg.hash.clone(),
+                                outcome: proto::MessageOutcome::Merged as i32,
+                                reason: "".to_string(),
+                            });
                         }
                         Err(err) => {
                             warn!(
@@ -520,6 +975,11 @@ impl ShardEngine {
                                 "Error merging message: {:?}",
                                 err
                             );
+                            merged.receipts.push(MessageReceipt {
+                                hash: msg.hash.clone(),
+                                outcome: proto::MessageOutcome::MergeConflict as i32,
+                                reason: err.code(),
+                            });
                         }
                     }
                 }
@@ -530,20 +990,52 @@ impl ShardEngine {
                         "Error validating user message: {:?}",
                         err
                     );
-                    validation_errors.push(err);
+                    merged.receipts.push(MessageReceipt {
+                        hash: msg.hash.clone(),
+                        outcome: proto::MessageOutcome::Invalid as i32,
+                        reason: err.code(),
+                    });
+                    merged.validation_errors.push(err);
                 }
             }
         }
 
-        for msg_type in message_types {
+        merged
+    }
+
+    // Applies merged user messages to the trie, prunes and computes the new account root
+    fn finish_replay(
+        &mut self,
+        trie_ctx: &merkle_trie::Context,
+        snapchain_txn: &Transaction,
+        txn_batch: &mut RocksDbTransactionBatch,
+        mut replay: TransactionReplay,
+        merged: MergedUserMessages,
+    ) -> Result<ReplayedTransaction, EngineError> {
+        let merged_messages_count = merged.events.len();
+        for event in merged.events {
+            self.update_trie(trie_ctx, &event, txn_batch)?;
+            replay.events.push(event);
+        }
+
+        for msg_type in merged.message_types {
             let fid = snapchain_txn.fid as u32;
             let result = self.prune_messages(fid, msg_type, txn_batch);
             match result {
                 Ok(pruned_events) => {
                     for event in pruned_events {
-                        pruned_messages_count += 1;
+                        replay.pruned_messages_count += 1;
                         self.update_trie(trie_ctx, &event, txn_batch)?;
-                        events.push(event.clone());
+                        if let Some(proto::hub_event::Body::PruneMessageBody(prune)) = &event.body {
+                            if let Some(message) = &prune.message {
+                                replay.removals.push(MessageReceipt {
+                                    hash: message.hash.clone(),
+                                    outcome: proto::MessageOutcome::Pruned as i32,
+                                    reason: "storage_limit_reached".to_string(),
+                                });
+                            }
+                        }
+                        replay.events.push(event.clone());
                     }
                 }
                 Err(err) => {
@@ -564,26 +1056,32 @@ impl ShardEngine {
         );
         info!(
             fid = snapchain_txn.fid,
-            num_user_messages = total_user_messages,
-            num_system_messages = total_system_messages,
-            user_messages_merged = user_messages_count,
-            system_messages_merged = system_messages_count,
-            onchain_events_merged = onchain_events_count,
-            fnames_merged = merged_fnames_count,
+            num_user_messages = snapchain_txn.user_messages.len(),
+            num_system_messages = snapchain_txn.system_messages.len(),
+            user_messages_merged = merged_messages_count,
+            system_messages_merged = replay.system_messages_count,
+            onchain_events_merged = replay.onchain_events_count,
+            fnames_merged = replay.merged_fnames_count,
             messages_merged = merged_messages_count,
-            messages_pruned = pruned_messages_count,
-            messages_revoked = revoked_messages_count,
+            messages_pruned = replay.pruned_messages_count,
+            messages_revoked = replay.revoked_messages_count,
             new_account_root = hex::encode(&account_root),
             tx_account_root = hex::encode(&snapchain_txn.account_root),
             "Replayed transaction"
         );
 
         // Return the new account root hash
-        Ok((account_root, events, validation_errors))
+        Ok(ReplayedTransaction {
+            account_root,
+            events: replay.events,
+            validation_errors: merged.validation_errors,
+            receipts: merged.receipts,
+            removals: replay.removals,
+        })
     }
 
     fn merge_message(
-        &mut self,
+        stores: &Stores,
         msg: &proto::Message,
         txn_batch: &mut RocksDbTransactionBatch,
     ) -> Result<proto::HubEvent, MessageValidationError> {
@@ -595,33 +1093,30 @@ impl ShardEngine {
             .or(Err(MessageValidationError::InvalidMessageType(data.r#type)))?;
 
         let event = match mt {
-            MessageType::CastAdd | MessageType::CastRemove => self
-                .stores
+            MessageType::CastAdd | MessageType::CastRemove => stores
                 .cast_store
                 .merge(msg, txn_batch)
                 .map_err(MessageValidationError::new_store_error(msg.hash.clone())),
-            MessageType::LinkAdd | MessageType::LinkRemove | MessageType::LinkCompactState => self
-                .stores
-                .link_store
-                .merge(msg, txn_batch)
-                .map_err(MessageValidationError::new_store_error(msg.hash.clone())),
-            MessageType::ReactionAdd | MessageType::ReactionRemove => self
-                .stores
+            MessageType::LinkAdd | MessageType::LinkRemove | MessageType::LinkCompactState => {
+                stores
+                    .link_store
+                    .merge(msg, txn_batch)
+                    .map_err(MessageValidationError::new_store_error(msg.hash.clone()))
+            }
+            MessageType::ReactionAdd | MessageType::ReactionRemove => stores
                 .reaction_store
                 .merge(msg, txn_batch)
                 .map_err(MessageValidationError::new_store_error(msg.hash.clone())),
-            MessageType::UserDataAdd => self
-                .stores
+            MessageType::UserDataAdd => stores
                 .user_data_store
                 .merge(msg, txn_batch)
                 .map_err(MessageValidationError::new_store_error(msg.hash.clone())),
-            MessageType::VerificationAddEthAddress | MessageType::VerificationRemove => self
-                .stores
+            MessageType::VerificationAddEthAddress | MessageType::VerificationRemove => stores
                 .verification_store
                 .merge(msg, txn_batch)
                 .map_err(MessageValidationError::new_store_error(msg.hash.clone())),
             MessageType::UsernameProof => {
-                let store = &self.stores.username_proof_store;
+                let store = &stores.username_proof_store;
                 let result = store.merge(msg, txn_batch);
                 result.map_err(MessageValidationError::new_store_error(msg.hash.clone()))
             }
@@ -785,14 +1280,14 @@ impl ShardEngine {
             }
             &None => {
                 // This should never happen
-                panic!("No body in event");
+                return Err(EngineError::MissingEventBody);
             }
         }
         Ok(())
     }
 
     fn validate_user_message(
-        &self,
+        stores: &Stores,
         message: &proto::Message,
     ) -> Result<(), MessageValidationError> {
         // Ensure message data is present
@@ -804,14 +1299,14 @@ impl ShardEngine {
         // TODO(aditi): Check network
 
         // Check that the user has a custody address
-        self.stores
+        stores
             .onchain_event_store
             .get_id_register_event_by_fid(message_data.fid as u32)
             .map_err(|_| MessageValidationError::MissingFid)?
             .ok_or(MessageValidationError::MissingFid)?;
 
         // Check that signer is valid
-        self.stores
+        stores
             .onchain_event_store
             .get_active_signer(message_data.fid as u32, message.signer.clone())
             .map_err(|_| MessageValidationError::MissingSigner)?
@@ -820,7 +1315,7 @@ impl ShardEngine {
         match &message_data.body {
             Some(proto::message_data::Body::UserDataBody(user_data)) => {
                 if user_data.r#type == proto::UserDataType::Username as i32 {
-                    self.validate_username(message_data.fid as u32, &user_data.value)?;
+                    Self::validate_username(stores, message_data.fid as u32, &user_data.value)?;
                 }
             }
             Some(proto::message_data::Body::UsernameProofBody(_)) => {
@@ -838,7 +1333,11 @@ impl ShardEngine {
         Ok(())
     }
 
-    fn validate_username(&self, fid: u32, fname: &str) -> Result<(), MessageValidationError> {
+    fn validate_username(
+        stores: &Stores,
+        fid: u32,
+        fname: &str,
+    ) -> Result<(), MessageValidationError> {
         if fname.is_empty() {
             // Setting an empty username is allowed, no need to validate the proof
             return Ok(());
@@ -850,11 +1349,11 @@ impl ShardEngine {
             // TODO: Validate ens names
         } else {
             let proof =
-                UserDataStore::get_username_proof(&self.stores.user_data_store, fname.as_bytes())
+                UserDataStore::get_username_proof(&stores.user_data_store, fname.as_bytes())
                     .map_err(|e| MessageValidationError::StoreError {
-                    inner: e,
-                    hash: vec![],
-                })?;
+                        inner: e,
+                        hash: vec![],
+                    })?;
             match proof {
                 Some(proof) => {
                     if proof.fid as u32 != fid {
@@ -870,12 +1369,95 @@ impl ShardEngine {
     }
 
     pub fn validate_state_change(&mut self, shard_state_change: &ShardStateChange) -> bool {
+        self.replay_state_change(shard_state_change).is_some()
+    }
+
+    // Replays the chunk on top of the current state without committing it and returns the first
+    // transaction (or the shard root) that doesn't match. Meant for investigating a chunk that
+    // failed to commit, so it also works while the shard is halted.
+    pub fn find_divergence(
+        &mut self,
+        shard_chunk: &ShardChunk,
+    ) -> Result<Option<ChunkDivergence>, EngineError> {
+        let Some(header) = &shard_chunk.header else {
+            return Err(ShardStorageError::ShardMissingHeader.into());
+        };
+
+        let mut txn = RocksDbTransactionBatch::new();
+        let trie_ctx = merkle_trie::Context::new();
+        let divergence = self.replay_until_divergence(
+            &trie_ctx,
+            &mut txn,
+            &shard_chunk.transactions,
+            &header.shard_root,
+        );
+
+        self.stores.trie.reload(&self.db)?;
+        divergence
+    }
+
+    fn replay_until_divergence(
+        &mut self,
+        trie_ctx: &merkle_trie::Context,
+        txn_batch: &mut RocksDbTransactionBatch,
+        transactions: &[Transaction],
+        shard_root: &[u8],
+    ) -> Result<Option<ChunkDivergence>, EngineError> {
+        let replayed_txns = self.replay_transactions(trie_ctx, transactions, txn_batch)?;
+        for (snapchain_txn, replayed) in transactions.iter().zip(replayed_txns) {
+            if replayed.account_root != snapchain_txn.account_root
+                || replayed.receipts != snapchain_txn.receipts
+            {
+                let trie_keys = self.stores.trie.get_all_values(
+                    trie_ctx,
+                    &self.db,
+                    &TrieKey::for_fid(snapchain_txn.fid as u32),
+                )?;
+                return Ok(Some(ChunkDivergence::Transaction {
+                    fid: snapchain_txn.fid,
+                    expected_account_root: snapchain_txn.account_root.clone(),
+                    account_root: replayed.account_root,
+                    expected_receipts: snapchain_txn.receipts.clone(),
+                    receipts: replayed.receipts,
+                    trie_keys,
+                }));
+            }
+        }
+
+        let root = self.stores.trie.root_hash()?;
+        if root != shard_root {
+            return Ok(Some(ChunkDivergence::ShardRoot {
+                expected: shard_root.to_vec(),
+                actual: root,
+            }));
+        }
+        Ok(None)
+    }
+
+    // Validates the state change, returning the replayed result if it's valid so it can be
+    // committed without replaying it again (see commit_replayed_shard_chunk)
+    pub fn replay_state_change(
+        &mut self,
+        shard_state_change: &ShardStateChange,
+    ) -> Option<ReplayedStateChange> {
+        if self.is_halted() {
+            warn!("Not validating state change, shard is halted");
+            self.count("validate.false", 1);
+            return None;
+        }
+        if self.read_only {
+            warn!("Not validating state change, engine is a read only replica");
+            self.count("validate.false", 1);
+            return None;
+        }
+
+        let parent_root = self.trie_root_hash();
         let mut txn = RocksDbTransactionBatch::new();
 
         let transactions = &shard_state_change.transactions;
         let shard_root = &shard_state_change.new_state_root;
 
-        let mut result = true;
+        let mut result = None;
 
         let count_fn = Self::make_count_fn(self.statsd_client.clone(), self.shard_id);
         let count_callback = move |read_count: u64| {
@@ -883,19 +1465,23 @@ impl ShardEngine {
             count_fn("trie.db_get_count.for_validate", read_count);
         };
 
-        if let Err(err) = self.replay_proposal(
+        match self.replay_proposal(
             &merkle_trie::Context::with_callback(count_callback),
             &mut txn,
             transactions,
             shard_root,
         ) {
-            error!("State change validation failed: {}", err);
-            result = false;
+            Err(err) => {
+                error!("State change validation failed: {}", err);
+            }
+            Ok((events, message_results)) => {
+                result = Some((events, message_results));
+            }
         }
 
         self.stores.trie.reload(&self.db).unwrap();
 
-        if result {
+        if result.is_some() {
             self.count("validate.true", 1);
             self.count("validate.false", 0);
         } else {
@@ -903,7 +1489,15 @@ impl ShardEngine {
             self.count("validate.true", 0);
         }
 
-        result
+        result.map(|(events, message_results)| ReplayedStateChange {
+            parent_version: self.state_version,
+            parent_root,
+            shard_root: shard_root.clone(),
+            transactions: transactions.clone(),
+            txn,
+            events,
+            message_results,
+        })
     }
 
     pub fn commit_and_emit_events(
@@ -912,21 +1506,102 @@ impl ShardEngine {
         events: Vec<HubEvent>,
         txn: RocksDbTransactionBatch,
     ) {
-        self.db.commit(txn).unwrap();
+        if let Err(err) = self.write_shard_chunk(shard_chunk, txn) {
+            self.halt(Self::block_number(shard_chunk), &err);
+            return;
+        }
+        self.state_version += 1;
+
+        // Only emit events once the commit is durable
         for event in events {
             // An error here just means there are no active receivers, which is fine and will happen if there are no active subscribe rpcs
             let _ = self.senders.events_tx.send(event);
         }
-        self.stores.trie.reload(&self.db).unwrap();
+        self.senders
+            .mempool
+            .remove_committed(&shard_chunk.transactions);
 
         _ = self.emit_commit_metrics(&shard_chunk);
+    }
 
-        match self.stores.shard_store.put_shard_chunk(shard_chunk) {
-            Err(err) => {
-                error!("Unable to write shard chunk to store {}", err)
-            }
-            Ok(()) => {}
+    // The chunk is written in the same batch as the state changes, so a crash can never leave the
+    // state ahead of the last stored chunk
+    fn write_shard_chunk(
+        &self,
+        shard_chunk: &ShardChunk,
+        txn: RocksDbTransactionBatch,
+    ) -> Result<(), EngineError> {
+        let result = self.commit_with_undo_log(shard_chunk, txn);
+        self.stores.trie.reload(&self.db)?;
+        result
+    }
+
+    fn commit_with_undo_log(
+        &self,
+        shard_chunk: &ShardChunk,
+        mut txn: RocksDbTransactionBatch,
+    ) -> Result<(), EngineError> {
+        self.stores
+            .shard_store
+            .put_shard_chunk_in_txn(&mut txn, shard_chunk)?;
+        undo_log::put_undo_log_entry(&self.db, &mut txn, Self::block_number(shard_chunk))?;
+        self.stores.trie.commit_to_db(&self.db, txn)?;
+        Ok(())
+    }
+
+    // Reverts the stores, trie and event log to the state right after the given block was
+    // committed. Later shard chunks are deleted, so consensus continues from the next block.
+    // Only what was written by committed chunks is undone: messages merged by diff sync have no
+    // undo entries, so a read only replica that merged any fails the consistency check after
+    // rolling back. The block store isn't touched either, blocks that already reference the
+    // undone chunks keep referencing them.
+    pub fn rollback_to(&mut self, block_number: u64) -> Result<(), EngineError> {
+        let current_block_number = self.get_confirmed_height().block_number;
+        if block_number > current_block_number {
+            return Err(EngineError::StateNotAvailable(block_number));
         }
+
+        let mut txn = RocksDbTransactionBatch::new();
+        for undone_block_number in (block_number + 1..=current_block_number).rev() {
+            let entry = undo_log::get_undo_log_entry(&self.db, undone_block_number)?
+                .ok_or(EngineError::StateNotAvailable(block_number))?;
+            undo_log::undo(&mut txn, undone_block_number, entry);
+        }
+
+        let result = self.stores.trie.commit_to_db(&self.db, txn);
+        self.stores.trie.reload(&self.db)?;
+        result?;
+        self.state_version += 1;
+
+        self.check_consistency()?;
+        self.senders.health.resume();
+        info!(
+            shard_id = self.shard_id,
+            from_block_number = current_block_number,
+            block_number,
+            "Rolled back shard"
+        );
+        Ok(())
+    }
+
+    // Makes sure the trie matches the last committed shard chunk, e.g. after a restart
+    pub fn check_consistency(&self) -> Result<(), EngineError> {
+        let Some(shard_chunk) = self.stores.shard_store.get_last_shard_chunk()? else {
+            return Ok(());
+        };
+        let header = shard_chunk.header.unwrap_or_default();
+        let block_number = header.height.unwrap_or_default().block_number;
+        let trie_root = self.trie_root_hash();
+        if trie_root != header.shard_root {
+            error!(
+                block_number,
+                trie_root = hex::encode(&trie_root),
+                shard_root = hex::encode(&header.shard_root),
+                "Trie root does not match the last committed shard chunk"
+            );
+            return Err(EngineError::InconsistentState(block_number));
+        }
+        Ok(())
     }
 
     fn emit_commit_metrics(&mut self, shard_chunk: &&ShardChunk) -> Result<(), EngineError> {
@@ -958,6 +1633,24 @@ impl ShardEngine {
         );
         self.gauge("max_messages_per_block", self.max_messages_per_block as u64);
 
+        if let Some(stats) = self.stores.trie.node_cache_stats() {
+            self.count("trie.node_cache.hits", stats.hits);
+            self.count("trie.node_cache.misses", stats.misses);
+            self.gauge("trie.node_cache.entries", stats.entries);
+            self.gauge("trie.node_cache.size_bytes", stats.size_bytes);
+        }
+
+        let mempool = &self.senders.mempool;
+        self.statsd_client
+            .gauge_with_shard(self.shard_id, "mempool.size", mempool.size() as u64);
+        self.statsd_client.gauge_with_shard(
+            self.shard_id,
+            "mempool.oldest_message_age_ms",
+            mempool
+                .oldest_message_age()
+                .map_or(0, |age| age.as_millis() as u64),
+        );
+
         Ok(())
     }
 
@@ -968,10 +1661,48 @@ impl ShardEngine {
         }
     }
 
+    pub fn is_halted(&self) -> bool {
+        self.senders.health.is_halted()
+    }
+
+    // Stops the shard from proposing, validating or committing anything else until it's resumed
+    // by an operator
+    pub fn halt(&mut self, block_number: u64, err: &EngineError) {
+        if self.senders.health.halt(block_number, err.to_string()) {
+            error!(
+                shard_id = self.shard_id,
+                block_number, "Halting shard: {}", err
+            );
+            self.count("halted", 1);
+        }
+    }
+
+    fn block_number(shard_chunk: &ShardChunk) -> u64 {
+        shard_chunk
+            .header
+            .as_ref()
+            .and_then(|header| header.height)
+            .map_or(0, |height| height.block_number)
+    }
+
     pub fn commit_shard_chunk(&mut self, shard_chunk: &ShardChunk) {
+        if self.is_halted() {
+            warn!(
+                block_number = Self::block_number(shard_chunk),
+                "Not committing shard chunk, shard is halted"
+            );
+            return;
+        }
+
+        let Some(header) = &shard_chunk.header else {
+            let err = EngineError::from(ShardStorageError::ShardMissingHeader);
+            self.halt(0, &err);
+            return;
+        };
+
         let mut txn = RocksDbTransactionBatch::new();
 
-        let shard_root = &shard_chunk.header.as_ref().unwrap().shard_root;
+        let shard_root = &header.shard_root;
         let transactions = &shard_chunk.transactions;
 
         let count_fn = Self::make_count_fn(self.statsd_client.clone(), self.shard_id);
@@ -984,12 +1715,338 @@ impl ShardEngine {
         match self.replay_proposal(trie_ctx, &mut txn, transactions, shard_root) {
             Err(err) => {
                 error!("State change commit failed: {}", err);
-                panic!("State change commit failed: {}", err);
+                if let Err(err) = self.stores.trie.reload(&self.db) {
+                    error!("Unable to reload trie: {}", err);
+                }
+                self.halt(Self::block_number(shard_chunk), &err);
+            }
+            Ok((events, message_results)) => {
+                self.commit_replayed_transactions(shard_chunk, txn, events, &message_results);
+            }
+        }
+    }
+
+    // Commits a shard chunk that was already replayed during proposal or validation, unless
+    // something else was committed since, in which case it's replayed again
+    pub fn commit_replayed_shard_chunk(
+        &mut self,
+        shard_chunk: &ShardChunk,
+        replayed: ReplayedStateChange,
+    ) {
+        if self.is_halted() {
+            warn!(
+                block_number = Self::block_number(shard_chunk),
+                "Not committing shard chunk, shard is halted"
+            );
+            return;
+        }
+
+        let header = shard_chunk.header.as_ref().unwrap();
+        let is_current = replayed.parent_version == self.state_version
+            && replayed.parent_root == self.trie_root_hash()
+            && replayed.shard_root == header.shard_root
+            && replayed.transactions == shard_chunk.transactions;
+
+        if !is_current {
+            self.count("commit.replayed", 1);
+            self.commit_shard_chunk(shard_chunk);
+            return;
+        }
+
+        self.count("commit.reused_replay", 1);
+        self.commit_replayed_transactions(
+            shard_chunk,
+            replayed.txn,
+            replayed.events,
+            &replayed.message_results,
+        );
+    }
+
+    fn commit_replayed_transactions(
+        &mut self,
+        shard_chunk: &ShardChunk,
+        mut txn: RocksDbTransactionBatch,
+        events: Vec<HubEvent>,
+        message_results: &[Vec<MessageReceipt>],
+    ) {
+        let statuses = self.index_message_statuses(&mut txn, shard_chunk, message_results);
+        if let Err(err) = self.index_height(&mut txn, shard_chunk, &events) {
+            if let Err(err) = self.stores.trie.reload(&self.db) {
+                error!("Unable to reload trie: {}", err);
+            }
+            self.halt(Self::block_number(shard_chunk), &err);
+            return;
+        }
+        self.commit_and_emit_events(shard_chunk, events, txn);
+        if self.is_halted() {
+            return;
+        }
+        for status in statuses {
+            // No receivers just means nobody is waiting on a message
+            let _ = self.senders.message_status_tx.send(status);
+        }
+    }
+
+    fn index_message_statuses(
+        &self,
+        txn: &mut RocksDbTransactionBatch,
+        shard_chunk: &ShardChunk,
+        message_results: &[Vec<MessageReceipt>],
+    ) -> Vec<proto::MessageStatus> {
+        let block_number = shard_chunk
+            .header
+            .as_ref()
+            .and_then(|header| header.height)
+            .map_or(0, |height| height.block_number);
+
+        let mut statuses = vec![];
+        for (transaction_index, results) in message_results.iter().enumerate() {
+            for result in results {
+                let status = proto::MessageStatus {
+                    hash: result.hash.clone(),
+                    shard_id: self.shard_id,
+                    block_number,
+                    transaction_index: transaction_index as u32,
+                    outcome: result.outcome,
+                    reason: result.reason.clone(),
+                };
+                match message_status::put_message_status(&self.db, txn, &status) {
+                    Ok(true) => statuses.push(status),
+                    Ok(false) => {}
+                    Err(err) => {
+                        error!(
+                            hash = hex::encode(&result.hash),
+                            "Unable to index message status: {}", err
+                        );
+                    }
+                }
             }
-            Ok(events) => {
-                self.commit_and_emit_events(shard_chunk, events, txn);
+        }
+        statuses
+    }
+
+    // Applies messages fetched from another node's trie during diff sync. They don't go through
+    // consensus, but are validated and merged the same way a committed transaction would be.
+    // The result isn't part of any chunk, so only read only replicas accept them. Returns the
+    // number of user messages merged.
+    pub fn merge_synced_messages(
+        &mut self,
+        messages: Vec<MempoolMessage>,
+    ) -> Result<usize, EngineError> {
+        if !self.read_only {
+            return Err(EngineError::NotReadOnly);
+        }
+        // User message validation reads signers etc. from the db, so onchain events and fnames
+        // need to be committed first
+        let (user_messages, validator_messages): (Vec<_>, Vec<_>) = messages
+            .into_iter()
+            .partition(|msg| matches!(msg, MempoolMessage::UserMessage(_)));
+
+        let merged = self.merge_synced_batch(validator_messages)?;
+        Ok(merged + self.merge_synced_batch(user_messages)?)
+    }
+
+    fn merge_synced_batch(&mut self, messages: Vec<MempoolMessage>) -> Result<usize, EngineError> {
+        if messages.is_empty() {
+            return Ok(0);
+        }
+
+        let mut txn = RocksDbTransactionBatch::new();
+        let trie_ctx = merkle_trie::Context::new();
+
+        let grouped_messages = messages.into_iter().into_group_map_by(|msg| msg.fid());
+        let mut merged = 0;
+        let mut events = vec![];
+        for fid in grouped_messages.keys().sorted() {
+            let mut transaction = Transaction {
+                fid: *fid as u64,
+                account_root: vec![],
+                system_messages: vec![],
+                user_messages: vec![],
+                receipts: vec![],
+            };
+            for msg in &grouped_messages[fid] {
+                match msg {
+                    MempoolMessage::UserMessage(msg) => {
+                        transaction.user_messages.push(msg.clone());
+                    }
+                    MempoolMessage::ValidatorMessage(msg) => {
+                        transaction.system_messages.push(msg.clone());
+                    }
+                }
             }
+
+            let invalid_signatures = self.verify_signatures(std::slice::from_ref(&transaction));
+            let replayed = match self.replay_snapchain_txn(
+                &trie_ctx,
+                &transaction,
+                &mut txn,
+                &invalid_signatures,
+            ) {
+                Ok(replayed) => replayed,
+                Err(err) => {
+                    self.stores.trie.reload(&self.db)?;
+                    return Err(err);
+                }
+            };
+            merged += replayed
+                .receipts
+                .iter()
+                .filter(|receipt| receipt.outcome == proto::MessageOutcome::Merged as i32)
+                .count();
+            events.extend(replayed.events);
+        }
+
+        let result = self.stores.trie.commit_to_db(&self.db, txn);
+        self.stores.trie.reload(&self.db)?;
+        result?;
+        self.state_version += 1;
+
+        for event in events {
+            let _ = self.senders.events_tx.send(event);
         }
+        self.count("sync.merged_messages", merged as u64);
+
+        Ok(merged)
+    }
+
+    fn index_height(
+        &self,
+        txn: &mut RocksDbTransactionBatch,
+        shard_chunk: &ShardChunk,
+        events: &[HubEvent],
+    ) -> Result<(), EngineError> {
+        let header = shard_chunk
+            .header
+            .as_ref()
+            .ok_or(ShardStorageError::ShardMissingHeader)?;
+        let block_number = header.height.map_or(0, |height| height.block_number);
+
+        let last_event_id = match events.iter().map(|event| event.id).max() {
+            Some(id) => id,
+            None => height_index::get_height_index(&self.db, block_number.saturating_sub(1))?
+                .map_or(0, |entry| entry.last_event_id),
+        };
+
+        height_index::put_height_index(
+            txn,
+            block_number,
+            &proto::HeightIndexEntry {
+                shard_root: header.shard_root.clone(),
+                last_event_id,
+            },
+        );
+        Ok(())
+    }
+
+    pub fn get_state_root_at(&self, block_number: u64) -> Result<Option<Vec<u8>>, HubError> {
+        let entry = height_index::get_height_index(&self.db, block_number)?;
+        Ok(entry.map(|entry| entry.shard_root))
+    }
+
+    // Reads messages as they were right after the given block was committed, by undoing the
+    // hub events emitted between it and the last committed block. This is linear in the number
+    // of events in between, so it's meant for audits rather than the hot path.
+    pub fn get_messages_by_fid_at(
+        &self,
+        fid: u32,
+        message_type: MessageType,
+        at_block_number: Option<u64>,
+    ) -> Result<Vec<proto::Message>, EngineError> {
+        let current = self
+            .stores
+            .get_messages_by_fid_and_type(fid, message_type)?;
+
+        let Some(block_number) = at_block_number else {
+            return Ok(current);
+        };
+        let confirmed_block_number = self.get_confirmed_height().block_number;
+        if block_number > confirmed_block_number {
+            return Err(EngineError::StateNotAvailable(block_number));
+        }
+        if block_number == confirmed_block_number {
+            return Ok(current);
+        }
+        let entry = height_index::get_height_index(&self.db, block_number)?
+            .ok_or(EngineError::StateNotAvailable(block_number))?;
+        // Events are only undone up to the last committed block, anything emitted after it isn't
+        // part of the current state being read
+        let confirmed_entry = height_index::get_height_index(&self.db, confirmed_block_number)?
+            .ok_or(EngineError::StateNotAvailable(confirmed_block_number))?;
+
+        let mut events = vec![];
+        let mut page_token = None;
+        loop {
+            let page = HubEvent::get_events(
+                self.db.clone(),
+                entry.last_event_id + 1,
+                Some(confirmed_entry.last_event_id + 1),
+                Some(PageOptions {
+                    page_size: None,
+                    page_token,
+                    reverse: false,
+                }),
+            )?;
+            events.extend(page.events);
+            page_token = page.next_page_token;
+            if page_token.is_none() {
+                break;
+            }
+        }
+
+        let mut messages: Vec<proto::Message> = current;
+        let is_match =
+            |message: &proto::Message| message.fid() == fid && message.msg_type() == message_type;
+        for event in events.iter().rev() {
+            let (added, removed): (Vec<&proto::Message>, Vec<&proto::Message>) = match &event.body {
+                Some(proto::hub_event::Body::MergeMessageBody(merge)) => (
+                    merge.deleted_messages.iter().collect(),
+                    merge.message.iter().collect(),
+                ),
+                Some(proto::hub_event::Body::PruneMessageBody(prune)) => {
+                    (prune.message.iter().collect(), vec![])
+                }
+                Some(proto::hub_event::Body::RevokeMessageBody(revoke)) => {
+                    (revoke.message.iter().collect(), vec![])
+                }
+                Some(proto::hub_event::Body::MergeUsernameProofBody(merge)) => (
+                    merge.deleted_username_proof_message.iter().collect(),
+                    merge.username_proof_message.iter().collect(),
+                ),
+                _ => (vec![], vec![]),
+            };
+
+            for message in removed {
+                messages.retain(|existing| existing.hash != message.hash);
+            }
+            for message in added {
+                if is_match(message) && !messages.iter().any(|m| m.hash == message.hash) {
+                    messages.push(message.clone());
+                }
+            }
+        }
+
+        messages.sort_by(|a, b| {
+            let a_timestamp = a.data.as_ref().map_or(0, |data| data.timestamp);
+            let b_timestamp = b.data.as_ref().map_or(0, |data| data.timestamp);
+            a_timestamp.cmp(&b_timestamp).then(a.hash.cmp(&b.hash))
+        });
+        Ok(messages)
+    }
+
+    pub fn get_casts_by_fid_at(
+        &self,
+        fid: u32,
+        at_block_number: Option<u64>,
+    ) -> Result<Vec<proto::Message>, EngineError> {
+        self.get_messages_by_fid_at(fid, MessageType::CastAdd, at_block_number)
+    }
+
+    pub fn get_message_status(
+        &self,
+        hash: &[u8],
+    ) -> Result<Option<proto::MessageStatus>, HubError> {
+        message_status::get_message_status(&self.db, hash)
     }
 
     pub fn simulate_message(&mut self, message: &Message) -> Result<(), MessageValidationError> {
@@ -999,12 +2056,21 @@ impl ShardEngine {
             account_root: vec![],
             system_messages: vec![],
             user_messages: vec![message.clone()],
+            receipts: vec![],
         };
-        let result =
-            self.replay_snapchain_txn(&merkle_trie::Context::new(), &snapchain_txn, &mut txn);
+        let invalid_signatures = self.verify_signatures(std::slice::from_ref(&snapchain_txn));
+        let result = self.replay_snapchain_txn(
+            &merkle_trie::Context::new(),
+            &snapchain_txn,
+            &mut txn,
+            &invalid_signatures,
+        );
 
         match result {
-            Ok((_, _, errors)) => {
+            Ok(ReplayedTransaction {
+                validation_errors: errors,
+                ..
+            }) => {
                 self.stores.trie.reload(&self.db).map_err(|e| {
                     MessageValidationError::StoreError {
                         inner: HubError::invalid_internal_state(&*e.to_string()),
@@ -1151,18 +2217,56 @@ impl ShardEngine {
 
 pub struct BlockEngine {
     block_store: BlockStore,
+    validator_sets: ValidatorSets,
+    // Height of the last chunk of each shard included in a block
+    last_chunk_heights: HashMap<u32, u64>,
 }
 
 impl BlockEngine {
-    pub fn new(block_store: BlockStore) -> Self {
-        BlockEngine { block_store }
+    pub fn new(block_store: BlockStore, validator_sets: ValidatorSets) -> Self {
+        let mut engine = BlockEngine {
+            block_store,
+            validator_sets,
+            last_chunk_heights: HashMap::new(),
+        };
+        engine.load_chunk_heights();
+        engine
+    }
+
+    fn load_chunk_heights(&mut self) {
+        let num_shards = self.validator_sets.num_shards();
+        match self.block_store.get_last_chunk_heights(num_shards) {
+            Ok(last_chunk_heights) => self.last_chunk_heights = last_chunk_heights,
+            Err(err) => error!("Unable to load the last chunk heights {:#?}", err),
+        }
+    }
+
+    fn record_chunk_heights(&mut self, block: &Block) {
+        for chunk in &block.shard_chunks {
+            if let Some(height) = chunk.header.as_ref().and_then(|header| header.height) {
+                self.last_chunk_heights
+                    .insert(height.shard_index, height.block_number);
+            }
+        }
     }
 
     pub fn commit_block(&mut self, block: Block) {
-        let result = self.block_store.put_block(block);
+        let result = self.block_store.put_block(block.clone());
         if result.is_err() {
             error!("Failed to store block: {:?}", result.err());
+            return;
         }
+        self.record_chunk_heights(&block);
+        self.validator_sets.apply_block(&block);
+    }
+
+    // None until a block including a chunk of the shard is known
+    pub fn last_chunk_height(&self, shard_index: u32) -> Option<u64> {
+        self.last_chunk_heights.get(&shard_index).copied()
+    }
+
+    pub fn validator_sets(&self) -> &ValidatorSets {
+        &self.validator_sets
     }
 
     pub fn get_last_block(&self) -> Option<Block> {
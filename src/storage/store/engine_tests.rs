@@ -1,18 +1,28 @@
 #[cfg(test)]
 mod tests {
+    use crate::consensus::validator_set::MIN_ACTIVATION_DELAY;
     use crate::proto::ShardChunk;
     use crate::proto::{self, ReactionType};
     use crate::proto::{HubEvent, ValidatorMessage};
     use crate::proto::{OnChainEvent, OnChainEventType};
-    use crate::storage::db::RocksDbTransactionBatch;
-    use crate::storage::store::engine::{MempoolMessage, ShardEngine};
+    use crate::storage::db::{RocksDB, RocksDbTransactionBatch};
+    use crate::storage::store::engine::{
+        BlockEngine, ChunkDivergence, EngineError, MempoolMessage, ShardEngine,
+    };
+    use crate::storage::store::shard;
+    use crate::storage::store::snapshot::{self, SnapshotError};
     use crate::storage::store::test_helper;
     use crate::storage::store::test_helper::{register_user, FID2_FOR_TEST, FID_FOR_TEST};
+    use crate::storage::store::BlockStore;
     use crate::storage::trie::merkle_trie;
     use crate::storage::trie::merkle_trie::TrieKey;
+    use crate::storage::trie::node_cache::TrieNodeCache;
     use crate::utils::factory::{self, events_factory, messages_factory, time, username_factory};
     use ed25519_dalek::SigningKey;
+    use libp2p::identity::ed25519::Keypair;
     use prost::Message as _;
+    use std::sync::Arc;
+    use std::time::Duration;
     use tracing_subscriber::EnvFilter;
 
     fn trie_ctx() -> &'static mut merkle_trie::Context<'static> {
@@ -170,6 +180,7 @@ mod tests {
             vec![MempoolMessage::ValidatorMessage(ValidatorMessage {
                 on_chain_event: Some(events_factory::create_onchain_event(FID_FOR_TEST)),
                 fname_transfer: None,
+                validator_set_update: None,
             })],
         );
 
@@ -181,7 +192,6 @@ mod tests {
     }
 
     #[tokio::test]
-    #[should_panic(expected = "State change commit failed: merkle trie root hash mismatch")]
     async fn test_engine_commit_with_mismatched_hash() {
         let (mut engine, _tmpdir) = test_helper::new_engine();
         let mut state_change = engine.propose_state_change(1, vec![]);
@@ -202,7 +212,25 @@ mod tests {
 
         chunk.header.as_mut().unwrap().shard_root = invalid_hash;
 
+        let root_before = engine.trie_root_hash();
+        let height_before = engine.get_confirmed_height();
         engine.commit_shard_chunk(&chunk);
+
+        // The shard halts instead of crashing, and nothing is committed
+        assert!(engine.is_halted());
+        let halt = engine.get_senders().health.halted().unwrap();
+        assert_eq!(halt.reason, "merkle trie root hash mismatch");
+        assert_eq!(engine.trie_root_hash(), root_before);
+        assert_eq!(engine.get_confirmed_height(), height_before);
+
+        // Until it's resumed, nothing else is proposed, validated or committed
+        let state_change = engine.propose_state_change(1, vec![]);
+        assert!(state_change.transactions.is_empty());
+        assert_eq!(state_change.new_state_root, root_before);
+        assert!(!engine.validate_state_change(&state_change));
+
+        engine.get_senders().health.resume();
+        assert!(engine.validate_state_change(&state_change));
     }
 
     #[tokio::test]
@@ -670,6 +698,7 @@ mod tests {
             vec![MempoolMessage::ValidatorMessage(ValidatorMessage {
                 on_chain_event: Some(onchain_event.clone()),
                 fname_transfer: None,
+                validator_set_update: None,
             })],
         );
         assert_eq!(1, state_change.shard_id);
@@ -997,6 +1026,7 @@ mod tests {
             vec![MempoolMessage::ValidatorMessage(ValidatorMessage {
                 on_chain_event: None,
                 fname_transfer: Some(fname_transfer.clone()),
+                validator_set_update: None,
             })],
         );
         test_helper::validate_and_commit_state_change(&mut engine, &state_change);
@@ -1052,6 +1082,7 @@ mod tests {
             vec![MempoolMessage::ValidatorMessage(ValidatorMessage {
                 on_chain_event: None,
                 fname_transfer: Some(transfer),
+                validator_set_update: None,
             })],
         );
         test_helper::validate_and_commit_state_change(&mut engine, &state_change);
@@ -1209,4 +1240,773 @@ mod tests {
         let result = engine.simulate_message(&message);
         assert_eq!(result.is_ok(), true);
     }
+
+    #[tokio::test]
+    async fn test_message_status_is_indexed_on_commit() {
+        let (mut engine, _tmpdir) = test_helper::new_engine();
+        let mut status_rx = engine.get_senders().message_status_tx.subscribe();
+
+        let message = default_message("msg1");
+        assert!(engine.get_message_status(&message.hash).unwrap().is_none());
+
+        let chunk = assert_commit_fails(&mut engine, &message).await;
+        let status = engine.get_message_status(&message.hash).unwrap().unwrap();
+        assert_eq!(status.outcome, proto::MessageOutcome::Invalid as i32);
+        assert_eq!(status.reason, "missing_fid");
+        assert_eq!(
+            status.block_number,
+            chunk.header.unwrap().height.unwrap().block_number
+        );
+        assert_eq!(status_rx.try_recv().unwrap(), status);
+
+        register_user(FID_FOR_TEST, test_helper::default_signer(), &mut engine).await;
+        let chunk = commit_message(&mut engine, &message).await;
+        let status = engine.get_message_status(&message.hash).unwrap().unwrap();
+        assert_eq!(status.outcome, proto::MessageOutcome::Merged as i32);
+        assert_eq!(status.shard_id, 1);
+        assert_eq!(status.transaction_index, 0);
+        assert_eq!(status.reason, "");
+        assert_eq!(
+            status.block_number,
+            chunk.header.unwrap().height.unwrap().block_number
+        );
+
+        // A duplicate inclusion doesn't overwrite the merged status
+        let state_change =
+            engine.propose_state_change(1, vec![MempoolMessage::UserMessage(message.clone())]);
+        test_helper::validate_and_commit_state_change(&mut engine, &state_change);
+        assert_eq!(
+            engine.get_message_status(&message.hash).unwrap().unwrap(),
+            status
+        );
+    }
+
+    #[tokio::test]
+    async fn test_committed_messages_are_not_proposed_again() {
+        let (mut engine, _tmpdir) = test_helper::new_engine();
+        register_user(FID_FOR_TEST, test_helper::default_signer(), &mut engine).await;
+
+        let committed = default_message("msg1");
+        let pending = default_message("msg2");
+        commit_message(&mut engine, &committed).await;
+
+        // Queued by a validator that didn't propose the chunk, and gossiped twice
+        let messages_tx = engine.messages_tx();
+        for message in [&committed, &pending, &pending] {
+            messages_tx
+                .send(MempoolMessage::UserMessage(message.clone()))
+                .await
+                .unwrap();
+        }
+
+        let messages = engine
+            .wait_for_messages(Duration::ZERO, Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        assert!(matches!(&messages[0], MempoolMessage::UserMessage(msg) if *msg == pending));
+    }
+
+    #[tokio::test]
+    async fn test_receipts_are_committed_with_transactions() {
+        let (mut engine, _tmpdir) = test_helper::new_engine();
+
+        let message = default_message("msg1");
+        let chunk = assert_commit_fails(&mut engine, &message).await;
+        assert_eq!(
+            chunk.transactions[0].receipts,
+            vec![proto::MessageReceipt {
+                hash: message.hash.clone(),
+                outcome: proto::MessageOutcome::Invalid as i32,
+                reason: "missing_fid".to_string(),
+            }]
+        );
+
+        register_user(FID_FOR_TEST, test_helper::default_signer(), &mut engine).await;
+        let chunk = commit_message(&mut engine, &message).await;
+        assert_eq!(
+            chunk.transactions[0].receipts,
+            vec![proto::MessageReceipt {
+                hash: message.hash.clone(),
+                outcome: proto::MessageOutcome::Merged as i32,
+                reason: "".to_string(),
+            }]
+        );
+
+        let stored_chunk = engine.get_last_shard_chunk().unwrap();
+        assert_eq!(
+            stored_chunk.transactions[0].receipts,
+            chunk.transactions[0].receipts
+        );
+
+        let duplicate = MempoolMessage::UserMessage(message.clone());
+        let mut state_change = engine.propose_state_change(1, vec![duplicate]);
+        let receipt = &state_change.transactions[0].receipts[0];
+        assert_eq!(receipt.outcome, proto::MessageOutcome::MergeConflict as i32);
+
+        // Validators reject a proposal whose receipts don't match the replay
+        state_change.transactions[0].receipts[0].outcome = proto::MessageOutcome::Merged as i32;
+        assert!(!engine.validate_state_change(&state_change));
+    }
+
+    #[tokio::test]
+    async fn test_reads_as_of_block_number() {
+        let timestamp = messages_factory::farcaster_time();
+        let (mut engine, _tmpdir) = test_helper::new_engine();
+        test_helper::register_user(FID_FOR_TEST, test_helper::default_signer(), &mut engine).await;
+
+        let cast1 =
+            messages_factory::casts::create_cast_add(FID_FOR_TEST, "msg1", Some(timestamp), None);
+        let cast2 = messages_factory::casts::create_cast_add(
+            FID_FOR_TEST,
+            "msg2",
+            Some(timestamp + 1),
+            None,
+        );
+        let remove_cast1 = messages_factory::casts::create_cast_remove(
+            FID_FOR_TEST,
+            &cast1.hash,
+            Some(timestamp + 2),
+            None,
+        );
+
+        let chunk1 = commit_message(&mut engine, &cast1).await;
+        let chunk2 = commit_message(&mut engine, &cast2).await;
+        commit_message(&mut engine, &remove_cast1).await;
+
+        let height1 = chunk1.header.as_ref().unwrap().height.unwrap().block_number;
+        let height2 = chunk2.header.as_ref().unwrap().height.unwrap().block_number;
+
+        let hashes = |messages: Vec<proto::Message>| {
+            messages
+                .into_iter()
+                .map(|message| message.hash)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            hashes(engine.get_casts_by_fid_at(FID_FOR_TEST, None).unwrap()),
+            vec![cast2.hash.clone()]
+        );
+        assert_eq!(
+            hashes(
+                engine
+                    .get_casts_by_fid_at(FID_FOR_TEST, Some(height1))
+                    .unwrap()
+            ),
+            vec![cast1.hash.clone()]
+        );
+        assert_eq!(
+            hashes(
+                engine
+                    .get_casts_by_fid_at(FID_FOR_TEST, Some(height2))
+                    .unwrap()
+            ),
+            vec![cast1.hash.clone(), cast2.hash.clone()]
+        );
+        assert_eq!(
+            hashes(
+                engine
+                    .get_messages_by_fid_at(
+                        FID_FOR_TEST,
+                        proto::MessageType::CastRemove,
+                        Some(height2)
+                    )
+                    .unwrap()
+            ),
+            Vec::<Vec<u8>>::new()
+        );
+
+        assert_eq!(
+            engine.get_state_root_at(height2).unwrap().unwrap(),
+            chunk2.header.unwrap().shard_root
+        );
+        assert!(engine.get_state_root_at(height2 + 10).unwrap().is_none());
+        assert!(engine
+            .get_casts_by_fid_at(FID_FOR_TEST, Some(height2 + 10))
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_commit_replayed_state_change() {
+        let (mut engine, _tmpdir) = test_helper::new_engine();
+        register_user(FID_FOR_TEST, test_helper::default_signer(), &mut engine).await;
+        let mut event_rx = engine.get_senders().events_tx.subscribe();
+
+        let timestamp = messages_factory::farcaster_time();
+        let cast1 =
+            messages_factory::casts::create_cast_add(FID_FOR_TEST, "msg1", Some(timestamp), None);
+        let cast2 = messages_factory::casts::create_cast_add(
+            FID_FOR_TEST,
+            "msg2",
+            Some(timestamp + 1),
+            None,
+        );
+
+        // Nothing was committed since the proposal was replayed, so it's committed as is
+        let (state_change, replayed) = engine
+            .propose_replayed_state_change(1, vec![MempoolMessage::UserMessage(cast1.clone())]);
+        let height = engine.get_confirmed_height();
+        let chunk =
+            test_helper::state_change_to_shard_chunk(1, height.block_number + 1, &state_change);
+        engine.commit_replayed_shard_chunk(&chunk, replayed);
+
+        assert_eq!(engine.trie_root_hash(), state_change.new_state_root);
+        assert!(engine.trie_key_exists(trie_ctx(), &TrieKey::for_message(&cast1)));
+        assert_merge_event(&event_rx.try_recv().unwrap(), &cast1);
+        assert_eq!(
+            engine
+                .get_message_status(&cast1.hash)
+                .unwrap()
+                .unwrap()
+                .outcome,
+            proto::MessageOutcome::Merged as i32
+        );
+        assert_eq!(
+            engine.get_confirmed_height().block_number,
+            height.block_number + 1
+        );
+
+        // An empty chunk is committed after validation, so the change is replayed again on commit
+        let state_change =
+            engine.propose_state_change(1, vec![MempoolMessage::UserMessage(cast2.clone())]);
+        let replayed = engine.replay_state_change(&state_change).unwrap();
+        let empty_state_change = engine.propose_state_change(1, vec![]);
+        test_helper::validate_and_commit_state_change(&mut engine, &empty_state_change);
+
+        let height = engine.get_confirmed_height();
+        let chunk =
+            test_helper::state_change_to_shard_chunk(1, height.block_number + 1, &state_change);
+        engine.commit_replayed_shard_chunk(&chunk, replayed);
+
+        assert_eq!(engine.trie_root_hash(), state_change.new_state_root);
+        assert!(engine.trie_key_exists(trie_ctx(), &TrieKey::for_message(&cast2)));
+        assert_merge_event(&event_rx.try_recv().unwrap(), &cast2);
+        assert_eq!(
+            engine.get_confirmed_height().block_number,
+            height.block_number + 1
+        );
+    }
+
+    #[tokio::test]
+    async fn test_parallel_replay_matches_sequential_replay() {
+        let fid3 = FID2_FOR_TEST + 1;
+        let (mut sequential_engine, _tmpdir1) = test_helper::new_engine();
+        let (mut parallel_engine, _tmpdir2) = test_helper::new_engine();
+        parallel_engine.set_parallel_replay(true);
+        for engine in [&mut sequential_engine, &mut parallel_engine] {
+            for fid in [FID_FOR_TEST, FID2_FOR_TEST, fid3] {
+                register_user(fid, test_helper::default_signer(), engine).await;
+            }
+        }
+        assert_eq!(
+            sequential_engine.trie_root_hash(),
+            parallel_engine.trie_root_hash()
+        );
+
+        let timestamp = messages_factory::farcaster_time();
+        let mut messages = vec![];
+        for fid in [FID_FOR_TEST, FID2_FOR_TEST, fid3] {
+            let cast1 =
+                messages_factory::casts::create_cast_add(fid, "msg1", Some(timestamp), None);
+            let cast2 =
+                messages_factory::casts::create_cast_add(fid, "msg2", Some(timestamp + 1), None);
+            let remove = messages_factory::casts::create_cast_remove(
+                fid,
+                &cast1.hash,
+                Some(timestamp + 2),
+                None,
+            );
+            messages.extend([cast1, cast2, remove].map(MempoolMessage::UserMessage));
+        }
+        // A system message makes its transaction replay on its own, between the parallel ones
+        messages.push(MempoolMessage::ValidatorMessage(ValidatorMessage {
+            on_chain_event: Some(events_factory::create_onchain_event(FID2_FOR_TEST)),
+            fname_transfer: None,
+            validator_set_update: None,
+        }));
+        // Invalid, the fid is not registered
+        let unregistered_cast =
+            messages_factory::casts::create_cast_add(fid3 + 1, "msg", Some(timestamp), None);
+        messages.push(MempoolMessage::UserMessage(unregistered_cast));
+
+        // Proposed in parallel, validated sequentially
+        let state_change = parallel_engine.propose_state_change(1, messages.clone());
+        assert_eq!(state_change.transactions.len(), 3);
+        assert!(sequential_engine.validate_state_change(&state_change));
+
+        // Proposed sequentially, validated in parallel
+        let state_change = sequential_engine.propose_state_change(1, messages);
+        assert!(parallel_engine.validate_state_change(&state_change));
+
+        let mut sequential_rx = sequential_engine.get_senders().events_tx.subscribe();
+        let mut parallel_rx = parallel_engine.get_senders().events_tx.subscribe();
+        test_helper::validate_and_commit_state_change(&mut sequential_engine, &state_change);
+        test_helper::validate_and_commit_state_change(&mut parallel_engine, &state_change);
+        assert_eq!(
+            sequential_engine.trie_root_hash(),
+            parallel_engine.trie_root_hash()
+        );
+
+        let mut sequential_events = vec![];
+        while let Ok(event) = sequential_rx.try_recv() {
+            sequential_events.push(event);
+        }
+        let mut parallel_events = vec![];
+        while let Ok(event) = parallel_rx.try_recv() {
+            parallel_events.push(event);
+        }
+        assert_eq!(sequential_events.len(), parallel_events.len());
+        for (sequential_event, parallel_event) in sequential_events.iter().zip(&parallel_events) {
+            assert_eq!(sequential_event.r#type, parallel_event.r#type);
+            assert_eq!(sequential_event.body, parallel_event.body);
+        }
+        assert!(parallel_events.windows(2).all(|w| w[0].id < w[1].id));
+    }
+
+    #[tokio::test]
+    async fn test_messages_with_invalid_signatures_are_not_merged() {
+        let (mut engine, _tmpdir) = test_helper::new_engine();
+        register_user(FID_FOR_TEST, test_helper::default_signer(), &mut engine).await;
+
+        let timestamp = messages_factory::farcaster_time();
+        let cast1 =
+            messages_factory::casts::create_cast_add(FID_FOR_TEST, "msg1", Some(timestamp), None);
+        let cast2 = messages_factory::casts::create_cast_add(
+            FID_FOR_TEST,
+            "msg2",
+            Some(timestamp + 1),
+            None,
+        );
+        let mut forged = messages_factory::casts::create_cast_add(
+            FID_FOR_TEST,
+            "msg3",
+            Some(timestamp + 2),
+            None,
+        );
+        forged.signature = cast1.signature.clone();
+
+        // Messages are verified on submit, and only valid ones are remembered
+        let verified_messages = engine.get_senders().verified_messages;
+        engine.simulate_message(&cast1).unwrap();
+        assert!(verified_messages.contains(&cast1));
+        assert_eq!(
+            engine.simulate_message(&forged).unwrap_err().to_string(),
+            "invalid message hash or signature"
+        );
+        assert!(!verified_messages.contains(&forged));
+
+        let state_change = engine.propose_state_change(
+            1,
+            vec![
+                MempoolMessage::UserMessage(cast1.clone()),
+                MempoolMessage::UserMessage(cast2.clone()),
+                MempoolMessage::UserMessage(forged.clone()),
+            ],
+        );
+        test_helper::validate_and_commit_state_change(&mut engine, &state_change);
+
+        assert!(verified_messages.contains(&cast2));
+        assert!(engine.trie_key_exists(trie_ctx(), &TrieKey::for_message(&cast1)));
+        assert!(engine.trie_key_exists(trie_ctx(), &TrieKey::for_message(&cast2)));
+        assert!(!engine.trie_key_exists(trie_ctx(), &TrieKey::for_message(&forged)));
+        let status = engine.get_message_status(&forged.hash).unwrap().unwrap();
+        assert_eq!(status.outcome, proto::MessageOutcome::Invalid as i32);
+        assert_eq!(status.reason, "invalid message hash or signature");
+    }
+
+    #[tokio::test]
+    async fn test_read_only_engine() {
+        let (mut engine, _tmpdir) = test_helper::new_engine();
+        register_user(FID_FOR_TEST, test_helper::default_signer(), &mut engine).await;
+
+        let message = default_message("msg1");
+        assert!(matches!(
+            engine.merge_synced_messages(vec![MempoolMessage::UserMessage(message.clone())]),
+            Err(EngineError::NotReadOnly)
+        ));
+
+        engine.set_read_only(true);
+        let root_before = engine.trie_root_hash();
+        let state_change =
+            engine.propose_state_change(1, vec![MempoolMessage::UserMessage(message.clone())]);
+        assert!(state_change.transactions.is_empty());
+        assert_eq!(state_change.new_state_root, root_before);
+        assert!(!engine.validate_state_change(&state_change));
+        assert!(!engine.is_halted());
+
+        let merged = engine
+            .merge_synced_messages(vec![MempoolMessage::UserMessage(message)])
+            .unwrap();
+        assert_eq!(merged, 1);
+        assert_ne!(engine.trie_root_hash(), root_before);
+    }
+
+    #[tokio::test]
+    async fn test_check_consistency() {
+        let (mut engine, _tmpdir) = test_helper::new_engine();
+        assert!(engine.check_consistency().is_ok());
+
+        register_user(FID_FOR_TEST, test_helper::default_signer(), &mut engine).await;
+        let chunk = commit_message(&mut engine, &default_message("msg1")).await;
+        assert!(engine.check_consistency().is_ok());
+
+        // The chunk is stored along with the state it produced
+        let stored_chunk = engine.get_last_shard_chunk().unwrap();
+        assert_eq!(stored_chunk, chunk);
+        assert_eq!(
+            stored_chunk.header.unwrap().shard_root,
+            engine.trie_root_hash()
+        );
+
+        // State that moved past the last chunk is detected
+        engine.set_read_only(true);
+        engine
+            .merge_synced_messages(vec![MempoolMessage::UserMessage(default_message("msg2"))])
+            .unwrap();
+        let block_number = chunk.header.unwrap().height.unwrap().block_number;
+        assert!(matches!(
+            engine.check_consistency(),
+            Err(EngineError::InconsistentState(height)) if height == block_number
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rollback_to() {
+        let (mut engine, _tmpdir) = test_helper::new_engine();
+        register_user(FID_FOR_TEST, test_helper::default_signer(), &mut engine).await;
+
+        let timestamp = messages_factory::farcaster_time();
+        let cast1 =
+            messages_factory::casts::create_cast_add(FID_FOR_TEST, "msg1", Some(timestamp), None);
+        let cast2 = messages_factory::casts::create_cast_add(
+            FID_FOR_TEST,
+            "msg2",
+            Some(timestamp + 1),
+            None,
+        );
+        let remove1 = messages_factory::casts::create_cast_remove(
+            FID_FOR_TEST,
+            &cast1.hash,
+            Some(timestamp + 2),
+            None,
+        );
+
+        let chunk = commit_message(&mut engine, &cast1).await;
+        let block_number = chunk.header.unwrap().height.unwrap().block_number;
+        let root = engine.trie_root_hash();
+        let events_count = HubEvent::get_events(engine.db.clone(), 0, None, None)
+            .unwrap()
+            .events
+            .len();
+
+        commit_message(&mut engine, &cast2).await;
+        commit_message(&mut engine, &remove1).await;
+        assert!(!engine.trie_key_exists(trie_ctx(), &TrieKey::for_message(&cast1)));
+
+        // Can't roll forward
+        assert!(engine.rollback_to(block_number + 3).is_err());
+
+        engine.rollback_to(block_number).unwrap();
+        assert_eq!(engine.trie_root_hash(), root);
+        assert_eq!(engine.get_confirmed_height().block_number, block_number);
+        assert!(engine.trie_key_exists(trie_ctx(), &TrieKey::for_message(&cast1)));
+        assert!(!engine.trie_key_exists(trie_ctx(), &TrieKey::for_message(&cast2)));
+        assert_eq!(
+            engine
+                .get_casts_by_fid(FID_FOR_TEST)
+                .unwrap()
+                .messages_bytes
+                .len(),
+            1
+        );
+        assert!(engine.get_message_status(&cast2.hash).unwrap().is_none());
+        assert_eq!(
+            HubEvent::get_events(engine.db.clone(), 0, None, None)
+                .unwrap()
+                .events
+                .len(),
+            events_count
+        );
+
+        // Consensus continues from the next block
+        let chunk = commit_message(&mut engine, &cast2).await;
+        assert_eq!(
+            chunk.header.unwrap().height.unwrap().block_number,
+            block_number + 1
+        );
+        assert!(engine.trie_key_exists(trie_ctx(), &TrieKey::for_message(&cast2)));
+        assert!(engine.check_consistency().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_export_and_import() {
+        let (mut engine, _tmpdir) = test_helper::new_engine();
+        register_user(FID_FOR_TEST, test_helper::default_signer(), &mut engine).await;
+        let keypair = Keypair::generate();
+        let validator_sets = test_helper::validator_sets(&keypair);
+
+        // Snapshots are only imported if their chunk was decided by the validators
+        let uncertified_dir = tempfile::TempDir::new().unwrap();
+        snapshot::export_snapshot_to_dir(&engine.db, 1, uncertified_dir.path()).unwrap();
+
+        let cast = default_message("msg1");
+        let state_change =
+            engine.propose_state_change(1, vec![MempoolMessage::UserMessage(cast.clone())]);
+        let mut chunk = test_helper::state_change_to_shard_chunk(
+            1,
+            engine.get_confirmed_height().block_number + 1,
+            &state_change,
+        );
+        test_helper::certify_shard_chunk(&mut chunk, &keypair);
+        engine.commit_shard_chunk(&chunk);
+
+        let snapshot_dir = tempfile::TempDir::new().unwrap();
+        let manifest =
+            snapshot::export_snapshot_to_dir(&engine.db, 1, snapshot_dir.path()).unwrap();
+        let header = chunk.header.unwrap();
+        assert_eq!(manifest.shard_id, 1);
+        assert_eq!(manifest.block_number, header.height.unwrap().block_number);
+        assert_eq!(manifest.shard_root, engine.trie_root_hash());
+        assert_eq!(manifest.chunk_hash, chunk.hash);
+
+        // Committed after the snapshot, so not part of it
+        commit_message(&mut engine, &default_message("msg2")).await;
+
+        let db_dir = tempfile::TempDir::new().unwrap();
+        let db = Arc::new(RocksDB::new(db_dir.path().to_str().unwrap()));
+        db.open().unwrap();
+        assert!(matches!(
+            snapshot::import_snapshot_from_dir(
+                &db,
+                1,
+                uncertified_dir.path(),
+                16,
+                &validator_sets,
+                None
+            ),
+            Err(SnapshotError::InvalidCertificate(_))
+        ));
+        assert_eq!(shard::get_current_height(&db).unwrap(), None);
+        // Nodes cached for whatever was in the db before are dropped
+        let node_cache = TrieNodeCache::new(1024);
+        node_cache.insert(vec![1], vec![2], node_cache.generation());
+        let imported = snapshot::import_snapshot_from_dir(
+            &db,
+            1,
+            snapshot_dir.path(),
+            16,
+            &validator_sets,
+            Some(&node_cache),
+        )
+        .unwrap();
+        assert_eq!(imported, manifest);
+        assert_eq!(node_cache.get(&[1]), None);
+        assert_eq!(
+            shard::get_current_height(&db).unwrap(),
+            Some(manifest.block_number)
+        );
+        assert!(
+            HubEvent::get_events(db.clone(), 0, None, None)
+                .unwrap()
+                .events
+                .len()
+                > 0
+        );
+        assert!(matches!(
+            snapshot::import_snapshot_from_dir(
+                &db,
+                1,
+                snapshot_dir.path(),
+                16,
+                &validator_sets,
+                None
+            ),
+            Err(SnapshotError::DbNotEmpty)
+        ));
+
+        // A snapshot that doesn't match its manifest is rejected and leaves the db empty
+        db.clear().unwrap();
+        let manifest_path = snapshot_dir.path().join("shard1").join("manifest.pb");
+        let tampered = proto::SnapshotManifest {
+            shard_root: vec![1; 20],
+            ..manifest.clone()
+        };
+        std::fs::write(&manifest_path, tampered.encode_to_vec()).unwrap();
+        assert!(matches!(
+            snapshot::import_snapshot_from_dir(
+                &db,
+                1,
+                snapshot_dir.path(),
+                16,
+                &validator_sets,
+                None
+            ),
+            Err(SnapshotError::ManifestMismatch(_))
+        ));
+        assert_eq!(shard::get_current_height(&db).unwrap(), None);
+
+        // An incomplete snapshot has no manifest
+        std::fs::remove_file(&manifest_path).unwrap();
+        assert!(matches!(
+            snapshot::import_snapshot_from_dir(
+                &db,
+                1,
+                snapshot_dir.path(),
+                16,
+                &validator_sets,
+                None
+            ),
+            Err(SnapshotError::MissingManifest)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_import_needs_the_blocks_before_it() {
+        let (mut engine, _tmpdir) = test_helper::new_engine();
+        register_user(FID_FOR_TEST, test_helper::default_signer(), &mut engine).await;
+        let keypair = Keypair::generate();
+        let validator_sets = test_helper::validator_sets(&keypair);
+
+        // Far enough past the genesis that blocks could have changed the validators by then
+        let block_number = MIN_ACTIVATION_DELAY + 1;
+        let state_change = engine.propose_state_change(
+            1,
+            vec![MempoolMessage::UserMessage(default_message("msg1"))],
+        );
+        let mut chunk = test_helper::state_change_to_shard_chunk(1, block_number, &state_change);
+        test_helper::certify_shard_chunk(&mut chunk, &keypair);
+        engine.commit_shard_chunk(&chunk);
+        let snapshot_dir = tempfile::TempDir::new().unwrap();
+        snapshot::export_snapshot_to_dir(&engine.db, 1, snapshot_dir.path()).unwrap();
+
+        let db_dir = tempfile::TempDir::new().unwrap();
+        let db = Arc::new(RocksDB::new(db_dir.path().to_str().unwrap()));
+        db.open().unwrap();
+        assert!(matches!(
+            snapshot::import_snapshot_from_dir(
+                &db,
+                1,
+                snapshot_dir.path(),
+                16,
+                &validator_sets,
+                None
+            ),
+            Err(SnapshotError::UnknownValidatorSet)
+        ));
+        assert_eq!(shard::get_current_height(&db).unwrap(), None);
+
+        // A block with an earlier chunk of the shard rules out changes up to the snapshot
+        validator_sets.apply_block(&proto::Block {
+            header: Some(proto::BlockHeader {
+                height: Some(proto::Height::new(0, 1)),
+                ..Default::default()
+            }),
+            shard_chunks: vec![ShardChunk {
+                header: Some(proto::ShardHeader {
+                    height: Some(proto::Height::new(1, 1)),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        });
+        let imported = snapshot::import_snapshot_from_dir(
+            &db,
+            1,
+            snapshot_dir.path(),
+            16,
+            &validator_sets,
+            None,
+        )
+        .unwrap();
+        assert_eq!(imported.block_number, block_number);
+    }
+
+    #[tokio::test]
+    async fn test_find_divergence() {
+        let (mut engine, _tmpdir) = test_helper::new_engine();
+        register_user(FID_FOR_TEST, test_helper::default_signer(), &mut engine).await;
+        let cast = default_message("msg1");
+
+        let state_change =
+            engine.propose_state_change(1, vec![MempoolMessage::UserMessage(cast.clone())]);
+        let height = engine.get_confirmed_height();
+        let chunk =
+            test_helper::state_change_to_shard_chunk(1, height.block_number + 1, &state_change);
+        let root = engine.trie_root_hash();
+
+        assert_eq!(engine.find_divergence(&chunk).unwrap(), None);
+        assert_eq!(engine.trie_root_hash(), root);
+
+        let mut wrong_shard_root = chunk.clone();
+        wrong_shard_root.header.as_mut().unwrap().shard_root = vec![1; 20];
+        assert_eq!(
+            engine.find_divergence(&wrong_shard_root).unwrap(),
+            Some(ChunkDivergence::ShardRoot {
+                expected: vec![1; 20],
+                actual: state_change.new_state_root.clone(),
+            })
+        );
+
+        let mut wrong_account_root = chunk.clone();
+        wrong_account_root.transactions[0].account_root = vec![2; 20];
+        match engine.find_divergence(&wrong_account_root).unwrap() {
+            Some(ChunkDivergence::Transaction {
+                fid,
+                expected_account_root,
+                account_root,
+                trie_keys,
+                ..
+            }) => {
+                assert_eq!(fid, FID_FOR_TEST as u64);
+                assert_eq!(expected_account_root, vec![2; 20]);
+                assert_eq!(account_root, chunk.transactions[0].account_root);
+                assert!(trie_keys.contains(&TrieKey::for_message(&cast)));
+            }
+            divergence => panic!("Unexpected divergence: {:?}", divergence),
+        }
+        assert_eq!(engine.trie_root_hash(), root);
+
+        // Nothing was committed, the chunk can still be committed
+        engine.commit_shard_chunk(&chunk);
+        assert_eq!(engine.trie_root_hash(), state_change.new_state_root);
+    }
+
+    #[test]
+    fn test_block_engine_loads_last_chunk_heights() {
+        let db_dir = tempfile::TempDir::new().unwrap();
+        let db = Arc::new(RocksDB::new(db_dir.path().to_str().unwrap()));
+        db.open().unwrap();
+        let block_store = BlockStore::new(db);
+
+        // Only the first block has a chunk of the shard
+        for block_number in 1..=3 {
+            let shard_chunks = if block_number == 1 {
+                vec![ShardChunk {
+                    header: Some(proto::ShardHeader {
+                        height: Some(proto::Height::new(1, 7)),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }]
+            } else {
+                vec![]
+            };
+            let block = proto::Block {
+                header: Some(proto::BlockHeader {
+                    height: Some(proto::Height::new(0, block_number)),
+                    ..Default::default()
+                }),
+                shard_chunks,
+                ..Default::default()
+            };
+            block_store.put_block(block).unwrap();
+        }
+
+        let validator_sets = test_helper::validator_sets(&Keypair::generate());
+        let engine = BlockEngine::new(block_store, validator_sets);
+        assert_eq!(engine.last_chunk_height(1), Some(7));
+        assert_eq!(engine.last_chunk_height(2), None);
+    }
 }
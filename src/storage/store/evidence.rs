@@ -0,0 +1,161 @@
+use crate::core::error::HubError;
+use crate::proto::consensus_message::ConsensusMessage;
+use crate::proto::Evidence;
+use crate::storage::constants::RootPrefix;
+use crate::storage::db::{PageOptions, RocksDB, RocksdbError};
+use prost::Message;
+use std::sync::Arc;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum EvidenceStorageError {
+    #[error(transparent)]
+    RocksdbError(#[from] RocksdbError),
+
+    #[error(transparent)]
+    HubError(#[from] HubError),
+
+    #[error("Evidence missing consensus message")]
+    MissingMessage,
+}
+
+// Proposals sort after the votes of the same round
+const PROPOSAL_KIND: u8 = 2;
+
+fn make_evidence_key(evidence: &Evidence) -> Result<Vec<u8>, EvidenceStorageError> {
+    let (height, round, kind, signer) = match evidence
+        .first
+        .as_ref()
+        .and_then(|msg| msg.consensus_message.as_ref())
+    {
+        Some(ConsensusMessage::Vote(vote)) => {
+            (vote.height, vote.round, vote.r#type as u8, &vote.voter)
+        }
+        Some(ConsensusMessage::Proposal(proposal)) => (
+            proposal.height,
+            proposal.round,
+            PROPOSAL_KIND,
+            &proposal.proposer,
+        ),
+        None => return Err(EvidenceStorageError::MissingMessage),
+    };
+    let height = height.unwrap_or_default();
+
+    let mut key = vec![RootPrefix::Evidence as u8];
+    // One record per validator, height, round and message type, ordered by shard and height
+    key.extend_from_slice(&height.shard_index.to_be_bytes());
+    key.extend_from_slice(&height.block_number.to_be_bytes());
+    key.extend_from_slice(&(round.max(0) as u64).to_be_bytes());
+    key.push(kind);
+    key.extend_from_slice(signer);
+
+    Ok(key)
+}
+
+fn make_shard_prefix(shard_id: u32) -> Vec<u8> {
+    let mut key = vec![RootPrefix::Evidence as u8];
+    key.extend_from_slice(&shard_id.to_be_bytes());
+    key
+}
+
+/// Conflicting messages signed by validators. Only the first conflict of each validator, height,
+/// round and message type is kept. Clones share the same db.
+#[derive(Clone)]
+pub struct EvidenceStore {
+    db: Arc<RocksDB>,
+}
+
+impl EvidenceStore {
+    pub fn new(db: Arc<RocksDB>) -> EvidenceStore {
+        EvidenceStore { db }
+    }
+
+    // Returns whether the evidence wasn't stored yet
+    pub fn put_evidence(&self, evidence: &Evidence) -> Result<bool, EvidenceStorageError> {
+        let key = make_evidence_key(evidence)?;
+        if self.db.get(&key)?.is_some() {
+            return Ok(false);
+        }
+        self.db.put(&key, &evidence.encode_to_vec())?;
+        Ok(true)
+    }
+
+    pub fn get_evidence(
+        &self,
+        shard_id: Option<u32>,
+    ) -> Result<Vec<Evidence>, EvidenceStorageError> {
+        let (start, stop) = match shard_id {
+            Some(shard_id) => (make_shard_prefix(shard_id), make_shard_prefix(shard_id + 1)),
+            None => (
+                vec![RootPrefix::Evidence as u8],
+                vec![RootPrefix::Evidence as u8 + 1],
+            ),
+        };
+        let mut evidence = vec![];
+        self.db.for_each_iterator_by_prefix(
+            Some(start),
+            Some(stop),
+            &PageOptions::default(),
+            |_key, value| {
+                evidence.push(Evidence::decode(value).map_err(HubError::from)?);
+                Ok(false)
+            },
+        )?;
+        Ok(evidence)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{self, Height, VoteType};
+
+    fn vote(shard_index: u32, round: i64, value: u8) -> proto::ConsensusMessage {
+        proto::ConsensusMessage {
+            consensus_message: Some(ConsensusMessage::Vote(proto::Vote {
+                r#type: VoteType::Prevote as i32,
+                height: Some(Height::new(shard_index, 3)),
+                round,
+                value: Some(proto::ShardHash {
+                    shard_index,
+                    hash: vec![value],
+                }),
+                voter: vec![7; 32],
+            })),
+            signature: vec![value],
+        }
+    }
+
+    fn evidence(shard_index: u32, round: i64) -> Evidence {
+        Evidence {
+            first: Some(vote(shard_index, round, 1)),
+            second: Some(vote(shard_index, round, 2)),
+            detected_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_evidence_is_stored_once() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let db = RocksDB::new(dir.path().join("evidence").to_str().unwrap());
+        db.open().unwrap();
+        let store = EvidenceStore::new(Arc::new(db));
+
+        assert!(store.put_evidence(&evidence(1, 0)).unwrap());
+        assert!(!store.put_evidence(&evidence(1, 0)).unwrap());
+        assert!(store.put_evidence(&evidence(1, 1)).unwrap());
+        assert!(store.put_evidence(&evidence(2, 0)).unwrap());
+        assert!(store
+            .put_evidence(&Evidence {
+                first: None,
+                second: None,
+                detected_at: 0
+            })
+            .is_err());
+
+        assert_eq!(store.get_evidence(None).unwrap().len(), 3);
+        let shard1 = store.get_evidence(Some(1)).unwrap();
+        assert_eq!(shard1, vec![evidence(1, 0), evidence(1, 1)]);
+        assert!(store.get_evidence(Some(3)).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,51 @@
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ShardHalt {
+    // The block that could not be proposed or committed
+    pub block_number: u64,
+    pub reason: String,
+    pub halted_at: SystemTime,
+}
+
+/// Whether a shard's engine is still applying chunks. A shard halts instead of crashing the
+/// process when a chunk can't be committed, so the other shards and the read rpcs keep serving
+/// while an operator rolls back or resyncs it. Clones share the same state.
+#[derive(Clone, Default)]
+pub struct ShardHealth {
+    halt: Arc<Mutex<Option<ShardHalt>>>,
+}
+
+impl ShardHealth {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Only the first failure is kept, anything after it is likely a consequence of it. Returns
+    // whether the shard wasn't halted yet.
+    pub fn halt(&self, block_number: u64, reason: String) -> bool {
+        let mut halt = self.halt.lock().unwrap();
+        if halt.is_some() {
+            return false;
+        }
+        *halt = Some(ShardHalt {
+            block_number,
+            reason,
+            halted_at: SystemTime::now(),
+        });
+        true
+    }
+
+    pub fn halted(&self) -> Option<ShardHalt> {
+        self.halt.lock().unwrap().clone()
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halt.lock().unwrap().is_some()
+    }
+
+    pub fn resume(&self) {
+        self.halt.lock().unwrap().take();
+    }
+}
@@ -0,0 +1,32 @@
+use crate::core::error::HubError;
+use crate::proto::HeightIndexEntry;
+use crate::storage::constants::RootPrefix;
+use crate::storage::db::{RocksDB, RocksDbTransactionBatch};
+use prost::Message;
+
+fn make_height_index_key(block_number: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(1 + 8);
+
+    key.push(RootPrefix::HeightIndex as u8);
+    key.extend_from_slice(&block_number.to_be_bytes());
+
+    key
+}
+
+pub fn put_height_index(
+    txn: &mut RocksDbTransactionBatch,
+    block_number: u64,
+    entry: &HeightIndexEntry,
+) {
+    txn.put(make_height_index_key(block_number), entry.encode_to_vec());
+}
+
+pub fn get_height_index(
+    db: &RocksDB,
+    block_number: u64,
+) -> Result<Option<HeightIndexEntry>, HubError> {
+    match db.get(&make_height_index_key(block_number))? {
+        None => Ok(None),
+        Some(bytes) => Ok(Some(HeightIndexEntry::decode(bytes.as_slice())?)),
+    }
+}
@@ -0,0 +1,54 @@
+use crate::core::error::HubError;
+use crate::proto::{MessageOutcome, MessageStatus};
+use crate::storage::constants::RootPrefix;
+use crate::storage::db::{RocksDB, RocksDbTransactionBatch};
+use prost::Message;
+
+fn make_message_status_key(hash: &[u8]) -> Vec<u8> {
+    let mut key = Vec::with_capacity(1 + hash.len());
+
+    key.push(RootPrefix::MessageStatus as u8);
+    key.extend_from_slice(hash);
+
+    key
+}
+
+pub fn get_message_status(db: &RocksDB, hash: &[u8]) -> Result<Option<MessageStatus>, HubError> {
+    match db.get(&make_message_status_key(hash))? {
+        None => Ok(None),
+        Some(bytes) => Ok(Some(MessageStatus::decode(bytes.as_slice())?)),
+    }
+}
+
+// Messages can be included more than once (e.g. if multiple validators had them in their
+// mempool). Once a message is merged, later inclusions are rejected as duplicates and must not
+// overwrite the merged status; only a prune or revoke can.
+pub fn put_message_status(
+    db: &RocksDB,
+    txn: &mut RocksDbTransactionBatch,
+    status: &MessageStatus,
+) -> Result<bool, HubError> {
+    let key = make_message_status_key(&status.hash);
+
+    let is_removal = status.outcome == MessageOutcome::Pruned as i32
+        || status.outcome == MessageOutcome::Revoked as i32;
+    if !is_removal {
+        let existing = match txn.batch.get(&key) {
+            Some(value) => value.clone(),
+            None => db.get(&key)?,
+        };
+        if let Some(bytes) = existing {
+            let existing = MessageStatus::decode(bytes.as_slice())?;
+            if existing.outcome == MessageOutcome::Merged as i32 {
+                return Ok(false);
+            }
+        }
+    }
+
+    txn.put(key, status.encode_to_vec());
+    Ok(true)
+}
+
+pub fn is_final_outcome(outcome: i32) -> bool {
+    outcome != MessageOutcome::Unknown as i32 && outcome != MessageOutcome::Pending as i32
+}
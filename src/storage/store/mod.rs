@@ -3,8 +3,14 @@ pub use self::block::*;
 pub mod account;
 pub mod block;
 pub mod engine;
+pub mod evidence;
+pub mod health;
+pub mod height_index;
+pub mod message_status;
 pub mod shard;
+pub mod snapshot;
 pub mod stores;
+pub mod undo_log;
 pub mod utils;
 
 pub(crate) mod test_helper;
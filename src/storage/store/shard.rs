@@ -2,7 +2,7 @@ use super::super::constants::PAGE_SIZE_MAX;
 use crate::core::error::HubError;
 use crate::proto::ShardChunk;
 use crate::storage::constants::RootPrefix;
-use crate::storage::db::{PageOptions, RocksDB, RocksdbError};
+use crate::storage::db::{PageOptions, RocksDB, RocksDbTransactionBatch, RocksdbError};
 use prost::Message;
 use std::sync::Arc;
 use thiserror::Error;
@@ -98,6 +98,26 @@ fn get_shard_page_by_prefix(
     })
 }
 
+pub fn get_first_shard_chunk(db: &RocksDB) -> Result<Option<ShardChunk>, ShardStorageError> {
+    let start_shard_key = make_shard_key(0);
+    let shard_page = get_shard_page_by_prefix(
+        db,
+        &PageOptions {
+            reverse: false,
+            page_size: Some(1),
+            page_token: None,
+        },
+        Some(start_shard_key),
+        None,
+    )?;
+
+    if shard_page.shard_chunks.len() > 1 {
+        return Err(ShardStorageError::TooManyShardsInResult);
+    }
+
+    Ok(shard_page.shard_chunks.get(0).cloned())
+}
+
 pub fn get_last_shard_chunk(db: &RocksDB) -> Result<Option<ShardChunk>, ShardStorageError> {
     let start_shard_key = make_shard_key(0);
     let shard_page = get_shard_page_by_prefix(
@@ -132,8 +152,7 @@ pub fn get_current_height(db: &RocksDB) -> Result<Option<u64>, ShardStorageError
     }
 }
 
-pub fn put_shard_chunk(db: &RocksDB, shard_chunk: &ShardChunk) -> Result<(), ShardStorageError> {
-    // TODO: We need to introduce a transaction model
+fn make_shard_chunk_key(shard_chunk: &ShardChunk) -> Result<Vec<u8>, ShardStorageError> {
     let header = shard_chunk
         .header
         .as_ref()
@@ -142,11 +161,26 @@ pub fn put_shard_chunk(db: &RocksDB, shard_chunk: &ShardChunk) -> Result<(), Sha
         .height
         .as_ref()
         .ok_or(ShardStorageError::ShardMissingHeight)?;
-    let primary_key = make_shard_key(height.block_number);
+    Ok(make_shard_key(height.block_number))
+}
+
+pub fn put_shard_chunk(db: &RocksDB, shard_chunk: &ShardChunk) -> Result<(), ShardStorageError> {
+    let primary_key = make_shard_chunk_key(shard_chunk)?;
     db.put(&primary_key, shard_chunk.encode_to_vec().as_slice())?;
     Ok(())
 }
 
+// Writes the chunk as part of a transaction, so it's committed atomically with the state it
+// produced
+pub fn put_shard_chunk_in_txn(
+    txn: &mut RocksDbTransactionBatch,
+    shard_chunk: &ShardChunk,
+) -> Result<(), ShardStorageError> {
+    let primary_key = make_shard_chunk_key(shard_chunk)?;
+    txn.put(primary_key, shard_chunk.encode_to_vec());
+    Ok(())
+}
+
 pub fn get_shard_chunks_in_range(
     db: &RocksDB,
     page_options: &PageOptions,
@@ -173,6 +207,18 @@ impl ShardStore {
         put_shard_chunk(&self.db, shard_chunk)
     }
 
+    pub fn put_shard_chunk_in_txn(
+        &self,
+        txn: &mut RocksDbTransactionBatch,
+        shard_chunk: &ShardChunk,
+    ) -> Result<(), ShardStorageError> {
+        put_shard_chunk_in_txn(txn, shard_chunk)
+    }
+
+    pub fn get_first_shard_chunk(&self) -> Result<Option<ShardChunk>, ShardStorageError> {
+        get_first_shard_chunk(&self.db)
+    }
+
     pub fn get_last_shard_chunk(&self) -> Result<Option<ShardChunk>, ShardStorageError> {
         get_last_shard_chunk(&self.db)
     }
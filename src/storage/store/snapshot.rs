@@ -0,0 +1,514 @@
+use crate::consensus::sync::{verify_synced_block, SyncError};
+use crate::consensus::validator_set::ValidatorSets;
+use crate::core::error::HubError;
+use crate::core::merkle::{transactions_root, EMPTY_ROOT};
+use crate::proto::hub_service_client::HubServiceClient;
+use crate::proto::shard_snapshot_response::Response as SnapshotResponse;
+use crate::proto::{
+    BlocksRequest, Height, ShardChunk, ShardHash, ShardSnapshotRequest, SnapshotBatch,
+    SnapshotKeyValue, SnapshotManifest,
+};
+use crate::storage::constants::RootPrefix;
+use crate::storage::db::{RocksDB, RocksdbError};
+use crate::storage::store::shard::{self, ShardStorageError};
+use crate::storage::store::{BlockStorageError, BlockStore};
+use crate::storage::trie::errors::TrieError;
+use crate::storage::trie::merkle_trie::MerkleTrie;
+use crate::storage::trie::node_cache::TrieNodeCache;
+use prost::Message;
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+use tracing::{info, warn};
+
+// Batches are flushed once their keys and values add up to this many bytes, which keeps them well
+// under the grpc message size limit
+const SNAPSHOT_BATCH_BYTES: usize = 1024 * 1024;
+
+// Blocks are downloaded from the snapshot's source this many at a time
+const BLOCK_SYNC_PAGE_SIZE: u64 = 100;
+
+const MANIFEST_FILE: &str = "manifest.pb";
+const DATA_FILE: &str = "data.bin";
+
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error(transparent)]
+    HubError(#[from] HubError),
+
+    #[error(transparent)]
+    RocksdbError(#[from] RocksdbError),
+
+    #[error(transparent)]
+    ShardStorageError(#[from] ShardStorageError),
+
+    #[error(transparent)]
+    BlockStorageError(#[from] BlockStorageError),
+
+    #[error(transparent)]
+    SyncError(#[from] SyncError),
+
+    #[error(transparent)]
+    TrieError(#[from] TrieError),
+
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+
+    #[error(transparent)]
+    DecodeError(#[from] prost::DecodeError),
+
+    #[error(transparent)]
+    RpcError(#[from] tonic::Status),
+
+    #[error(transparent)]
+    TransportError(#[from] tonic::transport::Error),
+
+    #[error("Shard has no committed chunks")]
+    NoShardChunks,
+
+    #[error("Database is not empty")]
+    DbNotEmpty,
+
+    #[error("Snapshot is missing its manifest")]
+    MissingManifest,
+
+    #[error("Snapshot does not match its manifest: {0}")]
+    ManifestMismatch(String),
+
+    #[error("Snapshot's shard chunk was not decided by the validators: {0}")]
+    InvalidCertificate(String),
+
+    #[error("Validator set at the snapshot's height is unknown, the blocks before it are needed")]
+    UnknownValidatorSet,
+
+    #[error("Snapshot export was cancelled")]
+    Cancelled,
+}
+
+// Undo log entries are only useful to the node that recorded them, a node bootstrapped from a
+// snapshot can't roll back past it anyway
+fn is_exported(key: &[u8]) -> bool {
+    key.first() != Some(&(RootPrefix::UndoLog as u8))
+}
+
+fn is_shard_chunk_key(key: &[u8]) -> bool {
+    key.first() == Some(&(RootPrefix::Shard as u8))
+}
+
+/// Passes every key of the shard's db, as of a single point in time, to `on_batch` and returns
+/// the manifest describing the snapshot. Shard chunks are committed atomically with their state,
+/// so the snapshot's last chunk is the height its trie corresponds to.
+pub fn export_snapshot<F>(
+    db: &RocksDB,
+    shard_id: u32,
+    mut on_batch: F,
+) -> Result<SnapshotManifest, SnapshotError>
+where
+    F: FnMut(SnapshotBatch) -> Result<(), SnapshotError>,
+{
+    let mut batch = SnapshotBatch::default();
+    let mut batch_bytes = 0;
+    let mut num_keys = 0;
+    let mut last_chunk = None;
+    let mut batch_error = None;
+
+    db.for_each_in_snapshot(|key, value| {
+        if !is_exported(key) {
+            return Ok(false);
+        }
+        if is_shard_chunk_key(key) {
+            // Keys are sorted, the last chunk has the highest block number
+            last_chunk = Some(value.to_vec());
+        }
+
+        num_keys += 1;
+        batch_bytes += key.len() + value.len();
+        batch.values.push(SnapshotKeyValue {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        });
+        if batch_bytes >= SNAPSHOT_BATCH_BYTES {
+            batch_bytes = 0;
+            if let Err(err) = on_batch(std::mem::take(&mut batch)) {
+                batch_error = Some(err);
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    })?;
+
+    if let Some(err) = batch_error {
+        return Err(err);
+    }
+    if !batch.values.is_empty() {
+        on_batch(batch)?;
+    }
+
+    let Some(last_chunk) = last_chunk else {
+        return Err(SnapshotError::NoShardChunks);
+    };
+    let chunk = ShardChunk::decode(last_chunk.as_slice())?;
+    let header = chunk.header.unwrap_or_default();
+    Ok(SnapshotManifest {
+        shard_id,
+        block_number: header.height.unwrap_or_default().block_number,
+        shard_root: header.shard_root,
+        chunk_hash: chunk.hash,
+        votes: chunk.votes,
+        num_keys,
+    })
+}
+
+fn shard_snapshot_dir(dir: &Path, shard_id: u32) -> PathBuf {
+    dir.join(format!("shard{}", shard_id))
+}
+
+/// Writes a snapshot of the shard to `<dir>/shard<shard_id>`. The data file is a sequence of
+/// `SnapshotBatch`es, each prefixed by its length as a big endian u32. The manifest is written
+/// last, so a snapshot without one is incomplete.
+pub fn export_snapshot_to_dir(
+    db: &RocksDB,
+    shard_id: u32,
+    dir: &Path,
+) -> Result<SnapshotManifest, SnapshotError> {
+    let dir = shard_snapshot_dir(dir, shard_id);
+    fs::create_dir_all(&dir)?;
+    let manifest_path = dir.join(MANIFEST_FILE);
+    if manifest_path.exists() {
+        fs::remove_file(&manifest_path)?;
+    }
+
+    let mut data = BufWriter::new(fs::File::create(dir.join(DATA_FILE))?);
+    let manifest = export_snapshot(db, shard_id, |batch| {
+        let bytes = batch.encode_to_vec();
+        data.write_all(&(bytes.len() as u32).to_be_bytes())?;
+        data.write_all(&bytes)?;
+        Ok(())
+    })?;
+    data.into_inner()
+        .map_err(|err| err.into_error())?
+        .sync_all()?;
+
+    fs::write(&manifest_path, manifest.encode_to_vec())?;
+    info!(
+        shard_id,
+        block_number = manifest.block_number,
+        num_keys = manifest.num_keys,
+        dir = dir.to_string_lossy().as_ref(),
+        "Exported shard snapshot"
+    );
+    Ok(manifest)
+}
+
+fn ensure_empty(db: &RocksDB) -> Result<(), SnapshotError> {
+    let mut empty = true;
+    db.for_each_in_snapshot(|_, _| {
+        empty = false;
+        Ok(true)
+    })?;
+    if !empty {
+        return Err(SnapshotError::DbNotEmpty);
+    }
+    Ok(())
+}
+
+fn import_batch(db: &RocksDB, batch: SnapshotBatch) -> Result<u64, SnapshotError> {
+    let mut txn = db.txn();
+    let mut num_keys = 0;
+    for SnapshotKeyValue { key, value } in batch.values {
+        txn.put(key, value);
+        num_keys += 1;
+    }
+    db.commit(txn)?;
+    Ok(num_keys)
+}
+
+fn mismatch(what: &str) -> SnapshotError {
+    SnapshotError::ManifestMismatch(what.to_string())
+}
+
+/// Checks that the imported data is the state the manifest describes: the trie root and the last
+/// shard chunk match it, the chunk's hash covers its header and the chunk was decided by the
+/// validators. The imported trie nodes are rebuilt from its leaves first, so the root can't just
+/// claim the expected hash.
+pub fn verify_snapshot(
+    db: &RocksDB,
+    manifest: &SnapshotManifest,
+    num_keys: u64,
+    trie_branching_factor: u32,
+    validator_sets: &ValidatorSets,
+) -> Result<(), SnapshotError> {
+    if num_keys != manifest.num_keys {
+        return Err(mismatch("number of keys"));
+    }
+
+    let mut trie = MerkleTrie::new(trie_branching_factor)?;
+    trie.rebuild(db)?;
+    if trie.root_hash()? != manifest.shard_root {
+        return Err(mismatch("trie root"));
+    }
+
+    let Some(chunk) = shard::get_last_shard_chunk(db)? else {
+        return Err(SnapshotError::NoShardChunks);
+    };
+    let header = chunk.header.unwrap_or_default();
+    let height = header.height.unwrap_or_default();
+    if height.shard_index != manifest.shard_id || height.block_number != manifest.block_number {
+        return Err(mismatch("shard chunk height"));
+    }
+    if header.shard_root != manifest.shard_root {
+        return Err(mismatch("shard chunk root"));
+    }
+    if chunk.hash != manifest.chunk_hash
+        || chunk.hash != header.hash()
+        || header.transactions_root != transactions_root(&chunk.transactions)
+    {
+        return Err(mismatch("shard chunk hash"));
+    }
+
+    // Blocks this node doesn't have could have changed the validators by the chunk's height
+    if !validator_sets.is_known_at(height.shard_index, height.block_number) {
+        return Err(SnapshotError::UnknownValidatorSet);
+    }
+    let Some(votes) = &chunk.votes else {
+        return Err(SnapshotError::InvalidCertificate(
+            "no commit certificate".to_string(),
+        ));
+    };
+    let value = ShardHash {
+        shard_index: height.shard_index,
+        hash: chunk.hash.clone(),
+    };
+    validator_sets
+        .verify_commit_certificate(height, &value, votes)
+        .map_err(|err| SnapshotError::InvalidCertificate(err.to_string()))
+}
+
+// A snapshot that fails to import must not be left behind half written, the node would start from
+// an arbitrary state. Either way the import wrote trie nodes without going through the trie, so
+// whatever the trie's node cache holds for the db is stale.
+fn clear_on_error<T>(
+    db: &RocksDB,
+    node_cache: Option<&TrieNodeCache>,
+    result: Result<T, SnapshotError>,
+) -> Result<T, SnapshotError> {
+    if result.is_err() {
+        if let Err(err) = db.clear() {
+            warn!("Unable to clear db after a failed snapshot import: {}", err);
+        }
+    }
+    if let Some(node_cache) = node_cache {
+        node_cache.clear();
+    }
+    result
+}
+
+fn read_batch(data: &mut impl Read) -> Result<Option<SnapshotBatch>, SnapshotError> {
+    let mut len = [0u8; 4];
+    match data.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let mut bytes = vec![0u8; u32::from_be_bytes(len) as usize];
+    data.read_exact(&mut bytes)?;
+    Ok(Some(SnapshotBatch::decode(bytes.as_slice())?))
+}
+
+fn import_data_from_dir(
+    db: &RocksDB,
+    dir: &Path,
+    trie_branching_factor: u32,
+    validator_sets: &ValidatorSets,
+) -> Result<SnapshotManifest, SnapshotError> {
+    let manifest_path = dir.join(MANIFEST_FILE);
+    if !manifest_path.exists() {
+        return Err(SnapshotError::MissingManifest);
+    }
+    let manifest = SnapshotManifest::decode(fs::read(manifest_path)?.as_slice())?;
+
+    let mut data = BufReader::new(fs::File::open(dir.join(DATA_FILE))?);
+    let mut num_keys = 0;
+    while let Some(batch) = read_batch(&mut data)? {
+        num_keys += import_batch(db, batch)?;
+    }
+
+    verify_snapshot(
+        db,
+        &manifest,
+        num_keys,
+        trie_branching_factor,
+        validator_sets,
+    )?;
+    Ok(manifest)
+}
+
+/// Imports a snapshot written by `export_snapshot_to_dir` into an empty db. The node cache of a
+/// trie already open on the db, if any, is cleared.
+pub fn import_snapshot_from_dir(
+    db: &RocksDB,
+    shard_id: u32,
+    dir: &Path,
+    trie_branching_factor: u32,
+    validator_sets: &ValidatorSets,
+    node_cache: Option<&TrieNodeCache>,
+) -> Result<SnapshotManifest, SnapshotError> {
+    ensure_empty(db)?;
+    let dir = shard_snapshot_dir(dir, shard_id);
+    let result = import_data_from_dir(db, &dir, trie_branching_factor, validator_sets);
+    clear_on_error(db, node_cache, result)
+}
+
+async fn import_data_from_rpc(
+    db: &RocksDB,
+    shard_id: u32,
+    url: &str,
+    trie_branching_factor: u32,
+    validator_sets: &ValidatorSets,
+) -> Result<SnapshotManifest, SnapshotError> {
+    let mut client = HubServiceClient::connect(url.to_string()).await?;
+    let mut stream = client
+        .get_shard_snapshot(ShardSnapshotRequest { shard_id })
+        .await?
+        .into_inner();
+
+    let mut manifest = None;
+    let mut num_keys = 0;
+    while let Some(response) = stream.message().await? {
+        match response.response {
+            Some(SnapshotResponse::Batch(batch)) => num_keys += import_batch(db, batch)?,
+            Some(SnapshotResponse::Manifest(received)) => manifest = Some(received),
+            None => {}
+        }
+    }
+
+    let Some(manifest) = manifest else {
+        return Err(SnapshotError::MissingManifest);
+    };
+    verify_snapshot(
+        db,
+        &manifest,
+        num_keys,
+        trie_branching_factor,
+        validator_sets,
+    )?;
+    Ok(manifest)
+}
+
+/// Imports the snapshot served by another node's `GetShardSnapshot` rpc into an empty db. The
+/// node cache of a trie already open on the db, if any, is cleared.
+pub async fn import_snapshot_from_rpc(
+    db: &RocksDB,
+    shard_id: u32,
+    url: &str,
+    trie_branching_factor: u32,
+    validator_sets: &ValidatorSets,
+    node_cache: Option<&TrieNodeCache>,
+) -> Result<SnapshotManifest, SnapshotError> {
+    ensure_empty(db)?;
+    let result =
+        import_data_from_rpc(db, shard_id, url, trie_branching_factor, validator_sets).await;
+    clear_on_error(db, node_cache, result)
+}
+
+/// Snapshots are served over rpc by the node at an http(s) url, and read from a directory otherwise
+pub fn is_rpc_source(source: &str) -> bool {
+    source.starts_with("http://") || source.starts_with("https://")
+}
+
+/// Commits the blocks the node at the url has and the block store doesn't, each once it's checked
+/// to have been decided by the validators of its height. A snapshot's certificate can only be
+/// checked once the blocks that could have changed the validators by its height are known. Returns
+/// the last block number.
+pub async fn sync_blocks_from_rpc(
+    block_store: &BlockStore,
+    url: &str,
+    validator_sets: &ValidatorSets,
+) -> Result<u64, SnapshotError> {
+    let mut client = HubServiceClient::connect(url.to_string()).await?;
+    let mut block_number = block_store.max_block_number()?;
+    let mut parent_hash = match block_store.get_last_block()? {
+        Some(block) => block.hash,
+        None => EMPTY_ROOT.to_vec(),
+    };
+    loop {
+        let mut stream = client
+            .get_blocks(BlocksRequest {
+                shard_id: 0,
+                start_block_number: block_number + 1,
+                stop_block_number: Some(block_number + 1 + BLOCK_SYNC_PAGE_SIZE),
+            })
+            .await?
+            .into_inner();
+        let mut num_blocks = 0;
+        while let Some(block) = stream.message().await? {
+            let height = Height::new(0, block_number + 1);
+            verify_synced_block(&block, height, &parent_hash, validator_sets)?;
+            block_store.put_block(block.clone())?;
+            validator_sets.apply_block(&block);
+            block_number = height.block_number;
+            parent_hash = block.hash;
+            num_blocks += 1;
+        }
+        if num_blocks == 0 {
+            info!(block_number, url, "Synced blocks from snapshot source");
+            return Ok(block_number);
+        }
+    }
+}
+
+/// Bootstraps a shard that hasn't committed any chunks from a snapshot, served over rpc if the
+/// source is an http(s) url or read from a local directory otherwise. Chunks after the snapshot's
+/// height are then synced as usual. Returns `None` if the shard already has state.
+pub async fn bootstrap_from_snapshot(
+    db: &RocksDB,
+    shard_id: u32,
+    source: &str,
+    trie_branching_factor: u32,
+    validator_sets: &ValidatorSets,
+    node_cache: Option<&TrieNodeCache>,
+) -> Result<Option<SnapshotManifest>, SnapshotError> {
+    if let Some(block_number) = shard::get_current_height(db)? {
+        info!(
+            shard_id,
+            block_number, "Shard already has state, not bootstrapping from snapshot"
+        );
+        return Ok(None);
+    }
+
+    // Whatever is there (e.g. an empty trie root) predates the first chunk
+    let result = db.clear();
+    if let Some(node_cache) = node_cache {
+        node_cache.clear();
+    }
+    result?;
+
+    let manifest = if is_rpc_source(source) {
+        import_snapshot_from_rpc(
+            db,
+            shard_id,
+            source,
+            trie_branching_factor,
+            validator_sets,
+            node_cache,
+        )
+        .await?
+    } else {
+        import_snapshot_from_dir(
+            db,
+            shard_id,
+            Path::new(source),
+            trie_branching_factor,
+            validator_sets,
+            node_cache,
+        )?
+    };
+    info!(
+        shard_id,
+        block_number = manifest.block_number,
+        source,
+        "Bootstrapped shard from snapshot"
+    );
+    Ok(Some(manifest))
+}
@@ -3,16 +3,20 @@ use super::account::{
     VerificationStoreDef,
 };
 use crate::core::error::HubError;
-use crate::proto::HubEvent;
 use crate::proto::MessageType;
-use crate::storage::db::{RocksDB, RocksDbTransactionBatch};
+use crate::proto::{self, HubEvent, OnChainEventType};
+use crate::storage::constants::PAGE_SIZE_MAX;
+use crate::storage::db::{PageOptions, RocksDB, RocksDbTransactionBatch};
 use crate::storage::store::account::{
-    CastStore, CastStoreDef, IntoU8, LinkStore, OnchainEventStorageError, OnchainEventStore, Store,
-    StoreEventHandler, UsernameProofStore, UsernameProofStoreDef,
+    message_decode, CastStore, CastStoreDef, IntoU8, LinkStore, MessagesPage,
+    OnchainEventStorageError, OnchainEventStore, Store, StoreEventHandler, UsernameProofStore,
+    UsernameProofStoreDef, HASH_LENGTH,
 };
+use crate::storage::store::engine::MempoolMessage;
 use crate::storage::store::shard::ShardStore;
+use crate::storage::trie::errors::TrieError;
 use crate::storage::trie::merkle_trie;
-use crate::storage::trie::merkle_trie::TrieKey;
+use crate::storage::trie::merkle_trie::{NodeMetadata, TrieKey, TrieSnapshot};
 use std::sync::Arc;
 use thiserror::Error;
 
@@ -29,6 +33,12 @@ pub enum StoresError {
         inner: HubError, // TODO: move away from HubError when we can
         hash: Vec<u8>,
     },
+
+    #[error(transparent)]
+    HubError(#[from] HubError),
+
+    #[error("invalid sync id")]
+    InvalidSyncId(Vec<u8>),
 }
 
 #[derive(Clone)]
@@ -224,6 +234,161 @@ impl Stores {
         );
         Ok(revoke_events)
     }
+
+    // The trie held by the stores is only reloaded by the engine, so reads from other tasks
+    // (e.g. rpcs) need a fresh copy of the root
+    fn reloaded_trie(&self) -> Result<merkle_trie::MerkleTrie, TrieError> {
+        let mut trie = self.trie.clone();
+        trie.reload(&self.db)?;
+        Ok(trie)
+    }
+
+    // Sync prefixes are paths in the trie, i.e. key prefixes expanded for the branching factor
+    pub fn get_sync_snapshot(&self, prefix: &[u8]) -> Result<TrieSnapshot, TrieError> {
+        self.reloaded_trie()?
+            .get_snapshot(&merkle_trie::Context::new(), &self.db, prefix)
+    }
+
+    pub fn get_sync_metadata(&self, prefix: &[u8]) -> Result<Option<NodeMetadata>, TrieError> {
+        let trie = self.reloaded_trie()?;
+        match trie.get_trie_node_metadata(&self.db, &mut RocksDbTransactionBatch::new(), prefix) {
+            Ok(metadata) => Ok(Some(metadata)),
+            Err(TrieError::NodeNotFound { .. }) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn get_all_sync_ids(&self, prefix: &[u8]) -> Result<Vec<Vec<u8>>, TrieError> {
+        self.reloaded_trie()?
+            .get_all_values_at_path(&merkle_trie::Context::new(), &self.db, prefix)
+    }
+
+    // Looks up the data a trie key was created for (see TrieKey). Returns an empty list if it's
+    // no longer present in the stores.
+    pub fn get_by_sync_id(&self, sync_id: &[u8]) -> Result<Vec<MempoolMessage>, StoresError> {
+        if sync_id.len() < 5 {
+            return Err(StoresError::InvalidSyncId(sync_id.to_vec()));
+        }
+        let fid = u32::from_be_bytes(sync_id[0..4].try_into().unwrap());
+        let type_byte = sync_id[4];
+        let rest = &sync_id[5..];
+
+        // Cast adds (1 << 3) share their type byte with fnames, and message keys end with a hash
+        // as long as some fnames. A key of that length that isn't a message may still be an fname.
+        if type_byte & 0x07 == 0 && rest.len() == HASH_LENGTH {
+            let message_type = MessageType::try_from((type_byte >> 3) as i32)
+                .map_err(|_| StoresError::InvalidSyncId(sync_id.to_vec()))?;
+            if let Some(message) = self.find_message(fid, message_type, rest)? {
+                return Ok(vec![MempoolMessage::UserMessage(message)]);
+            }
+            if type_byte != 8 {
+                return Ok(vec![]);
+            }
+        }
+
+        if type_byte == 8 {
+            let proof = UserDataStore::get_username_proof(&self.user_data_store, rest)?;
+            return Ok(proof
+                .into_iter()
+                .filter(|proof| proof.fid == fid as u64)
+                .map(|proof| {
+                    MempoolMessage::ValidatorMessage(proto::ValidatorMessage {
+                        on_chain_event: None,
+                        fname_transfer: Some(proto::FnameTransfer {
+                            id: 0,
+                            from_fid: 0,
+                            proof: Some(proof),
+                        }),
+                        validator_set_update: None,
+                    })
+                })
+                .collect());
+        }
+
+        let event_type = OnChainEventType::try_from(type_byte as i32)
+            .map_err(|_| StoresError::InvalidSyncId(sync_id.to_vec()))?;
+        let events = self
+            .onchain_event_store
+            .get_onchain_events(event_type, fid)?;
+        Ok(events
+            .into_iter()
+            .filter(|event| event.transaction_hash == rest)
+            .map(|event| {
+                MempoolMessage::ValidatorMessage(proto::ValidatorMessage {
+                    on_chain_event: Some(event),
+                    fname_transfer: None,
+                    validator_set_update: None,
+                })
+            })
+            .collect())
+    }
+
+    fn find_message(
+        &self,
+        fid: u32,
+        message_type: MessageType,
+        hash: &[u8],
+    ) -> Result<Option<proto::Message>, HubError> {
+        let messages = self.get_messages_by_fid_and_type(fid, message_type)?;
+        Ok(messages.into_iter().find(|message| message.hash == hash))
+    }
+
+    // All messages of the given type currently in the stores for an fid, in store order
+    pub fn get_messages_by_fid_and_type(
+        &self,
+        fid: u32,
+        message_type: MessageType,
+    ) -> Result<Vec<proto::Message>, HubError> {
+        let mut messages = vec![];
+        let mut page_token = None;
+        loop {
+            let page_options = PageOptions {
+                page_size: Some(PAGE_SIZE_MAX),
+                page_token,
+                reverse: false,
+            };
+            let page: MessagesPage = match message_type {
+                MessageType::CastAdd | MessageType::CastRemove => self
+                    .cast_store
+                    .get_all_messages_by_fid(fid, None, None, &page_options)?,
+                MessageType::LinkAdd | MessageType::LinkRemove => self
+                    .link_store
+                    .get_all_messages_by_fid(fid, None, None, &page_options)?,
+                MessageType::LinkCompactState => self
+                    .link_store
+                    .get_compact_state_messages_by_fid(fid, &page_options)?,
+                MessageType::ReactionAdd | MessageType::ReactionRemove => self
+                    .reaction_store
+                    .get_all_messages_by_fid(fid, None, None, &page_options)?,
+                MessageType::UserDataAdd => {
+                    self.user_data_store
+                        .get_all_messages_by_fid(fid, None, None, &page_options)?
+                }
+                MessageType::VerificationAddEthAddress | MessageType::VerificationRemove => self
+                    .verification_store
+                    .get_all_messages_by_fid(fid, None, None, &page_options)?,
+                MessageType::UsernameProof => self.username_proof_store.get_all_messages_by_fid(
+                    fid,
+                    None,
+                    None,
+                    &page_options,
+                )?,
+                MessageType::FrameAction | MessageType::None => return Ok(vec![]),
+            };
+
+            for message_bytes in &page.messages_bytes {
+                let message = message_decode(message_bytes)?;
+                if message.msg_type() == message_type {
+                    messages.push(message);
+                }
+            }
+
+            match page.next_page_token {
+                None => return Ok(messages),
+                Some(token) => page_token = Some(token),
+            }
+        }
+    }
 }
 
 #[cfg(test)]
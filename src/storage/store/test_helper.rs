@@ -1,7 +1,11 @@
+use crate::consensus::genesis::{Genesis, GenesisValidator};
+use crate::consensus::validator_set::ValidatorSets;
+use crate::core::merkle::transactions_root;
 use crate::storage::db;
 use crate::storage::store::engine::ShardEngine;
 use crate::storage::store::stores::StoreLimits;
 use crate::storage::trie::merkle_trie;
+use crate::storage::trie::node_cache::{TrieNodeCache, DEFAULT_TRIE_NODE_CACHE_BYTES};
 use crate::utils::statsd_wrapper::StatsdClientWrapper;
 use ed25519_dalek::{SecretKey, SigningKey};
 use std::sync::Arc;
@@ -13,6 +17,9 @@ use crate::proto::{Height, ShardChunk, ShardHeader, Transaction};
 use crate::storage::store::engine::{MempoolMessage, ShardStateChange};
 use crate::utils::factory::{events_factory, username_factory};
 use hex::FromHex;
+use libp2p::identity::ed25519::Keypair;
+use prost::Message as _;
+use std::time::SystemTime;
 
 pub const FID_FOR_TEST: u32 = 1234;
 
@@ -79,7 +86,9 @@ pub fn new_engine_with_options(options: EngineOptions) -> (ShardEngine, tempfile
     (
         ShardEngine::new(
             Arc::new(db),
-            merkle_trie::MerkleTrie::new(16).unwrap(),
+            merkle_trie::MerkleTrie::new(16)
+                .unwrap()
+                .with_node_cache(TrieNodeCache::new(DEFAULT_TRIE_NODE_CACHE_BYTES)),
             1,
             test_limits,
             statsd_client,
@@ -100,6 +109,7 @@ pub async fn commit_event(engine: &mut ShardEngine, event: &OnChainEvent) -> Sha
         vec![MempoolMessage::ValidatorMessage(proto::ValidatorMessage {
             on_chain_event: Some(event.clone()),
             fname_transfer: None,
+            validator_set_update: None,
         })],
     );
 
@@ -115,6 +125,7 @@ pub fn default_shard_chunk() -> ShardChunk {
             system_messages: vec![],
             fid: FID_FOR_TEST as u64,
             account_root: vec![5, 5, 6, 6], //TODO,
+            receipts: vec![],
         }],
         hash: vec![],
         votes: None,
@@ -134,9 +145,47 @@ pub fn state_change_to_shard_chunk(
         block_number,
     });
     chunk.transactions = change.transactions.clone();
+    // Same as the proposer, so the chunk can be verified (e.g. when importing a snapshot)
+    chunk.header.as_mut().unwrap().transactions_root = transactions_root(&chunk.transactions);
+    chunk.hash = chunk.header.as_ref().unwrap().hash();
     chunk
 }
 
+// A single validator decides every chunk
+pub fn validator_sets(keypair: &Keypair) -> ValidatorSets {
+    ValidatorSets::from_genesis(&Genesis {
+        chain_id: "snapchain-test".to_string(),
+        network: "devnet".to_string(),
+        genesis_time: SystemTime::UNIX_EPOCH,
+        num_shards: 1,
+        validators: vec![GenesisValidator {
+            public_key: hex::encode(keypair.public().to_bytes()),
+            voting_power: 1,
+            rpc_address: "127.0.0.1:3383".to_string(),
+        }],
+    })
+}
+
+// Adds the commit certificate of the validator (see validator_sets) to the chunk
+pub fn certify_shard_chunk(chunk: &mut ShardChunk, keypair: &Keypair) {
+    let height = chunk.header.as_ref().unwrap().height.unwrap();
+    let vote = proto::Vote {
+        r#type: proto::VoteType::Precommit as i32,
+        height: Some(height),
+        round: 0,
+        value: Some(proto::ShardHash {
+            shard_index: height.shard_index,
+            hash: chunk.hash.clone(),
+        }),
+        voter: keypair.public().to_bytes().to_vec(),
+    };
+    let signature = keypair.sign(&vote.encode_to_vec());
+    chunk.votes = Some(proto::ConfirmedVotes {
+        votes: vec![vote],
+        signatures: vec![signature],
+    });
+}
+
 pub fn validate_and_commit_state_change(
     engine: &mut ShardEngine,
     state_change: &ShardStateChange,
@@ -178,6 +227,7 @@ pub async fn register_fname(
         vec![MempoolMessage::ValidatorMessage(proto::ValidatorMessage {
             on_chain_event: None,
             fname_transfer: Some(fname_transfer),
+            validator_set_update: None,
         })],
     );
 
@@ -0,0 +1,67 @@
+use crate::core::error::HubError;
+use crate::proto::{UndoLogEntry, UndoLogValue};
+use crate::storage::constants::RootPrefix;
+use crate::storage::db::{RocksDB, RocksDbTransactionBatch};
+use prost::Message;
+
+// Shard chunks older than this can't be rolled back
+pub const MAX_ROLLBACK_BLOCKS: u64 = 1000;
+
+fn make_undo_log_key(block_number: u64) -> Vec<u8> {
+    let mut key = Vec::with_capacity(1 + 8);
+
+    key.push(RootPrefix::UndoLog as u8);
+    key.extend_from_slice(&block_number.to_be_bytes());
+
+    key
+}
+
+// Records the current values of all keys the transaction is about to write, in the transaction
+// itself. Must be called right before it's committed.
+pub fn put_undo_log_entry(
+    db: &RocksDB,
+    txn: &mut RocksDbTransactionBatch,
+    block_number: u64,
+) -> Result<(), HubError> {
+    let mut values = Vec::with_capacity(txn.len());
+    for key in txn.batch.keys() {
+        if key.first() == Some(&(RootPrefix::UndoLog as u8)) {
+            continue;
+        }
+        values.push(UndoLogValue {
+            key: key.clone(),
+            value: db.get(key)?,
+        });
+    }
+
+    txn.put(
+        make_undo_log_key(block_number),
+        UndoLogEntry { values }.encode_to_vec(),
+    );
+    if block_number > MAX_ROLLBACK_BLOCKS {
+        txn.delete(make_undo_log_key(block_number - MAX_ROLLBACK_BLOCKS));
+    }
+    Ok(())
+}
+
+pub fn get_undo_log_entry(
+    db: &RocksDB,
+    block_number: u64,
+) -> Result<Option<UndoLogEntry>, HubError> {
+    match db.get(&make_undo_log_key(block_number))? {
+        None => Ok(None),
+        Some(bytes) => Ok(Some(UndoLogEntry::decode(bytes.as_slice())?)),
+    }
+}
+
+// Adds the writes that revert the block to the transaction. When rolling back several blocks,
+// they must be undone from the latest to the earliest.
+pub fn undo(txn: &mut RocksDbTransactionBatch, block_number: u64, entry: UndoLogEntry) {
+    for UndoLogValue { key, value } in entry.values {
+        match value {
+            Some(value) => txn.put(key, value),
+            None => txn.delete(key),
+        }
+    }
+    txn.delete(make_undo_log_key(block_number));
+}
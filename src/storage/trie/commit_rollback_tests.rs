@@ -3,6 +3,7 @@ mod tests {
     use crate::storage::db::{RocksDB, RocksDbTransactionBatch};
     use crate::storage::trie::errors::TrieError;
     use crate::storage::trie::merkle_trie::{Context, MerkleTrie};
+    use crate::storage::trie::node_cache::TrieNodeCache;
     use hex;
     use tempfile::TempDir;
 
@@ -120,4 +121,60 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_node_cache_is_consistent_across_commits_and_rollbacks() -> Result<(), TrieError> {
+        let ctx = &Context::new();
+
+        let dir = TempDir::new().unwrap();
+        let db_path = dir.path().join("a.db");
+        let db = &RocksDB::new(db_path.to_str().unwrap());
+        db.open().unwrap();
+
+        let mut t = MerkleTrie::new(16)
+            .unwrap()
+            .with_node_cache(TrieNodeCache::new(1024 * 1024));
+        t.initialize(db)?;
+
+        let hashes = generate_hashes(vec![1], 100);
+        let (committed, rolled_back) = hashes.split_at(50);
+
+        let mut txn_batch = RocksDbTransactionBatch::new();
+        t.insert(ctx, db, &mut txn_batch, committed.to_vec())?;
+        t.commit_to_db(db, txn_batch)?;
+        t.reload(db)?;
+        let committed_root = t.root_hash()?;
+        assert_eq!(t.get_all_values(ctx, db, &[])?.len(), 50);
+
+        // Uncommitted changes are dropped on reload, and never make it into the cache
+        let mut txn_batch = RocksDbTransactionBatch::new();
+        t.insert(ctx, db, &mut txn_batch, rolled_back.to_vec())?;
+        t.delete(ctx, db, &mut txn_batch, committed[..10].to_vec())?;
+        t.reload(db)?;
+        assert_eq!(t.root_hash()?, committed_root);
+        assert_eq!(t.get_all_values(ctx, db, &[])?.len(), 50);
+
+        let mut txn_batch = RocksDbTransactionBatch::new();
+        t.delete(ctx, db, &mut txn_batch, committed[..10].to_vec())?;
+        t.commit_to_db(db, txn_batch)?;
+        t.reload(db)?;
+        assert_eq!(t.get_all_values(ctx, db, &[])?.len(), 40);
+        assert!(!t.exists(ctx, db, &committed[0])?);
+        assert!(t.exists(ctx, db, &committed[10])?);
+
+        let stats = t.node_cache_stats().unwrap();
+        assert!(stats.hits > 0);
+
+        // A trie reading straight from the db sees the same state
+        let mut uncached = MerkleTrie::new(16).unwrap();
+        uncached.initialize(db)?;
+        assert_eq!(uncached.root_hash()?, t.root_hash()?);
+        let mut cached_values = t.get_all_values(ctx, db, &[])?;
+        let mut uncached_values = uncached.get_all_values(&Context::new(), db, &[])?;
+        cached_values.sort();
+        uncached_values.sort();
+        assert_eq!(cached_values, uncached_values);
+
+        Ok(())
+    }
 }
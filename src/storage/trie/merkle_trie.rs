@@ -1,7 +1,9 @@
-use super::super::db::{RocksDB, RocksDbTransactionBatch};
+use super::super::db::{PageOptions, RocksDB, RocksDbTransactionBatch};
 use super::errors::TrieError;
+use super::node_cache::{self, TrieNodeCache, TrieNodeCacheStats};
 use super::trie_node::{TrieNode, TIMESTAMP_LENGTH};
 use crate::proto;
+use crate::storage::constants::RootPrefix;
 use crate::storage::store::account::IntoU8;
 use crate::storage::trie::{trie_node, util};
 use std::collections::HashMap;
@@ -10,6 +12,9 @@ pub use trie_node::Context;
 
 pub const TRIE_DBPATH_PREFIX: &str = "trieDb";
 
+// Keys written per transaction when rebuilding the trie
+const REBUILD_BATCH_SIZE: usize = 10_000;
+
 pub struct TrieKey {}
 
 impl TrieKey {
@@ -70,6 +75,7 @@ pub struct MerkleTrie {
     branch_xform: util::BranchingFactorTransform,
     root: Option<TrieNode>,
     branching_factor: u32,
+    node_cache: Option<TrieNodeCache>,
 }
 
 impl MerkleTrie {
@@ -81,9 +87,61 @@ impl MerkleTrie {
             root: None,
             branch_xform,
             branching_factor,
+            node_cache: None,
         })
     }
 
+    // Clones of the trie share the cache, so read-only copies also benefit from it
+    pub fn with_node_cache(mut self, node_cache: TrieNodeCache) -> Self {
+        self.node_cache = Some(node_cache);
+        self
+    }
+
+    pub fn node_cache_stats(&self) -> Option<TrieNodeCacheStats> {
+        self.node_cache.as_ref().map(|cache| cache.take_stats())
+    }
+
+    fn attach_node_cache(&self, ctx: &Context) {
+        if let Some(node_cache) = self.node_cache.as_ref() {
+            ctx.set_node_cache(node_cache);
+        }
+    }
+
+    fn read_node_bytes(&self, db: &RocksDB, node_key: &[u8]) -> Result<Option<Vec<u8>>, TrieError> {
+        if let Some(node_cache) = self.node_cache.as_ref() {
+            if let Some(bytes) = node_cache.get(node_key) {
+                return Ok(Some(bytes));
+            }
+
+            let generation = node_cache.generation();
+            let bytes = db.get(node_key).map_err(TrieError::wrap_database)?;
+            if let Some(bytes) = &bytes {
+                node_cache.insert(node_key.to_vec(), bytes.clone(), generation);
+            }
+            Ok(bytes)
+        } else {
+            db.get(node_key).map_err(TrieError::wrap_database)
+        }
+    }
+
+    /// Commits the transaction to the db and keeps the node cache in sync with it. Anything that
+    /// commits trie changes must go through here, otherwise the cache may serve stale nodes.
+    pub fn commit_to_db(
+        &self,
+        db: &RocksDB,
+        txn_batch: RocksDbTransactionBatch,
+    ) -> Result<(), TrieError> {
+        match self.node_cache.as_ref() {
+            None => db.commit(txn_batch).map_err(TrieError::wrap_database),
+            Some(node_cache) => {
+                let writes = node_cache::trie_node_writes(&txn_batch);
+                db.commit(txn_batch).map_err(TrieError::wrap_database)?;
+                node_cache.apply_committed(writes);
+                Ok(())
+            }
+        }
+    }
+
     fn create_empty_root(&mut self, txn_batch: &mut RocksDbTransactionBatch) {
         let root_key = TrieNode::make_primary_key(&[], None);
         let empty = TrieNode::new();
@@ -104,7 +162,7 @@ impl MerkleTrie {
             info!("Initializing empty merkle trie root");
             let mut txn_batch = RocksDbTransactionBatch::new();
             self.create_empty_root(&mut txn_batch);
-            db.commit(txn_batch).map_err(TrieError::wrap_database)?;
+            self.commit_to_db(db, txn_batch)?;
         }
 
         Ok(())
@@ -113,7 +171,7 @@ impl MerkleTrie {
     fn load_root(&self, db: &RocksDB) -> Result<Option<TrieNode>, TrieError> {
         let root_key = TrieNode::make_primary_key(&[], None);
 
-        if let Some(root_bytes) = db.get(&root_key).map_err(TrieError::wrap_database)? {
+        if let Some(root_bytes) = self.read_node_bytes(db, &root_key)? {
             let root_node = TrieNode::deserialize(&root_bytes.as_slice())?;
             Ok(Some(root_node))
         } else {
@@ -135,6 +193,63 @@ impl MerkleTrie {
         }
     }
 
+    /// Rebuilds every node of the trie from the leaves stored in the db. The hashes then only
+    /// depend on the keys the trie holds, not on what was stored for the nodes above them, e.g.
+    /// after importing nodes from an untrusted source. All leaves are held in memory meanwhile.
+    pub fn rebuild(&mut self, db: &RocksDB) -> Result<(), TrieError> {
+        let start_prefix = vec![RootPrefix::SyncMerkleTrieNode as u8];
+        let stop_prefix = vec![RootPrefix::SyncMerkleTrieNode as u8 + 1];
+        let mut node_keys = vec![];
+        let mut leaves = vec![];
+        let mut deserialize_error = None;
+        db.for_each_iterator_by_prefix(
+            Some(start_prefix),
+            Some(stop_prefix),
+            &PageOptions::default(),
+            |key, value| {
+                node_keys.push(key.to_vec());
+                match TrieNode::deserialize(value) {
+                    Ok(node) => {
+                        if let Some(leaf) = node.value().filter(|leaf| !leaf.is_empty()) {
+                            leaves.push((self.branch_xform.combine)(leaf));
+                        }
+                        Ok(false)
+                    }
+                    Err(err) => {
+                        deserialize_error = Some(err);
+                        Ok(true)
+                    }
+                }
+            },
+        )
+        .map_err(|err| TrieError::DatabaseError {
+            source: Box::new(err),
+        })?;
+        if let Some(err) = deserialize_error {
+            return Err(err);
+        }
+
+        for keys in node_keys.chunks(REBUILD_BATCH_SIZE) {
+            let mut txn_batch = RocksDbTransactionBatch::new();
+            for key in keys {
+                txn_batch.delete(key.clone());
+            }
+            self.commit_to_db(db, txn_batch)?;
+        }
+        let mut txn_batch = RocksDbTransactionBatch::new();
+        self.create_empty_root(&mut txn_batch);
+        self.commit_to_db(db, txn_batch)?;
+
+        let ctx = Context::new();
+        for keys in leaves.chunks(REBUILD_BATCH_SIZE) {
+            let mut txn_batch = RocksDbTransactionBatch::new();
+            self.insert(&ctx, db, &mut txn_batch, keys.to_vec())?;
+            self.commit_to_db(db, txn_batch)?;
+            self.reload(db)?;
+        }
+        Ok(())
+    }
+
     pub fn insert(
         &mut self,
         ctx: &Context,
@@ -154,6 +269,7 @@ impl MerkleTrie {
             }
         }
 
+        self.attach_node_cache(ctx);
         if let Some(root) = self.root.as_mut() {
             let mut txn = RocksDbTransactionBatch::new();
             let results = root.insert(ctx, db, &mut txn, keys, 0)?;
@@ -184,6 +300,7 @@ impl MerkleTrie {
             }
         }
 
+        self.attach_node_cache(ctx);
         if let Some(root) = self.root.as_mut() {
             let mut txn = RocksDbTransactionBatch::new();
             let results = root.delete(ctx, db, &mut txn, keys, 0)?;
@@ -203,6 +320,7 @@ impl MerkleTrie {
     ) -> Result<bool, TrieError> {
         let key: Vec<u8> = (self.branch_xform.expand)(key.clone());
 
+        self.attach_node_cache(ctx);
         if let Some(root) = self.root.as_mut() {
             root.exists(ctx, db, &key, 0)
         } else {
@@ -224,8 +342,17 @@ impl MerkleTrie {
         txn_batch: &mut RocksDbTransactionBatch,
         prefix: &[u8],
     ) -> Option<TrieNode> {
-        let prefix = (self.branch_xform.expand)(prefix.to_vec());
-        let node_key = TrieNode::make_primary_key(&prefix, None);
+        let path = (self.branch_xform.expand)(prefix.to_vec());
+        self.get_node_at_path(db, txn_batch, &path)
+    }
+
+    fn get_node_at_path(
+        &self,
+        db: &RocksDB,
+        txn_batch: &mut RocksDbTransactionBatch,
+        path: &[u8],
+    ) -> Option<TrieNode> {
+        let node_key = TrieNode::make_primary_key(path, None);
 
         // First, attempt to get it from the DB cache
         if let Some(Some(node_bytes)) = txn_batch.batch.get(&node_key) {
@@ -234,8 +361,8 @@ impl MerkleTrie {
             }
         }
 
-        // Else, get it directly from the DB
-        if let Some(node_bytes) = db.get(&node_key).ok().flatten() {
+        // Else, get it from the node cache or directly from the DB
+        if let Some(node_bytes) = self.read_node_bytes(db, &node_key).ok().flatten() {
             if let Ok(node) = TrieNode::deserialize(&node_bytes) {
                 return Some(node);
             }
@@ -280,11 +407,26 @@ impl MerkleTrie {
         db: &RocksDB,
         prefix: &[u8],
     ) -> Result<Vec<Vec<u8>>, TrieError> {
-        let prefix = (self.branch_xform.expand)(prefix.to_vec());
+        let path = (self.branch_xform.expand)(prefix.to_vec());
+        self.get_all_values_at_path(ctx, db, &path)
+    }
 
+    // The path in the trie for a key prefix, i.e. the prefix expanded for the branching factor
+    pub fn path_for_prefix(&self, prefix: &[u8]) -> Vec<u8> {
+        (self.branch_xform.expand)(prefix.to_vec())
+    }
+
+    // Same as get_all_values, but takes a path in the trie (i.e. an already expanded key prefix)
+    pub fn get_all_values_at_path(
+        &mut self,
+        ctx: &Context,
+        db: &RocksDB,
+        path: &[u8],
+    ) -> Result<Vec<Vec<u8>>, TrieError> {
+        self.attach_node_cache(ctx);
         if let Some(root) = self.root.as_mut() {
-            if let Some(node) = root.get_node_from_trie(ctx, db, &prefix, 0) {
-                match node.get_all_values(ctx, db, &prefix) {
+            if let Some(node) = root.get_node_from_trie(ctx, db, path, 0) {
+                match node.get_all_values(ctx, db, path) {
                     Ok(values) => Ok(values.into_iter().map(self.branch_xform.combine).collect()),
                     Err(e) => Err(e),
                 }
@@ -302,6 +444,7 @@ impl MerkleTrie {
         db: &RocksDB,
         prefix: &[u8],
     ) -> Result<TrieSnapshot, TrieError> {
+        self.attach_node_cache(ctx);
         if let Some(root) = self.root.as_mut() {
             root.get_snapshot(ctx, db, prefix, 0)
         } else {
@@ -309,20 +452,22 @@ impl MerkleTrie {
         }
     }
 
+    // Like get_snapshot, the prefix is a path in the trie rather than a key prefix, so children
+    // can be addressed for any branching factor
     pub fn get_trie_node_metadata(
         &self,
         db: &RocksDB,
         txn_batch: &mut RocksDbTransactionBatch,
         prefix: &[u8],
     ) -> Result<NodeMetadata, TrieError> {
-        if let Some(node) = self.get_node(db, txn_batch, prefix) {
+        if let Some(node) = self.get_node_at_path(db, txn_batch, prefix) {
             let mut children = HashMap::new();
 
             for char in node.children().keys() {
                 let mut child_prefix = prefix.to_vec();
                 child_prefix.push(*char);
 
-                let child_node = self.get_node(db, txn_batch, &child_prefix).ok_or(
+                let child_node = self.get_node_at_path(db, txn_batch, &child_prefix).ok_or(
                     TrieError::ChildNotFound {
                         char: *char,
                         prefix: prefix.to_vec(),
@@ -1,9 +1,12 @@
 #[cfg(test)]
 mod tests {
+    use crate::proto::DbTrieNode;
+    use crate::storage::constants::RootPrefix;
     use crate::storage::db::{RocksDB, RocksDbTransactionBatch};
     use crate::storage::store::account::IntoU8;
     use crate::storage::trie::merkle_trie::{Context, MerkleTrie, TrieKey};
     use crate::utils::factory::{events_factory, messages_factory};
+    use prost::Message as _;
 
     fn random_hash() -> Vec<u8> {
         (0..32).map(|_| rand::random::<u8>()).collect()
@@ -114,4 +117,45 @@ mod tests {
         assert_eq!(event_key[4], event.r#type as u8);
         assert_eq!(event_key[5..], event.transaction_hash);
     }
+
+    #[test]
+    fn test_rebuild_from_leaves() {
+        let ctx = &Context::new();
+
+        let tmp_path = tempfile::tempdir()
+            .unwrap()
+            .path()
+            .as_os_str()
+            .to_string_lossy()
+            .to_string();
+
+        let db = &RocksDB::new(&tmp_path);
+        db.open().unwrap();
+
+        let mut trie = MerkleTrie::new(16).unwrap();
+        trie.initialize(db).unwrap();
+
+        let hashes: Vec<Vec<u8>> = (0..3).map(|_| random_hash()).collect();
+        let mut txn_batch = RocksDbTransactionBatch::new();
+        trie.insert(ctx, db, &mut txn_batch, hashes.clone())
+            .unwrap();
+        db.commit(txn_batch).unwrap();
+        trie.reload(db).unwrap();
+        let root_hash = trie.root_hash().unwrap();
+
+        // The stored root claims a hash that doesn't follow from its leaves
+        let root_key = vec![RootPrefix::SyncMerkleTrieNode as u8];
+        let mut root = DbTrieNode::decode(db.get(&root_key).unwrap().unwrap().as_slice()).unwrap();
+        root.hash = random_hash();
+        db.put(&root_key, &root.encode_to_vec()).unwrap();
+        trie.reload(db).unwrap();
+        assert_ne!(trie.root_hash().unwrap(), root_hash);
+
+        trie.rebuild(db).unwrap();
+        assert_eq!(trie.root_hash().unwrap(), root_hash);
+        assert_eq!(trie.items().unwrap(), 3);
+        for hash in &hashes {
+            assert!(trie.exists(ctx, db, hash).unwrap());
+        }
+    }
 }
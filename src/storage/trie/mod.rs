@@ -1,5 +1,6 @@
 pub mod errors;
 pub mod merkle_trie;
+pub mod node_cache;
 mod trie_node; // this is private on purpose
 mod util;
 
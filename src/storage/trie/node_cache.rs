@@ -0,0 +1,244 @@
+use crate::storage::constants::RootPrefix;
+use crate::storage::db::RocksDbTransactionBatch;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+pub const DEFAULT_TRIE_NODE_CACHE_BYTES: usize = 64 * 1024 * 1024;
+
+pub fn trie_node_writes(txn: &RocksDbTransactionBatch) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+    txn.batch
+        .iter()
+        .filter(|(key, _)| key.first() == Some(&(RootPrefix::SyncMerkleTrieNode as u8)))
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect()
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TrieNodeCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: u64,
+    pub size_bytes: u64,
+}
+
+struct CacheEntry {
+    bytes: Vec<u8>,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct TrieNodeCacheInner {
+    entries: HashMap<Vec<u8>, CacheEntry>,
+    // Orders keys by last use, so the least recently used entry is evicted first
+    lru: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    size_bytes: usize,
+    // Bumped on every commit, so a value read from the db before a commit is never cached after it
+    generation: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl TrieNodeCacheInner {
+    fn entry_size(key: &[u8], bytes: &[u8]) -> usize {
+        key.len() + bytes.len()
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.lru.remove(&entry.last_used);
+            self.size_bytes -= Self::entry_size(key, &entry.bytes);
+        }
+    }
+
+    fn put(&mut self, key: Vec<u8>, bytes: Vec<u8>, max_bytes: usize) {
+        self.remove(&key);
+
+        let size = Self::entry_size(&key, &bytes);
+        if size > max_bytes {
+            return;
+        }
+
+        while self.size_bytes + size > max_bytes {
+            match self.lru.pop_first() {
+                Some((_, evicted)) => {
+                    if let Some(entry) = self.entries.remove(&evicted) {
+                        self.size_bytes -= Self::entry_size(&evicted, &entry.bytes);
+                    }
+                }
+                None => break,
+            }
+        }
+
+        self.tick += 1;
+        self.lru.insert(self.tick, key.clone());
+        self.entries.insert(
+            key,
+            CacheEntry {
+                bytes,
+                last_used: self.tick,
+            },
+        );
+        self.size_bytes += size;
+    }
+}
+
+/// A bounded LRU cache of serialized trie nodes, keyed by their db key (i.e. the node prefix).
+/// The cache only ever holds committed state: entries are filled from db reads and updated when
+/// a transaction is committed, so uncommitted (and rolled back) changes never leak into it.
+/// Anything that writes trie nodes without going through `MerkleTrie::commit_to_db` (e.g. a
+/// snapshot import) must `clear` it. Clones share the same underlying cache.
+#[derive(Clone)]
+pub struct TrieNodeCache {
+    max_bytes: usize,
+    inner: Arc<Mutex<TrieNodeCacheInner>>,
+}
+
+impl TrieNodeCache {
+    pub fn new(max_bytes: usize) -> Self {
+        TrieNodeCache {
+            max_bytes,
+            inner: Arc::new(Mutex::new(TrieNodeCacheInner::default())),
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+
+        let last_used = match inner.entries.get_mut(key) {
+            Some(entry) => std::mem::replace(&mut entry.last_used, tick),
+            None => {
+                inner.misses += 1;
+                return None;
+            }
+        };
+
+        inner.hits += 1;
+        inner.lru.remove(&last_used);
+        inner.lru.insert(tick, key.to_vec());
+        inner.entries.get(key).map(|entry| entry.bytes.clone())
+    }
+
+    /// Must be read before going to the db, and passed back to `insert` with the db value.
+    pub fn generation(&self) -> u64 {
+        self.inner.lock().unwrap().generation
+    }
+
+    pub fn insert(&self, key: Vec<u8>, bytes: Vec<u8>, generation: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.generation != generation {
+            // A commit happened since the value was read, it may be stale
+            return;
+        }
+        inner.put(key, bytes, self.max_bytes);
+    }
+
+    /// Applies trie node writes (see `trie_node_writes`) to the cache. Must be called after the
+    /// transaction they came from is committed to the db.
+    pub fn apply_committed(&self, writes: Vec<(Vec<u8>, Option<Vec<u8>>)>) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.generation += 1;
+
+        for (key, value) in writes {
+            match value {
+                // Only refresh nodes that are already cached, so a large commit doesn't flush the
+                // cache with nodes that may never be read again
+                Some(bytes) if inner.entries.contains_key(&key) => {
+                    inner.put(key, bytes, self.max_bytes)
+                }
+                Some(_) => {}
+                None => inner.remove(&key),
+            }
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.generation += 1;
+        inner.entries.clear();
+        inner.lru.clear();
+        inner.size_bytes = 0;
+    }
+
+    /// Returns the current stats. Hit and miss counts are reset, so they can be reported as deltas.
+    pub fn take_stats(&self) -> TrieNodeCacheStats {
+        let mut inner = self.inner.lock().unwrap();
+        let stats = TrieNodeCacheStats {
+            hits: inner.hits,
+            misses: inner.misses,
+            entries: inner.entries.len() as u64,
+            size_bytes: inner.size_bytes as u64,
+        };
+        inner.hits = 0;
+        inner.misses = 0;
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_key(prefix: &[u8]) -> Vec<u8> {
+        let mut key = vec![RootPrefix::SyncMerkleTrieNode as u8];
+        key.extend_from_slice(prefix);
+        key
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        // Each entry is 2 + 8 = 10 bytes
+        let cache = TrieNodeCache::new(30);
+        let generation = cache.generation();
+        cache.insert(node_key(&[1]), vec![1; 8], generation);
+        cache.insert(node_key(&[2]), vec![2; 8], generation);
+        cache.insert(node_key(&[3]), vec![3; 8], generation);
+
+        // Touch the first entry, so the second one is evicted next
+        assert_eq!(cache.get(&node_key(&[1])), Some(vec![1; 8]));
+        cache.insert(node_key(&[4]), vec![4; 8], generation);
+
+        assert_eq!(cache.get(&node_key(&[2])), None);
+        assert_eq!(cache.get(&node_key(&[1])), Some(vec![1; 8]));
+        assert_eq!(cache.get(&node_key(&[3])), Some(vec![3; 8]));
+        assert_eq!(cache.get(&node_key(&[4])), Some(vec![4; 8]));
+
+        let stats = cache.take_stats();
+        assert_eq!(stats.hits, 4);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.entries, 3);
+        assert_eq!(stats.size_bytes, 30);
+
+        let stats = cache.take_stats();
+        assert_eq!(stats.hits, 0);
+        assert_eq!(stats.misses, 0);
+    }
+
+    #[test]
+    fn test_apply_committed() {
+        let cache = TrieNodeCache::new(1024);
+        let generation = cache.generation();
+        cache.insert(node_key(&[1]), vec![1], generation);
+        cache.insert(node_key(&[2]), vec![2], generation);
+
+        let mut txn = RocksDbTransactionBatch::new();
+        txn.put(node_key(&[1]), vec![10]);
+        txn.delete(node_key(&[2]));
+        txn.put(node_key(&[3]), vec![3]);
+        txn.put(vec![RootPrefix::HubEvents as u8, 1], vec![1]);
+
+        let writes = trie_node_writes(&txn);
+        assert_eq!(writes.len(), 3);
+        cache.apply_committed(writes);
+
+        assert_eq!(cache.get(&node_key(&[1])), Some(vec![10]));
+        assert_eq!(cache.get(&node_key(&[2])), None);
+        assert_eq!(cache.get(&node_key(&[3])), None);
+
+        // Values read before the commit are not cached
+        cache.insert(node_key(&[2]), vec![2], generation);
+        assert_eq!(cache.get(&node_key(&[2])), None);
+    }
+}
@@ -5,10 +5,11 @@ use super::super::{
 };
 use super::errors::TrieError;
 use super::merkle_trie::TrieSnapshot;
+use super::node_cache::TrieNodeCache;
 use crate::proto::DbTrieNode;
 use prost::Message as _;
 use std::collections::HashMap;
-use std::sync::atomic;
+use std::sync::{atomic, OnceLock};
 
 // TODO: remove or reduce this and/or rename (make sure it works under all branching factors)
 pub const TIMESTAMP_LENGTH: usize = 10;
@@ -19,6 +20,8 @@ const MAX_VALUES_RETURNED_PER_CALL: usize = 1024;
 pub struct Context<'a> {
     db_read_count: atomic::AtomicU64,
     on_drop: Option<Box<dyn FnOnce(u64) + 'a>>,
+    // Set by the MerkleTrie the context is used with, if it has a node cache
+    node_cache: OnceLock<TrieNodeCache>,
 }
 
 impl<'a> Context<'a> {
@@ -26,6 +29,7 @@ impl<'a> Context<'a> {
         Self {
             db_read_count: atomic::AtomicU64::new(0),
             on_drop: None,
+            node_cache: OnceLock::new(),
         }
     }
 
@@ -36,6 +40,7 @@ impl<'a> Context<'a> {
         Self {
             db_read_count: atomic::AtomicU64::new(0),
             on_drop: Some(Box::new(callback)),
+            node_cache: OnceLock::new(),
         }
     }
 
@@ -46,6 +51,15 @@ impl<'a> Context<'a> {
     pub fn read_count(&self) -> u64 {
         self.db_read_count.load(atomic::Ordering::Relaxed)
     }
+
+    pub(crate) fn set_node_cache(&self, node_cache: &TrieNodeCache) {
+        // A context is only used with a single trie, so if it's already set it's the same cache
+        let _ = self.node_cache.set(node_cache.clone());
+    }
+
+    pub(crate) fn node_cache(&self) -> Option<&TrieNodeCache> {
+        self.node_cache.get()
+    }
 }
 
 impl<'a> Drop for Context<'a> {
@@ -151,7 +165,6 @@ impl TrieNode {
         self.hash.clone()
     }
 
-    #[cfg(test)]
     pub fn value(&self) -> Option<Vec<u8>> {
         // Value is only defined for leaf nodes
         if self.is_leaf() {
@@ -493,15 +506,11 @@ impl TrieNode {
             Entry::Occupied(mut entry) => {
                 if let TrieNodeType::Serialized(_) = entry.get_mut() {
                     let child_prefix = Self::make_primary_key(prefix, Some(char));
-                    let child_node = db
-                        .get(&child_prefix)
-                        .map_err(TrieError::wrap_database)?
+                    let child_node = Self::load_node_bytes(ctx, db, &child_prefix)?
                         .map(|b| TrieNode::deserialize(&b).unwrap())
                         .unwrap_or_default();
 
                     *entry.get_mut() = TrieNodeType::Node(child_node);
-
-                    ctx.db_read_count.fetch_add(1, atomic::Ordering::Relaxed);
                 }
                 match entry.into_mut() {
                     TrieNodeType::Node(node) => Ok(node),
@@ -515,6 +524,28 @@ impl TrieNode {
         }
     }
 
+    // Reads a serialized node from the node cache if there is one, falling back to the db
+    fn load_node_bytes(
+        ctx: &Context,
+        db: &RocksDB,
+        node_key: &[u8],
+    ) -> Result<Option<Vec<u8>>, TrieError> {
+        let node_cache = ctx.node_cache();
+        if let Some(bytes) = node_cache.and_then(|cache| cache.get(node_key)) {
+            return Ok(Some(bytes));
+        }
+
+        let generation = node_cache.map(|cache| cache.generation());
+        let bytes = db.get(node_key).map_err(TrieError::wrap_database)?;
+        ctx.db_read_count.fetch_add(1, atomic::Ordering::Relaxed);
+
+        if let (Some(cache), Some(generation), Some(bytes)) = (node_cache, generation, &bytes) {
+            cache.insert(node_key.to_vec(), bytes.clone(), generation);
+        }
+
+        Ok(bytes)
+    }
+
     fn update_hash(&mut self, ctx: &Context, db: &RocksDB, prefix: &[u8]) -> Result<(), TrieError> {
         if self.is_leaf() {
             self.hash = blake3_20(&self.key.as_ref().unwrap_or(&vec![]));
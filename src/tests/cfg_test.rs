@@ -32,9 +32,11 @@ impl Drop for EnvRestorer {
 mod tests {
     use super::*;
     use crate::cfg::load_and_merge_config;
+    use malachite_config::TimeoutConfig;
     use serial_test::serial; // for setting env vars
     use std::fs::File;
     use std::io::Write;
+    use std::time::Duration;
     use tempfile::{tempdir, TempDir};
 
     fn run_test<T>(envs: Vec<Env>, test: T)
@@ -205,6 +207,53 @@ mod tests {
         )
     }
 
+    #[test]
+    #[serial]
+    fn test_consensus_timeouts_config() {
+        run_test(
+            vec![set("SNAPCHAIN_CONSENSUS__TIMEOUTS__COMMIT", "500ms")],
+            || {
+                let (_tmpdir, file_path) = write_config_file(
+                    r#"
+                [consensus]
+                wait_for_messages = true
+                max_propose_delay = "2s"
+
+                [consensus.timeouts]
+                propose = "4s"
+
+                [consensus.shard_timeouts.2]
+                prevote = "3s"
+            "#,
+                );
+
+                let args = vec![
+                    "test_binary".to_string(),
+                    "--config-path".to_string(),
+                    file_path.to_string(),
+                ];
+
+                let config = load_and_merge_config(args).expect("Failed to load config");
+                assert_eq!(
+                    config.consensus.max_propose_delay(),
+                    Some(Duration::from_secs(2))
+                );
+
+                let defaults = TimeoutConfig::default();
+                let shard1 = config.consensus.timeout_config(1);
+                assert_eq!(shard1.timeout_propose, Duration::from_secs(4));
+                assert_eq!(shard1.timeout_commit, Duration::from_millis(500));
+                assert_eq!(shard1.timeout_prevote, defaults.timeout_prevote);
+
+                // A shard's own timeouts replace the shared ones entirely
+                let shard2 = config.consensus.timeout_config(2);
+                assert_eq!(shard2.timeout_prevote, Duration::from_secs(3));
+                assert_eq!(shard2.timeout_propose, defaults.timeout_propose);
+                assert_eq!(shard2.timeout_commit, defaults.timeout_commit);
+            },
+        )
+    }
+
     #[test]
     #[serial]
     fn test_missing_config_file() {
@@ -0,0 +1,289 @@
+use crate::proto::hub_service_client::HubServiceClient;
+use crate::proto::{ShardChunk, ShardChunkRangeRequest, ShardChunksRequest, TrieNodePrefix};
+use crate::storage::db::RocksDB;
+use crate::storage::store::engine::{ChunkDivergence, ShardEngine};
+use crate::storage::store::stores::{StoreLimits, Stores};
+use crate::storage::trie::merkle_trie::{MerkleTrie, TrieKey};
+use crate::utils::statsd_wrapper::StatsdClientWrapper;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::sync::Arc;
+use tonic::transport::Channel;
+
+// Chunks are fetched in ranges of this many blocks
+const BLOCKS_PER_REQUEST: u64 = 100;
+
+/// Where the chunks to replay are read from. A node's db can only be opened by one process, so
+/// reading it directly requires the node to be stopped (or a copy of the db).
+pub enum ChunkSource {
+    Db(Stores),
+    Rpc(HubServiceClient<Channel>),
+}
+
+impl ChunkSource {
+    pub fn open_db(path: &str, trie_branching_factor: u32) -> Result<Self, Box<dyn Error>> {
+        let db = RocksDB::new(path);
+        db.open()?;
+        let trie = MerkleTrie::new(trie_branching_factor)?;
+        Ok(ChunkSource::Db(Stores::new(
+            Arc::new(db),
+            trie,
+            StoreLimits::default(),
+        )))
+    }
+
+    pub async fn connect(addr: String) -> Result<Self, Box<dyn Error>> {
+        Ok(ChunkSource::Rpc(HubServiceClient::connect(addr).await?))
+    }
+
+    // Block numbers of the first and last chunks the source has, None if it has none. There can be
+    // gaps in between (e.g. blocks without a chunk for the shard).
+    async fn get_chunk_range(
+        &mut self,
+        shard_id: u32,
+    ) -> Result<Option<(u64, u64)>, Box<dyn Error>> {
+        let (first, last) = match self {
+            ChunkSource::Db(stores) => (
+                stores
+                    .shard_store
+                    .get_first_shard_chunk()?
+                    .map(|chunk| block_number(&chunk)),
+                stores
+                    .shard_store
+                    .get_last_shard_chunk()?
+                    .map(|chunk| block_number(&chunk)),
+            ),
+            ChunkSource::Rpc(client) => {
+                let range = client
+                    .get_shard_chunk_range(ShardChunkRangeRequest { shard_id })
+                    .await?
+                    .into_inner();
+                (range.first_block_number, range.last_block_number)
+            }
+        };
+        Ok(first.zip(last))
+    }
+
+    // Stop is exclusive
+    async fn get_shard_chunks(
+        &mut self,
+        shard_id: u32,
+        start_block_number: u64,
+        stop_block_number: u64,
+    ) -> Result<Vec<ShardChunk>, Box<dyn Error>> {
+        match self {
+            ChunkSource::Db(stores) => Ok(stores
+                .shard_store
+                .get_shard_chunks(start_block_number, Some(stop_block_number))?),
+            ChunkSource::Rpc(client) => {
+                let request = ShardChunksRequest {
+                    shard_id,
+                    start_block_number,
+                    stop_block_number: Some(stop_block_number),
+                };
+                Ok(client
+                    .get_shard_chunks(request)
+                    .await?
+                    .into_inner()
+                    .shard_chunks)
+            }
+        }
+    }
+
+    // As of the source's latest block
+    async fn get_trie_keys(
+        &mut self,
+        shard_id: u32,
+        path: Vec<u8>,
+    ) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+        match self {
+            ChunkSource::Db(stores) => Ok(stores.get_all_sync_ids(&path)?),
+            ChunkSource::Rpc(client) => {
+                let request = TrieNodePrefix {
+                    shard_id,
+                    prefix: path,
+                };
+                Ok(client
+                    .get_all_sync_ids_by_prefix(request)
+                    .await?
+                    .into_inner()
+                    .sync_ids)
+            }
+        }
+    }
+}
+
+pub struct ReplayOptions {
+    pub shard_id: u32,
+    // Inclusive, replays everything the source has if not set
+    pub stop_block_number: Option<u64>,
+    pub trie_branching_factor: u32,
+    pub parallel_replay: bool,
+}
+
+pub struct Divergence {
+    pub block_number: u64,
+    // Why the engine halted, it may have nothing to do with the chunk (e.g. a db error)
+    pub reason: String,
+    pub details: Option<ChunkDivergence>,
+    // Trie keys of the diverging fid that the source has but the replay doesn't, and vice versa.
+    // The source's keys are as of its latest block, so the diff also includes the fid's changes in
+    // the blocks listed in later_blocks_with_fid.
+    pub missing_trie_keys: Vec<Vec<u8>>,
+    pub unexpected_trie_keys: Vec<Vec<u8>>,
+    pub later_blocks_with_fid: Vec<u64>,
+}
+
+pub struct ReplayReport {
+    pub chunks_replayed: u64,
+    pub last_block_number: u64,
+    pub shard_root: Vec<u8>,
+    pub divergence: Option<Divergence>,
+}
+
+fn block_number(chunk: &ShardChunk) -> u64 {
+    chunk
+        .header
+        .as_ref()
+        .and_then(|header| header.height)
+        .map_or(0, |height| height.block_number)
+}
+
+fn new_engine(
+    options: &ReplayOptions,
+    dir: &tempfile::TempDir,
+) -> Result<ShardEngine, Box<dyn Error>> {
+    let statsd_client = StatsdClientWrapper::new(
+        cadence::StatsdClient::builder("", cadence::NopMetricSink {}).build(),
+        true,
+    );
+    let db = RocksDB::new(dir.path().join("replay").to_str().unwrap());
+    db.open()?;
+
+    let mut engine = ShardEngine::new(
+        Arc::new(db),
+        MerkleTrie::new(options.trie_branching_factor)?,
+        options.shard_id,
+        StoreLimits::default(),
+        statsd_client,
+        0, // Nothing is proposed
+    );
+    engine.set_parallel_replay(options.parallel_replay);
+    Ok(engine)
+}
+
+/// Replays the source's shard chunks into a fresh engine, in order, checking every account root
+/// and shard root on the way. Stops at the first chunk that doesn't replay to the same state.
+/// Replay starts from the source's first chunk, so a source that bootstrapped from a snapshot will
+/// diverge right away.
+pub async fn replay_chunks(
+    source: &mut ChunkSource,
+    options: &ReplayOptions,
+) -> Result<ReplayReport, Box<dyn Error>> {
+    let Some((first_block_number, last_block_number)) =
+        source.get_chunk_range(options.shard_id).await?
+    else {
+        return Err(format!("the source has no chunks for shard {}", options.shard_id).into());
+    };
+    let stop_block_number = options
+        .stop_block_number
+        .map_or(last_block_number, |stop| stop.min(last_block_number))
+        .saturating_add(1);
+
+    let dir = tempfile::TempDir::new()?;
+    let mut engine = new_engine(options, &dir)?;
+
+    let mut report = ReplayReport {
+        chunks_replayed: 0,
+        last_block_number: 0,
+        shard_root: engine.trie_root_hash(),
+        divergence: None,
+    };
+
+    let mut start_block_number = first_block_number;
+    while start_block_number < stop_block_number {
+        let stop = stop_block_number.min(start_block_number.saturating_add(BLOCKS_PER_REQUEST));
+        let chunks = source
+            .get_shard_chunks(options.shard_id, start_block_number, stop)
+            .await?;
+
+        for chunk in chunks {
+            engine.commit_shard_chunk(&chunk);
+            if let Some(halt) = engine.get_senders().health.halted() {
+                let divergence =
+                    describe_divergence(source, &mut engine, options, &chunk, halt.reason).await?;
+                report.divergence = Some(divergence);
+                return Ok(report);
+            }
+
+            report.chunks_replayed += 1;
+            report.last_block_number = block_number(&chunk);
+            report.shard_root = engine.trie_root_hash();
+        }
+        start_block_number = stop;
+    }
+
+    if report.chunks_replayed == 0 {
+        return Err(format!(
+            "no chunks replayed, the source's chunks start at block {}",
+            first_block_number
+        )
+        .into());
+    }
+    Ok(report)
+}
+
+async fn describe_divergence(
+    source: &mut ChunkSource,
+    engine: &mut ShardEngine,
+    options: &ReplayOptions,
+    chunk: &ShardChunk,
+    reason: String,
+) -> Result<Divergence, Box<dyn Error>> {
+    let mut divergence = Divergence {
+        block_number: block_number(chunk),
+        reason,
+        details: engine.find_divergence(chunk)?,
+        missing_trie_keys: vec![],
+        unexpected_trie_keys: vec![],
+        later_blocks_with_fid: vec![],
+    };
+
+    let (fid, replayed) = match &divergence.details {
+        Some(ChunkDivergence::Transaction { fid, trie_keys, .. }) => {
+            (*fid, trie_keys.iter().cloned().collect::<BTreeSet<_>>())
+        }
+        _ => return Ok(divergence),
+    };
+
+    let path = MerkleTrie::new(options.trie_branching_factor)?
+        .path_for_prefix(&TrieKey::for_fid(fid as u32));
+    let expected: BTreeSet<Vec<u8>> = source
+        .get_trie_keys(options.shard_id, path)
+        .await?
+        .into_iter()
+        .collect();
+    divergence.missing_trie_keys = expected.difference(&replayed).cloned().collect();
+    divergence.unexpected_trie_keys = replayed.difference(&expected).cloned().collect();
+
+    // The source may have committed more chunks since the replay started
+    let last_block_number = source
+        .get_chunk_range(options.shard_id)
+        .await?
+        .map_or(divergence.block_number, |(_, last)| last);
+    let mut start_block_number = divergence.block_number + 1;
+    while start_block_number <= last_block_number {
+        let stop = start_block_number + BLOCKS_PER_REQUEST;
+        let chunks = source
+            .get_shard_chunks(options.shard_id, start_block_number, stop)
+            .await?;
+        for chunk in chunks {
+            if chunk.transactions.iter().any(|txn| txn.fid == fid) {
+                divergence.later_blocks_with_fid.push(block_number(&chunk));
+            }
+        }
+        start_block_number = stop;
+    }
+
+    Ok(divergence)
+}
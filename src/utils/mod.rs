@@ -1,3 +1,4 @@
+pub mod chunk_replay;
 pub mod cli;
 pub mod factory;
 pub mod statsd_wrapper;
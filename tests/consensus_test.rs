@@ -1,15 +1,18 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeSet, HashMap};
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use hex;
 use libp2p::identity::ed25519::Keypair;
+use snapchain::consensus::genesis::{Genesis, GenesisValidator};
 use snapchain::network::server::MyHubService;
 use snapchain::node::snapchain_node::SnapchainNode;
 use snapchain::proto::hub_service_server::HubServiceServer;
 use snapchain::proto::Block;
 use snapchain::storage::db::{PageOptions, RocksDB};
 use snapchain::storage::store::BlockStore;
+use snapchain::storage::trie::node_cache::DEFAULT_TRIE_NODE_CACHE_BYTES;
 use snapchain::utils::factory::messages_factory;
 use snapchain::utils::statsd_wrapper::StatsdClientWrapper;
 use snapchain::{
@@ -25,7 +28,6 @@ use tracing_subscriber::EnvFilter;
 
 struct NodeForTest {
     keypair: Keypair,
-    num_shards: u32,
     node: SnapchainNode,
     gossip_rx: mpsc::Receiver<GossipEvent<SnapchainValidatorContext>>,
     grpc_addr: String,
@@ -52,8 +54,28 @@ fn make_tmp_path() -> String {
         .to_string()
 }
 
+fn make_genesis(keypairs: &[Keypair], num_shards: u32, base_grpc_port: u32) -> Genesis {
+    Genesis {
+        chain_id: "snapchain-test".to_string(),
+        network: "devnet".to_string(),
+        // Far enough in the future that the tests start the heights themselves
+        genesis_time: SystemTime::now() + time::Duration::from_secs(3600),
+        num_shards,
+        validators: keypairs
+            .iter()
+            .enumerate()
+            .map(|(i, keypair)| GenesisValidator {
+                public_key: hex::encode(keypair.public().to_bytes()),
+                voting_power: 1,
+                rpc_address: format!("0.0.0.0:{}", base_grpc_port + i as u32),
+            })
+            .collect(),
+    }
+}
+
 impl NodeForTest {
-    pub async fn create(keypair: Keypair, num_shards: u32, grpc_port: u32) -> Self {
+    pub async fn create(keypair: Keypair, genesis: &Genesis, grpc_port: u32) -> Self {
+        let num_shards = genesis.num_shards;
         let statsd_client = StatsdClientWrapper::new(
             cadence::StatsdClient::builder("", cadence::NopMetricSink {}).build(),
             true,
@@ -71,13 +93,15 @@ impl NodeForTest {
         let node = SnapchainNode::create(
             keypair.clone(),
             config,
-            None,
             gossip_tx,
             Some(block_tx),
             block_store.clone(),
             make_tmp_path(),
             statsd_client.clone(),
             16,
+            DEFAULT_TRIE_NODE_CACHE_BYTES,
+            HashMap::new(),
+            genesis,
         )
         .await;
 
@@ -116,6 +140,7 @@ impl NodeForTest {
                 grpc_shard_stores,
                 grpc_shard_senders,
                 statsd_client.clone(),
+                None,
             );
 
             let grpc_socket_addr: SocketAddr = addr.parse().unwrap();
@@ -133,7 +158,6 @@ impl NodeForTest {
 
         Self {
             keypair,
-            num_shards,
             node,
             gossip_rx,
             grpc_addr: grpc_addr.clone(),
@@ -155,17 +179,6 @@ impl NodeForTest {
         self.node.start_height(block_number);
     }
 
-    pub fn register_keypair(&self, keypair: Keypair, rpc_address: String) {
-        for i in 0..=self.num_shards {
-            self.cast(ConsensusMsg::RegisterValidator(SnapchainValidator::new(
-                SnapchainShard::new(i),
-                keypair.public().clone(),
-                Some(rpc_address.clone()),
-                0,
-            )));
-        }
-    }
-
     pub fn id(&self) -> String {
         self.node.id()
     }
@@ -201,39 +214,29 @@ impl NodeForTest {
 }
 
 pub struct TestNetwork {
+    genesis: Genesis,
     nodes: Vec<NodeForTest>,
 }
 
 impl TestNetwork {
     // These networks can be created in parallel, so make sure the base port is far enough part to avoid conflicts
     pub async fn create(num_nodes: u32, num_shards: u32, base_grpc_port: u32) -> Self {
+        let keypairs: Vec<Keypair> = (0..num_nodes).map(|_| Keypair::generate()).collect();
+        let genesis = make_genesis(&keypairs, num_shards, base_grpc_port);
+
         let mut nodes = Vec::new();
-        let mut keypairs = Vec::new();
-        for i in 0..num_nodes {
-            let keypair = Keypair::generate();
-            keypairs.push(keypair.clone());
-            let node = NodeForTest::create(keypair, num_shards, base_grpc_port + i).await;
+        for (i, keypair) in keypairs.into_iter().enumerate() {
+            let node = NodeForTest::create(keypair, &genesis, base_grpc_port + i as u32).await;
             nodes.push(node);
         }
-
-        // Register validators
-        for i in 0..num_nodes {
-            for keypair in keypairs.iter() {
-                nodes[i as usize]
-                    .register_keypair(keypair.clone(), format!("0.0.0.0:{}", base_grpc_port + i));
-            }
-        }
-        // Wait for the RegisterValidator message to be processed
+        // Wait for the validator sets to be loaded
         tokio::time::sleep(time::Duration::from_millis(200)).await;
 
-        Self { nodes }
+        Self { genesis, nodes }
     }
 
+    // Nodes that aren't in the genesis follow the network without voting
     fn add_node(&mut self, new_node: NodeForTest) {
-        for node in self.nodes.iter() {
-            new_node.register_keypair(node.keypair.clone(), node.grpc_addr.clone());
-            node.register_keypair(new_node.keypair.clone(), new_node.grpc_addr.clone());
-        }
         self.nodes.push(new_node)
     }
 
@@ -276,10 +279,10 @@ impl TestNetwork {
                                 ConsensusMsg::ReceivedSignedVote(vote.clone()),
                             );
                         }
-                        GossipEvent::BroadcastFullProposal(full_proposal) => {
+                        GossipEvent::BroadcastProposalPart(part) => {
                             self.dispatch_to_other_nodes(
                                 i,
-                                ConsensusMsg::ReceivedFullProposal(full_proposal.clone()),
+                                ConsensusMsg::ReceivedProposalPart(part.clone()),
                             );
                         }
                         _ => {}
@@ -419,8 +422,7 @@ async fn test_basic_sync() {
         );
     }
 
-    let node4 = NodeForTest::create(keypair4.clone(), num_shards, 3227).await;
-    node4.register_keypair(keypair4.clone(), format!("0.0.0.0:{}", 3227));
+    let node4 = NodeForTest::create(keypair4.clone(), &network.genesis, 3227).await;
     node4.cast(ConsensusMsg::RegisterValidator(SnapchainValidator::new(
         SnapchainShard::new(0),
         network.nodes[0].keypair.public().clone(),